@@ -22,6 +22,7 @@ use futures::prelude::*;
 use muxing::{Shutdown, StreamMuxer};
 use std::io::{Error as IoError, Read, Write};
 use tokio_io::{AsyncRead, AsyncWrite};
+use void::{self, Void};
 use Multiaddr;
 
 /// Implements `AsyncRead` and `AsyncWrite` and dispatches all method calls to
@@ -32,6 +33,28 @@ pub enum EitherOutput<A, B> {
     Second(B),
 }
 
+impl<B> EitherOutput<Void, B> {
+    /// Returns the content of `Second`. There can't be a `First` since `Void` has no value.
+    #[inline]
+    pub fn into_second(self) -> B {
+        match self {
+            EitherOutput::First(a) => void::unreachable(a),
+            EitherOutput::Second(b) => b,
+        }
+    }
+}
+
+impl<A> EitherOutput<A, Void> {
+    /// Returns the content of `First`. There can't be a `Second` since `Void` has no value.
+    #[inline]
+    pub fn into_first(self) -> A {
+        match self {
+            EitherOutput::First(a) => a,
+            EitherOutput::Second(b) => void::unreachable(b),
+        }
+    }
+}
+
 impl<A, B> AsyncRead for EitherOutput<A, B>
 where
     A: AsyncRead,
@@ -26,7 +26,7 @@ use nodes::handled_node_tasks::{HandledNodesEvent, HandledNodesTasks};
 use nodes::handled_node_tasks::{Task as HandledNodesTask, TaskId};
 use nodes::handled_node::NodeHandler;
 use std::{collections::hash_map::Entry, fmt, io, mem};
-use PeerId;
+use {Endpoint, Multiaddr, PeerId};
 
 // TODO: make generic over PeerId
 
@@ -273,7 +273,11 @@ impl<TInEvent, TOutEvent, THandler> CollectionStream<TInEvent, TOutEvent, THandl
     ///
     /// This method spawns a task dedicated to resolving this future and processing the node's
     /// events.
-    pub fn add_reach_attempt<TFut, TMuxer>(&mut self, future: TFut, handler: THandler)
+    ///
+    /// `endpoint` and `remote_addr` describe how the node is being reached. See
+    /// `HandledNodesTasks::add_reach_attempt`.
+    pub fn add_reach_attempt<TFut, TMuxer>(&mut self, future: TFut, handler: THandler,
+                                           endpoint: Endpoint, remote_addr: Multiaddr)
         -> ReachAttemptId
     where
         TFut: Future<Item = (PeerId, TMuxer), Error = io::Error> + Send + 'static,
@@ -284,7 +288,7 @@ impl<TInEvent, TOutEvent, THandler> CollectionStream<TInEvent, TOutEvent, THandl
         TMuxer: StreamMuxer + Send + Sync + 'static,  // TODO: Send + Sync + 'static shouldn't be required
         TMuxer::OutboundSubstream: Send + 'static,  // TODO: shouldn't be required
     {
-        let id = self.inner.add_reach_attempt(future, handler);
+        let id = self.inner.add_reach_attempt(future, handler, endpoint, remote_addr);
         self.tasks.insert(id, TaskState::Pending);
         ReachAttemptId(id)
     }
@@ -22,6 +22,7 @@ use muxing::StreamMuxer;
 use nodes::node::{NodeEvent, NodeStream, Substream};
 use futures::{prelude::*, stream::Fuse};
 use std::io::Error as IoError;
+use {Endpoint, Multiaddr, PeerId};
 
 /// Handler for the substreams of a node.
 // TODO: right now it is possible for a node handler to be built, then shut down right after if we
@@ -65,6 +66,15 @@ pub trait NodeHandler {
     /// Injects an event coming from the outside into the handler.
     fn inject_event(&mut self, event: Self::InEvent);
 
+    /// Passes information about the connection to the handler. Called once, when the node has
+    /// been reached, before any other method.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        let _ = info;
+    }
+
     /// Indicates to the node that it should shut down. After that, it is expected that `poll()`
     /// returns `Ready(None)` as soon as possible.
     ///
@@ -77,6 +87,48 @@ pub trait NodeHandler {
     fn poll(&mut self) -> Poll<Option<NodeHandlerEvent<Self::OutboundOpenInfo, Self::OutEvent>>, IoError>;
 }
 
+/// Information about the connection with a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Identity of the remote.
+    peer_id: PeerId,
+    /// Whether we dialed the remote or the remote dialed us.
+    endpoint: Endpoint,
+    /// Address of the remote. See `remote_addr()`.
+    remote_addr: Multiaddr,
+}
+
+impl ConnectionInfo {
+    /// Builds a `ConnectionInfo`.
+    #[inline]
+    pub fn new(peer_id: PeerId, endpoint: Endpoint, remote_addr: Multiaddr) -> Self {
+        ConnectionInfo {
+            peer_id,
+            endpoint,
+            remote_addr,
+        }
+    }
+
+    /// Returns the identity of the remote.
+    #[inline]
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Returns whether we dialed the remote or the remote dialed us.
+    #[inline]
+    pub fn endpoint(&self) -> Endpoint {
+        self.endpoint
+    }
+
+    /// Returns the address of the remote: the address that we dialed if we are the dialer, or
+    /// the address to send back data to if we are the listener.
+    #[inline]
+    pub fn remote_addr(&self) -> &Multiaddr {
+        &self.remote_addr
+    }
+}
+
 /// Endpoint for a received substream.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum NodeHandlerEndpoint<TOutboundOpenInfo> {
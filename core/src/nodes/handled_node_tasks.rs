@@ -22,14 +22,14 @@ use fnv::FnvHashMap;
 use futures::{prelude::*, stream, sync::mpsc};
 use muxing::StreamMuxer;
 use nodes::node::Substream;
-use nodes::handled_node::{HandledNode, NodeHandler};
+use nodes::handled_node::{ConnectionInfo, HandledNode, NodeHandler};
 use smallvec::SmallVec;
 use std::collections::hash_map::{Entry, OccupiedEntry};
 use std::io::Error as IoError;
 use std::{fmt, mem};
 use tokio_executor;
 use void::Void;
-use PeerId;
+use {Endpoint, Multiaddr, PeerId};
 
 // TODO: make generic over PeerId
 
@@ -134,7 +134,12 @@ impl<TInEvent, TOutEvent, THandler> HandledNodesTasks<TInEvent, TOutEvent, THand
     ///
     /// This method spawns a task dedicated to resolving this future and processing the node's
     /// events.
-    pub fn add_reach_attempt<TFut, TMuxer>(&mut self, future: TFut, handler: THandler)
+    ///
+    /// `endpoint` and `remote_addr` describe how the node is being reached, and are passed to the
+    /// handler along with the identity of the node once it has been reached. See
+    /// `ConnectionInfo`.
+    pub fn add_reach_attempt<TFut, TMuxer>(&mut self, future: TFut, handler: THandler,
+                                           endpoint: Endpoint, remote_addr: Multiaddr)
         -> TaskId
     where
         TFut: Future<Item = (PeerId, TMuxer), Error = IoError> + Send + 'static,
@@ -155,6 +160,8 @@ impl<TInEvent, TOutEvent, THandler> HandledNodesTasks<TInEvent, TOutEvent, THand
             inner: NodeTaskInner::Future {
                 future,
                 handler,
+                endpoint,
+                remote_addr,
                 events_buffer: Vec::new(),
             },
             events_tx: self.events_tx.clone(),
@@ -332,6 +339,10 @@ where
         future: TFut,
         /// The handler that will be used to build the `HandledNode`.
         handler: THandler,
+        /// Whether we are dialing the node or the node dialed us.
+        endpoint: Endpoint,
+        /// Address of the node, passed to the handler in the `ConnectionInfo`.
+        remote_addr: Multiaddr,
         /// While we are dialing the future, we need to buffer the events received on
         /// `in_events_rx` so that they get delivered once dialing succeeds. We can't simply leave
         /// events in `in_events_rx` because we have to detect if it gets closed.
@@ -359,7 +370,13 @@ where
         loop {
             match mem::replace(&mut self.inner, NodeTaskInner::Poisoned) {
                 // First possibility: we are still trying to reach a node.
-                NodeTaskInner::Future { mut future, handler, mut events_buffer } => {
+                NodeTaskInner::Future {
+                    mut future,
+                    mut handler,
+                    endpoint,
+                    remote_addr,
+                    mut events_buffer,
+                } => {
                     // If self.in_events_rx is closed, we stop the task.
                     loop {
                         match self.in_events_rx.poll() {
@@ -373,6 +390,8 @@ where
                     // Check whether dialing succeeded.
                     match future.poll() {
                         Ok(Async::Ready((peer_id, muxer))) => {
+                            let info = ConnectionInfo::new(peer_id.clone(), endpoint, remote_addr);
+                            handler.inject_connection_info(info);
                             let event = InToExtMessage::NodeReached(peer_id);
                             let mut node = HandledNode::new(muxer, handler);
                             for event in events_buffer {
@@ -384,7 +403,13 @@ where
                             self.inner = NodeTaskInner::Node(node);
                         }
                         Ok(Async::NotReady) => {
-                            self.inner = NodeTaskInner::Future { future, handler, events_buffer };
+                            self.inner = NodeTaskInner::Future {
+                                future,
+                                handler,
+                                endpoint,
+                                remote_addr,
+                                events_buffer,
+                            };
                             return Ok(Async::NotReady);
                         },
                         Err(err) => {
@@ -450,3 +475,53 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::oneshot;
+    use rand::random;
+    use std::io;
+    use tests::dummy_handler::{Event, Handler};
+    use tests::dummy_muxer::DummyMuxer;
+    use tokio_mock_task::MockTask;
+    use PublicKey;
+
+    #[test]
+    fn connection_info_is_injected_before_buffered_events() {
+        let peer_id = PublicKey::Rsa((0 .. 2048).map(|_| -> u8 { random() }).collect())
+            .into_peer_id();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        let (reach_tx, reach_rx) = oneshot::channel();
+        let (in_events_tx, in_events_rx) = mpsc::unbounded();
+        let (events_tx, _events_rx) = mpsc::unbounded();
+        let mut node_task: NodeTask<_, DummyMuxer, Handler, Event, Event> = NodeTask {
+            events_tx,
+            in_events_rx: in_events_rx.fuse(),
+            inner: NodeTaskInner::Future {
+                future: reach_rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "cancelled")),
+                handler: Handler::default(),
+                endpoint: Endpoint::Dialer,
+                remote_addr: addr.clone(),
+                events_buffer: Vec::new(),
+            },
+            id: TaskId(0),
+        };
+
+        let mut task = MockTask::new();
+        // The event is buffered while the node is being reached.
+        in_events_tx.unbounded_send(Event::Custom("banana")).unwrap();
+        assert_matches!(task.enter(|| node_task.poll()), Ok(Async::NotReady));
+
+        reach_tx.send((peer_id.clone(), DummyMuxer::new())).unwrap();
+        assert_matches!(task.enter(|| node_task.poll()), Ok(Async::NotReady));
+
+        let info = ConnectionInfo::new(peer_id, Endpoint::Dialer, addr);
+        match node_task.inner {
+            NodeTaskInner::Node(ref node) => assert_eq!(node.handler().events, vec![
+                Event::ConnectionInfo(info), Event::Custom("banana")
+            ]),
+            _ => panic!("the node should have been reached"),
+        }
+    }
+}
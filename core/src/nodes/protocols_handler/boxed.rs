@@ -0,0 +1,461 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{any::Any, fmt, io};
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{self, BoxUpgrade};
+use {ConnectionUpgrade, Endpoint};
+
+/// Protocol of a `BoxProtocolsHandler`. The output of the upgrade is boxed as well.
+pub type BoxProtocol<TSubstream> = BoxUpgrade<TSubstream, Box<Any + Send>>;
+
+/// Implementation of `ProtocolsHandler` that erases the type of the handler it wraps. Only the
+/// type of the substream and of the events remain.
+///
+/// This makes it possible, for example, to pick between handlers of different types at runtime.
+/// Create one with `ProtocolsHandler::boxed()`.
+///
+/// The protocol is turned into a `BoxProtocol` and the information of the outbound substream
+/// requests into a `Box<Any + Send>`. This imposes the following constraints on the handler:
+///
+/// - The handler, its protocol, the future and the output of the protocol and the information
+///   of the outbound substream requests must all be `Send` and `'static`.
+/// - The protocol must be `Clone`, as required by `upgrade::boxed`.
+///
+/// The values that the `BoxProtocolsHandler` passes to the handler are downcast back to their
+/// original type. Passing a value that doesn't originate from the same handler, such as the
+/// information of an outbound substream request of another handler, results in a panic.
+pub struct BoxProtocolsHandler<TSubstream, TInEvent, TOutEvent> {
+    inner: Box<AbstractProtocolsHandler<TSubstream, TInEvent, TOutEvent> + Send>,
+}
+
+impl<TSubstream, TInEvent, TOutEvent> BoxProtocolsHandler<TSubstream, TInEvent, TOutEvent> {
+    /// Creates a `BoxProtocolsHandler`.
+    #[inline]
+    pub(crate) fn new<TProtoHandler>(inner: TProtoHandler) -> Self
+    where
+        TProtoHandler: ProtocolsHandler<
+                Substream = TSubstream,
+                InEvent = TInEvent,
+                OutEvent = TOutEvent,
+            > + Send
+            + 'static,
+        TProtoHandler::Protocol: Clone + Send + 'static,
+        <TProtoHandler::Protocol as ConnectionUpgrade<TSubstream>>::Future: Send + 'static,
+        <TProtoHandler::Protocol as ConnectionUpgrade<TSubstream>>::Output: Send + 'static,
+        TProtoHandler::OutboundOpenInfo: Send + 'static,
+    {
+        BoxProtocolsHandler {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl<TSubstream, TInEvent, TOutEvent> fmt::Debug
+    for BoxProtocolsHandler<TSubstream, TInEvent, TOutEvent>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("BoxProtocolsHandler").finish()
+    }
+}
+
+impl<TSubstream, TInEvent, TOutEvent> ProtocolsHandler
+    for BoxProtocolsHandler<TSubstream, TInEvent, TOutEvent>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    type InEvent = TInEvent;
+    type OutEvent = TOutEvent;
+    type Substream = TSubstream;
+    type Protocol = BoxProtocol<TSubstream>;
+    type OutboundOpenInfo = Box<Any + Send>;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: Box<Any + Send>,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), Box<Any + Send>> {
+        self.inner.inject_fully_negotiated(protocol, endpoint, None)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: Box<Any + Send>,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), Box<Any + Send>> {
+        self.inner.inject_fully_negotiated(protocol, endpoint, Some(timing))
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: TInEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = TInEvent>,
+    {
+        self.inner.inject_events(events.into_iter().collect())
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        self.inner.poll()
+    }
+}
+
+/// Object-safe equivalent of `ProtocolsHandler`, with the protocol and the information of the
+/// outbound substream requests boxed.
+trait AbstractProtocolsHandler<TSubstream, TInEvent, TOutEvent> {
+    fn listen_protocol(&self) -> BoxProtocol<TSubstream>;
+    fn listen_protocol_version(&self) -> Option<u64>;
+    fn should_accept_inbound(&self) -> bool;
+    fn should_open_outbound(&self, info: &Box<Any + Send>) -> bool;
+    /// Calls `inject_fully_negotiated_with_timing` if `timing` is `Some`, and
+    /// `inject_fully_negotiated` otherwise.
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: Box<Any + Send>,
+        endpoint: NodeHandlerEndpoint<Box<Any + Send>>,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), Box<Any + Send>>;
+    fn inject_event(&mut self, event: TInEvent);
+    fn inject_events(&mut self, events: Vec<TInEvent>);
+    fn ready_for_event(&self) -> bool;
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error>;
+    fn is_idle(&self) -> bool;
+    fn inject_connection_info(&mut self, info: ConnectionInfo);
+    fn inject_dial_upgrade_error(&mut self, info: Box<Any + Send>, error: ProtocolsHandlerUpgrErr);
+    fn inject_listen_upgrade_error(&mut self, error: io::Error);
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint);
+    fn inject_inbound_closed(&mut self);
+    fn inject_outbound_closed(&mut self);
+    fn inject_outbound_ready(&mut self, ready: bool);
+    fn shutdown(&mut self);
+    fn connection_keep_alive(&self) -> KeepAlive;
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<BoxProtocol<TSubstream>, Box<Any + Send>, TOutEvent>>,
+        io::Error,
+    >;
+}
+
+impl<TProtoHandler> AbstractProtocolsHandler<
+    TProtoHandler::Substream,
+    TProtoHandler::InEvent,
+    TProtoHandler::OutEvent,
+> for TProtoHandler
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::Protocol: Clone + Send + 'static,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::Future:
+        Send + 'static,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::Output:
+        Send + 'static,
+    TProtoHandler::OutboundOpenInfo: Send + 'static,
+{
+    #[inline]
+    fn listen_protocol(&self) -> BoxProtocol<TProtoHandler::Substream> {
+        box_protocol(ProtocolsHandler::listen_protocol(self))
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        ProtocolsHandler::listen_protocol_version(self)
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        ProtocolsHandler::should_accept_inbound(self)
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Box<Any + Send>) -> bool {
+        let info = info
+            .downcast_ref()
+            .expect("the information was produced by the same handler; qed");
+        ProtocolsHandler::should_open_outbound(self, info)
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: Box<Any + Send>,
+        endpoint: NodeHandlerEndpoint<Box<Any + Send>>,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), Box<Any + Send>> {
+        let protocol = *protocol
+            .downcast()
+            .expect("the protocol was produced by the same handler; qed");
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer(info) => NodeHandlerEndpoint::Dialer(*info
+                .downcast()
+                .expect("the information was produced by the same handler; qed")),
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+        let result = match timing {
+            Some(timing) => ProtocolsHandler::inject_fully_negotiated_with_timing(
+                self, protocol, endpoint, timing,
+            ),
+            None => ProtocolsHandler::inject_fully_negotiated(self, protocol, endpoint),
+        };
+        result.map_err(|protocol| Box::new(protocol) as Box<Any + Send>)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: TProtoHandler::InEvent) {
+        ProtocolsHandler::inject_event(self, event)
+    }
+
+    #[inline]
+    fn inject_events(&mut self, events: Vec<TProtoHandler::InEvent>) {
+        ProtocolsHandler::inject_events(self, events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        ProtocolsHandler::ready_for_event(self)
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        ProtocolsHandler::poll_ready_event(self)
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        ProtocolsHandler::is_idle(self)
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        ProtocolsHandler::inject_connection_info(self, info)
+    }
+
+    fn inject_dial_upgrade_error(&mut self, info: Box<Any + Send>, error: ProtocolsHandlerUpgrErr) {
+        let info = *info
+            .downcast()
+            .expect("the information was produced by the same handler; qed");
+        ProtocolsHandler::inject_dial_upgrade_error(self, info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        ProtocolsHandler::inject_listen_upgrade_error(self, error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        ProtocolsHandler::inject_protocol_negotiated(self, name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        ProtocolsHandler::inject_inbound_closed(self)
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        ProtocolsHandler::inject_outbound_closed(self)
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        ProtocolsHandler::inject_outbound_ready(self, ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        ProtocolsHandler::shutdown(self)
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        ProtocolsHandler::connection_keep_alive(self)
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<
+            ProtocolsHandlerEvent<
+                BoxProtocol<TProtoHandler::Substream>,
+                Box<Any + Send>,
+                TProtoHandler::OutEvent,
+            >,
+        >,
+        io::Error,
+    > {
+        let event = try_ready!(ProtocolsHandler::poll(self)).map(|event| {
+            event
+                .map_protocol(box_protocol)
+                .map_outbound_open_info(|info| Box::new(info) as Box<Any + Send>)
+        });
+        Ok(Async::Ready(event))
+    }
+}
+
+/// Boxes an upgrade and its output.
+#[inline]
+fn box_protocol<TSubstream, TUpgrade>(upgrade: TUpgrade) -> BoxProtocol<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone + Send + 'static,
+    TUpgrade::Future: Send + 'static,
+    TUpgrade::Output: Send + 'static,
+{
+    upgrade::boxed(upgrade::map(upgrade, box_output::<TUpgrade::Output>))
+}
+
+#[inline]
+fn box_output<T>(output: T) -> Box<Any + Send>
+where
+    T: Send + 'static,
+{
+    Box::new(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use std::io::Cursor;
+    use void::Void;
+
+    type Substream = Cursor<Vec<u8>>;
+
+    #[test]
+    fn handlers_of_different_types_can_be_boxed_together() {
+        let handlers: Vec<BoxProtocolsHandler<Substream, Void, Void>> = vec![
+            DummyProtocolsHandler::<Substream>::default().boxed(),
+            DummyProtocolsHandler::<Substream>::closing().dedup_outbound(|_: &Void| ()).boxed(),
+        ];
+
+        let finished = handlers
+            .into_iter()
+            .map(|mut handler| match ProtocolsHandler::poll(&mut handler).unwrap() {
+                Async::Ready(None) => true,
+                Async::NotReady => false,
+                Async::Ready(Some(_)) => panic!("unexpected event"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(finished, vec![false, true]);
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::{prelude::*, task};
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that holds back the events injected with `inject_event`
+/// until a first substream has been fully negotiated, in either direction.
+///
+/// The events are then passed to the handler in order, right after the negotiated substream.
+/// At most `capacity` events are buffered. What happens to the events beyond that is determined
+/// by the `BufferOverflow` policy. If the handler is shut down before any substream has been
+/// negotiated, the buffered events and the ones injected afterwards are discarded.
+pub struct BufferUntilReady<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Events waiting for the first substream. Always empty once `ready` is true.
+    buffer: VecDeque<TProtoHandler::InEvent>,
+    /// Maximum number of events in `buffer`.
+    capacity: usize,
+    /// What to do when `buffer` is full.
+    overflow: BufferOverflow,
+    /// If true, a substream has been negotiated and the events are no longer buffered.
+    ready: bool,
+    /// If true, the buffer has overflowed with the `BufferOverflow::Close` policy and we must
+    /// close the connection.
+    overflowed: bool,
+    /// If true, `shutdown()` has been called and the events are no longer buffered.
+    shutting_down: bool,
+    /// Task waiting in `poll_ready_event` for the buffer to be flushed.
+    blocked_task: Option<task::Task>,
+    /// Task that last polled the handler. Notified when the connection must be closed.
+    poll_task: Option<task::Task>,
+}
+
+/// What `BufferUntilReady` does with an event that doesn't fit in its buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BufferOverflow {
+    /// The event is discarded.
+    Drop,
+    /// The event is discarded and the connection is closed with an error.
+    Close,
+}
+
+impl<TProtoHandler> BufferUntilReady<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `BufferUntilReady`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, capacity: usize, overflow: BufferOverflow) -> Self {
+        BufferUntilReady {
+            inner,
+            buffer: VecDeque::new(),
+            capacity,
+            overflow,
+            ready: false,
+            overflowed: false,
+            shutting_down: false,
+            blocked_task: None,
+            poll_task: None,
+        }
+    }
+
+    /// Returns true if a substream has been negotiated and the events are passed directly to
+    /// the handler.
+    #[inline]
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Returns the number of events waiting for the first substream.
+    #[inline]
+    pub fn buffered_events(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Called after a substream has been accepted by the handler. Passes the buffered events to
+    /// the handler if it is the first one.
+    fn set_ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.inner.inject_events(self.buffer.drain(..));
+            self.unblock();
+        }
+    }
+
+    /// Notifies the task waiting in `poll_ready_event`, if any.
+    fn unblock(&mut self) {
+        if let Some(task) = self.blocked_task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for BufferUntilReady<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("BufferUntilReady")
+            .field("inner", &self.inner)
+            .field("buffered_events", &self.buffer.len())
+            .field("capacity", &self.capacity)
+            .field("overflow", &self.overflow)
+            .field("ready", &self.ready)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for BufferUntilReady<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)?;
+        self.set_ready();
+        Ok(())
+    }
+
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)?;
+        self.set_ready();
+        Ok(())
+    }
+
+    fn inject_event(&mut self, event: Self::InEvent) {
+        if self.ready {
+            self.inner.inject_event(event);
+        } else if self.shutting_down {
+            // No substream will be negotiated anymore.
+            debug!("Discarding an event injected during shutdown");
+        } else if self.buffer.len() < self.capacity {
+            self.buffer.push_back(event);
+        } else {
+            debug!("Event buffer full; discarding event");
+            if self.overflow == BufferOverflow::Close {
+                self.overflowed = true;
+                if let Some(task) = self.poll_task.take() {
+                    task.notify();
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        if self.ready {
+            self.inner.ready_for_event()
+        } else {
+            // The events are discarded once shutting down, so they don't need to be held back.
+            self.shutting_down || self.buffer.len() < self.capacity
+        }
+    }
+
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        if self.ready {
+            self.inner.poll_ready_event()
+        } else if self.shutting_down || self.buffer.len() < self.capacity {
+            Ok(Async::Ready(()))
+        } else {
+            self.blocked_task = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.buffer.is_empty() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // No substream will be negotiated anymore, so the buffered events would never be
+        // delivered.
+        if !self.buffer.is_empty() {
+            debug!("Discarding {} buffered events because of shutdown", self.buffer.len());
+            self.buffer.clear();
+        }
+        self.shutting_down = true;
+        self.unblock();
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if self.overflowed {
+            self.overflowed = false;
+            let err = io::Error::new(io::ErrorKind::Other, "buffer of pending events overflowed");
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))));
+        }
+
+        if !self.ready {
+            self.poll_task = Some(task::current());
+        }
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that accepts every substream and ignores the events injected in it.
+    struct PassiveHandler;
+
+    impl ProtocolsHandler for PassiveHandler {
+        type InEvent = u32;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: u32) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), ()>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    type TestBuffer = BufferUntilReady<TestProtocolsHandler<PassiveHandler>>;
+
+    fn buffer(capacity: usize, overflow: BufferOverflow) -> TestBuffer {
+        TestProtocolsHandler::new(PassiveHandler).buffer_until_ready(capacity, overflow)
+    }
+
+    #[test]
+    fn events_are_flushed_in_order_on_first_negotiation() {
+        let mut handler = buffer(3, BufferOverflow::Drop);
+        for event in 0..3 {
+            handler.inject_event(event);
+        }
+        assert!(handler.inner.calls().is_empty());
+        assert_eq!(handler.buffered_events(), 3);
+
+        handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).unwrap();
+        handler.inject_event(3);
+        assert!(handler.is_ready());
+        let expected = vec![
+            Call::FullyNegotiated(NodeHandlerEndpoint::Listener),
+            Call::Event(0),
+            Call::Event(1),
+            Call::Event(2),
+            Call::Event(3),
+        ];
+        assert_eq!(handler.inner.calls(), &expected[..]);
+    }
+
+    #[test]
+    fn overflowing_events_are_dropped() {
+        let mut handler = buffer(1, BufferOverflow::Drop);
+        let mut task = MockTask::new();
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        handler.inject_event(0);
+        handler.inject_event(1);
+        assert!(!task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+        handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).unwrap();
+        assert_eq!(handler.inner.calls().last(), Some(&Call::Event(0)));
+    }
+
+    #[test]
+    fn overflow_can_close_the_connection() {
+        let mut handler = buffer(1, BufferOverflow::Close);
+        let mut task = MockTask::new();
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        handler.inject_event(0);
+        assert!(!task.is_notified());
+        handler.inject_event(1);
+        assert!(task.is_notified());
+        match task.enter(|| handler.poll()).unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Close(_))) => (),
+            _ => panic!("expected the connection to be closed"),
+        }
+    }
+
+    #[test]
+    fn shutdown_unblocks_the_events_source() {
+        let mut handler = buffer(1, BufferOverflow::Drop);
+        let mut task = MockTask::new();
+        handler.inject_event(0);
+        assert!(task.enter(|| handler.poll_ready_event()).unwrap().is_not_ready());
+
+        handler.shutdown();
+        assert!(task.is_notified());
+        assert!(task.enter(|| handler.poll_ready_event()).unwrap().is_ready());
+
+        // The events injected from now on are discarded.
+        handler.inject_event(1);
+        assert_eq!(handler.buffered_events(), 0);
+        assert_eq!(handler.inner.calls(), &[Call::Shutdown][..]);
+    }
+}
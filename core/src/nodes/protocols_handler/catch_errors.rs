@@ -0,0 +1,331 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that passes the errors produced by `poll()` to a closure,
+/// which can turn them into events instead of closing the connection.
+///
+/// Once an error produced by `poll()` has been turned into an event, the handler is considered
+/// finished and isn't polled anymore. A `Close` event that has been turned into an event, on the
+/// other hand, doesn't stop the handler from being polled.
+pub struct CatchErrors<TProtoHandler, TCatch> {
+    inner: TProtoHandler,
+    on_error: TCatch,
+    /// True if `poll()` has produced an error that has been turned into an event.
+    failed: bool,
+}
+
+impl<TProtoHandler, TCatch> CatchErrors<TProtoHandler, TCatch> {
+    /// Creates an `CatchErrors`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, on_error: TCatch) -> Self {
+        CatchErrors { inner, on_error, failed: false }
+    }
+}
+
+impl<TProtoHandler, TCatch> fmt::Debug for CatchErrors<TProtoHandler, TCatch>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("CatchErrors")
+            .field("inner", &self.inner)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TCatch> ProtocolsHandler for CatchErrors<TProtoHandler, TCatch>
+where
+    TProtoHandler: ProtocolsHandler,
+    TCatch: FnMut(&io::Error) -> Option<TProtoHandler::OutEvent>,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        // A handler that has produced an error can't be polled again.
+        if self.failed {
+            return Ok(Async::Ready(None));
+        }
+
+        // Both the errors and `Close` events would close the connection.
+        let event = match self.inner.poll() {
+            Err(err) => match (self.on_error)(&err) {
+                Some(event) => {
+                    self.failed = true;
+                    ProtocolsHandlerEvent::Custom(event)
+                }
+                None => return Err(err),
+            },
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err)))) => {
+                match (self.on_error)(&err) {
+                    Some(event) => ProtocolsHandlerEvent::Custom(event),
+                    None => ProtocolsHandlerEvent::Close(err),
+                }
+            }
+            other => return other,
+        };
+        Ok(Async::Ready(Some(event)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that fails with the given kind at each call to `poll()`, either by producing an
+    /// error or a `Close` event. Panics if polled again after having produced an error.
+    struct FailingHandler {
+        kind: io::ErrorKind,
+        close: bool,
+        failed: bool,
+        polls: usize,
+    }
+
+    impl FailingHandler {
+        fn new(kind: io::ErrorKind, close: bool) -> Self {
+            FailingHandler { kind, close, failed: false, polls: 0 }
+        }
+    }
+
+    impl ProtocolsHandler for FailingHandler {
+        type InEvent = ();
+        type OutEvent = String;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: ()) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), String>>, io::Error>
+        {
+            assert!(!self.failed, "polled after an error");
+            self.polls += 1;
+            let err = io::Error::new(self.kind, "failure");
+            if self.close {
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))));
+            }
+            self.failed = true;
+            Err(err)
+        }
+    }
+
+    #[test]
+    fn caught_errors_become_events() {
+        let on_error = |err: &io::Error| match err.kind() {
+            io::ErrorKind::TimedOut => Some(err.to_string()),
+            _ => None,
+        };
+
+        let mut handler = FailingHandler::new(io::ErrorKind::TimedOut, false)
+            .catch_errors(on_error);
+        match handler.poll() {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(ref event)))) => {
+                assert_eq!(event, "failure")
+            }
+            _ => panic!("expected the error to be turned into an event"),
+        }
+        // The handler is finished once its error has been reported.
+        match handler.poll() {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to be finished"),
+        }
+        assert_eq!(handler.inner.polls, 1);
+
+        let mut handler = FailingHandler::new(io::ErrorKind::Other, false).catch_errors(on_error);
+        match handler.poll() {
+            Err(ref err) if err.kind() == io::ErrorKind::Other => (),
+            _ => panic!("expected the error to be propagated"),
+        }
+    }
+
+    #[test]
+    fn caught_close_events_dont_stop_the_handler() {
+        let on_error = |err: &io::Error| Some(err.to_string());
+        let mut handler = FailingHandler::new(io::ErrorKind::Other, true).catch_errors(on_error);
+        for _ in 0..3 {
+            match handler.poll() {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(ref event)))) => {
+                    assert_eq!(event, "failure")
+                }
+                _ => panic!("expected the close event to be turned into an event"),
+            }
+        }
+        assert_eq!(handler.inner.polls, 3);
+    }
+}
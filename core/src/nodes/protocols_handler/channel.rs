@@ -0,0 +1,238 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::{prelude::*, sync::mpsc};
+use nodes::handled_node::{NodeHandler, NodeHandlerEvent};
+use nodes::protocols_handler::{NodeHandlerWrapper, ProtocolsHandler};
+use std::{fmt, io};
+use ConnectionUpgrade;
+
+/// Builds a future that drives `handler` with the events received on `rx`, and sends the events
+/// that it produces on `tx`.
+///
+/// Each time the future is polled, all the events available on `rx` are injected in the handler
+/// with `inject_event`, then the handler is polled through a `NodeHandlerWrapper`. Since there is
+/// no connection, the outbound substream requests of the handler are answered as if the muxer
+/// was closed.
+///
+/// Once `rx` is closed, the handler is shut down. The future finishes once the handler has
+/// finished. If the receiving side of `tx` is dropped, the events produced afterwards are
+/// discarded.
+#[inline]
+pub fn channel_driver<TProtoHandler>(
+    handler: TProtoHandler,
+    rx: mpsc::Receiver<TProtoHandler::InEvent>,
+    tx: mpsc::Sender<TProtoHandler::OutEvent>,
+) -> ChannelDriver<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    ChannelDriver {
+        wrapper: handler.into_node_handler(),
+        rx: Some(rx),
+        tx: Some(tx),
+        pending: None,
+    }
+}
+
+/// Future returned by `channel_driver`.
+pub struct ChannelDriver<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The handler being driven.
+    wrapper: NodeHandlerWrapper<TProtoHandler>,
+    /// Where the events to inject come from. `None` once it has been closed.
+    rx: Option<mpsc::Receiver<TProtoHandler::InEvent>>,
+    /// Where to send the events produced by the handler. `None` once the receiving side has been
+    /// dropped.
+    tx: Option<mpsc::Sender<TProtoHandler::OutEvent>>,
+    /// Event produced by the handler that couldn't be sent yet because `tx` is full.
+    pending: Option<TProtoHandler::OutEvent>,
+}
+
+impl<TProtoHandler> ChannelDriver<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Sends an event on `tx`. Returns false if the channel is full, in which case the event is
+    /// stored in `pending` and the current task will be notified once there is room.
+    fn send(&mut self, event: TProtoHandler::OutEvent) -> bool {
+        let result = match self.tx {
+            Some(ref mut tx) => tx.start_send(event),
+            None => return true,
+        };
+
+        match result {
+            Ok(AsyncSink::Ready) => true,
+            Ok(AsyncSink::NotReady(event)) => {
+                self.pending = Some(event);
+                false
+            }
+            Err(_) => {
+                debug!("Events receiver dropped; discarding the events of the handler");
+                self.tx = None;
+                true
+            }
+        }
+    }
+}
+
+impl<TProtoHandler> Future for ChannelDriver<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::NamesIter: Clone,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        // The handler isn't polled as long as the previous event couldn't be sent.
+        if let Some(event) = self.pending.take() {
+            if !self.send(event) {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        loop {
+            let event = match self.rx {
+                Some(ref mut rx) => rx.poll(),
+                None => break,
+            };
+            match event {
+                Ok(Async::Ready(Some(event))) => self.wrapper.inject_event(event),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    debug!("Events channel closed; shutting down the handler");
+                    self.rx = None;
+                    self.wrapper.shutdown();
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        loop {
+            match try_ready!(NodeHandler::poll(&mut self.wrapper)) {
+                Some(NodeHandlerEvent::OutboundSubstreamRequest(id)) => {
+                    // There is nobody to open the substream.
+                    self.wrapper.inject_outbound_closed(id);
+                }
+                Some(NodeHandlerEvent::Custom(event)) => {
+                    if !self.send(event) {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for ChannelDriver<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ChannelDriver")
+            .field("wrapper", &self.wrapper)
+            .field("rx_closed", &self.rx.is_none())
+            .field("tx_closed", &self.tx.is_none())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::handled_node::NodeHandlerEndpoint;
+    use nodes::protocols_handler::{ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that produces the events injected in it, and finishes once shut down.
+    #[derive(Default)]
+    struct EchoHandler {
+        events: VecDeque<u32>,
+        shutting_down: bool,
+    }
+
+    impl ProtocolsHandler for EchoHandler {
+        type InEvent = u32;
+        type OutEvent = u32;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.events.push_back(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {
+            self.shutting_down = true;
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), u32>>, io::Error> {
+            match self.events.pop_front() {
+                Some(event) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))),
+                None if self.shutting_down => Ok(Async::Ready(None)),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn events_are_forwarded_until_the_channel_closes() {
+        let (mut in_tx, in_rx) = mpsc::channel(8);
+        let (out_tx, out_rx) = mpsc::channel(0);
+        for event in 0..5 {
+            in_tx.try_send(event).unwrap();
+        }
+        drop(in_tx);
+
+        // The output channel is smaller than the number of events, which requires reading it
+        // concurrently.
+        let driver = channel_driver(EchoHandler::default(), in_rx, out_tx);
+        let (events, ()) = out_rx
+            .collect()
+            .map_err(|()| io::Error::new(io::ErrorKind::Other, "channel error"))
+            .join(driver)
+            .wait()
+            .unwrap();
+        assert_eq!(events, vec![0, 1, 2, 3, 4]);
+    }
+}
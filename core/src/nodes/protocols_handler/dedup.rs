@@ -0,0 +1,388 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use fnv::FnvHashSet;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, hash::Hash, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that drops the outbound substream requests that are
+/// identical to a request that is still in progress.
+///
+/// Two requests are identical if the key extracted from their `OutboundOpenInfo` is the same.
+/// A request is in progress until its substream has been negotiated or has failed. Once that is
+/// the case, a new request with the same key goes through again.
+///
+/// The handler isn't notified about the requests that have been dropped.
+pub struct DedupOutbound<TProtoHandler, TKeyFn, TKey> {
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Extracts the key of a request from its `OutboundOpenInfo`.
+    key: TKeyFn,
+    /// Keys of the requests that are in progress.
+    in_flight: FnvHashSet<TKey>,
+}
+
+impl<TProtoHandler, TKeyFn, TKey> DedupOutbound<TProtoHandler, TKeyFn, TKey>
+where
+    TKey: Eq + Hash,
+{
+    /// Creates a `DedupOutbound`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, key: TKeyFn) -> Self {
+        DedupOutbound {
+            inner,
+            key,
+            in_flight: FnvHashSet::default(),
+        }
+    }
+
+    /// Returns the number of requests in progress.
+    #[inline]
+    pub fn num_in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+impl<TProtoHandler, TKeyFn, TKey> fmt::Debug for DedupOutbound<TProtoHandler, TKeyFn, TKey>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("DedupOutbound")
+            .field("inner", &self.inner)
+            .field("in_flight", &self.in_flight.len())
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TKeyFn, TKey> ProtocolsHandler for DedupOutbound<TProtoHandler, TKeyFn, TKey>
+where
+    TProtoHandler: ProtocolsHandler,
+    TKeyFn: Fn(&TProtoHandler::OutboundOpenInfo) -> TKey,
+    TKey: Eq + Hash,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        if let NodeHandlerEndpoint::Dialer(ref info) = endpoint {
+            self.in_flight.remove(&(self.key)(info));
+        }
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        if let NodeHandlerEndpoint::Dialer(ref info) = endpoint {
+            self.in_flight.remove(&(self.key)(info));
+        }
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.in_flight.remove(&(self.key)(&info));
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }) => {
+                    if !self.in_flight.insert((self.key)(&info)) {
+                        debug!("Dropping an outbound substream request identical to a pending one");
+                        continue;
+                    }
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                        upgrade,
+                        info,
+                        timeout,
+                        priority,
+                    })));
+                }
+                Some(ProtocolsHandlerEvent::CancelOutbound) => {
+                    // We don't know which requests get cancelled, and the handler won't hear
+                    // about them anymore. Forget about all of them; at worst, an identical
+                    // request goes through while one is still in progress.
+                    self.in_flight.clear();
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                }
+                Some(ProtocolsHandlerEvent::CloseOutbound) => {
+                    // None of the requests will be answered anymore.
+                    self.in_flight.clear();
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                }
+                event => return Ok(Async::Ready(event)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// What `ScriptedHandler` does when polled.
+    #[derive(Debug, Clone)]
+    enum Action {
+        Request(u32),
+        Cancel,
+        Close,
+    }
+
+    /// Handler that produces the event matching each action injected in it.
+    #[derive(Default)]
+    struct ScriptedHandler {
+        actions: VecDeque<Action>,
+    }
+
+    impl ProtocolsHandler for ScriptedHandler {
+        type InEvent = Action;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, action: Action) {
+            self.actions.push_back(action);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            let event = match self.actions.pop_front() {
+                Some(Action::Request(info)) => ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: DeniedConnectionUpgrade,
+                    info,
+                    timeout: None,
+                    priority: 0,
+                },
+                Some(Action::Cancel) => ProtocolsHandlerEvent::CancelOutbound,
+                Some(Action::Close) => ProtocolsHandlerEvent::CloseOutbound,
+                None => return Ok(Async::NotReady),
+            };
+            Ok(Async::Ready(Some(event)))
+        }
+    }
+
+    type TestDedup = DedupOutbound<ScriptedHandler, fn(&u32) -> u32, u32>;
+
+    fn dedup() -> TestDedup {
+        ScriptedHandler::default().dedup_outbound(|info: &u32| *info)
+    }
+
+    /// Injects `requests` and returns the information of the requests that go through.
+    fn requests(handler: &mut TestDedup, requests: &[u32]) -> Vec<u32> {
+        for request in requests {
+            handler.inject_event(Action::Request(*request));
+        }
+        let mut infos = Vec::new();
+        loop {
+            match handler.poll().unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info, ..
+                })) => infos.push(info),
+                Async::NotReady => return infos,
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn identical_requests_are_dropped_while_in_flight() {
+        let mut handler = dedup();
+        assert_eq!(requests(&mut handler, &[1, 2, 1]), vec![1, 2]);
+        assert_eq!(requests(&mut handler, &[2]), Vec::<u32>::new());
+        assert_eq!(handler.num_in_flight(), 2);
+    }
+
+    #[test]
+    fn answered_requests_free_their_key() {
+        let mut handler = dedup();
+        assert_eq!(requests(&mut handler, &[1, 2]), vec![1, 2]);
+
+        handler.inject_fully_negotiated((), NodeHandlerEndpoint::Dialer(1)).unwrap();
+        handler.inject_dial_upgrade_error(2, ProtocolsHandlerUpgrErr::Timeout);
+        assert_eq!(handler.num_in_flight(), 0);
+        assert_eq!(requests(&mut handler, &[1, 2]), vec![1, 2]);
+
+        // Inbound substreams don't affect the requests.
+        handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).unwrap();
+        assert_eq!(handler.num_in_flight(), 2);
+    }
+
+    #[test]
+    fn cancelling_or_closing_clears_the_requests() {
+        for action in vec![Action::Cancel, Action::Close] {
+            let mut handler = dedup();
+            assert_eq!(requests(&mut handler, &[1, 2]), vec![1, 2]);
+
+            handler.inject_event(action);
+            match handler.poll().unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound))
+                | Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)) => (),
+                _ => panic!("expected the event to be forwarded"),
+            }
+            assert_eq!(handler.num_in_flight(), 0);
+            assert_eq!(requests(&mut handler, &[1]), vec![1]);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::prelude::*;
+use nodes::handled_node::NodeHandlerEndpoint;
+use nodes::protocols_handler::{ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+use std::{fmt, io, marker::PhantomData};
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::DeniedConnectionUpgrade;
+use void::Void;
+use ConnectionUpgrade;
+
+/// Implementation of `ProtocolsHandler` that doesn't handle anything.
+pub struct DummyProtocolsHandler<TSubstream> {
+    shutting_down: bool,
+    marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream> Default for DummyProtocolsHandler<TSubstream> {
+    #[inline]
+    fn default() -> Self {
+        DummyProtocolsHandler {
+            shutting_down: false,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<TSubstream> DummyProtocolsHandler<TSubstream> {
+    /// Creates a `DummyProtocolsHandler` that is already shutting down. The first call to
+    /// `poll()` will produce `Ready(None)`.
+    ///
+    /// This is useful for denying a peer: the connection is closed as soon as the handler is
+    /// polled.
+    #[inline]
+    pub fn closing() -> Self {
+        DummyProtocolsHandler {
+            shutting_down: true,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<TSubstream> fmt::Debug for DummyProtocolsHandler<TSubstream> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("DummyProtocolsHandler")
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TSubstream> ProtocolsHandler for DummyProtocolsHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    type InEvent = Void;
+    type OutEvent = Void;
+    type Substream = TSubstream;
+    type Protocol = DeniedConnectionUpgrade;
+    type OutboundOpenInfo = Void;
+    const SINGLE_PROTOCOL: bool = true;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        DeniedConnectionUpgrade
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        // The protocol never changes.
+        Some(0)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        _: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_event(&mut self, _: Self::InEvent) {}
+
+    #[inline]
+    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: ProtocolsHandlerUpgrErr) {
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {}
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if self.shutting_down {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
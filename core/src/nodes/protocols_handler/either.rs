@@ -0,0 +1,476 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use either::EitherOutput;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    inject_negotiated, KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::io;
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{self, map::Map, OrUpgrade};
+use {ConnectionUpgrade, Endpoint};
+
+/// Implementation of `ProtocolsHandler` that is either one handler or another one, the choice
+/// being made at runtime.
+///
+/// Contrary to `ProtocolsHandlerSelect`, only one of the two handlers exists. All the methods
+/// are delegated to it, and events that target the other handler are ignored.
+#[derive(Debug, Clone)]
+pub enum EitherProtocolsHandler<TProto1, TProto2> {
+    /// The first handler is active.
+    First(TProto1),
+    /// The second handler is active.
+    Second(TProto2),
+}
+
+impl<TSubstream, TProto1, TProto2, TProto1Out, TProto2Out>
+    EitherProtocolsHandler<TProto1, TProto2>
+where
+    TProto1: ProtocolsHandler<Substream = TSubstream>,
+    TProto2: ProtocolsHandler<Substream = TSubstream>,
+    TProto1::Protocol: ConnectionUpgrade<TSubstream, Output = TProto1Out>,
+    TProto2::Protocol: ConnectionUpgrade<TSubstream, Output = TProto2Out>,
+{
+    /// Dispatches a fully-negotiated substream to the active handler.
+    fn dispatch_negotiated(
+        &mut self,
+        protocol: EitherOutput<TProto1Out, TProto2Out>,
+        endpoint: NodeHandlerEndpoint<
+            EitherOutput<TProto1::OutboundOpenInfo, TProto2::OutboundOpenInfo>,
+        >,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), EitherOutput<TProto1Out, TProto2Out>> {
+        match (self, protocol, endpoint) {
+            (
+                &mut EitherProtocolsHandler::First(ref mut proto),
+                EitherOutput::First(protocol),
+                NodeHandlerEndpoint::Dialer(EitherOutput::First(info)),
+            ) => {
+                let endpoint = NodeHandlerEndpoint::Dialer(info);
+                inject_negotiated(proto, protocol, endpoint, timing).map_err(EitherOutput::First)
+            }
+            (
+                &mut EitherProtocolsHandler::First(ref mut proto),
+                EitherOutput::First(protocol),
+                NodeHandlerEndpoint::Listener,
+            ) => {
+                let endpoint = NodeHandlerEndpoint::Listener;
+                inject_negotiated(proto, protocol, endpoint, timing).map_err(EitherOutput::First)
+            }
+            (
+                &mut EitherProtocolsHandler::Second(ref mut proto),
+                EitherOutput::Second(protocol),
+                NodeHandlerEndpoint::Dialer(EitherOutput::Second(info)),
+            ) => {
+                let endpoint = NodeHandlerEndpoint::Dialer(info);
+                inject_negotiated(proto, protocol, endpoint, timing).map_err(EitherOutput::Second)
+            }
+            (
+                &mut EitherProtocolsHandler::Second(ref mut proto),
+                EitherOutput::Second(protocol),
+                NodeHandlerEndpoint::Listener,
+            ) => {
+                let endpoint = NodeHandlerEndpoint::Listener;
+                inject_negotiated(proto, protocol, endpoint, timing).map_err(EitherOutput::Second)
+            }
+            _ => panic!("wrong API usage: the protocol doesn't match the active handler"),
+        }
+    }
+}
+
+/// Upgrade used by `EitherProtocolsHandler`. Only the side that corresponds to the active handler
+/// is `Some`.
+pub type EitherUpgrade<TProto1, TProto2, TProto1Out, TProto2Out> = OrUpgrade<
+    Option<Map<TProto1, fn(TProto1Out) -> EitherOutput<TProto1Out, TProto2Out>>>,
+    Option<Map<TProto2, fn(TProto2Out) -> EitherOutput<TProto1Out, TProto2Out>>>,
+>;
+
+impl<TSubstream, TProto1, TProto2, TProto1Out, TProto2Out>
+    ProtocolsHandler for EitherProtocolsHandler<TProto1, TProto2>
+where
+    TProto1: ProtocolsHandler<Substream = TSubstream>,
+    TProto2: ProtocolsHandler<Substream = TSubstream>,
+    TSubstream: AsyncRead + AsyncWrite,
+    TProto1::Protocol: ConnectionUpgrade<TSubstream, Output = TProto1Out>,
+    TProto2::Protocol: ConnectionUpgrade<TSubstream, Output = TProto2Out>,
+    TProto1Out: Send + 'static,
+    TProto2Out: Send + 'static,
+    <TProto1::Protocol as ConnectionUpgrade<TSubstream>>::Future: Send + 'static,
+    <TProto2::Protocol as ConnectionUpgrade<TSubstream>>::Future: Send + 'static,
+{
+    type InEvent = EitherOutput<TProto1::InEvent, TProto2::InEvent>;
+    type OutEvent = EitherOutput<TProto1::OutEvent, TProto2::OutEvent>;
+    type Substream = TSubstream;
+    type Protocol = EitherUpgrade<TProto1::Protocol, TProto2::Protocol, TProto1Out, TProto2Out>;
+    type OutboundOpenInfo = EitherOutput<TProto1::OutboundOpenInfo, TProto2::OutboundOpenInfo>;
+    const SINGLE_PROTOCOL: bool = TProto1::SINGLE_PROTOCOL && TProto2::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => {
+                let map = EitherOutput::First as fn(_) -> _;
+                upgrade::or(Some(upgrade::map(proto.listen_protocol(), map)), None)
+            }
+            EitherProtocolsHandler::Second(ref proto) => {
+                let map = EitherOutput::Second as fn(_) -> _;
+                upgrade::or(None, Some(upgrade::map(proto.listen_protocol(), map)))
+            }
+        }
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => proto.listen_protocol_version(),
+            EitherProtocolsHandler::Second(ref proto) => proto.listen_protocol_version(),
+        }
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => proto.should_accept_inbound(),
+            EitherProtocolsHandler::Second(ref proto) => proto.should_accept_inbound(),
+        }
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        match (self, info) {
+            (&EitherProtocolsHandler::First(ref proto), &EitherOutput::First(ref info)) => {
+                proto.should_open_outbound(info)
+            }
+            (&EitherProtocolsHandler::Second(ref proto), &EitherOutput::Second(ref info)) => {
+                proto.should_open_outbound(info)
+            }
+            // The request was produced by the handler that isn't active anymore.
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, None)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, Some(timing))
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match (self, event) {
+            (
+                &mut EitherProtocolsHandler::First(ref mut proto),
+                EitherOutput::First(event),
+            ) => {
+                proto.inject_event(event)
+            }
+            (
+                &mut EitherProtocolsHandler::Second(ref mut proto),
+                EitherOutput::Second(event),
+            ) => {
+                proto.inject_event(event)
+            }
+            // The event targets the handler that isn't active. Ignore it.
+            _ => (),
+        }
+    }
+
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        // The events that target the handler that isn't active are ignored.
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => {
+                proto.inject_events(events.into_iter().filter_map(|event| match event {
+                    EitherOutput::First(event) => Some(event),
+                    EitherOutput::Second(_) => None,
+                }))
+            }
+            EitherProtocolsHandler::Second(ref mut proto) => {
+                proto.inject_events(events.into_iter().filter_map(|event| match event {
+                    EitherOutput::First(_) => None,
+                    EitherOutput::Second(event) => Some(event),
+                }))
+            }
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => proto.ready_for_event(),
+            EitherProtocolsHandler::Second(ref proto) => proto.ready_for_event(),
+        }
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.poll_ready_event(),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.poll_ready_event(),
+        }
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => proto.is_idle(),
+            EitherProtocolsHandler::Second(ref proto) => proto.is_idle(),
+        }
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.inject_connection_info(info),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.inject_connection_info(info),
+        }
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        match (self, info) {
+            (
+                &mut EitherProtocolsHandler::First(ref mut proto),
+                EitherOutput::First(info),
+            ) => {
+                proto.inject_dial_upgrade_error(info, error)
+            }
+            (
+                &mut EitherProtocolsHandler::Second(ref mut proto),
+                EitherOutput::Second(info),
+            ) => {
+                proto.inject_dial_upgrade_error(info, error)
+            }
+            _ => panic!("wrong API usage: the upgrade info doesn't match the active handler"),
+        }
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => {
+                proto.inject_listen_upgrade_error(error)
+            }
+            EitherProtocolsHandler::Second(ref mut proto) => {
+                proto.inject_listen_upgrade_error(error)
+            }
+        }
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => {
+                proto.inject_protocol_negotiated(name, endpoint)
+            }
+            EitherProtocolsHandler::Second(ref mut proto) => {
+                proto.inject_protocol_negotiated(name, endpoint)
+            }
+        }
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.inject_inbound_closed(),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.inject_inbound_closed(),
+        }
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.inject_outbound_closed(),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.inject_outbound_closed(),
+        }
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.inject_outbound_ready(ready),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.inject_outbound_ready(ready),
+        }
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        match *self {
+            EitherProtocolsHandler::First(ref mut proto) => proto.shutdown(),
+            EitherProtocolsHandler::Second(ref mut proto) => proto.shutdown(),
+        }
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        match *self {
+            EitherProtocolsHandler::First(ref proto) => proto.connection_keep_alive(),
+            EitherProtocolsHandler::Second(ref proto) => proto.connection_keep_alive(),
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let event = match *self {
+            EitherProtocolsHandler::First(ref mut proto) => try_ready!(proto.poll())
+                .map(|event| {
+                    event
+                        .map_custom(EitherOutput::First)
+                        .map_outbound_open_info(EitherOutput::First)
+                        .map_protocol(|proto| {
+                            let proto = upgrade::map(proto, EitherOutput::First as fn(_) -> _);
+                            upgrade::or(Some(proto), None)
+                        })
+                }),
+            EitherProtocolsHandler::Second(ref mut proto) => try_ready!(proto.poll())
+                .map(|event| {
+                    event
+                        .map_custom(EitherOutput::Second)
+                        .map_outbound_open_info(EitherOutput::Second)
+                        .map_protocol(|proto| {
+                            let proto = upgrade::map(proto, EitherOutput::Second as fn(_) -> _);
+                            upgrade::or(None, Some(proto))
+                        })
+                }),
+        };
+
+        Ok(Async::Ready(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that produces the events injected in it, and finishes once shut down.
+    #[derive(Default)]
+    struct EchoHandler {
+        events: VecDeque<u32>,
+        shutting_down: bool,
+    }
+
+    impl ProtocolsHandler for EchoHandler {
+        type InEvent = u32;
+        type OutEvent = u32;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.events.push_back(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {
+            self.shutting_down = true;
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), u32>>, io::Error> {
+            match self.events.pop_front() {
+                Some(event) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))),
+                None if self.shutting_down => Ok(Async::Ready(None)),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_active_handler_is_called() {
+        let inner = TestProtocolsHandler::new(EchoHandler::default());
+        let mut handler: EitherProtocolsHandler<_, TestProtocolsHandler<EchoHandler>> =
+            EitherProtocolsHandler::First(inner);
+        handler.inject_event(EitherOutput::First(1));
+        handler.inject_event(EitherOutput::Second(2));
+
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Custom(EitherOutput::First(1)))) => (),
+            _ => panic!("expected the event of the first handler"),
+        }
+        // The active handler hasn't finished, so neither has the combined handler.
+        assert!(handler.poll().unwrap().is_not_ready());
+
+        handler.shutdown();
+        match handler.poll().unwrap() {
+            Async::Ready(None) => (),
+            _ => panic!("expected the handler to have finished"),
+        }
+
+        let calls = match handler {
+            EitherProtocolsHandler::First(ref inner) => inner.calls().to_vec(),
+            EitherProtocolsHandler::Second(_) => unreachable!(),
+        };
+        let expected = vec![
+            Call::Event(1),
+            Call::Poll,
+            Call::Poll,
+            Call::Shutdown,
+            Call::Poll,
+        ];
+        assert_eq!(calls, expected);
+    }
+}
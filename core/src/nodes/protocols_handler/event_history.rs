@@ -0,0 +1,312 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that remembers the last events injected in it and the last
+/// events it produced, for diagnostic purposes.
+pub struct EventHistory<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    inner: TProtoHandler,
+    /// Maximum number of events remembered in each direction.
+    capacity: usize,
+    /// The last events injected in the handler, oldest first.
+    in_events: VecDeque<TProtoHandler::InEvent>,
+    /// The last custom events produced by the handler, oldest first.
+    out_events: VecDeque<TProtoHandler::OutEvent>,
+}
+
+impl<TProtoHandler> EventHistory<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates an `EventHistory`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, capacity: usize) -> Self {
+        EventHistory {
+            inner,
+            capacity,
+            in_events: VecDeque::with_capacity(capacity),
+            out_events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the last events injected in the handler, oldest first.
+    #[inline]
+    pub fn recent_in_events(&self) -> &VecDeque<TProtoHandler::InEvent> {
+        &self.in_events
+    }
+
+    /// Returns the last custom events produced by the handler, oldest first.
+    #[inline]
+    pub fn recent_out_events(&self) -> &VecDeque<TProtoHandler::OutEvent> {
+        &self.out_events
+    }
+}
+
+/// Appends `event` to `history`, removing the oldest events so that it doesn't hold more than
+/// `capacity` of them.
+fn remember<T>(history: &mut VecDeque<T>, capacity: usize, event: T) {
+    if capacity == 0 {
+        return;
+    }
+    while history.len() >= capacity {
+        history.pop_front();
+    }
+    history.push_back(event);
+}
+
+impl<TProtoHandler> fmt::Debug for EventHistory<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+    TProtoHandler::InEvent: fmt::Debug,
+    TProtoHandler::OutEvent: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("EventHistory")
+            .field("inner", &self.inner)
+            .field("in_events", &self.in_events)
+            .field("out_events", &self.out_events)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for EventHistory<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::InEvent: Clone,
+    TProtoHandler::OutEvent: Clone,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        remember(&mut self.in_events, self.capacity, event.clone());
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        let history = &mut self.in_events;
+        let capacity = self.capacity;
+        self.inner.inject_events(events.into_iter().map(|event| {
+            remember(history, capacity, event.clone());
+            event
+        }))
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let event = try_ready!(self.inner.poll());
+        if let Some(ProtocolsHandlerEvent::Custom(ref event)) = event {
+            remember(&mut self.out_events, self.capacity, event.clone());
+        }
+        Ok(Async::Ready(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that produces the events injected in it, plus one.
+    #[derive(Default)]
+    struct IncrementHandler {
+        events: VecDeque<u32>,
+    }
+
+    impl ProtocolsHandler for IncrementHandler {
+        type InEvent = u32;
+        type OutEvent = u32;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.events.push_back(event + 1);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), u32>>, io::Error> {
+            match self.events.pop_front() {
+                Some(event) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_last_events_are_remembered() {
+        let mut handler = IncrementHandler::default().with_event_history(2);
+        handler.inject_event(10);
+        handler.inject_events(vec![20, 30]);
+        while let Async::Ready(Some(_)) = handler.poll().unwrap() {}
+
+        assert_eq!(handler.recent_in_events().iter().collect::<Vec<_>>(), vec![&20, &30]);
+        assert_eq!(handler.recent_out_events().iter().collect::<Vec<_>>(), vec![&21, &31]);
+    }
+}
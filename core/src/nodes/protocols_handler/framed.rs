@@ -0,0 +1,366 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::prelude::*;
+use nodes::handled_node::NodeHandlerEndpoint;
+use nodes::protocols_handler::{
+    KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io, marker::PhantomData};
+use tokio_io::{AsyncRead, AsyncWrite};
+use ConnectionUpgrade;
+
+/// Builds a `ProtocolsHandler` that exchanges messages on the substreams negotiated with
+/// `upgrade`, after `split` has turned each of them into a sink and a stream of messages.
+///
+/// The events injected in the handler are sent on the oldest open substream. If there is none,
+/// an outbound substream is opened first. The messages received on any of the substreams are
+/// produced as `FramedHandlerEvent::Message`, and each substream that gets closed produces a
+/// `FramedHandlerEvent::Closed`.
+#[inline]
+pub fn framed_handler<TSubstream, TUpgrade, TSplit, TSink, TStream>(
+    upgrade: TUpgrade,
+    split: TSplit,
+) -> FramedHandler<TSubstream, TUpgrade, TSplit, TSink, TStream>
+where
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TSplit: FnMut(TUpgrade::Output) -> (TSink, TStream),
+    TSink: Sink<SinkError = io::Error>,
+    TStream: Stream<Error = io::Error>,
+{
+    FramedHandler {
+        upgrade,
+        split,
+        substreams: Vec::new(),
+        send_queue: VecDeque::new(),
+        outbound_requested: false,
+        pending_events: VecDeque::new(),
+        shutting_down: false,
+        marker: PhantomData,
+    }
+}
+
+/// Event produced by a `FramedHandler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramedHandlerEvent<TMessage> {
+    /// A message has been received on one of the substreams.
+    Message(TMessage),
+    /// One of the substreams has been closed, either by the remote or because of an error.
+    Closed,
+}
+
+/// Implementation of `ProtocolsHandler` returned by `framed_handler`.
+pub struct FramedHandler<TSubstream, TUpgrade, TSplit, TSink, TStream>
+where
+    TSink: Sink,
+    TStream: Stream,
+{
+    /// The upgrade to apply to the substreams.
+    upgrade: TUpgrade,
+    /// Turns the output of the upgrade into a sink and a stream.
+    split: TSplit,
+    /// The open substreams, oldest first.
+    substreams: Vec<(TSink, TStream)>,
+    /// Messages injected in the handler and not sent yet.
+    send_queue: VecDeque<TSink::SinkItem>,
+    /// If true, we have requested an outbound substream and are waiting for it.
+    outbound_requested: bool,
+    /// Events to return from `poll()`.
+    pending_events: VecDeque<FramedHandlerEvent<TStream::Item>>,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream, TUpgrade, TSplit, TSink, TStream>
+    FramedHandler<TSubstream, TUpgrade, TSplit, TSink, TStream>
+where
+    TSink: Sink<SinkError = io::Error>,
+    TStream: Stream<Error = io::Error>,
+{
+    /// Removes a substream and reports its closing.
+    fn remove_substream(&mut self, n: usize) {
+        self.substreams.remove(n);
+        self.pending_events.push_back(FramedHandlerEvent::Closed);
+    }
+
+    /// Sends the queued messages on the oldest substream, and flushes all of them.
+    fn poll_send(&mut self) {
+        for n in (0..self.substreams.len()).rev() {
+            let result = {
+                let queue = if n == 0 { Some(&mut self.send_queue) } else { None };
+                send_and_flush(&mut self.substreams[n].0, queue)
+            };
+            if let Err(err) = result {
+                debug!("Error while sending on a framed substream: {}", err);
+                self.remove_substream(n);
+            }
+        }
+    }
+
+    /// Reads the messages received on all the substreams.
+    fn poll_receive(&mut self) {
+        for n in (0..self.substreams.len()).rev() {
+            loop {
+                match self.substreams[n].1.poll() {
+                    Ok(Async::Ready(Some(message))) => {
+                        self.pending_events.push_back(FramedHandlerEvent::Message(message));
+                    }
+                    Ok(Async::Ready(None)) => {
+                        self.remove_substream(n);
+                        break;
+                    }
+                    Ok(Async::NotReady) => break,
+                    Err(err) => {
+                        debug!("Error while receiving on a framed substream: {}", err);
+                        self.remove_substream(n);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Starts sending the messages of `queue`, if any, until `sink` is full, then flushes `sink`.
+fn send_and_flush<TSink>(
+    sink: &mut TSink,
+    queue: Option<&mut VecDeque<TSink::SinkItem>>,
+) -> Result<(), TSink::SinkError>
+where
+    TSink: Sink,
+{
+    if let Some(queue) = queue {
+        while let Some(message) = queue.pop_front() {
+            if let AsyncSink::NotReady(message) = sink.start_send(message)? {
+                queue.push_front(message);
+                break;
+            }
+        }
+    }
+    sink.poll_complete()?;
+    Ok(())
+}
+
+impl<TSubstream, TUpgrade, TSplit, TSink, TStream> ProtocolsHandler
+    for FramedHandler<TSubstream, TUpgrade, TSplit, TSink, TStream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TSplit: FnMut(TUpgrade::Output) -> (TSink, TStream),
+    TSink: Sink<SinkError = io::Error>,
+    TStream: Stream<Error = io::Error>,
+{
+    type InEvent = TSink::SinkItem;
+    type OutEvent = FramedHandlerEvent<TStream::Item>;
+    type Substream = TSubstream;
+    type Protocol = TUpgrade;
+    type OutboundOpenInfo = ();
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.upgrade.clone()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        // The protocol never changes.
+        Some(0)
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        !self.shutting_down
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        if endpoint.is_dialer() {
+            self.outbound_requested = false;
+        }
+        if self.shutting_down {
+            return Err(protocol);
+        }
+        let substream = (self.split)(protocol);
+        self.substreams.push(substream);
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_event(&mut self, message: Self::InEvent) {
+        if self.shutting_down {
+            debug!("Dropping a message injected in a framed handler that is shutting down");
+            return;
+        }
+        self.send_queue.push_back(message);
+    }
+
+    fn inject_dial_upgrade_error(&mut self, _: (), error: ProtocolsHandlerUpgrErr) {
+        self.outbound_requested = false;
+        // Opening another substream would most likely fail as well.
+        if self.substreams.is_empty() && !self.send_queue.is_empty() {
+            debug!(
+                "Failed to open a framed substream: {}; dropping {} messages",
+                error,
+                self.send_queue.len()
+            );
+            self.send_queue.clear();
+        }
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {}
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        self.send_queue.clear();
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if !self.substreams.is_empty() || !self.send_queue.is_empty() || self.outbound_requested {
+            KeepAlive::Forever
+        } else {
+            KeepAlive::Now
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+        }
+
+        if self.shutting_down {
+            // Close the sinks, and finish once all of them are closed.
+            for n in (0..self.substreams.len()).rev() {
+                match self.substreams[n].0.close() {
+                    Ok(Async::NotReady) => (),
+                    Ok(Async::Ready(())) => {
+                        self.substreams.remove(n);
+                    }
+                    Err(err) => {
+                        debug!("Error while closing a framed substream: {}", err);
+                        self.substreams.remove(n);
+                    }
+                }
+            }
+            return if self.substreams.is_empty() {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::NotReady)
+            };
+        }
+
+        self.poll_send();
+        self.poll_receive();
+
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+        }
+
+        if self.substreams.is_empty() && !self.send_queue.is_empty() && !self.outbound_requested {
+            self.outbound_requested = true;
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade: self.upgrade.clone(),
+                info: (),
+                timeout: None,
+                priority: 0,
+            })));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl<TSubstream, TUpgrade, TSplit, TSink, TStream> fmt::Debug
+    for FramedHandler<TSubstream, TUpgrade, TSplit, TSink, TStream>
+where
+    TUpgrade: fmt::Debug,
+    TSink: Sink,
+    TStream: Stream,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("FramedHandler")
+            .field("upgrade", &self.upgrade)
+            .field("substreams", &self.substreams.len())
+            .field("send_queue", &self.send_queue.len())
+            .field("outbound_requested", &self.outbound_requested)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, sync::mpsc};
+    use std::io::Cursor;
+    use tokio_mock_task::MockTask;
+    use upgrade::PlainTextConfig;
+
+    #[test]
+    fn messages_go_through_the_substream() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut incoming = Some(stream::iter_ok::<_, io::Error>(vec![1, 2]));
+        let mut handler = framed_handler(PlainTextConfig, move |_: Cursor<Vec<u8>>| {
+            let sink = tx
+                .clone()
+                .sink_map_err(|_| io::Error::new(io::ErrorKind::Other, "receiver dropped"));
+            (sink, incoming.take().expect("a single substream is opened; qed"))
+        });
+        let mut task = MockTask::new();
+
+        // Sending a message opens a substream.
+        handler.inject_event(7);
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest { .. }))) => (),
+            _ => panic!("expected an outbound substream request"),
+        }
+        assert!(handler.connection_keep_alive().is_forever());
+        let endpoint = NodeHandlerEndpoint::Dialer(());
+        assert!(handler.inject_fully_negotiated(Cursor::new(Vec::new()), endpoint).is_ok());
+
+        let mut events = Vec::new();
+        while let Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))) =
+            task.enter(|| handler.poll())
+        {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                FramedHandlerEvent::Message(1),
+                FramedHandlerEvent::Message(2),
+                FramedHandlerEvent::Closed,
+            ]
+        );
+        assert!(!handler.connection_keep_alive().is_forever());
+        drop(handler);
+        assert_eq!(rx.collect().wait(), Ok(vec![7]));
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    BufferOverflow, KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io, time::Duration};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that holds back its outbound substream requests as long as
+/// a gate, controlled with `inject_event`, is closed.
+///
+/// The gate is initially closed. Once it is opened, the requests that have been held back are
+/// produced in order. Contrary to `Toggle`, the inbound substreams aren't affected.
+pub struct GatedOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// If true, the outbound substream requests are no longer held back.
+    open: bool,
+    /// Outbound substream requests produced by the handler while the gate was closed.
+    queue: VecDeque<(
+        TProtoHandler::Protocol,
+        TProtoHandler::OutboundOpenInfo,
+        Option<Duration>,
+        u8,
+    )>,
+    /// Maximum number of requests in `queue`.
+    capacity: usize,
+    /// What to do when `queue` is full.
+    overflow: BufferOverflow,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+}
+
+/// Event that can be injected in a `GatedOutbound`.
+#[derive(Debug, Clone)]
+pub enum GateEvent<TInEvent> {
+    /// Opens the gate, if it was closed.
+    Open,
+    /// Closes the gate, if it was open.
+    Close,
+    /// Event for the underlying handler.
+    Inner(TInEvent),
+}
+
+impl<TProtoHandler> GatedOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `GatedOutbound`, with its gate closed.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, capacity: usize, overflow: BufferOverflow) -> Self {
+        GatedOutbound {
+            inner,
+            open: false,
+            queue: VecDeque::new(),
+            capacity,
+            overflow,
+            shutting_down: false,
+        }
+    }
+
+    /// Returns true if the gate is open.
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Returns the number of outbound substream requests held back by the gate.
+    #[inline]
+    pub fn queued_requests(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Holds back an outbound substream request, or applies the `BufferOverflow` policy if the
+    /// queue is full. The handler is told that the requests that don't fit have failed.
+    ///
+    /// Returns an error if the connection must be closed.
+    fn hold_back(
+        &mut self,
+        upgrade: TProtoHandler::Protocol,
+        info: TProtoHandler::OutboundOpenInfo,
+        timeout: Option<Duration>,
+        priority: u8,
+    ) -> Result<(), io::Error> {
+        if self.queue.len() < self.capacity {
+            self.queue.push_back((upgrade, info, timeout, priority));
+            return Ok(());
+        }
+
+        debug!("Queue of gated outbound requests full; discarding request");
+        let err = io::Error::new(io::ErrorKind::Other, "queue of gated outbound requests full");
+        self.inner.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Upgrade(err));
+        if self.overflow == BufferOverflow::Close {
+            Err(io::Error::new(io::ErrorKind::Other, "queue of gated outbound requests overflowed"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reports an error for all the requests held back.
+    fn drain_queue<F>(&mut self, mut error: F)
+    where
+        F: FnMut() -> ProtocolsHandlerUpgrErr,
+    {
+        for (_, info, _, _) in self.queue.drain(..) {
+            self.inner.inject_dial_upgrade_error(info, error());
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for GatedOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("GatedOutbound")
+            .field("inner", &self.inner)
+            .field("open", &self.open)
+            .field("queued_requests", &self.queue.len())
+            .field("capacity", &self.capacity)
+            .field("overflow", &self.overflow)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for GatedOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = GateEvent<TProtoHandler::InEvent>;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match event {
+            GateEvent::Open => self.open = true,
+            GateEvent::Close => self.open = false,
+            GateEvent::Inner(event) => self.inner.inject_event(event),
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    fn inject_outbound_closed(&mut self) {
+        // The requests that are still held back would fail anyway.
+        self.inner.inject_outbound_closed();
+        self.drain_queue(|| ProtocolsHandlerUpgrErr::MuxerClosed);
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // The requests that are still held back will never be reported. Let the handler know.
+        self.shutting_down = true;
+        self.drain_queue(|| {
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "handler shutting down");
+            ProtocolsHandlerUpgrErr::Upgrade(err)
+        });
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        // The requests that have been held back go before the new ones.
+        if self.open {
+            if let Some((upgrade, info, timeout, priority)) = self.queue.pop_front() {
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                })));
+            }
+        }
+
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }) => {
+                    if self.shutting_down {
+                        let err = io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "handler shutting down",
+                        );
+                        let err = ProtocolsHandlerUpgrErr::Upgrade(err);
+                        self.inner.inject_dial_upgrade_error(info, err);
+                    } else if self.open {
+                        return Ok(Async::Ready(Some(
+                            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                                upgrade,
+                                info,
+                                timeout,
+                                priority,
+                            },
+                        )));
+                    } else if let Err(err) = self.hold_back(upgrade, info, timeout, priority) {
+                        return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))));
+                    }
+                }
+                Some(ProtocolsHandlerEvent::CancelOutbound) => {
+                    let inner = &self.inner;
+                    self.queue.retain(|&(_, ref info, _, _)| inner.should_open_outbound(info));
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                }
+                Some(ProtocolsHandlerEvent::CloseOutbound) => {
+                    // The requests held back are dropped like the ones of the wrapper.
+                    self.queue.clear();
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                }
+                event => return Ok(Async::Ready(event)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that requests an outbound substream for each event injected in it, and records
+    /// the requests that failed.
+    #[derive(Default)]
+    struct RequestingHandler {
+        requests: VecDeque<u32>,
+        failed: Vec<u32>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = u32;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.requests.push_back(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, info: u32, _: ProtocolsHandlerUpgrErr) {
+            self.failed.push(info);
+        }
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            match self.requests.pop_front() {
+                Some(info) => Ok(Async::Ready(Some(
+                    ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                        upgrade: DeniedConnectionUpgrade,
+                        info,
+                        timeout: None,
+                        priority: 0,
+                    },
+                ))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Polls the handler until it is no longer ready, and returns the information of the
+    /// requests it has produced.
+    fn requests(handler: &mut GatedOutbound<RequestingHandler>) -> Vec<u32> {
+        let mut infos = Vec::new();
+        loop {
+            match handler.poll().unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info, ..
+                })) => infos.push(info),
+                Async::NotReady => return infos,
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn requests_are_released_in_order_once_open() {
+        let inner = RequestingHandler::default();
+        let mut handler = inner.gated_outbound(2, BufferOverflow::Drop);
+        for request in 0..3 {
+            handler.inject_event(GateEvent::Inner(request));
+        }
+        assert!(requests(&mut handler).is_empty());
+        assert_eq!(handler.queued_requests(), 2);
+        assert_eq!(handler.inner.failed, vec![2]);
+
+        handler.inject_event(GateEvent::Open);
+        handler.inject_event(GateEvent::Inner(3));
+        assert_eq!(requests(&mut handler), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn overflow_can_close_the_connection() {
+        let inner = RequestingHandler::default();
+        let mut handler = inner.gated_outbound(0, BufferOverflow::Close);
+        handler.inject_event(GateEvent::Inner(0));
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Close(_))) => (),
+            _ => panic!("expected the connection to be closed"),
+        }
+        assert_eq!(handler.inner.failed, vec![0]);
+    }
+}
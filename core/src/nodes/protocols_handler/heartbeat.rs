@@ -0,0 +1,287 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use either::EitherOutput;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io, time::Duration};
+use tokio_timer::{clock, Interval};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that produces an event at a regular interval, independently
+/// of the inner handler.
+///
+/// The events of the inner handler are wrapped in `EitherOutput::First`, and the heartbeats in
+/// `EitherOutput::Second`. The heartbeats stop as soon as `shutdown()` is called.
+pub struct Heartbeat<TProtoHandler, TMakeEvent> {
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Stream that produces an element at each heartbeat.
+    interval: Interval,
+    /// Builds the event to produce at each heartbeat.
+    make_event: TMakeEvent,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+}
+
+impl<TProtoHandler, TMakeEvent> Heartbeat<TProtoHandler, TMakeEvent> {
+    /// Creates a `Heartbeat`. The first heartbeat happens after `interval`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, interval: Duration, make_event: TMakeEvent) -> Self {
+        Heartbeat {
+            inner,
+            interval: Interval::new(clock::now() + interval, interval),
+            make_event,
+            shutting_down: false,
+        }
+    }
+}
+
+impl<TProtoHandler, TMakeEvent> fmt::Debug for Heartbeat<TProtoHandler, TMakeEvent>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Heartbeat")
+            .field("inner", &self.inner)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TMakeEvent, THeartbeat> ProtocolsHandler
+    for Heartbeat<TProtoHandler, TMakeEvent>
+where
+    TProtoHandler: ProtocolsHandler,
+    TMakeEvent: FnMut() -> THeartbeat,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = EitherOutput<TProtoHandler::OutEvent, THeartbeat>;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        // The heartbeats alone aren't a reason to keep the connection alive.
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if !self.shutting_down {
+            // Polling the interval registers the current task, so that we get polled again at
+            // the next heartbeat even if the inner handler has nothing to do.
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    let event = EitherOutput::Second((self.make_event)());
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => (),
+                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+            }
+        }
+
+        Ok(self
+            .inner
+            .poll()?
+            .map(|event| event.map(|event| event.map_custom(EitherOutput::First))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use std::io::Cursor;
+    use tests::fake_time::FakeTime;
+    use tokio_mock_task::MockTask;
+
+    type TestHeartbeat = Heartbeat<DummyProtocolsHandler<Cursor<Vec<u8>>>, fn()>;
+
+    fn beat() {}
+
+    /// Polls the handler until it is no longer ready, and returns the number of heartbeats it
+    /// has produced, along with whether it has finished.
+    fn heartbeats(
+        time: &FakeTime,
+        task: &mut MockTask,
+        handler: &mut TestHeartbeat,
+    ) -> (usize, bool) {
+        time.enter(|| {
+            let mut heartbeats = 0;
+            loop {
+                match task.enter(|| handler.poll()).unwrap() {
+                    Async::Ready(Some(ProtocolsHandlerEvent::Custom(EitherOutput::Second(())))) => {
+                        heartbeats += 1
+                    }
+                    Async::Ready(None) => return (heartbeats, true),
+                    Async::NotReady => return (heartbeats, false),
+                    _ => panic!("unexpected event"),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn heartbeats_stop_after_shutdown() {
+        let mut time = FakeTime::new();
+        let mut task = MockTask::new();
+        let inner = DummyProtocolsHandler::default();
+        let mut handler: TestHeartbeat =
+            time.enter(|| inner.with_heartbeat(Duration::from_secs(10), beat as fn()));
+        assert_eq!(heartbeats(&time, &mut task, &mut handler), (0, false));
+
+        // The inner handler is idle, but the task is woken up at each heartbeat.
+        time.advance(Duration::from_secs(5));
+        assert!(!task.is_notified());
+        time.advance(Duration::from_secs(5));
+        assert!(task.is_notified());
+        assert_eq!(heartbeats(&time, &mut task, &mut handler), (1, false));
+        time.advance(Duration::from_secs(20));
+        assert_eq!(heartbeats(&time, &mut task, &mut handler), (2, false));
+
+        handler.shutdown();
+        time.advance(Duration::from_secs(10));
+        assert_eq!(heartbeats(&time, &mut task, &mut handler), (0, true));
+    }
+}
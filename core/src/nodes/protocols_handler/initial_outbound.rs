@@ -0,0 +1,319 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that requests an outbound substream the first time it is
+/// polled, then delegates entirely to the inner handler.
+pub struct InitialOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// The request to produce on the first call to `poll()`. `None` once it has been produced, or
+    /// if `shutdown()` has been called before.
+    initial: Option<(TProtoHandler::Protocol, TProtoHandler::OutboundOpenInfo)>,
+}
+
+impl<TProtoHandler> InitialOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates an `InitialOutbound`.
+    #[inline]
+    pub(crate) fn new(
+        inner: TProtoHandler,
+        upgrade: TProtoHandler::Protocol,
+        info: TProtoHandler::OutboundOpenInfo,
+    ) -> Self {
+        InitialOutbound {
+            inner,
+            initial: Some((upgrade, info)),
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for InitialOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("InitialOutbound")
+            .field("inner", &self.inner)
+            .field("initial_pending", &self.initial.is_some())
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for InitialOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.initial.is_none() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // If we haven't been polled yet, the initial request will never be produced. Let the
+        // handler know.
+        if let Some((_, info)) = self.initial.take() {
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "handler shutting down");
+            self.inner.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Upgrade(err));
+        }
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.initial.is_some() {
+            KeepAlive::Forever
+        } else {
+            self.inner.connection_keep_alive()
+        }
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if let Some((upgrade, info)) = self.initial.take() {
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade,
+                info,
+                timeout: None,
+                priority: 0,
+            })));
+        }
+
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that does nothing by itself, and finishes once shut down.
+    #[derive(Default)]
+    struct IdleHandler {
+        shutting_down: bool,
+    }
+
+    impl ProtocolsHandler for IdleHandler {
+        type InEvent = ();
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: ()) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {
+            self.shutting_down = true;
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            if self.shutting_down {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn initial_request_is_produced_on_first_poll() {
+        let mut handler = TestProtocolsHandler::new(IdleHandler::default())
+            .with_initial_outbound(DeniedConnectionUpgrade, 7);
+        let mut task = MockTask::new();
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                info: 7,
+                ..
+            }))) => (),
+            _ => panic!("expected the initial request"),
+        }
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+        // The request has been produced, so shutting down doesn't report it as failed.
+        handler.shutdown();
+        assert_eq!(handler.inner.calls(), &[Call::Poll, Call::Shutdown]);
+    }
+
+    #[test]
+    fn shutdown_before_first_poll_reports_the_initial_request() {
+        let mut handler = TestProtocolsHandler::new(IdleHandler::default())
+            .with_initial_outbound(DeniedConnectionUpgrade, 7);
+        let mut task = MockTask::new();
+
+        handler.shutdown();
+        match *handler.inner.calls() {
+            [Call::DialUpgradeError(7, _), Call::Shutdown] => (),
+            ref calls => panic!("unexpected calls: {:?}", calls),
+        }
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to finish without producing the request"),
+        }
+    }
+}
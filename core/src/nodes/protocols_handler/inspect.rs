@@ -0,0 +1,295 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that passes every event produced by `poll()` to a closure
+/// before returning it unchanged.
+pub struct InspectOut<TProtoHandler, TInspect> {
+    inner: TProtoHandler,
+    inspect: TInspect,
+}
+
+impl<TProtoHandler, TInspect> InspectOut<TProtoHandler, TInspect> {
+    /// Creates an `InspectOut`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, inspect: TInspect) -> Self {
+        InspectOut { inner, inspect }
+    }
+}
+
+impl<TProtoHandler, TInspect> fmt::Debug for InspectOut<TProtoHandler, TInspect>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("InspectOut")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TInspect> ProtocolsHandler for InspectOut<TProtoHandler, TInspect>
+where
+    TProtoHandler: ProtocolsHandler,
+    TInspect: FnMut(
+        &ProtocolsHandlerEvent<
+            TProtoHandler::Protocol,
+            TProtoHandler::OutboundOpenInfo,
+            TProtoHandler::OutEvent,
+        >,
+    ),
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let event = try_ready!(self.inner.poll());
+        if let Some(ref event) = event {
+            (self.inspect)(event);
+        }
+        Ok(Async::Ready(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that produces the given events, then nothing.
+    struct ScriptedHandler {
+        events: VecDeque<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, u32>>,
+    }
+
+    impl ProtocolsHandler for ScriptedHandler {
+        type InEvent = ();
+        type OutEvent = u32;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: ()) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, u32>>, io::Error> {
+            match self.events.pop_front() {
+                Some(event) => Ok(Async::Ready(Some(event))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn requests_and_custom_events_are_inspected() {
+        let mut events = VecDeque::new();
+        events.push_back(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            upgrade: DeniedConnectionUpgrade,
+            info: 1,
+            timeout: None,
+            priority: 0,
+        });
+        events.push_back(ProtocolsHandlerEvent::Custom(2));
+
+        let mut inspected = Vec::new();
+        {
+            let inspect = |event: &ProtocolsHandlerEvent<_, u32, u32>| match *event {
+                ProtocolsHandlerEvent::OutboundSubstreamRequest { info, .. } => {
+                    inspected.push(format!("request {}", info))
+                }
+                ProtocolsHandlerEvent::Custom(event) => inspected.push(format!("custom {}", event)),
+                _ => panic!("unexpected event"),
+            };
+            let mut handler = ScriptedHandler { events }.inspect_out(inspect);
+            let mut task = MockTask::new();
+
+            // The events go through unchanged.
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info: 1,
+                    ..
+                }))) => (),
+                _ => panic!("expected an outbound substream request"),
+            }
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(2)))) => (),
+                _ => panic!("expected a custom event"),
+            }
+            assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        }
+        assert_eq!(inspected, vec!["request 1", "custom 2"]);
+    }
+}
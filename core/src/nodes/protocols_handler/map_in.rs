@@ -0,0 +1,512 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io, marker::PhantomData};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that turns the input event into something else.
+///
+/// Events for which the closure returns `None` are dropped. Their number can be obtained with
+/// `dropped_events()`.
+pub struct MapInEvent<TProtoHandler, TNewIn, TMap> {
+    inner: TProtoHandler,
+    map: TMap,
+    /// Number of events that the closure has turned into `None`.
+    dropped_events: u64,
+    marker: PhantomData<TNewIn>,
+}
+
+impl<TProtoHandler, TMap, TNewIn> MapInEvent<TProtoHandler, TNewIn, TMap> {
+    /// Creates a `MapInEvent`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, map: TMap) -> Self {
+        MapInEvent {
+            inner,
+            map,
+            dropped_events: 0,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of events that have been dropped because the closure returned `None`.
+    #[inline]
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
+    }
+
+    /// Returns a reference to the underlying handler.
+    #[inline]
+    pub fn get_ref(&self) -> &TProtoHandler {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying handler.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut TProtoHandler {
+        &mut self.inner
+    }
+}
+
+impl<TProtoHandler, TNewIn, TMap> fmt::Debug for MapInEvent<TProtoHandler, TNewIn, TMap>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MapInEvent")
+            .field("inner", &self.inner)
+            .field("dropped_events", &self.dropped_events)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TMap, TNewIn> ProtocolsHandler for MapInEvent<TProtoHandler, TNewIn, TMap>
+where
+    TProtoHandler: ProtocolsHandler,
+    TMap: Fn(TNewIn) -> Option<TProtoHandler::InEvent>,
+{
+    type InEvent = TNewIn;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: TNewIn) {
+        if let Some(event) = (self.map)(event) {
+            self.inner.inject_event(event);
+        } else {
+            self.dropped_events += 1;
+            debug!("MapInEvent dropped an event; total dropped: {}", self.dropped_events);
+        }
+    }
+
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = TNewIn>,
+    {
+        let map = &self.map;
+        let dropped_events = &mut self.dropped_events;
+        self.inner.inject_events(events.into_iter().filter_map(|event| {
+            let event = map(event);
+            if event.is_none() {
+                *dropped_events += 1;
+                debug!("MapInEvent dropped an event; total dropped: {}", dropped_events);
+            }
+            event
+        }))
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        self.inner.poll()
+    }
+}
+
+/// Wrapper around a protocol handler that turns the input event into something else, and passes
+/// the events that couldn't be turned to a fallback closure.
+pub struct MapInEventWithFallback<TProtoHandler, TNewIn, TMap, TFallback> {
+    inner: TProtoHandler,
+    map: TMap,
+    fallback: TFallback,
+    marker: PhantomData<TNewIn>,
+}
+
+impl<TProtoHandler, TNewIn, TMap, TFallback>
+    MapInEventWithFallback<TProtoHandler, TNewIn, TMap, TFallback>
+{
+    /// Creates a `MapInEventWithFallback`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, map: TMap, fallback: TFallback) -> Self {
+        MapInEventWithFallback {
+            inner,
+            map,
+            fallback,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the underlying handler.
+    #[inline]
+    pub fn get_ref(&self) -> &TProtoHandler {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying handler.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut TProtoHandler {
+        &mut self.inner
+    }
+}
+
+impl<TProtoHandler, TNewIn, TMap, TFallback> fmt::Debug
+    for MapInEventWithFallback<TProtoHandler, TNewIn, TMap, TFallback>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MapInEventWithFallback")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TNewIn, TMap, TFallback> ProtocolsHandler
+    for MapInEventWithFallback<TProtoHandler, TNewIn, TMap, TFallback>
+where
+    TProtoHandler: ProtocolsHandler,
+    TMap: Fn(TNewIn) -> Result<TProtoHandler::InEvent, TNewIn>,
+    TFallback: FnMut(TNewIn),
+{
+    type InEvent = TNewIn;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: TNewIn) {
+        match (self.map)(event) {
+            Ok(event) => self.inner.inject_event(event),
+            Err(event) => (self.fallback)(event),
+        }
+    }
+
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = TNewIn>,
+    {
+        let map = &self.map;
+        let fallback = &mut self.fallback;
+        self.inner.inject_events(events.into_iter().filter_map(|event| match map(event) {
+            Ok(event) => Some(event),
+            Err(event) => {
+                fallback(event);
+                None
+            }
+        }))
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that accepts events and does nothing else.
+    struct EventsHandler;
+
+    impl ProtocolsHandler for EventsHandler {
+        type InEvent = u32;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: u32) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), ()>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn unmapped_events_are_dropped_and_counted() {
+        let inner = TestProtocolsHandler::new(EventsHandler);
+        let mut handler =
+            inner.map_in_event(|event: u32| if event % 2 == 0 { Some(event) } else { None });
+
+        handler.inject_event(1);
+        handler.inject_event(2);
+        assert_eq!(handler.dropped_events(), 1);
+        handler.inject_events(vec![3, 4, 5, 6, 7]);
+        assert_eq!(handler.dropped_events(), 4);
+        assert_eq!(handler.inner.calls(), &[Call::Event(2), Call::Event(4), Call::Event(6)]);
+    }
+
+    #[test]
+    fn unmapped_events_go_to_the_fallback() {
+        let mut fallen_back = Vec::new();
+        {
+            let inner = TestProtocolsHandler::new(EventsHandler);
+            let mut handler = inner.map_in_event_with_fallback(
+                |event: u32| if event % 2 == 0 { Ok(event) } else { Err(event) },
+                |event| fallen_back.push(event),
+            );
+
+            handler.inject_event(1);
+            handler.inject_event(2);
+            handler.inject_events(vec![3, 4, 5, 6, 7]);
+            assert_eq!(handler.inner.calls(), &[Call::Event(2), Call::Event(4), Call::Event(6)]);
+        }
+        assert_eq!(fallen_back, vec![1, 3, 5, 7]);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io, marker::PhantomData};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that turns its `OutboundOpenInfo` into something else.
+///
+/// The `map` closure is applied to the information of the outbound substream requests produced
+/// by the handler, and the `unmap` closure is applied to the information passed back to the
+/// handler.
+pub struct MapOutboundOpenInfo<TProtoHandler, TNewInfo, TMap, TUnmap> {
+    inner: TProtoHandler,
+    map: TMap,
+    unmap: TUnmap,
+    marker: PhantomData<TNewInfo>,
+}
+
+impl<TProtoHandler, TNewInfo, TMap, TUnmap>
+    MapOutboundOpenInfo<TProtoHandler, TNewInfo, TMap, TUnmap>
+{
+    /// Creates a `MapOutboundOpenInfo`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, map: TMap, unmap: TUnmap) -> Self {
+        MapOutboundOpenInfo {
+            inner,
+            map,
+            unmap,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<TProtoHandler, TNewInfo, TMap, TUnmap> fmt::Debug
+    for MapOutboundOpenInfo<TProtoHandler, TNewInfo, TMap, TUnmap>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MapOutboundOpenInfo")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TNewInfo, TMap, TUnmap> ProtocolsHandler
+    for MapOutboundOpenInfo<TProtoHandler, TNewInfo, TMap, TUnmap>
+where
+    TProtoHandler: ProtocolsHandler,
+    TNewInfo: Clone,
+    TMap: FnMut(TProtoHandler::OutboundOpenInfo) -> TNewInfo,
+    TUnmap: Fn(TNewInfo) -> TProtoHandler::OutboundOpenInfo,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TNewInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(&(self.unmap)(info.clone()))
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer(info) => NodeHandlerEndpoint::Dialer((self.unmap)(info)),
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer(info) => NodeHandlerEndpoint::Dialer((self.unmap)(info)),
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error((self.unmap)(info), error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        Ok(self
+            .inner
+            .poll()?
+            .map(|ev| ev.map(|ev| ev.map_outbound_open_info(&mut self.map))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that requests one outbound substream, and only wants the requests with an even
+    /// information.
+    struct RequestingHandler {
+        request: Option<u32>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = ();
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn should_open_outbound(&self, info: &u32) -> bool {
+            info % 2 == 0
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: ()) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            match self.request.take() {
+                Some(info) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: DeniedConnectionUpgrade,
+                    info,
+                    timeout: None,
+                    priority: 0,
+                }))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn information_is_unmapped_for_the_inner_handler() {
+        let inner = TestProtocolsHandler::new(RequestingHandler { request: Some(4) });
+        let mut handler = inner.map_outbound_open_info(
+            |info: u32| info.to_string(),
+            |info: String| info.parse::<u32>().unwrap(),
+        );
+        let mut task = MockTask::new();
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                ref info,
+                ..
+            }))) => assert_eq!(info, "4"),
+            _ => panic!("expected an outbound substream request"),
+        }
+
+        assert!(handler.should_open_outbound(&"4".to_owned()));
+        assert!(!handler.should_open_outbound(&"5".to_owned()));
+        let endpoint = NodeHandlerEndpoint::Dialer("4".to_owned());
+        assert!(handler.inject_fully_negotiated((), endpoint).is_ok());
+        let err = io::Error::new(io::ErrorKind::Other, "failure");
+        handler.inject_dial_upgrade_error("6".to_owned(), ProtocolsHandlerUpgrErr::Upgrade(err));
+
+        match *handler.inner.calls() {
+            [
+                Call::Poll,
+                Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(4)),
+                Call::DialUpgradeError(6, _),
+            ] => (),
+            ref calls => panic!("unexpected calls: {:?}", calls),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io, marker::PhantomData};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that turns its `Protocol` into something else.
+///
+/// The `map` closure is applied to the upgrades produced by the handler, and the `unmap` closure
+/// is applied to the output of the new upgrades before passing them back to the handler.
+pub struct MapProtocol<TProtoHandler, TNewProto, TMap, TUnmap> {
+    inner: TProtoHandler,
+    map: TMap,
+    unmap: TUnmap,
+    marker: PhantomData<TNewProto>,
+}
+
+impl<TProtoHandler, TNewProto, TMap, TUnmap> MapProtocol<TProtoHandler, TNewProto, TMap, TUnmap> {
+    /// Creates a `MapProtocol`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, map: TMap, unmap: TUnmap) -> Self {
+        MapProtocol {
+            inner,
+            map,
+            unmap,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<TProtoHandler, TNewProto, TMap, TUnmap> fmt::Debug
+    for MapProtocol<TProtoHandler, TNewProto, TMap, TUnmap>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MapProtocol")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TNewProto, TMap, TUnmap> ProtocolsHandler
+    for MapProtocol<TProtoHandler, TNewProto, TMap, TUnmap>
+where
+    TProtoHandler: ProtocolsHandler,
+    TNewProto: ConnectionUpgrade<TProtoHandler::Substream>,
+    TMap: Fn(TProtoHandler::Protocol) -> TNewProto,
+    TUnmap: Fn(
+        TNewProto::Output,
+    ) -> <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::Output,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TNewProto;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        (self.map)(self.inner.listen_protocol())
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        // If the handler rejects the substream, we can't turn it back into the output of the new
+        // upgrade. Dropping it here closes it as well.
+        let _ = self.inner.inject_fully_negotiated((self.unmap)(protocol), endpoint);
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let protocol = (self.unmap)(protocol);
+        let _ = self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing);
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let map = &self.map;
+        Ok(self.inner.poll()?.map(|ev| ev.map(|ev| ev.map_protocol(map))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::{self, PlainTextConfig};
+
+    /// Output of the upgrades produced by `map`.
+    struct Wrapped(Cursor<Vec<u8>>);
+
+    /// Handler that records the content of the substreams injected in it, and rejects them if
+    /// `accept` is false.
+    struct RecordingHandler {
+        accept: bool,
+        received: Vec<(NodeHandlerEndpoint<u32>, Vec<u8>)>,
+    }
+
+    impl ProtocolsHandler for RecordingHandler {
+        type InEvent = ();
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            substream: Cursor<Vec<u8>>,
+            endpoint: NodeHandlerEndpoint<u32>,
+        ) -> Result<(), Cursor<Vec<u8>>> {
+            self.received.push((endpoint, substream.get_ref().clone()));
+            if self.accept {
+                Ok(())
+            } else {
+                Err(substream)
+            }
+        }
+
+        fn inject_event(&mut self, _: ()) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, u32, ()>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn negotiated_substreams_are_unmapped() {
+        for &accept in &[true, false] {
+            let inner = RecordingHandler { accept, received: Vec::new() };
+            let mut handler = inner.map_protocol(
+                |proto| upgrade::map(proto, Wrapped as fn(_) -> _),
+                |Wrapped(substream)| substream,
+            );
+
+            // The inner handler gets back the output of its own upgrade, whatever the endpoint.
+            // A rejected substream can't be mapped again, so it is dropped instead.
+            let substream = Wrapped(Cursor::new(vec![1]));
+            let result = handler.inject_fully_negotiated(substream, NodeHandlerEndpoint::Listener);
+            assert!(result.is_ok());
+            let substream = Wrapped(Cursor::new(vec![2]));
+            let endpoint = NodeHandlerEndpoint::Dialer(7);
+            assert!(handler.inject_fully_negotiated(substream, endpoint).is_ok());
+            assert_eq!(
+                handler.inner.received,
+                vec![
+                    (NodeHandlerEndpoint::Listener, vec![1]),
+                    (NodeHandlerEndpoint::Dialer(7), vec![2]),
+                ]
+            );
+        }
+    }
+}
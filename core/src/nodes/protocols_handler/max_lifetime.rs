@@ -0,0 +1,257 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io, time::Duration};
+use tokio_timer::{clock, Delay};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that shuts it down once it has existed for a certain
+/// duration, whatever it is doing.
+///
+/// Contrary to the negotiation timeouts of the `NodeHandlerWrapper`, which apply to each
+/// substream individually, this bounds the lifetime of the whole handler. Once the deadline is
+/// reached, `shutdown()` is called on the handler, which is then polled until it produces
+/// `Ready(None)`.
+pub struct MaxLifetime<TProtoHandler> {
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Fires when the handler must be shut down. `None` once it has fired or once `shutdown()`
+    /// has been called.
+    deadline: Option<Delay>,
+}
+
+impl<TProtoHandler> MaxLifetime<TProtoHandler> {
+    /// Creates a `MaxLifetime`. The lifetime starts now.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, lifetime: Duration) -> Self {
+        MaxLifetime {
+            inner,
+            deadline: Some(Delay::new(clock::now() + lifetime)),
+        }
+    }
+
+    /// Returns true if the deadline has been reached.
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_none()
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for MaxLifetime<TProtoHandler>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("MaxLifetime")
+            .field("inner", &self.inner)
+            .field("deadline", &self.deadline.as_ref().map(|delay| delay.deadline()))
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for MaxLifetime<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        // The handler is already shutting down; no need to do it again once the deadline is
+        // reached.
+        self.deadline = None;
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        // Polling the deadline registers the current task, so that we get polled again when it
+        // is reached even if the inner handler has nothing to do.
+        let expired = match self.deadline.as_mut().map(|deadline| deadline.poll()) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Ok(Async::NotReady)) | None => false,
+            Some(Err(err)) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+        };
+        if expired {
+            debug!("Handler reached its maximum lifetime; shutting it down");
+            self.shutdown();
+        }
+
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn handler_is_shut_down_once_expired() {
+        let handler = DummyProtocolsHandler::<Cursor<Vec<u8>>>::default();
+        let handler = TestProtocolsHandler::new(handler);
+        let mut handler = handler.with_max_lifetime(Duration::from_millis(10));
+        let mut runtime = Runtime::new().unwrap();
+
+        // The dummy handler never does anything on its own. The wrapper must wake itself up
+        // when the deadline is reached.
+        runtime
+            .block_on(future::poll_fn(|| -> Poll<(), io::Error> {
+                match try_ready!(handler.poll()) {
+                    Some(_) => panic!("unexpected event"),
+                    None => Ok(Async::Ready(())),
+                }
+            }))
+            .unwrap();
+        assert!(handler.is_expired());
+        assert!(handler.inner.calls().contains(&Call::Shutdown));
+    }
+}
@@ -0,0 +1,1238 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use either::EitherOutput;
+use futures::{prelude::*, sync::mpsc};
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint, NodeHandlerEvent};
+use std::{cell::Cell, cmp::Ordering, error, fmt, hash::Hash, io, time::{Duration, Instant}};
+use tokio_io::{AsyncRead, AsyncWrite};
+use void::Void;
+use {ConnectionUpgrade, Endpoint};
+
+pub use self::boxed::{BoxProtocol, BoxProtocolsHandler};
+pub use self::buffer::{BufferOverflow, BufferUntilReady};
+pub use self::catch_errors::CatchErrors;
+pub use self::channel::{channel_driver, ChannelDriver};
+pub use self::dedup::DedupOutbound;
+pub use self::dummy::DummyProtocolsHandler;
+pub use self::either::{EitherProtocolsHandler, EitherUpgrade};
+pub use self::event_history::EventHistory;
+pub use self::framed::{framed_handler, FramedHandler, FramedHandlerEvent};
+pub use self::gated_outbound::{GateEvent, GatedOutbound};
+pub use self::heartbeat::Heartbeat;
+pub use self::initial_outbound::InitialOutbound;
+pub use self::inspect::InspectOut;
+pub use self::map_in::{MapInEvent, MapInEventWithFallback};
+pub use self::map_open_info::MapOutboundOpenInfo;
+pub use self::map_out::MapOutEvent;
+pub use self::map_protocol::MapProtocol;
+pub use self::max_lifetime::MaxLifetime;
+pub use self::mux::{
+    MuxTarget, MuxUpgrade, MuxUpgradeFuture, ProtocolsHandlerMux, ProtocolsHandlerMuxIn,
+};
+pub use self::node_handler::{
+    NegotiationStats, NodeHandlerWrapper, NodeHandlerWrapperBuilder, NodeHandlerWrapperClose,
+    NodeHandlerWrapperStats, NodeHandlerWrapperWith, ProtocolStats, ProtocolsCacheStats,
+    TimerFactory,
+};
+pub use self::oneshot::{oneshot_handler, OneShotHandler};
+pub use self::partition::PartitionOut;
+pub use self::passive::PassiveProtocolsHandler;
+pub use self::rate_limit::RateLimit;
+pub use self::request_response::{
+    request_response_handler, RequestResponseHandler, RequestResponseHandlerEvent,
+};
+pub use self::retry::{RetryOutbound, RetryOutboundInfo};
+pub use self::select::{
+    ConflictPolicy, ExcludeNames, ExcludeNamesIter, ProtocolsHandlerSelect,
+    ProtocolsHandlerSelectVoid, SelectUpgrade,
+};
+pub use self::single_inbound::SingleInbound;
+pub use self::tee::TeeOutEvents;
+pub use self::toggle::{Toggle, ToggleEvent};
+pub use self::tuple::{
+    tuple_handler, ProtocolsHandlerTuple, TupleHandler3, TupleHandler4, TupleOutput3, TupleOutput4,
+};
+
+mod boxed;
+mod buffer;
+mod catch_errors;
+mod channel;
+mod dedup;
+mod dummy;
+mod either;
+mod event_history;
+mod framed;
+mod gated_outbound;
+mod heartbeat;
+mod initial_outbound;
+mod inspect;
+mod map_in;
+mod map_open_info;
+mod map_out;
+mod map_protocol;
+mod max_lifetime;
+mod mux;
+mod node_handler;
+mod oneshot;
+mod partition;
+mod passive;
+mod rate_limit;
+mod request_response;
+mod retry;
+mod select;
+mod single_inbound;
+mod tee;
+mod toggle;
+mod tuple;
+
+/// Handler for a set of protocols for a specific connection with a remote.
+///
+/// This trait should be implemented on struct that hold the state for a specific protocol
+/// behaviour with a specific remote.
+///
+/// # Handling a protocol
+///
+/// Protocols with the remote can be opened in two different ways:
+///
+/// - Dialing, which is a voluntary process. In order to do so, make `poll()` return an
+///   `OutboundSubstreamRequest` variant containing the connection upgrade to use.
+/// - Listening, which is used to determine which protocols are supported when the remote wants
+///   to open a substream. The `listen_protocol()` method should return the upgrades supported when
+///   listening.
+///
+/// The upgrade when dialing and the upgrade when listening have to be of the same type, but you
+/// are free to return for example an `OrUpgrade` enum, or an enum of yours, containing the upgrade
+/// you want depending on the situation.
+///
+/// For protocols whose inbound and outbound sides are unrelated, a convenient choice is
+/// `OrUpgrade<Option<TInbound>, Option<TOutbound>>`: `listen_protocol()` returns
+/// `upgrade::or(Some(inbound), None)` and the outbound requests use
+/// `upgrade::or(None, Some(outbound))`. A `None` doesn't advertise any protocol, so each
+/// direction only negotiates its own protocols. Both sides must produce the same output, which
+/// can be achieved with `upgrade::map` and an enum.
+///
+/// # Shutting down
+///
+/// Implementors of this trait should keep in mind that the connection can be closed at any time.
+/// When a connection is closed (either by us or by the remote) `shutdown()` is called and the
+/// handler continues to be processed until it produces `None`. Then only the handler is destroyed.
+///
+/// This makes it possible for the handler to finish delivering events even after knowing that it
+/// is shutting down.
+///
+/// Implementors of this trait should keep in mind that when `shutdown()` is called, the connection
+/// might already be closed or unresponsive. They should therefore not rely on being able to
+/// deliver messages.
+///
+/// # Relationship with `NodeHandler`.
+///
+/// This trait is very similar to the `NodeHandler` trait. The fundamental differences are:
+///
+/// - The `NodeHandler` trait gives you more control and is therefore more difficult to implement.
+/// - The `NodeHandler` trait is designed to have exclusive ownership of the connection with a
+///   node, while the `ProtocolsHandler` trait is designed to handle only a specific set of
+///   protocols. Two or more implementations of `ProtocolsHandler` can be combined into one that
+///   supports all the protocols together, which is not possible with `NodeHandler`.
+pub trait ProtocolsHandler {
+    /// Custom event that can be received from the outside.
+    type InEvent;
+    /// Custom event that can be produced by the handler and that will be returned to the outside.
+    type OutEvent;
+    /// The type of the substream that contains the raw data.
+    type Substream: AsyncRead + AsyncWrite;
+    /// The upgrade for the protocol or protocols handled by this handler.
+    type Protocol: ConnectionUpgrade<Self::Substream>;
+    /// Information about a substream. Can be sent to the handler through a `NodeHandlerEndpoint`,
+    /// and will be passed back in `inject_substream` or `inject_outbound_closed`.
+    type OutboundOpenInfo;
+
+    /// If true, the handler guarantees that all its upgrades, both the ones returned by
+    /// `listen_protocol()` and the ones of its outbound substream requests, advertise at most one
+    /// protocol name. The `NodeHandlerWrapper` then only reads the first name of these upgrades
+    /// instead of collecting all of them.
+    ///
+    /// Wrappers that don't change the protocol forward the value of the handler they wrap.
+    const SINGLE_PROTOCOL: bool = false;
+
+    /// Produces a `ConnectionUpgrade` for the protocol or protocols to accept when listening.
+    ///
+    /// > **Note**: You should always accept all the protocols you support, even if in a specific
+    /// >           context you wouldn't accept one in particular (eg. only allow one substream at
+    /// >           a time for a given protocol). The reason is that remotes are allowed to put the
+    /// >           list of supported protocols in a cache in order to avoid spurious queries.
+    ///
+    /// This method is called for every inbound substream. If the upgrade is expensive to build
+    /// or to clone, consider storing it in an `Arc` and using `Arc<TUpgrade>` as `Protocol`, so
+    /// that this method only needs to clone the `Arc`.
+    fn listen_protocol(&self) -> Self::Protocol;
+
+    /// Returns a number that changes whenever the value returned by `listen_protocol()` changes,
+    /// or `None` if this isn't known.
+    ///
+    /// When this returns `Some` and the cache has been enabled with
+    /// `NodeHandlerWrapperBuilder::with_listen_protocol_cache`, the `NodeHandlerWrapper`
+    /// remembers the result of `listen_protocol()` and only calls it again once the number has
+    /// changed, instead of once per inbound substream. The default implementation returns `None`.
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns whether a new inbound substream should be accepted. Called whenever the remote
+    /// opens a substream, before any negotiation happens. If `false` is returned, the substream
+    /// is immediately closed.
+    ///
+    /// This can be used to apply back-pressure, for example when too many substreams are open.
+    ///
+    /// The default implementation always returns `true`.
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        true
+    }
+
+    /// Returns whether the outbound substream request that was produced with the given `info`
+    /// and that hasn't been answered yet is still wanted. Called by `NodeHandlerWrapper` for
+    /// each pending request after the handler has produced
+    /// `ProtocolsHandlerEvent::CancelOutbound`. The requests for which `false` is returned are
+    /// dropped, and the handler won't hear about them anymore.
+    ///
+    /// The default implementation returns `true`.
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        let _ = info;
+        true
+    }
+
+    /// Injects a fully-negotiated substream in the handler.
+    ///
+    /// This method is called when a substream has been successfully opened and negotiated.
+    ///
+    /// If the handler doesn't want the substream after all (for example because of something it
+    /// learned during the handshake), it can return it as an `Err`. The substream is then closed.
+    ///
+    /// > **Note**: Once injected, the substream belongs to the handler. Neither the muxer nor the
+    /// >           `NodeHandlerWrapper` keep track of it, and there is no notification when the
+    /// >           remote closes it. Handlers that need to know should detect it on the output of
+    /// >           the upgrade itself, for example by reading the end of the stream.
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output>;
+
+    /// Same as `inject_fully_negotiated`, but also indicates how long the negotiation of the
+    /// substream took compared to the timeout, and which protocol has been negotiated. This is
+    /// the method that `NodeHandlerWrapper` calls.
+    ///
+    /// Handlers can override this method in order to detect negotiations that were close to
+    /// timing out, or to know which of the protocols of their upgrade the remote has chosen. The
+    /// default implementation ignores the timing and calls `inject_fully_negotiated`.
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let _ = timing;
+        self.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    /// Indicates to the handler the name of the protocol that has been negotiated on a substream.
+    ///
+    /// `NodeHandlerWrapper` calls this method right before injecting the substream with
+    /// `inject_fully_negotiated_with_timing`. When the upgrade of the handler supports multiple
+    /// versions of a protocol, this makes it possible to know which one the remote has picked,
+    /// for example in order to track the adoption of a new version across the network.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        let _ = (name, endpoint);
+    }
+
+    /// Injects an event coming from the outside in the handler.
+    fn inject_event(&mut self, event: Self::InEvent);
+
+    /// Passes information about the connection, such as the identity of the remote, to the
+    /// handler. Called once by the `NodeHandlerWrapper` when the node has been reached, before
+    /// any substream is injected.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        let _ = info;
+    }
+
+    /// Injects multiple events coming from the outside in the handler, in order.
+    ///
+    /// The default implementation calls `inject_event` for each event. Wrappers override it in
+    /// order to pass the whole batch to the handler they wrap.
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        for event in events {
+            self.inject_event(event);
+        }
+    }
+
+    /// Returns whether the handler is ready to receive more events through `inject_event`.
+    ///
+    /// This is a cooperative backpressure mechanism: a handler that buffers the events it
+    /// receives can return false when its buffer is full. Well-behaved sources of events should
+    /// check this method before calling `inject_event`, and hold back their events while it
+    /// returns false. Nothing prevents events from being injected anyway.
+    ///
+    /// The default implementation always returns true.
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        true
+    }
+
+    /// Polling version of `ready_for_event`, in the style of `Sink::poll_complete`.
+    ///
+    /// Returns `Ready` if the handler can receive an event through `inject_event`. Returns
+    /// `NotReady` otherwise, in which case the current task is notified once the handler becomes
+    /// ready again. This lets a source of events wait for room instead of checking
+    /// `ready_for_event` in a loop. An error means that the handler will never be ready again.
+    ///
+    /// The default implementation always returns `Ready`. Handlers that override
+    /// `ready_for_event` should override this method as well.
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+
+    /// Returns whether the handler has nothing to do, such as data to send or requests waiting
+    /// for an answer, until something happens on the connection or an event is injected.
+    ///
+    /// This is combined with the state of the negotiations in `NodeHandlerWrapper::is_idle`,
+    /// which lets a connection manager reclaim the resources of dormant connections.
+    ///
+    /// The default implementation always returns true.
+    #[inline]
+    fn is_idle(&self) -> bool {
+        true
+    }
+
+    /// Indicates to the handler that upgrading a substream to the given protocol has failed.
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    );
+
+    /// Indicates to the handler that upgrading an inbound substream has failed.
+    ///
+    /// If the negotiation timed out, the error is of kind `io::ErrorKind::TimedOut`. Otherwise
+    /// the error is the one produced by the protocol negotiation or by the upgrade.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, _error: io::Error) {}
+
+    /// Indicates the handler that the inbound part of the muxer has been closed, and that
+    /// therefore no more inbound substream will be produced.
+    fn inject_inbound_closed(&mut self);
+
+    /// Indicates the handler that the outbound part of the muxer has been closed, and that
+    /// therefore the outbound substream requests will fail from now on.
+    ///
+    /// This is called once, before the error of the first request that failed because of this
+    /// is injected with `inject_dial_upgrade_error`. The requests produced afterwards still fail
+    /// individually with `ProtocolsHandlerUpgrErr::MuxerClosed`.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_outbound_closed(&mut self) {}
+
+    /// Indicates the handler whether a new outbound substream request would be reported to the
+    /// muxer right away. When `false`, the requests produced by `poll()` are held back by the
+    /// `NodeHandlerWrapper` (because of `with_max_negotiating_outbound`) or fail because the
+    /// outbound part of the muxer is closed, and the handler should keep its data instead of
+    /// queueing more requests.
+    ///
+    /// The handler is assumed to be ready when it is created. This is only called when the
+    /// readiness changes, before the handler is polled.
+    ///
+    /// The default implementation does nothing.
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        let _ = ready;
+    }
+
+    /// Indicates the node that it should shut down. After that, it is expected that `poll()`
+    /// returns `Ready(None)` as soon as possible.
+    ///
+    /// This method allows an implementation to perform a graceful shutdown of the substreams, and
+    /// send back various events.
+    ///
+    /// This method can be called multiple times, and implementations must ensure that calls
+    /// after the first one have no effect. Wrappers can therefore forward it without checking.
+    ///
+    /// Once `poll()` has returned `Ready(None)`, the `NodeHandlerWrapper` doesn't call any method
+    /// of the handler anymore.
+    fn shutdown(&mut self);
+
+    /// Returns until when the connection should be kept alive.
+    ///
+    /// This method is called by the `NodeHandlerWrapper` after each call to `poll()`, so that
+    /// the upper layers can decide whether to gracefully close a connection that is no longer
+    /// needed. For example a handler that periodically pings the remote should return
+    /// `KeepAlive::Now` between pings, while a handler with requests in flight should return
+    /// `KeepAlive::Forever`.
+    ///
+    /// The default implementation returns `KeepAlive::Now`.
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::Now
+    }
+
+    /// Should behave like `Stream::poll()`. Should close if no more event can be produced and the
+    /// node should be closed.
+    ///
+    /// > **Note**: If this handler is combined with other handlers, as soon as `poll()` returns
+    /// >           `Ok(Async::Ready(None))`, all the other handlers will receive a call to
+    /// >           `shutdown()` and will eventually be closed and destroyed.
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    >;
+
+    /// Adds a closure that turns the input event into something else.
+    ///
+    /// Events for which the closure returns `None` are dropped. See also
+    /// `map_in_event_with_fallback`.
+    #[inline]
+    fn map_in_event<TNewIn, TMap>(self, map: TMap) -> MapInEvent<Self, TNewIn, TMap>
+    where
+        Self: Sized,
+        TMap: Fn(TNewIn) -> Option<Self::InEvent>,
+    {
+        MapInEvent::new(self, map)
+    }
+
+    /// Adds a closure that turns the input event into something else. If the closure returns
+    /// `Err`, the event is passed to `fallback` instead of being silently dropped.
+    #[inline]
+    fn map_in_event_with_fallback<TNewIn, TMap, TFallback>(
+        self,
+        map: TMap,
+        fallback: TFallback,
+    ) -> MapInEventWithFallback<Self, TNewIn, TMap, TFallback>
+    where
+        Self: Sized,
+        TMap: Fn(TNewIn) -> Result<Self::InEvent, TNewIn>,
+        TFallback: FnMut(TNewIn),
+    {
+        MapInEventWithFallback::new(self, map, fallback)
+    }
+
+    /// Adds a closure that turns the output event into something else.
+    #[inline]
+    fn map_out_event<TMap, TNewOut>(self, map: TMap) -> MapOutEvent<Self, TMap>
+    where
+        Self: Sized,
+        TMap: FnMut(Self::OutEvent) -> TNewOut,
+    {
+        MapOutEvent::new(self, map)
+    }
+
+    /// Adds a closure that is called with a reference to every event produced by `poll()`,
+    /// including outbound substream requests, before the event is returned unchanged.
+    ///
+    /// Contrary to `map_out_event`, this doesn't change any type and is therefore suitable for
+    /// logging or metrics.
+    #[inline]
+    fn inspect_out<TInspect>(self, inspect: TInspect) -> InspectOut<Self, TInspect>
+    where
+        Self: Sized,
+        TInspect: FnMut(
+            &ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>,
+        ),
+    {
+        InspectOut::new(self, inspect)
+    }
+
+    /// Sorts the custom events produced by the handler in two categories with `classify`. The
+    /// events for which it returns true are produced as `EitherOutput::First`, and the others as
+    /// `EitherOutput::Second`.
+    ///
+    /// The order of the events is preserved across the two categories. Combined with
+    /// `map_out_event`, this lets downstream code route each category separately.
+    #[inline]
+    fn partition_out<TClassify>(self, classify: TClassify) -> PartitionOut<Self, TClassify>
+    where
+        Self: Sized,
+        TClassify: FnMut(&Self::OutEvent) -> bool,
+    {
+        PartitionOut::new(self, classify)
+    }
+
+    /// Remembers the last `n` events injected in the handler and the last `n` custom events it
+    /// produced, so that they can be examined when diagnosing a misbehaving connection. See
+    /// `EventHistory::recent_in_events` and `EventHistory::recent_out_events`.
+    #[inline]
+    fn with_event_history(self, n: usize) -> EventHistory<Self>
+    where
+        Self: Sized,
+        Self::InEvent: Clone,
+        Self::OutEvent: Clone,
+    {
+        EventHistory::new(self, n)
+    }
+
+    /// Sends a copy of every custom event produced by the handler to `sender`, for example for
+    /// auditing purposes. The events are still returned as usual.
+    ///
+    /// The copies that don't fit in the channel, or that are sent after the receiver has been
+    /// dropped, are silently dropped, so that the handler is never blocked. See
+    /// `TeeOutEvents::dropped_events`.
+    #[inline]
+    fn tee_out_events(self, sender: mpsc::Sender<Self::OutEvent>) -> TeeOutEvents<Self>
+    where
+        Self: Sized,
+        Self::OutEvent: Clone,
+    {
+        TeeOutEvents::new(self, sender)
+    }
+
+    /// Passes the errors produced by the handler, including `ProtocolsHandlerEvent::Close`, to
+    /// `on_error` instead of closing the connection right away. If it returns `Some`, the error
+    /// is dropped and the event is produced instead. If it returns `None`, the error closes the
+    /// connection as usual. A handler whose error has been turned into an event isn't polled
+    /// anymore, and finishes right after the event.
+    ///
+    /// This lets the users of the handler decide which errors are fatal.
+    #[inline]
+    fn catch_errors<TCatch>(self, on_error: TCatch) -> CatchErrors<Self, TCatch>
+    where
+        Self: Sized,
+        TCatch: FnMut(&io::Error) -> Option<Self::OutEvent>,
+    {
+        CatchErrors::new(self, on_error)
+    }
+
+    /// Adds closures that turn the protocol of the handler into something else.
+    ///
+    /// `map` is applied to the upgrades returned by `listen_protocol()` and to the upgrades of
+    /// the outbound substream requests. `unmap` is applied to the output of the new upgrades
+    /// before they are passed to `inject_fully_negotiated()`.
+    ///
+    /// > **Note**: The two closures must round-trip: `unmap` must be able to turn the output of
+    /// >           any upgrade produced by `map` back into the output of the original upgrade.
+    /// >           If that isn't possible, `unmap` has no choice but to panic.
+    #[inline]
+    fn map_protocol<TNewProto, TMap, TUnmap>(
+        self,
+        map: TMap,
+        unmap: TUnmap,
+    ) -> MapProtocol<Self, TNewProto, TMap, TUnmap>
+    where
+        Self: Sized,
+        TNewProto: ConnectionUpgrade<Self::Substream>,
+        TMap: Fn(Self::Protocol) -> TNewProto,
+        TUnmap: Fn(
+            TNewProto::Output,
+        ) -> <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+    {
+        MapProtocol::new(self, map, unmap)
+    }
+
+    /// Adds closures that turn the `OutboundOpenInfo` of the handler into something else.
+    ///
+    /// `map` is applied to the information of the outbound substream requests produced by the
+    /// handler. `unmap` is applied to the information before it is passed back to the handler,
+    /// for example in `inject_fully_negotiated()` or `inject_dial_upgrade_error()`.
+    ///
+    /// > **Note**: Since `should_open_outbound()` only receives a reference, the information is
+    /// >           cloned before being passed to `unmap` there.
+    #[inline]
+    fn map_outbound_open_info<TNewInfo, TMap, TUnmap>(
+        self,
+        map: TMap,
+        unmap: TUnmap,
+    ) -> MapOutboundOpenInfo<Self, TNewInfo, TMap, TUnmap>
+    where
+        Self: Sized,
+        TNewInfo: Clone,
+        TMap: FnMut(Self::OutboundOpenInfo) -> TNewInfo,
+        TUnmap: Fn(TNewInfo) -> Self::OutboundOpenInfo,
+    {
+        MapOutboundOpenInfo::new(self, map, unmap)
+    }
+
+    /// Wraps this handler so that `shutdown()` is called on it once `lifetime` has elapsed,
+    /// whatever it is doing. Contrary to the negotiation timeouts, this bounds the lifetime of the
+    /// whole handler.
+    #[inline]
+    fn with_max_lifetime(self, lifetime: Duration) -> MaxLifetime<Self>
+    where
+        Self: Sized,
+    {
+        MaxLifetime::new(self, lifetime)
+    }
+
+    /// Wraps this handler so that only one inbound substream can be open at a time. The
+    /// inbound substreams opened while one is active are closed.
+    ///
+    /// The handler must produce an event for which `is_closed` returns true once its inbound
+    /// substream has been closed, as this isn't reported otherwise.
+    #[inline]
+    fn single_inbound<TIsClosed>(self, is_closed: TIsClosed) -> SingleInbound<Self, TIsClosed>
+    where
+        Self: Sized,
+        TIsClosed: FnMut(&Self::OutEvent) -> bool,
+    {
+        SingleInbound::new(self, is_closed)
+    }
+
+    /// Wraps this handler so that it produces the event returned by `make_event` every
+    /// `interval`, even if this handler has nothing to do. The events of this handler are wrapped
+    /// in `EitherOutput::First`, and the heartbeats in `EitherOutput::Second`.
+    ///
+    /// The heartbeats stop once `shutdown()` has been called.
+    #[inline]
+    fn with_heartbeat<TMakeEvent, THeartbeat>(
+        self,
+        interval: Duration,
+        make_event: TMakeEvent,
+    ) -> Heartbeat<Self, TMakeEvent>
+    where
+        Self: Sized,
+        TMakeEvent: FnMut() -> THeartbeat,
+    {
+        Heartbeat::new(self, interval, make_event)
+    }
+
+    /// Wraps this handler so that the events injected with `inject_event` are held back until a
+    /// first substream has been negotiated, then passed to the handler in order.
+    ///
+    /// At most `capacity` events are held back. `overflow` determines what happens to the events
+    /// beyond that.
+    #[inline]
+    fn buffer_until_ready(
+        self,
+        capacity: usize,
+        overflow: BufferOverflow,
+    ) -> BufferUntilReady<Self>
+    where
+        Self: Sized,
+    {
+        BufferUntilReady::new(self, capacity, overflow)
+    }
+
+    /// Wraps this handler so that its outbound substream requests are held back until a gate is
+    /// opened by injecting `GateEvent::Open`, for example once some application-level condition
+    /// is met. The gate can be closed again with `GateEvent::Close`. The inbound substreams
+    /// aren't affected.
+    ///
+    /// At most `capacity` requests are held back. `overflow` determines what happens to the
+    /// requests beyond that, which fail in all cases.
+    #[inline]
+    fn gated_outbound(self, capacity: usize, overflow: BufferOverflow) -> GatedOutbound<Self>
+    where
+        Self: Sized,
+    {
+        GatedOutbound::new(self, capacity, overflow)
+    }
+
+    /// Wraps this handler so that an outbound substream request is dropped if a request with the
+    /// same key, as returned by `key`, is still in progress.
+    ///
+    /// The handler isn't notified about the requests that have been dropped.
+    #[inline]
+    fn dedup_outbound<TKeyFn, TKey>(self, key: TKeyFn) -> DedupOutbound<Self, TKeyFn, TKey>
+    where
+        Self: Sized,
+        TKeyFn: Fn(&Self::OutboundOpenInfo) -> TKey,
+        TKey: Eq + Hash,
+    {
+        DedupOutbound::new(self, key)
+    }
+
+    /// Wraps this handler so that it can be disabled and re-enabled at runtime by injecting
+    /// `ToggleEvent`s, without losing its state. `enabled` is the initial state.
+    ///
+    /// While disabled, inbound substreams are refused and outbound substream requests are
+    /// buffered until the handler is enabled again.
+    #[inline]
+    fn with_toggle(self, enabled: bool) -> Toggle<Self>
+    where
+        Self: Sized,
+    {
+        Toggle::new(self, enabled)
+    }
+
+    /// Wraps this handler so that it requests an outbound substream with the given upgrade and
+    /// `info` the first time it is polled. Afterwards, everything is delegated to this handler.
+    ///
+    /// If `shutdown()` is called before the first poll, the request is never produced and is
+    /// reported to this handler through `inject_dial_upgrade_error`.
+    #[inline]
+    fn with_initial_outbound(
+        self,
+        upgrade: Self::Protocol,
+        info: Self::OutboundOpenInfo,
+    ) -> InitialOutbound<Self>
+    where
+        Self: Sized,
+    {
+        InitialOutbound::new(self, upgrade, info)
+    }
+
+    /// Wraps this handler so that it doesn't produce more than `max_per_interval` outbound
+    /// substream requests during each `interval`. The requests beyond the limit are buffered and
+    /// produced later, in order.
+    #[inline]
+    fn rate_limit_outbound(self, max_per_interval: usize, interval: Duration) -> RateLimit<Self>
+    where
+        Self: Sized,
+    {
+        RateLimit::new(self, max_per_interval, interval)
+    }
+
+    /// Wraps this handler so that the outbound upgrades that fail because of a timeout or of an
+    /// error during the negotiation are attempted again after `backoff`, up to `max_attempts`
+    /// attempts in total. The error is only reported to this handler after the last attempt.
+    #[inline]
+    fn retry_outbound(self, max_attempts: usize, backoff: Duration) -> RetryOutbound<Self>
+    where
+        Self: Sized,
+        Self::Protocol: Clone,
+    {
+        RetryOutbound::new(self, max_attempts, backoff)
+    }
+
+    /// Builds an implementation of `ProtocolsHandler` that handles both this protocol and the
+    /// other one together.
+    #[inline]
+    fn select<TProto2>(self, other: TProto2) -> ProtocolsHandlerSelect<Self, TProto2>
+    where
+        Self: Sized,
+    {
+        ProtocolsHandlerSelect::new(self, other)
+    }
+
+    /// Same as `select`, but passes the events produced by the two handlers to `map`, which
+    /// turns them into a single type.
+    ///
+    /// This avoids nesting `EitherOutput`s when combining more than two handlers.
+    #[inline]
+    fn select_map<TProto2, TMap, TOut>(
+        self,
+        other: TProto2,
+        map: TMap,
+    ) -> MapOutEvent<ProtocolsHandlerSelect<Self, TProto2>, TMap>
+    where
+        Self: Sized,
+        TProto2: ProtocolsHandler,
+        TMap: FnMut(EitherOutput<Self::OutEvent, TProto2::OutEvent>) -> TOut,
+    {
+        MapOutEvent::new(ProtocolsHandlerSelect::new(self, other), map)
+    }
+
+    /// Same as `select`, for a handler that neither receives nor produces any event, such as
+    /// `DummyProtocolsHandler`. The events of the combined handler are directly those of `other`,
+    /// instead of `EitherOutput`s whose `First` can't exist.
+    ///
+    /// > **Note**: When only one direction uses `Void`, `EitherOutput::into_first` and
+    /// >           `EitherOutput::into_second` can be passed to `map_out_event` instead.
+    #[inline]
+    fn select_void<TProto2>(self, other: TProto2) -> ProtocolsHandlerSelectVoid<Self, TProto2>
+    where
+        Self: ProtocolsHandler<InEvent = Void, OutEvent = Void> + Sized,
+        TProto2: ProtocolsHandler,
+    {
+        let map_out: fn(_) -> _ = EitherOutput::into_second;
+        let map_in: fn(_) -> _ = |event| Some(EitherOutput::Second(event));
+        let select = MapOutEvent::new(ProtocolsHandlerSelect::new(self, other), map_out);
+        MapInEvent::new(select, map_in)
+    }
+
+    /// Creates a builder that will allow creating a `NodeHandler` that handles this protocol
+    /// exclusively.
+    #[inline]
+    fn into_node_handler_builder(self) -> NodeHandlerWrapperBuilder<Self>
+    where
+        Self: Sized,
+    {
+        NodeHandlerWrapperBuilder::new(self, Duration::from_secs(10), Duration::from_secs(10))
+    }
+
+    /// Erases the type of this handler, so that handlers of different types can be used
+    /// interchangeably. See `BoxProtocolsHandler` for the constraints this imposes.
+    #[inline]
+    fn boxed(self) -> BoxProtocolsHandler<Self::Substream, Self::InEvent, Self::OutEvent>
+    where
+        Self: Sized + Send + 'static,
+        Self::Protocol: Clone + Send + 'static,
+        <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Future: Send + 'static,
+        <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output: Send + 'static,
+        Self::OutboundOpenInfo: Send + 'static,
+    {
+        BoxProtocolsHandler::new(self)
+    }
+
+    /// Builds an implementation of `NodeHandler` that handles this protocol exclusively.
+    ///
+    /// > **Note**: This is a shortcut for `self.into_node_handler_builder().build()`.
+    #[inline]
+    fn into_node_handler(self) -> NodeHandlerWrapper<Self>
+    where
+        Self: Sized,
+    {
+        self.into_node_handler_builder().build()
+    }
+
+    /// Same as `into_node_handler`, but calls `on_event` on each event produced by the resulting
+    /// `NodeHandler`. This makes it possible to instrument a connection, for example to collect
+    /// metrics.
+    ///
+    /// > **Note**: This is a shortcut for `self.into_node_handler_builder().build_with(on_event)`.
+    /// >           Use the latter in order to configure the wrapper.
+    #[inline]
+    fn into_node_handler_with<TFn>(self, on_event: TFn) -> NodeHandlerWrapperWith<Self, TFn>
+    where
+        Self: Sized,
+        TFn: FnMut(&NodeHandlerEvent<u64, Self::OutEvent>),
+    {
+        self.into_node_handler_builder().build_with(on_event)
+    }
+}
+
+/// Injects a fully-negotiated substream in a handler, with `inject_fully_negotiated_with_timing`
+/// if `timing` is `Some` and with `inject_fully_negotiated` otherwise.
+///
+/// Used by the handlers that dispatch substreams to other handlers, so that they implement both
+/// methods with the same code.
+pub(crate) fn inject_negotiated<THandler>(
+    handler: &mut THandler,
+    protocol: <THandler::Protocol as ConnectionUpgrade<THandler::Substream>>::Output,
+    endpoint: NodeHandlerEndpoint<THandler::OutboundOpenInfo>,
+    timing: Option<NegotiationTiming>,
+) -> Result<(), <THandler::Protocol as ConnectionUpgrade<THandler::Substream>>::Output>
+where
+    THandler: ProtocolsHandler,
+{
+    match timing {
+        Some(timing) => handler.inject_fully_negotiated_with_timing(protocol, endpoint, timing),
+        None => handler.inject_fully_negotiated(protocol, endpoint),
+    }
+}
+
+/// Passes to `handler` the name of the negotiated protocol that a combining handler has received
+/// with `inject_protocol_negotiated`, if any.
+///
+/// The combining handlers only know which of their handlers a substream belongs to once it is
+/// injected, so they hold the name until then.
+pub(crate) fn inject_protocol_name<THandler>(
+    handler: &mut THandler,
+    negotiated: Option<(Bytes, Endpoint)>,
+) where
+    THandler: ProtocolsHandler,
+{
+    if let Some((name, endpoint)) = negotiated {
+        handler.inject_protocol_negotiated(&name, endpoint);
+    }
+}
+
+/// Turns a state made of multiple values into a number that changes whenever the state changes.
+///
+/// Used by the handlers whose `listen_protocol()` depends on multiple values, in order to
+/// implement `listen_protocol_version()`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ListenProtocolVersion<TState>
+where
+    TState: Copy,
+{
+    /// The last state that has been passed to `get`, and the version that corresponds to it.
+    last: Cell<Option<(TState, u64)>>,
+}
+
+impl<TState> ListenProtocolVersion<TState>
+where
+    TState: Copy + PartialEq,
+{
+    /// Returns the version that corresponds to `state`. Incremented whenever `state` differs
+    /// from the one of the previous call.
+    pub(crate) fn get(&self, state: TState) -> u64 {
+        let version = match self.last.get() {
+            Some((last, version)) if last == state => return version,
+            Some((_, version)) => version.wrapping_add(1),
+            None => 0,
+        };
+        self.last.set(Some((state, version)));
+        version
+    }
+}
+
+/// How long the negotiation of a substream took, and which protocol has been chosen. Passed to
+/// `ProtocolsHandler::inject_fully_negotiated_with_timing`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiationTiming {
+    elapsed: Duration,
+    timeout: Duration,
+    protocol: Bytes,
+}
+
+impl NegotiationTiming {
+    /// Builds a `NegotiationTiming`.
+    #[inline]
+    pub(crate) fn new(elapsed: Duration, timeout: Duration, protocol: Bytes) -> Self {
+        NegotiationTiming { elapsed, timeout, protocol }
+    }
+
+    /// Returns the name of the protocol that has been negotiated with `multistream-select`.
+    ///
+    /// This is useful for upgrades that support multiple protocols, such as multiple versions
+    /// of the same protocol, as it doesn't require the output of the upgrade to indicate which
+    /// one was chosen.
+    #[inline]
+    pub fn protocol(&self) -> &Bytes {
+        &self.protocol
+    }
+
+    /// Returns the time between the moment the substream was opened and the end of the
+    /// negotiation.
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the timeout that applied to the negotiation.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns how much time was left before the timeout.
+    #[inline]
+    pub fn remaining(&self) -> Duration {
+        if self.timeout > self.elapsed {
+            self.timeout - self.elapsed
+        } else {
+            Duration::new(0, 0)
+        }
+    }
+}
+
+/// Determines what a handler that combines multiple handlers, such as `ProtocolsHandlerSelect`
+/// or `ProtocolsHandlerMux`, does when one of them produces `Ready(None)`.
+///
+/// In both cases, calling `shutdown()` on the combined handler calls `shutdown()` on all the
+/// handlers it contains.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// `shutdown()` is called on all the other handlers as soon as one of them has finished.
+    Any,
+    /// The other handlers keep running. The combined handler only finishes once every handler
+    /// has finished on its own.
+    All,
+}
+
+/// How long the connection should be kept alive, as indicated by a `ProtocolsHandler`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeepAlive {
+    /// If nothing new happens, the connection should be closed at the given `Instant`.
+    Until(Instant),
+    /// The handler doesn't need the connection anymore, which can be closed as soon as possible.
+    Now,
+    /// The connection should be kept alive.
+    Forever,
+}
+
+impl KeepAlive {
+    /// Returns true for `Forever`, false otherwise.
+    #[inline]
+    pub fn is_forever(&self) -> bool {
+        match *self {
+            KeepAlive::Forever => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialOrd for KeepAlive {
+    #[inline]
+    fn partial_cmp(&self, other: &KeepAlive) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KeepAlive {
+    fn cmp(&self, other: &KeepAlive) -> Ordering {
+        use self::KeepAlive::*;
+
+        match (self, other) {
+            (Now, Now) | (Forever, Forever) => Ordering::Equal,
+            (Now, _) | (_, Forever) => Ordering::Less,
+            (_, Now) | (Forever, _) => Ordering::Greater,
+            (Until(expiration), Until(other_expiration)) => expiration.cmp(other_expiration),
+        }
+    }
+}
+
+/// Event produced by a handler.
+#[derive(Debug)]
+pub enum ProtocolsHandlerEvent<TConnectionUpgrade, TOutboundOpenInfo, TCustom> {
+    /// Require a new outbound substream to be opened with the remote.
+    OutboundSubstreamRequest {
+        /// The upgrade to apply on the substream.
+        upgrade: TConnectionUpgrade,
+        /// User-defind information, passed back when the substream is open.
+        info: TOutboundOpenInfo,
+        /// Timeout for the negotiation of this substream. If `None`, the timeout configured on
+        /// the `NodeHandlerWrapperBuilder` is used.
+        timeout: Option<Duration>,
+        /// How urgent this substream is compared to the other outbound substreams of the same
+        /// connection. Higher is more urgent. If the number of outbound substreams being opened
+        /// is capped, `NodeHandlerWrapper` reports the pending requests with the highest priority
+        /// first. Use 0 if you don't care.
+        priority: u8,
+    },
+
+    /// Cancel the pending outbound substream requests for which `should_open_outbound` now
+    /// returns false.
+    ///
+    /// > **Note**: A substream may have been opened for a request before its cancellation is
+    /// >           processed, in which case the handler receives it as usual. If the substream is
+    /// >           opened after the cancellation, it is closed without the handler being notified.
+    CancelOutbound,
+
+    /// Stop accepting inbound substreams, without shutting down. The negotiations in progress
+    /// continue, but the inbound substreams that are opened afterwards are closed immediately.
+    ///
+    /// > **Note**: This applies to the whole connection. When handlers are combined, for example
+    /// >           with `select`, none of them receives inbound substreams anymore.
+    CloseInbound,
+
+    /// Stop opening outbound substreams, without shutting down. The pending outbound substream
+    /// requests are dropped as with `CancelOutbound`, and the requests produced afterwards fail
+    /// immediately with `ProtocolsHandlerUpgrErr::MuxerClosed`.
+    ///
+    /// > **Note**: This applies to the whole connection. When handlers are combined, for example
+    /// >           with `select`, none of them can open outbound substreams anymore.
+    CloseOutbound,
+
+    /// Close the whole connection with the remote, for the given reason.
+    ///
+    /// Contrary to returning `Ready(None)` from `poll()`, which only indicates that this handler
+    /// has nothing more to do, this immediately tears down the connection.
+    Close(io::Error),
+
+    /// Other event.
+    Custom(TCustom),
+}
+
+/// Event produced by a handler.
+impl<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
+    ProtocolsHandlerEvent<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
+{
+    /// If this is `OutboundSubstreamRequest`, maps the content to something else.
+    #[inline]
+    pub fn map_outbound_open_info<F, I>(
+        self,
+        map: F,
+    ) -> ProtocolsHandlerEvent<TConnectionUpgrade, I, TCustom>
+    where
+        F: FnOnce(TOutboundOpenInfo) -> I,
+    {
+        match self {
+            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade,
+                info,
+                timeout,
+                priority,
+            } => {
+                ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info: map(info),
+                    timeout,
+                    priority,
+                }
+            }
+            ProtocolsHandlerEvent::CancelOutbound => ProtocolsHandlerEvent::CancelOutbound,
+            ProtocolsHandlerEvent::CloseInbound => ProtocolsHandlerEvent::CloseInbound,
+            ProtocolsHandlerEvent::CloseOutbound => ProtocolsHandlerEvent::CloseOutbound,
+            ProtocolsHandlerEvent::Close(err) => ProtocolsHandlerEvent::Close(err),
+            ProtocolsHandlerEvent::Custom(val) => ProtocolsHandlerEvent::Custom(val),
+        }
+    }
+
+    /// If this is `OutboundSubstreamRequest`, maps the protocol to another.
+    #[inline]
+    pub fn map_protocol<F, I>(
+        self,
+        map: F,
+    ) -> ProtocolsHandlerEvent<I, TOutboundOpenInfo, TCustom>
+    where
+        F: FnOnce(TConnectionUpgrade) -> I,
+    {
+        match self {
+            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade,
+                info,
+                timeout,
+                priority,
+            } => {
+                ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: map(upgrade),
+                    info,
+                    timeout,
+                    priority,
+                }
+            }
+            ProtocolsHandlerEvent::CancelOutbound => ProtocolsHandlerEvent::CancelOutbound,
+            ProtocolsHandlerEvent::CloseInbound => ProtocolsHandlerEvent::CloseInbound,
+            ProtocolsHandlerEvent::CloseOutbound => ProtocolsHandlerEvent::CloseOutbound,
+            ProtocolsHandlerEvent::Close(err) => ProtocolsHandlerEvent::Close(err),
+            ProtocolsHandlerEvent::Custom(val) => ProtocolsHandlerEvent::Custom(val),
+        }
+    }
+
+    /// If this is `Custom`, maps the content to something else.
+    #[inline]
+    pub fn map_custom<F, I>(
+        self,
+        map: F,
+    ) -> ProtocolsHandlerEvent<TConnectionUpgrade, TOutboundOpenInfo, I>
+    where
+        F: FnOnce(TCustom) -> I,
+    {
+        match self {
+            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade,
+                info,
+                timeout,
+                priority,
+            } => {
+                ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }
+            }
+            ProtocolsHandlerEvent::CancelOutbound => ProtocolsHandlerEvent::CancelOutbound,
+            ProtocolsHandlerEvent::CloseInbound => ProtocolsHandlerEvent::CloseInbound,
+            ProtocolsHandlerEvent::CloseOutbound => ProtocolsHandlerEvent::CloseOutbound,
+            ProtocolsHandlerEvent::Close(err) => ProtocolsHandlerEvent::Close(err),
+            ProtocolsHandlerEvent::Custom(val) => ProtocolsHandlerEvent::Custom(map(val)),
+        }
+    }
+}
+
+/// Error that can happen when opening and negotiating an outbound substream.
+#[derive(Debug)]
+pub enum ProtocolsHandlerUpgrErr {
+    /// The negotiation didn't finish before the timeout.
+    Timeout,
+    /// The muxer closed the substream, or couldn't open it.
+    MuxerClosed,
+    /// Error while negotiating the protocol or while applying the upgrade.
+    Upgrade(io::Error),
+}
+
+impl fmt::Display for ProtocolsHandlerUpgrErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolsHandlerUpgrErr::Timeout => {
+                write!(f, "timeout while opening a substream")
+            }
+            ProtocolsHandlerUpgrErr::MuxerClosed => {
+                write!(f, "the substream was closed by the muxer")
+            }
+            ProtocolsHandlerUpgrErr::Upgrade(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ProtocolsHandlerUpgrErr {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            ProtocolsHandlerUpgrErr::Timeout | ProtocolsHandlerUpgrErr::MuxerClosed => None,
+            ProtocolsHandlerUpgrErr::Upgrade(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<ProtocolsHandlerUpgrErr> for io::Error {
+    fn from(err: ProtocolsHandlerUpgrErr) -> io::Error {
+        let kind = match err {
+            ProtocolsHandlerUpgrErr::Timeout => io::ErrorKind::TimedOut,
+            ProtocolsHandlerUpgrErr::MuxerClosed => io::ErrorKind::ConnectionReset,
+            ProtocolsHandlerUpgrErr::Upgrade(err) => return err,
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use std::io::Cursor;
+
+    type Substream = Cursor<Vec<u8>>;
+
+    #[test]
+    fn dummy_handler_waits_for_shutdown() {
+        let mut handler = TestProtocolsHandler::new(DummyProtocolsHandler::<Substream>::default());
+        let (events, finished) = handler.poll_all().unwrap();
+        assert!(events.is_empty());
+        assert!(!finished);
+
+        handler.shutdown();
+        let (events, finished) = handler.poll_all().unwrap();
+        assert!(events.is_empty());
+        assert!(finished);
+        assert_eq!(handler.calls(), &[Call::Poll, Call::Shutdown, Call::Poll][..]);
+    }
+
+    #[test]
+    fn closing_dummy_handler_finishes_immediately() {
+        let mut handler = TestProtocolsHandler::new(DummyProtocolsHandler::<Substream>::closing());
+        let (events, finished) = handler.poll_all().unwrap();
+        assert!(events.is_empty());
+        assert!(finished);
+        assert_eq!(handler.calls(), &[Call::Poll][..]);
+        assert_eq!(handler.inner().connection_keep_alive(), KeepAlive::Now);
+    }
+
+    #[test]
+    fn upgrade_errors_keep_their_message_and_cause() {
+        let muxer_closed = ProtocolsHandlerUpgrErr::MuxerClosed;
+        let message = muxer_closed.to_string();
+        assert_eq!(io::Error::from(muxer_closed).to_string(), message);
+        assert!(error::Error::source(&ProtocolsHandlerUpgrErr::Timeout).is_none());
+
+        let inner = io::Error::new(io::ErrorKind::Other, "invalid handshake");
+        let upgrade = ProtocolsHandlerUpgrErr::Upgrade(inner);
+        let source = error::Error::source(&upgrade).expect("the upgrade error is the cause");
+        assert_eq!(source.to_string(), "invalid handshake");
+    }
+}
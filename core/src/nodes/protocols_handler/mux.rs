@@ -0,0 +1,791 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    inject_negotiated, inject_protocol_name, KeepAlive, NegotiationTiming, ProtocolsHandler,
+    ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, ShutdownPolicy,
+};
+use std::{io, vec};
+use tokio_io::{AsyncRead, AsyncWrite};
+use {ConnectionUpgrade, Endpoint};
+
+/// Implementation of `ProtocolsHandler` that dispatches between a dynamic list of handlers of
+/// the same type.
+///
+/// Each sub-handler is identified by a `u64` chosen by the user when the sub-handler is added
+/// with `ProtocolsHandlerMuxIn::Add`. The events produced by the sub-handlers are tagged with
+/// this identifier.
+///
+/// Inbound substreams are only offered to the sub-handlers whose `should_accept_inbound()`
+/// returns true. Since the sub-handlers usually support the same protocols, and since the first
+/// matching protocol is always picked during the negotiation, an inbound substream goes to the
+/// first of these sub-handlers, in the order in which they have been added.
+///
+/// A sub-handler that produces `Ready(None)` is removed from the list. By default, the other
+/// sub-handlers keep running. With `ShutdownPolicy::Any`, they are shut down instead. The mux
+/// produces `Ready(None)` once its list is empty, unless it has never contained any sub-handler
+/// and `shutdown()` hasn't been called.
+#[derive(Debug)]
+pub struct ProtocolsHandlerMux<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The sub-handlers, with their identifier.
+    handlers: Vec<(u64, TProtoHandler)>,
+    /// Outbound substream requests produced by the sub-handlers that haven't been answered yet,
+    /// with the identifier of the sub-handler that produced them. Used to route the events
+    /// targeted with `MuxTarget::ByOpenInfo`.
+    outbound: Vec<(u64, TProtoHandler::OutboundOpenInfo)>,
+    /// If true, at least one sub-handler has been added at some point.
+    ever_had_handlers: bool,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    /// Whether to shut down the other sub-handlers when one of them has finished.
+    shutdown_policy: ShutdownPolicy,
+    /// Index within `handlers` of the sub-handler to poll first during the next call to
+    /// `poll()`. Rotated at each call so that no sub-handler can starve the others.
+    next_poll_start: usize,
+    /// Information about the connection, once known. Passed to the sub-handlers that are added
+    /// afterwards.
+    connection_info: Option<ConnectionInfo>,
+    /// Last value passed to `inject_outbound_ready()`. Passed to the sub-handlers that are added
+    /// afterwards.
+    outbound_ready: bool,
+    /// Name passed to `inject_protocol_negotiated`, held until we know which sub-handler the
+    /// substream belongs to.
+    negotiated_protocol: Option<(Bytes, Endpoint)>,
+}
+
+/// Event that can be injected in a `ProtocolsHandlerMux`.
+pub enum ProtocolsHandlerMuxIn<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Adds a sub-handler with the given identifier. If a sub-handler already exists with the
+    /// same identifier, it is replaced and dropped.
+    Add(u64, TProtoHandler),
+    /// Calls `shutdown()` on the sub-handler with the given identifier. It is removed once it
+    /// produces `Ready(None)`.
+    Remove(u64),
+    /// Injects an event in the sub-handler with the given identifier. Ignored if there is no
+    /// such sub-handler.
+    Event(u64, TProtoHandler::InEvent),
+    /// Injects an event in the sub-handlers designated by the target.
+    Targeted(MuxTarget<TProtoHandler::OutboundOpenInfo>, TProtoHandler::InEvent),
+}
+
+/// Sub-handlers that a `ProtocolsHandlerMuxIn::Targeted` event is meant for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxTarget<TOutboundOpenInfo> {
+    /// All the sub-handlers. The event is cloned for each of them.
+    All,
+    /// The sub-handler that has produced an outbound substream request with this information,
+    /// as long as the substream hasn't been negotiated and the request hasn't failed or been
+    /// cancelled. The event is ignored if there is no such sub-handler.
+    ByOpenInfo(TOutboundOpenInfo),
+}
+
+impl<TProtoHandler> ProtocolsHandlerMux<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `ProtocolsHandlerMux` with the given sub-handlers.
+    #[inline]
+    pub fn new<I>(handlers: I) -> Self
+    where
+        I: IntoIterator<Item = (u64, TProtoHandler)>,
+    {
+        let handlers: Vec<_> = handlers.into_iter().collect();
+        ProtocolsHandlerMux {
+            ever_had_handlers: !handlers.is_empty(),
+            handlers,
+            outbound: Vec::new(),
+            shutting_down: false,
+            shutdown_policy: ShutdownPolicy::All,
+            next_poll_start: 0,
+            connection_info: None,
+            outbound_ready: true,
+            negotiated_protocol: None,
+        }
+    }
+
+    /// Sets what happens to the other sub-handlers when one of them has finished. The default is
+    /// `ShutdownPolicy::All`.
+    #[inline]
+    pub fn with_shutdown_policy(mut self, policy: ShutdownPolicy) -> Self {
+        self.shutdown_policy = policy;
+        self
+    }
+
+    /// Returns the number of sub-handlers.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// Returns true if there is no sub-handler.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Returns the sub-handler with the given identifier.
+    fn handler_mut(&mut self, id: u64) -> Option<&mut TProtoHandler> {
+        self.handlers
+            .iter_mut()
+            .find(|(handler_id, _)| *handler_id == id)
+            .map(|(_, handler)| handler)
+    }
+}
+
+/// Output of the upgrade of a sub-handler.
+type ProtocolOutput<THandler> = <<THandler as ProtocolsHandler>::Protocol as ConnectionUpgrade<
+    <THandler as ProtocolsHandler>::Substream,
+>>::Output;
+
+impl<TProtoHandler> ProtocolsHandlerMux<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::OutboundOpenInfo: PartialEq,
+{
+    /// Forgets about an outbound substream request, because it has been answered.
+    fn remove_outbound(&mut self, id: u64, info: &TProtoHandler::OutboundOpenInfo) {
+        if let Some(pos) = self
+            .outbound
+            .iter()
+            .position(|(out_id, out_info)| *out_id == id && out_info == info)
+        {
+            self.outbound.remove(pos);
+        }
+    }
+
+    /// Dispatches a fully-negotiated substream to the sub-handler it belongs to.
+    fn dispatch_negotiated(
+        &mut self,
+        protocol: (u64, ProtocolOutput<TProtoHandler>),
+        endpoint: NodeHandlerEndpoint<(u64, TProtoHandler::OutboundOpenInfo)>,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), (u64, ProtocolOutput<TProtoHandler>)> {
+        let (id, protocol) = protocol;
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer((info_id, info)) => {
+                debug_assert_eq!(id, info_id);
+                self.remove_outbound(id, &info);
+                NodeHandlerEndpoint::Dialer(info)
+            }
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+
+        // The sub-handler may have been removed in the meanwhile, in which case the substream is
+        // rejected.
+        let negotiated = self.negotiated_protocol.take();
+        match self.handler_mut(id) {
+            Some(handler) => {
+                inject_protocol_name(handler, negotiated);
+                inject_negotiated(handler, protocol, endpoint, timing)
+                    .map_err(|protocol| (id, protocol))
+            }
+            None => Err((id, protocol)),
+        }
+    }
+}
+
+impl<TProtoHandler> Default for ProtocolsHandlerMux<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    #[inline]
+    fn default() -> Self {
+        ProtocolsHandlerMux::new(Vec::new())
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for ProtocolsHandlerMux<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::InEvent: Clone,
+    TProtoHandler::OutboundOpenInfo: Clone + PartialEq,
+{
+    type InEvent = ProtocolsHandlerMuxIn<TProtoHandler>;
+    type OutEvent = (u64, TProtoHandler::OutEvent);
+    type Substream = TProtoHandler::Substream;
+    type Protocol = MuxUpgrade<TProtoHandler::Protocol>;
+    type OutboundOpenInfo = (u64, TProtoHandler::OutboundOpenInfo);
+
+    fn listen_protocol(&self) -> Self::Protocol {
+        // The sub-handlers that refuse inbound substreams aren't offered any, otherwise they
+        // could get the substreams meant for the ones that come after them.
+        MuxUpgrade {
+            upgrades: self
+                .handlers
+                .iter()
+                .filter(|(_, handler)| handler.should_accept_inbound())
+                .map(|(id, handler)| (*id, handler.listen_protocol()))
+                .collect(),
+        }
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.handlers.iter().any(|(_, handler)| handler.should_accept_inbound())
+    }
+
+    fn should_open_outbound(&self, &(id, ref info): &Self::OutboundOpenInfo) -> bool {
+        // The requests of the sub-handlers that have been removed are no longer wanted.
+        self.handlers
+            .iter()
+            .find(|(handler_id, _)| *handler_id == id)
+            .map(|(_, handler)| handler.should_open_outbound(info))
+            .unwrap_or(false)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, None)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, Some(timing))
+    }
+
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match event {
+            ProtocolsHandlerMuxIn::Add(id, mut handler) => {
+                if let Some(ref info) = self.connection_info {
+                    handler.inject_connection_info(info.clone());
+                }
+                if !self.outbound_ready {
+                    handler.inject_outbound_ready(false);
+                }
+                if self.shutting_down {
+                    handler.shutdown();
+                }
+                self.ever_had_handlers = true;
+                // The requests of the replaced sub-handler will never be answered.
+                self.outbound.retain(|(out_id, _)| *out_id != id);
+                if let Some(existing) = self.handler_mut(id) {
+                    *existing = handler;
+                    return;
+                }
+                self.handlers.push((id, handler));
+            }
+            ProtocolsHandlerMuxIn::Remove(id) => {
+                if let Some(handler) = self.handler_mut(id) {
+                    handler.shutdown();
+                }
+            }
+            ProtocolsHandlerMuxIn::Event(id, event) => {
+                if let Some(handler) = self.handler_mut(id) {
+                    handler.inject_event(event);
+                }
+            }
+            ProtocolsHandlerMuxIn::Targeted(MuxTarget::All, event) => {
+                for (_, handler) in self.handlers.iter_mut() {
+                    handler.inject_event(event.clone());
+                }
+            }
+            ProtocolsHandlerMuxIn::Targeted(MuxTarget::ByOpenInfo(info), event) => {
+                let id = self
+                    .outbound
+                    .iter()
+                    .find(|(_, out_info)| *out_info == info)
+                    .map(|(id, _)| *id);
+                match id.and_then(|id| self.handler_mut(id)) {
+                    Some(handler) => handler.inject_event(event),
+                    None => debug!("Ignoring an event targeting an unknown outbound request"),
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        // We don't know which of the sub-handlers the next event is for.
+        self.handlers.iter().all(|(_, handler)| handler.ready_for_event())
+    }
+
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        for (_, handler) in self.handlers.iter_mut() {
+            try_ready!(handler.poll_ready_event());
+        }
+        Ok(Async::Ready(()))
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.handlers.iter().all(|(_, handler)| handler.is_idle())
+    }
+
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.inject_connection_info(info.clone());
+        }
+        // Kept for the sub-handlers that are added later.
+        self.connection_info = Some(info);
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        (id, info): Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.remove_outbound(id, &info);
+        if let Some(handler) = self.handler_mut(id) {
+            handler.inject_dial_upgrade_error(info, error);
+        }
+    }
+
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        // We have no way to know which of the sub-handlers the remote wanted to talk to.
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.inject_listen_upgrade_error(io::Error::new(error.kind(), error.to_string()));
+        }
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.negotiated_protocol = Some((name.clone(), endpoint));
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.inject_inbound_closed();
+        }
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.inject_outbound_closed();
+        }
+    }
+
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.inject_outbound_ready(ready);
+        }
+        // Kept for the sub-handlers that are added later.
+        self.outbound_ready = ready;
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        for (_, handler) in self.handlers.iter_mut() {
+            handler.shutdown();
+        }
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.handlers
+            .iter()
+            .map(|(_, handler)| handler.connection_keep_alive())
+            .max()
+            .unwrap_or(KeepAlive::Now)
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let start = if self.next_poll_start < self.handlers.len() {
+            self.next_poll_start
+        } else {
+            0
+        };
+        self.next_poll_start = start + 1;
+
+        loop {
+            let shutting_down_before = self.shutting_down;
+
+            // We go through the list starting at `start`, wrapping around. Sub-handlers that are
+            // finished are removed, which shifts the following ones to the left.
+            let mut index = start;
+            let mut remaining = self.handlers.len();
+            while remaining != 0 {
+                remaining -= 1;
+                if index >= self.handlers.len() {
+                    index = 0;
+                }
+
+                let id = self.handlers[index].0;
+                match self.handlers[index].1.poll()? {
+                    Async::Ready(Some(event)) => {
+                        match event {
+                            ProtocolsHandlerEvent::OutboundSubstreamRequest { ref info, .. } => {
+                                self.outbound.push((id, info.clone()));
+                            }
+                            ProtocolsHandlerEvent::CancelOutbound => {
+                                let handler = &self.handlers[index].1;
+                                self.outbound.retain(|(out_id, info)| {
+                                    *out_id != id || handler.should_open_outbound(info)
+                                });
+                            }
+                            _ => (),
+                        }
+                        let event = event
+                            .map_custom(|event| (id, event))
+                            .map_outbound_open_info(|info| (id, info))
+                            .map_protocol(|upgrade| MuxUpgrade {
+                                upgrades: vec![(id, upgrade)],
+                            });
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                    Async::Ready(None) => {
+                        self.handlers.remove(index);
+                        self.outbound.retain(|(out_id, _)| *out_id != id);
+                        if self.shutdown_policy == ShutdownPolicy::Any && !self.shutting_down {
+                            self.shutdown();
+                        }
+                    }
+                    Async::NotReady => index += 1,
+                }
+            }
+
+            if self.handlers.is_empty() && (self.ever_had_handlers || self.shutting_down) {
+                return Ok(Async::Ready(None));
+            }
+
+            // If a sub-handler has just finished and the others have been shut down, they need
+            // to be polled again in order to observe the consequences of `shutdown()`.
+            if self.shutting_down == shutting_down_before {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// Upgrade used by `ProtocolsHandlerMux`. Dispatches between the upgrades of a list of
+/// sub-handlers and tags the output with the identifier of the sub-handler.
+#[derive(Debug, Clone)]
+pub struct MuxUpgrade<TUpgrade> {
+    upgrades: Vec<(u64, TUpgrade)>,
+}
+
+impl<C, TUpgrade> ConnectionUpgrade<C> for MuxUpgrade<TUpgrade>
+where
+    C: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<C>,
+{
+    type NamesIter = vec::IntoIter<(Bytes, (usize, TUpgrade::UpgradeIdentifier))>;
+    type UpgradeIdentifier = (usize, TUpgrade::UpgradeIdentifier);
+
+    fn protocol_names(&self) -> Self::NamesIter {
+        let mut names = Vec::new();
+        for (index, (_, upgrade)) in self.upgrades.iter().enumerate() {
+            names.extend(upgrade.protocol_names().map(|(name, id)| (name, (index, id))));
+        }
+        names.into_iter()
+    }
+
+    type Output = (u64, TUpgrade::Output);
+    type Future = MuxUpgradeFuture<TUpgrade::Future>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        (index, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+    ) -> Self::Future {
+        let (handler_id, upgrade) = self
+            .upgrades
+            .into_iter()
+            .nth(index)
+            .expect("the upgrade identifier was produced by protocol_names; qed");
+        MuxUpgradeFuture {
+            handler_id,
+            inner: upgrade.upgrade(socket, id, ty),
+        }
+    }
+}
+
+/// Future returned by the `ConnectionUpgrade` implementation of `MuxUpgrade`.
+pub struct MuxUpgradeFuture<TFuture> {
+    handler_id: u64,
+    inner: TFuture,
+}
+
+impl<TFuture> Future for MuxUpgradeFuture<TFuture>
+where
+    TFuture: Future,
+{
+    type Item = (u64, TFuture::Item);
+    type Error = TFuture::Error;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let output = try_ready!(self.inner.poll());
+        Ok(Async::Ready((self.handler_id, output)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use std::io::Cursor;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio_mock_task::MockTask;
+    use upgrade::PlainTextConfig;
+
+    /// Handler that produces one outbound substream request with the given information, and
+    /// finishes once shut down.
+    struct RequestHandler {
+        request: Option<u32>,
+        accept_inbound: bool,
+        shutting_down: bool,
+    }
+
+    impl ProtocolsHandler for RequestHandler {
+        type InEvent = &'static str;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn should_accept_inbound(&self) -> bool {
+            self.accept_inbound
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: Cursor<Vec<u8>>,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), Cursor<Vec<u8>>> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: &'static str) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {
+            self.shutting_down = true;
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, u32, ()>>, io::Error> {
+            if self.shutting_down {
+                return Ok(Async::Ready(None));
+            }
+            match self.request.take() {
+                Some(info) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: PlainTextConfig,
+                    info,
+                    timeout: None,
+                    priority: 0,
+                }))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    type TestMux = ProtocolsHandlerMux<TestProtocolsHandler<RequestHandler>>;
+
+    /// Builds a sub-handler that requests an outbound substream with `request` as information.
+    fn requesting(request: Option<u32>) -> TestProtocolsHandler<RequestHandler> {
+        TestProtocolsHandler::new(RequestHandler {
+            request,
+            accept_inbound: true,
+            shutting_down: false,
+        })
+    }
+
+    /// Polls the mux until it doesn't produce any event anymore. Returns the information of the
+    /// outbound substream requests, and whether the mux has finished.
+    fn poll_requests(mux: &mut TestMux) -> (Vec<(u64, u32)>, bool) {
+        let mut task = MockTask::new();
+        let mut requests = Vec::new();
+        loop {
+            match task.enter(|| mux.poll()).unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info, ..
+                })) => requests.push(info),
+                Async::Ready(Some(_)) => (),
+                Async::Ready(None) => return (requests, true),
+                Async::NotReady => return (requests, false),
+            }
+        }
+    }
+
+    /// Returns the calls made to the given sub-handler, except for the polls.
+    fn calls(mux: &TestMux, id: u64) -> Vec<Call<&'static str, u32>> {
+        let handler = &mux.handlers.iter().find(|(handler_id, _)| *handler_id == id).unwrap().1;
+        handler.calls().iter().filter(|call| **call != Call::Poll).cloned().collect()
+    }
+
+    /// Returns the events injected in the given sub-handler.
+    fn injected(
+        mux: &ProtocolsHandlerMux<TestProtocolsHandler<RequestHandler>>,
+        id: u64,
+    ) -> Vec<&'static str> {
+        let handler = &mux.handlers.iter().find(|(handler_id, _)| *handler_id == id).unwrap().1;
+        handler
+            .calls()
+            .iter()
+            .filter_map(|call| match *call {
+                Call::Event(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn targeted_events_are_routed() {
+        let mut mux = ProtocolsHandlerMux::new(vec![
+            (1, requesting(Some(10))),
+            (2, requesting(Some(20))),
+        ]);
+        let mut task = MockTask::new();
+        while let Ok(Async::Ready(Some(_))) = task.enter(|| mux.poll()) {}
+
+        mux.inject_event(ProtocolsHandlerMuxIn::Targeted(MuxTarget::ByOpenInfo(20), "second"));
+        mux.inject_event(ProtocolsHandlerMuxIn::Targeted(MuxTarget::All, "all"));
+        // Unknown requests are ignored.
+        mux.inject_event(ProtocolsHandlerMuxIn::Targeted(MuxTarget::ByOpenInfo(30), "unknown"));
+        assert_eq!(injected(&mux, 1), vec!["all"]);
+        assert_eq!(injected(&mux, 2), vec!["second", "all"]);
+
+        // Once the request has failed, it can no longer be targeted.
+        mux.inject_dial_upgrade_error((1, 10), ProtocolsHandlerUpgrErr::Timeout);
+        mux.inject_event(ProtocolsHandlerMuxIn::Targeted(MuxTarget::ByOpenInfo(10), "first"));
+        assert_eq!(injected(&mux, 1), vec!["all"]);
+    }
+
+    #[test]
+    fn shutdown_policy_decides_whether_siblings_are_shut_down() {
+        let handlers = || {
+            vec![
+                (1, DummyProtocolsHandler::<Cursor<Vec<u8>>>::closing()),
+                (2, DummyProtocolsHandler::<Cursor<Vec<u8>>>::default()),
+            ]
+        };
+
+        let mut all = ProtocolsHandlerMux::new(handlers());
+        assert!(all.poll().unwrap().is_not_ready());
+        assert_eq!(all.len(), 1);
+
+        let mut any =
+            ProtocolsHandlerMux::new(handlers()).with_shutdown_policy(ShutdownPolicy::Any);
+        match any.poll().unwrap() {
+            Async::Ready(None) => (),
+            _ => panic!("expected the mux to finish"),
+        }
+    }
+
+    #[test]
+    fn sub_handlers_can_be_added_and_removed() {
+        let mut mux = TestMux::default();
+        // An empty mux waits for its first sub-handler.
+        assert_eq!(poll_requests(&mut mux), (Vec::new(), false));
+
+        mux.inject_event(ProtocolsHandlerMuxIn::Add(1, requesting(Some(10))));
+        mux.inject_event(ProtocolsHandlerMuxIn::Add(2, requesting(Some(20))));
+        assert_eq!(mux.len(), 2);
+        let (mut requests, finished) = poll_requests(&mut mux);
+        requests.sort();
+        assert_eq!((requests, finished), (vec![(1, 10), (2, 20)], false));
+
+        mux.inject_event(ProtocolsHandlerMuxIn::Remove(1));
+        assert_eq!(calls(&mux, 1), vec![Call::Shutdown]);
+        assert_eq!(poll_requests(&mut mux), (Vec::new(), false));
+        assert_eq!(mux.len(), 1);
+
+        // The mux finishes once its last sub-handler has finished.
+        mux.inject_event(ProtocolsHandlerMuxIn::Remove(2));
+        assert_eq!(poll_requests(&mut mux), (Vec::new(), true));
+        assert!(mux.is_empty());
+    }
+
+    #[test]
+    fn outbound_results_go_to_the_requesting_sub_handler() {
+        let mut mux = ProtocolsHandlerMux::new(vec![
+            (1, requesting(Some(10))),
+            (2, requesting(Some(20))),
+        ]);
+        poll_requests(&mut mux);
+
+        let substream = (2, Cursor::new(Vec::new()));
+        let endpoint = NodeHandlerEndpoint::Dialer((2, 20));
+        assert!(mux.inject_fully_negotiated(substream, endpoint).is_ok());
+        mux.inject_dial_upgrade_error((1, 10), ProtocolsHandlerUpgrErr::Timeout);
+
+        let timeout = ProtocolsHandlerUpgrErr::Timeout.to_string();
+        assert_eq!(calls(&mux, 1), vec![Call::DialUpgradeError(10, timeout)]);
+        assert_eq!(calls(&mux, 2), vec![Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(20))]);
+
+        // The substreams of the sub-handlers that are gone are rejected.
+        let substream = (3, Cursor::new(Vec::new()));
+        assert!(mux.inject_fully_negotiated(substream, NodeHandlerEndpoint::Listener).is_err());
+    }
+
+    #[test]
+    fn inbound_substreams_go_to_the_first_accepting_sub_handler() {
+        let refusing = TestProtocolsHandler::new(RequestHandler {
+            request: None,
+            accept_inbound: false,
+            shutting_down: false,
+        });
+        let mut mux = ProtocolsHandlerMux::new(vec![
+            (1, refusing),
+            (2, requesting(None)),
+            (3, requesting(None)),
+        ]);
+        assert!(mux.should_accept_inbound());
+
+        let upgrade = mux.listen_protocol();
+        let names = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade);
+        let (_, id) = names.into_iter().next().unwrap();
+        let substream = upgrade
+            .upgrade(Cursor::new(Vec::new()), id, Endpoint::Listener)
+            .wait()
+            .unwrap();
+        assert_eq!(substream.0, 2);
+        assert!(mux.inject_fully_negotiated(substream, NodeHandlerEndpoint::Listener).is_ok());
+        assert!(calls(&mux, 1).is_empty());
+        assert_eq!(calls(&mux, 2), vec![Call::FullyNegotiated(NodeHandlerEndpoint::Listener)]);
+        assert!(calls(&mux, 3).is_empty());
+    }
+}
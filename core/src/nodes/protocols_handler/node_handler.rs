@@ -0,0 +1,4038 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use futures::{prelude::*, task};
+use multistream_select::ProtocolChoiceError;
+use nodes::handled_node::{ConnectionInfo, NodeHandler, NodeHandlerEndpoint, NodeHandlerEvent};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::{cmp, fmt, io, mem};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::{clock::{self, Clock}, timer, Delay, Timeout};
+use upgrade::{self, apply::UpgradeApplyFuture};
+use {ConnectionUpgrade, Endpoint};
+
+/// Logs a message tagged with the `log_id` of a `NodeHandlerWrapper`. Does nothing, and doesn't
+/// even format the message, if the wrapper doesn't have an identifier.
+macro_rules! debug_with_id {
+    ($wrapper:expr, $($arg:tt)+) => {
+        if let Some(ref log_id) = $wrapper.log_id {
+            debug!("[{}] {}", log_id, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Same as `debug_with_id!`, but for warnings. These are logged even if the wrapper doesn't have
+/// an identifier, in which case the message isn't tagged.
+macro_rules! warn_with_id {
+    ($wrapper:expr, $($arg:tt)+) => {
+        match $wrapper.log_id {
+            Some(ref log_id) => warn!("[{}] {}", log_id, format_args!($($arg)+)),
+            None => warn!($($arg)+),
+        }
+    };
+}
+
+/// Prototype for a `NodeHandlerWrapper`.
+pub struct NodeHandlerWrapperBuilder<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    handler: TProtoHandler,
+    /// Timeout for incoming substreams negotiation.
+    in_timeout: Duration,
+    /// Timeouts for incoming substreams, per negotiated protocol name.
+    inbound_timeouts: HashMap<Bytes, Duration>,
+    /// Timeout for outgoing substreams negotiation.
+    out_timeout: Duration,
+    /// Maximum time the muxer has to open a requested outbound substream.
+    open_timeout: Option<Duration>,
+    /// Whether to remember the protocols supported or not by the remote.
+    protocols_cache: bool,
+    /// Maximum number of outbound substreams being opened or negotiated at the same time.
+    max_negotiating_outbound: Option<usize>,
+    /// Maximum number of inbound substreams to accept during any period of the given duration.
+    inbound_rate_limit: Option<(usize, Duration)>,
+    /// Whether to report the outbound substream requests one at a time, in order.
+    ordered_outbound: bool,
+    /// Maximum time the handler has to finish after `shutdown()` has been called.
+    shutdown_timeout: Option<Duration>,
+    /// Maximum number of events the handler can produce after `shutdown()` has been called.
+    max_shutdown_events: Option<usize>,
+    /// Maximum number of negotiations to poll during each call to `poll()`.
+    poll_budget: Option<usize>,
+    /// Maximum relative deviation applied to the negotiation timeouts.
+    timeout_jitter: Option<f64>,
+    /// Source of time and timers.
+    timer: TimerFactory,
+    /// Identifier that tags the log messages of the wrapper.
+    log_id: Option<String>,
+    /// Whether to turn the panics of the handler into errors.
+    catch_unwind: bool,
+    /// Whether to poll the handler before the negotiations.
+    poll_handler_first: bool,
+    /// Clones the upgrade returned by `listen_protocol()`. `None` if it mustn't be cached.
+    listen_protocol_clone: Option<fn(&TProtoHandler::Protocol) -> TProtoHandler::Protocol>,
+}
+
+impl<TProtoHandler> NodeHandlerWrapperBuilder<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler
+{
+    /// Builds a `NodeHandlerWrapperBuilder`.
+    #[inline]
+    pub(crate) fn new(handler: TProtoHandler, in_timeout: Duration, out_timeout: Duration) -> Self {
+        NodeHandlerWrapperBuilder {
+            handler,
+            in_timeout,
+            inbound_timeouts: HashMap::new(),
+            out_timeout,
+            open_timeout: None,
+            protocols_cache: true,
+            max_negotiating_outbound: None,
+            inbound_rate_limit: None,
+            ordered_outbound: false,
+            shutdown_timeout: None,
+            max_shutdown_events: None,
+            poll_budget: None,
+            timeout_jitter: None,
+            timer: TimerFactory::default(),
+            log_id: None,
+            catch_unwind: false,
+            poll_handler_first: false,
+            listen_protocol_clone: None,
+        }
+    }
+
+    /// Sets the timeout to use when negotiating a protocol on an ingoing substream.
+    #[inline]
+    pub fn with_in_negotiation_timeout(mut self, timeout: Duration) -> Self {
+        self.in_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeouts to use for ingoing substreams, depending on the protocol that gets
+    /// negotiated on them.
+    ///
+    /// Since the protocol isn't known until `multistream-select` has finished, the timeout set
+    /// with `with_in_negotiation_timeout` applies at first. Once the protocol is known, its
+    /// timeout in this map replaces it, counting from the opening of the substream. Protocols
+    /// that aren't in the map keep the default timeout.
+    #[inline]
+    pub fn with_inbound_timeouts(mut self, timeouts: HashMap<Bytes, Duration>) -> Self {
+        self.inbound_timeouts = timeouts;
+        self
+    }
+
+    /// Sets the timeout to use when negotiating a protocol on an outgoing substream.
+    ///
+    /// This can be overridden for a specific substream with the `timeout` field of
+    /// `ProtocolsHandlerEvent::OutboundSubstreamRequest`.
+    #[inline]
+    pub fn with_out_negotiation_timeout(mut self, timeout: Duration) -> Self {
+        self.out_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum time the muxer has to open the outbound substreams requested by the
+    /// handler, before their negotiation starts. By default, there is no limit.
+    ///
+    /// A request whose substream isn't opened in time fails with
+    /// `ProtocolsHandlerUpgrErr::Timeout`, and the substream is closed if it gets opened later.
+    #[inline]
+    pub fn with_open_timeout(mut self, timeout: Duration) -> Self {
+        self.open_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables or disables the cache of the protocols supported by the remote. Enabled by
+    /// default.
+    ///
+    /// When enabled, the `NodeHandlerWrapper` remembers the protocols that the remote has
+    /// rejected during a negotiation. Later outbound substream requests for protocols that are
+    /// all known to be rejected fail immediately with `inject_dial_upgrade_error`, without
+    /// opening a substream.
+    ///
+    /// This should be disabled for handlers that expect the capabilities of the remote to change
+    /// during the lifetime of the connection.
+    #[inline]
+    pub fn with_protocol_cache(mut self, enabled: bool) -> Self {
+        self.protocols_cache = enabled;
+        self
+    }
+
+    /// Sets the maximum number of outbound substreams that can be opened or negotiated at the
+    /// same time. Unlimited by default.
+    ///
+    /// Outbound substream requests produced by the handler beyond this limit are queued, and are
+    /// only reported to the `NodeHandler` layer once a previous outbound substream has finished
+    /// negotiating, highest `priority` first. A value of 0 is treated as 1.
+    #[inline]
+    pub fn with_max_negotiating_outbound(mut self, max: usize) -> Self {
+        self.max_negotiating_outbound = Some(cmp::max(max, 1));
+        self
+    }
+
+    /// Limits the number of inbound substreams accepted during any period of `interval` to
+    /// `max_per_interval`. Unlimited by default.
+    ///
+    /// The inbound substreams beyond this limit are closed immediately, without being negotiated
+    /// and without the handler being involved. This protects against a remote that floods the
+    /// connection with substreams.
+    #[inline]
+    pub fn with_inbound_rate_limit(mut self, max_per_interval: usize, interval: Duration) -> Self {
+        self.inbound_rate_limit = Some((max_per_interval, interval));
+        self
+    }
+
+    /// Enables or disables the ordered mode for outbound substreams. Disabled by default.
+    ///
+    /// In ordered mode, an outbound substream request is only reported to the `NodeHandler`
+    /// layer once the substream of the previous request has been opened or has failed to open,
+    /// so that the muxer opens the substreams in the order in which the handler requested them.
+    /// The `priority` of the requests is ignored. The negotiations themselves may still happen in
+    /// parallel.
+    #[inline]
+    pub fn with_ordered_outbound(mut self, enabled: bool) -> Self {
+        self.ordered_outbound = enabled;
+        self
+    }
+
+    /// Sets the maximum time the handler has to produce `Ready(None)` after `shutdown()` has been
+    /// called. Once this delay has elapsed, the handler isn't polled anymore and the
+    /// `NodeHandlerWrapper` produces `Ready(None)` itself, after the events that the handler has
+    /// already produced, so that the connection gets closed. By default, there is no limit.
+    #[inline]
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum number of events the handler can produce after `shutdown()` has been
+    /// called. A handler that produces more is considered misbehaving: it isn't polled anymore
+    /// and the `NodeHandlerWrapper` produces `Ready(None)` itself, after the events that the
+    /// handler has already produced, so that the connection gets closed. By default, there is no
+    /// limit.
+    #[inline]
+    pub fn with_max_shutdown_events(mut self, max: usize) -> Self {
+        self.max_shutdown_events = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of substream negotiations to poll during each call to `poll()`.
+    /// Unlimited by default.
+    ///
+    /// When the budget is exhausted, the remaining negotiations are polled first during the next
+    /// call, and the current task is notified so that this happens soon, but after the other
+    /// tasks of the executor got a chance to run. A value of 0 is treated as 1.
+    #[inline]
+    pub fn with_poll_budget(mut self, budget: usize) -> Self {
+        self.poll_budget = Some(cmp::max(budget, 1));
+        self
+    }
+
+    /// Randomizes the timeout of each substream negotiation by up to `fraction` of its duration,
+    /// in both directions. Disabled by default.
+    ///
+    /// When lots of substreams are opened at the same time, for example after many connections
+    /// have been established at once, this avoids all their negotiations timing out at the same
+    /// moment. The value is clamped between 0 and 1, and NaN disables the jitter.
+    #[inline]
+    pub fn with_timeout_jitter(mut self, fraction: f64) -> Self {
+        let fraction = if fraction.is_nan() { 0.0 } else { fraction.max(0.0).min(1.0) };
+        self.timeout_jitter = Some(fraction);
+        self
+    }
+
+    /// Sets the source of time and timers used for the timeouts. By default, the clock and the
+    /// timer of the current tokio execution context are used.
+    ///
+    /// This is mostly useful in tests, in order to control the passage of time.
+    #[inline]
+    pub fn with_timer(mut self, timer: TimerFactory) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Sets an identifier, for example the address or the peer ID of the remote, that tags the
+    /// log messages produced at the key steps of the lifetime of the `NodeHandlerWrapper`:
+    /// substream negotiations, events produced by the handler, and shutdown. This makes it
+    /// possible to correlate the logs of a connection when there are many of them.
+    ///
+    /// Without an identifier, these messages aren't produced at all.
+    #[inline]
+    pub fn with_log_id<T>(mut self, id: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.log_id = Some(id.into());
+        self
+    }
+
+    /// Enables or disables catching the panics of the handler. Disabled by default.
+    ///
+    /// When enabled, a panic in one of the methods of the handler that poll it or inject
+    /// something in it is turned into an error that closes the connection, instead of unwinding
+    /// through the task that drives the connection. The events that the handler had produced
+    /// before are still delivered. This is meant for running handlers that can't be trusted in
+    /// a shared swarm.
+    ///
+    /// The handler doesn't need to be `UnwindSafe`, because it is never called again after it has
+    /// panicked, and is only dropped. It must however not share state with the rest of the
+    /// program that a panic could leave inconsistent.
+    #[inline]
+    pub fn with_catch_unwind(mut self, enabled: bool) -> Self {
+        self.catch_unwind = enabled;
+        self
+    }
+
+    /// Enables or disables polling the handler before the substream negotiations. Disabled by
+    /// default.
+    ///
+    /// By default, `poll()` continues the negotiations first and polls the handler last, so that
+    /// the handler immediately reacts to the substreams that have just been negotiated. When
+    /// enabled, the handler is additionally polled before the negotiations, so that the events
+    /// it produces independently of them aren't delayed by a large number of negotiations. The
+    /// cost is one more call to the handler's `poll()` at each call, and the events produced by
+    /// this first call don't take into account the negotiations that finish during the same call.
+    #[inline]
+    pub fn with_poll_handler_first(mut self, enabled: bool) -> Self {
+        self.poll_handler_first = enabled;
+        self
+    }
+
+    /// Enables or disables caching the upgrade returned by the handler's `listen_protocol()`.
+    /// Disabled by default.
+    ///
+    /// When enabled, and as long as the handler's `listen_protocol_version()` returns the same
+    /// number, each new inbound substream gets a clone of the previous upgrade instead of calling
+    /// `listen_protocol()` again. This only pays off if cloning the upgrade is cheaper than
+    /// building it, for example because its configuration is behind an `Arc`.
+    #[inline]
+    pub fn with_listen_protocol_cache(mut self, enabled: bool) -> Self
+    where
+        TProtoHandler::Protocol: Clone,
+    {
+        self.listen_protocol_clone = if enabled { Some(Clone::clone) } else { None };
+        self
+    }
+
+    /// Builds the `NodeHandlerWrapper`, and wraps it so that `on_event` is called on each event
+    /// that it produces.
+    #[inline]
+    pub fn build_with<TFn>(self, on_event: TFn) -> NodeHandlerWrapperWith<TProtoHandler, TFn>
+    where
+        TFn: FnMut(&NodeHandlerEvent<u64, TProtoHandler::OutEvent>),
+    {
+        NodeHandlerWrapperWith {
+            wrapper: self.build(),
+            on_event,
+        }
+    }
+
+    /// Builds the `NodeHandlerWrapper`.
+    #[inline]
+    pub fn build(self) -> NodeHandlerWrapper<TProtoHandler> {
+        NodeHandlerWrapper {
+            handler: self.handler,
+            negotiating_in: VecDeque::new(),
+            negotiating_out: VecDeque::new(),
+            in_timeout: self.in_timeout,
+            inbound_timeouts: self.inbound_timeouts,
+            out_timeout: self.out_timeout,
+            open_timeout: self.open_timeout,
+            open_deadlines: Vec::new(),
+            queued_dial_upgrades: BTreeMap::new(),
+            cancelled_dial_upgrades: Vec::new(),
+            delayed_dial_upgrades: VecDeque::new(),
+            max_negotiating_outbound: self.max_negotiating_outbound,
+            inbound_rate_limit: self.inbound_rate_limit,
+            inbound_accepted: VecDeque::new(),
+            ordered_outbound: self.ordered_outbound,
+            unique_dial_upgrade_id: 0,
+            keep_alive: KeepAlive::Forever,
+            negotiation_stats: NegotiationStats::default(),
+            protocol_stats: HashMap::new(),
+            poll_outbound_first: false,
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_deadline: None,
+            max_shutdown_events: self.max_shutdown_events,
+            shutdown_events: 0,
+            poll_budget: self.poll_budget,
+            timeout_jitter: self.timeout_jitter.map(TimeoutJitter::new),
+            timer: self.timer,
+            listen_protocol_cache: None,
+            listen_protocol_clone: self.listen_protocol_clone,
+            log_id: self.log_id,
+            catch_unwind: self.catch_unwind,
+            handler_panicked: false,
+            poll_handler_first: self.poll_handler_first,
+            outbound_closed: false,
+            inbound_closed_locally: false,
+            outbound_closed_locally: false,
+            outbound_ready: true,
+            shutting_down: false,
+            finished: false,
+            pending_events: VecDeque::new(),
+            handler_end: None,
+            protocols_cache: if self.protocols_cache {
+                Some(ProtocolsCache::new())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Wraps around an implementation of `ProtocolsHandler`, and implements `NodeHandler`.
+pub struct NodeHandlerWrapper<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    handler: TProtoHandler,
+    /// Futures that upgrade incoming substreams, and when the negotiation started. Polled in
+    /// order, from the front to the back.
+    negotiating_in: VecDeque<(
+        NegotiationStart,
+        Timeout<UpgradeApplyFuture<TProtoHandler::Substream, TProtoHandler::Protocol>>,
+    )>,
+    /// Futures that upgrade outgoing substreams. The first element of the tuple is the userdata
+    /// to pass back once successfully opened. The second element is the list of protocol names
+    /// of the upgrade. The third element is when the negotiation started. Polled in order, from
+    /// the front to the back.
+    negotiating_out: VecDeque<(
+        TProtoHandler::OutboundOpenInfo,
+        Vec<Bytes>,
+        NegotiationStart,
+        Timeout<UpgradeApplyFuture<TProtoHandler::Substream, TProtoHandler::Protocol>>,
+    )>,
+    /// Timeout for incoming substreams negotiation.
+    in_timeout: Duration,
+    /// Timeouts for incoming substreams, per negotiated protocol name. Replace `in_timeout` once
+    /// the protocol is known.
+    inbound_timeouts: HashMap<Bytes, Duration>,
+    /// Timeout for outgoing substreams negotiation.
+    out_timeout: Duration,
+    /// Maximum time the muxer has to open a requested outbound substream.
+    open_timeout: Option<Duration>,
+    /// For each entry of `queued_dial_upgrades`, if `open_timeout` is set, its identifier and
+    /// when its request fails. May contain entries for requests that are no longer queued.
+    open_deadlines: Vec<(u64, Delay)>,
+    /// For each outbound substream request, indexed by its unique identifier (see
+    /// `unique_dial_upgrade_id`), how to upgrade it. The second element of the tuple is the
+    /// timeout requested by the handler for this specific upgrade, if any. The last element is
+    /// the information to pass back to the handler.
+    ///
+    /// Since the identifiers are increasing, the requests are ordered from the oldest to the
+    /// newest.
+    queued_dial_upgrades: BTreeMap<
+        u64,
+        (TProtoHandler::Protocol, Option<Duration>, TProtoHandler::OutboundOpenInfo),
+    >,
+    /// Identifiers of the outbound substream requests that have been reported to the
+    /// `NodeHandler` layer, then cancelled by the handler. The substreams that get opened for
+    /// them are closed immediately.
+    cancelled_dial_upgrades: Vec<u64>,
+    /// Outbound substream requests produced by the handler that haven't been reported to the
+    /// `NodeHandler` layer yet because of `max_negotiating_outbound` or `ordered_outbound`.
+    /// Sorted by descending priority (the last element of the tuple), and in order of arrival
+    /// for equal priorities. In ordered mode, only in order of arrival.
+    delayed_dial_upgrades: VecDeque<(
+        TProtoHandler::Protocol,
+        TProtoHandler::OutboundOpenInfo,
+        Option<Duration>,
+        u8,
+    )>,
+    /// Maximum number of entries in `queued_dial_upgrades` and `negotiating_out` combined.
+    max_negotiating_outbound: Option<usize>,
+    /// Maximum number of inbound substreams to accept during any period of the given duration.
+    inbound_rate_limit: Option<(usize, Duration)>,
+    /// When the inbound substreams accepted during the last period of `inbound_rate_limit` were
+    /// accepted, oldest first. Always empty if there is no limit.
+    inbound_accepted: VecDeque<Instant>,
+    /// If true, at most one outbound substream request is reported to the `NodeHandler` layer
+    /// and waiting for its substream at any given time. See `with_ordered_outbound`.
+    ordered_outbound: bool,
+    /// Identifier to assign to the next outbound substream request.
+    ///
+    /// The identifiers are never reused, even once their request is over: the `NodeHandler`
+    /// layer can still report a substream for a request that has been cancelled, and it must not
+    /// be mistaken for a newer request. At one request per nanosecond, it would take centuries
+    /// to exhaust them.
+    unique_dial_upgrade_id: u64,
+    /// Strongest keep-alive between the one of the handler and the one required by the pending
+    /// negotiations. Updated at each call to `poll()`.
+    keep_alive: KeepAlive,
+    /// Protocols that the remote is known to support or not. `None` if disabled.
+    protocols_cache: Option<ProtocolsCache>,
+    /// Durations of the successful negotiations.
+    negotiation_stats: NegotiationStats,
+    /// Outcome of the outbound negotiations, per protocol name. See `protocol_stats()`.
+    protocol_stats: HashMap<Bytes, ProtocolStats>,
+    /// Whether `poll()` should start with the outbound negotiations rather than the inbound ones.
+    /// Alternated at each call.
+    poll_outbound_first: bool,
+    /// Maximum time the handler has to finish after `shutdown()` has been called.
+    shutdown_timeout: Option<Duration>,
+    /// Fires when the handler has been shut down for longer than `shutdown_timeout`. `None` if
+    /// `shutdown()` hasn't been called or if there is no timeout.
+    shutdown_deadline: Option<Delay>,
+    /// Maximum number of events the handler can produce after `shutdown()` has been called.
+    /// `None` if unlimited.
+    max_shutdown_events: Option<usize>,
+    /// Number of events the handler has produced since `shutdown()` has been called.
+    shutdown_events: usize,
+    /// Maximum number of negotiations to poll during each call to `poll()`. `None` if unlimited.
+    poll_budget: Option<usize>,
+    /// Randomizes the negotiation timeouts. `None` if disabled.
+    timeout_jitter: Option<TimeoutJitter>,
+    /// Source of time and timers.
+    timer: TimerFactory,
+    /// Last value returned by the handler's `listen_protocol()`, with the corresponding
+    /// `listen_protocol_version()`. Reused for the inbound substreams as long as the version
+    /// doesn't change.
+    listen_protocol_cache: Option<(u64, TProtoHandler::Protocol)>,
+    /// Clones the cached upgrade. `None` if the cache is disabled. See
+    /// `with_listen_protocol_cache`.
+    listen_protocol_clone: Option<fn(&TProtoHandler::Protocol) -> TProtoHandler::Protocol>,
+    /// Identifier that tags the log messages of the wrapper. See `with_log_id`.
+    log_id: Option<String>,
+    /// If true, the panics of the handler are turned into errors. See `with_catch_unwind`.
+    catch_unwind: bool,
+    /// If true, the handler has panicked and must not be called anymore.
+    handler_panicked: bool,
+    /// If true, the handler is also polled before the negotiations. See
+    /// `with_poll_handler_first`.
+    poll_handler_first: bool,
+    /// If true, the `NodeHandler` layer has reported that the outbound part of the muxer is
+    /// closed, and the handler has been notified.
+    outbound_closed: bool,
+    /// If true, the handler has produced `CloseInbound`, and the inbound substreams are closed.
+    inbound_closed_locally: bool,
+    /// If true, the handler has produced `CloseOutbound`, and its outbound substream requests
+    /// fail immediately.
+    outbound_closed_locally: bool,
+    /// Last value passed to the handler's `inject_outbound_ready()`, or `true` if it hasn't been
+    /// called yet.
+    outbound_ready: bool,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    /// If true, `poll()` has returned `Ready(None)` or an error. The handler isn't called
+    /// anymore, and `poll()` always returns `Ready(None)`.
+    finished: bool,
+    /// Events produced by the handler that haven't been returned by `poll()` yet. Contains at
+    /// most `MAX_PENDING_EVENTS` elements.
+    pending_events: VecDeque<NodeHandlerEvent<u64, TProtoHandler::OutEvent>>,
+    /// If `Some`, the handler has produced `Ready(None)` (`Ok`) or an error (`Err`), which must
+    /// be returned once `pending_events` is empty. The handler isn't polled anymore.
+    handler_end: Option<Result<(), io::Error>>,
+}
+
+/// Maximum number of events that `NodeHandlerWrapper::poll()` buffers when the handler produces
+/// multiple events in a row.
+const MAX_PENDING_EVENTS: usize = 32;
+
+impl<TProtoHandler> NodeHandlerWrapper<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Returns a reference to the underlying handler.
+    #[inline]
+    pub fn get_ref(&self) -> &TProtoHandler {
+        &self.handler
+    }
+
+    /// Returns a mutable reference to the underlying handler.
+    ///
+    /// The `NodeHandlerWrapper` isn't aware of what is done with it. In particular, the methods
+    /// that change what `poll()` would produce don't wake up the task, and the events injected
+    /// this way bypass the wrapper.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut TProtoHandler {
+        &mut self.handler
+    }
+
+    /// Replaces the handler with `new` on the live connection, and returns the events that the
+    /// old handler has produced while being disposed of.
+    ///
+    /// The old handler is shut down, then polled until it has finished or doesn't produce any
+    /// event anymore, at most `max_shutdown_events` times or 32 times if there is no such limit.
+    /// Its outbound substream requests fail with `MuxerClosed`. If it is still not finished at
+    /// that point, it is dropped anyway. Like `poll()`, this must be called from
+    /// within a task.
+    ///
+    /// The inbound substreams being negotiated are passed to the new handler once negotiated,
+    /// as the upgrade they use is of the same type. The outbound requests of the old handler
+    /// are abandoned, since their `OutboundOpenInfo` is meaningless to the new handler. The new
+    /// handler starts fresh: it is shut down immediately if the wrapper is shutting down, and
+    /// the `ConnectionInfo` passed earlier isn't injected in it again.
+    ///
+    /// Has no effect other than dropping `new` if the wrapper has already finished.
+    pub fn replace_handler(&mut self, new: TProtoHandler) -> Vec<TProtoHandler::OutEvent> {
+        if self.finished {
+            return Vec::new();
+        }
+        debug_with_id!(self, "Replacing the handler");
+        let mut old = mem::replace(&mut self.handler, new);
+        let events = if self.handler_panicked {
+            // The old handler must not be called anymore.
+            Vec::new()
+        } else if self.catch_unwind {
+            let dispose = AssertUnwindSafe(|| self.dispose_of_handler(&mut old));
+            panic::catch_unwind(dispose).unwrap_or_else(|_| {
+                debug_with_id!(self, "Handler panicked while being replaced");
+                Vec::new()
+            })
+        } else {
+            self.dispose_of_handler(&mut old)
+        };
+
+        self.cancelled_dial_upgrades.extend(self.queued_dial_upgrades.keys().cloned());
+        self.queued_dial_upgrades.clear();
+        self.delayed_dial_upgrades.clear();
+        self.negotiating_out.clear();
+        self.listen_protocol_cache = None;
+        self.handler_panicked = false;
+        self.handler_end = None;
+        self.inbound_closed_locally = false;
+        self.outbound_closed_locally = false;
+        self.outbound_ready = true;
+        self.shutdown_events = 0;
+        if self.shutting_down {
+            self.call_handler(|handler| handler.shutdown());
+        }
+        events
+    }
+
+    /// Returns until when the connection should be kept alive.
+    ///
+    /// This value is updated at each call to `poll()`. It is `KeepAlive::Forever` as long as
+    /// substreams are being negotiated or are queued, and is otherwise the value returned by the
+    /// handler's `connection_keep_alive()`. This can be used by the upper layers in order to
+    /// gracefully close connections that are no longer needed.
+    #[inline]
+    pub fn connection_keep_alive(&self) -> KeepAlive {
+        self.keep_alive
+    }
+
+    /// Returns the names of all the protocols that the handler currently accepts on inbound
+    /// substreams, as produced by its `listen_protocol()`.
+    ///
+    /// For handlers that combine multiple handlers, such as `ProtocolsHandlerSelect`, this
+    /// contains the names of all the underlying handlers. Empty if the handler has panicked.
+    pub fn supported_protocol_names(&self) -> Vec<Bytes> {
+        if self.handler_panicked {
+            return Vec::new();
+        }
+        self.handler
+            .listen_protocol()
+            .protocol_names()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Returns whether the handler is ready to receive more events. See
+    /// `ProtocolsHandler::ready_for_event`.
+    ///
+    /// Always true once the handler has panicked, as the events injected from then on are
+    /// discarded.
+    #[inline]
+    pub fn ready_for_event(&self) -> bool {
+        self.handler_panicked || self.handler.ready_for_event()
+    }
+
+    /// Polls whether the handler is ready to receive more events. See
+    /// `ProtocolsHandler::poll_ready_event`.
+    ///
+    /// Always ready once the handler has finished or panicked, as the events injected from then
+    /// on are discarded.
+    pub fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        if self.finished {
+            return Ok(Async::Ready(()));
+        }
+        self.call_handler(|handler| handler.poll_ready_event())
+            .unwrap_or(Ok(Async::Ready(())))
+    }
+
+    /// Returns true if there is nothing in progress: no substream being negotiated, no outbound
+    /// substream request waiting for its substream, no event waiting to be returned by `poll()`,
+    /// and the handler's `is_idle()` returns true. Always true once the handler has finished.
+    ///
+    /// This only reflects the state as of the last call to `poll()`.
+    pub fn is_idle(&self) -> bool {
+        if self.finished {
+            return true;
+        }
+        self.negotiating_in.is_empty()
+            && self.negotiating_out.is_empty()
+            && self.queued_dial_upgrades.is_empty()
+            && self.delayed_dial_upgrades.is_empty()
+            && self.pending_events.is_empty()
+            && self.handler_end.is_none()
+            && (self.handler_panicked || self.handler.is_idle())
+    }
+
+    /// Returns true if `shutdown()` has been called and the handler hasn't finished yet, in
+    /// other words if the wrapper is draining the remaining events of the handler.
+    ///
+    /// A handler that stays in this state for a long time may be stuck. See also
+    /// `NodeHandlerWrapperBuilder::with_shutdown_timeout`.
+    #[inline]
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down && !self.finished
+    }
+
+    /// Sets the timeout to use when negotiating a protocol on an ingoing substream. See
+    /// `NodeHandlerWrapperBuilder::with_in_negotiation_timeout`.
+    ///
+    /// Only the negotiations that start afterwards are affected. The timeouts set with
+    /// `with_inbound_timeouts` still apply once the protocol is known.
+    #[inline]
+    pub fn set_in_negotiation_timeout(&mut self, timeout: Duration) {
+        self.in_timeout = timeout;
+    }
+
+    /// Sets the timeout to use when negotiating a protocol on an outgoing substream. See
+    /// `NodeHandlerWrapperBuilder::with_out_negotiation_timeout`.
+    ///
+    /// Only the negotiations that start afterwards are affected, including the ones of the
+    /// requests that the handler has already produced.
+    #[inline]
+    pub fn set_out_negotiation_timeout(&mut self, timeout: Duration) {
+        self.out_timeout = timeout;
+    }
+
+    /// Returns statistics about the duration of the substream negotiations that have succeeded
+    /// on this connection, in both directions.
+    #[inline]
+    pub fn negotiation_stats(&self) -> &NegotiationStats {
+        &self.negotiation_stats
+    }
+
+    /// Returns, for each protocol, how many outbound substreams have been successfully negotiated
+    /// and how many have failed or timed out on this connection.
+    ///
+    /// A negotiation is attributed to the first protocol name of the upgrade, as produced by its
+    /// `protocol_names()`. For upgrades that support multiple protocols, this is the name of the
+    /// first one and not necessarily the one that has been negotiated.
+    #[inline]
+    pub fn protocol_stats(&self) -> &HashMap<Bytes, ProtocolStats> {
+        &self.protocol_stats
+    }
+
+    /// Returns how often the protocols cache has prevented opening a substream, or `None` if the
+    /// cache has been disabled with `with_protocol_cache`.
+    #[inline]
+    pub fn protocols_cache_stats(&self) -> Option<ProtocolsCacheStats> {
+        self.protocols_cache.as_ref().map(|cache| cache.stats)
+    }
+
+    /// Returns the number of inbound substreams whose negotiation is in progress.
+    #[inline]
+    pub fn num_negotiating_in(&self) -> usize {
+        self.negotiating_in.len()
+    }
+
+    /// Returns the number of outbound substreams whose negotiation is in progress.
+    #[inline]
+    pub fn num_negotiating_out(&self) -> usize {
+        self.negotiating_out.len()
+    }
+
+    /// Returns the number of outbound substreams that have been requested to the `NodeHandler`
+    /// layer and that haven't been opened yet.
+    #[inline]
+    pub fn num_queued_dial_upgrades(&self) -> usize {
+        self.queued_dial_upgrades.len()
+    }
+
+    /// Returns the identifier and the upgrade of the oldest outbound substream request that has
+    /// been reported to the `NodeHandler` layer and whose substream hasn't been opened yet.
+    ///
+    /// The identifier is the one passed to `inject_substream`, and that appears in the logs.
+    #[inline]
+    pub fn peek_next_dial_upgrade(&self) -> Option<(u64, &TProtoHandler::Protocol)> {
+        self.queued_dial_upgrades
+            .iter()
+            .next()
+            .map(|(id, (upgrade, _, _))| (*id, upgrade))
+    }
+
+    /// Returns the number of outbound substream requests that are held back because of the
+    /// limit set with `with_max_negotiating_outbound`.
+    #[inline]
+    pub fn num_delayed_dial_upgrades(&self) -> usize {
+        self.delayed_dial_upgrades.len()
+    }
+
+    /// Returns true if an outbound substream request produced by the handler now would be
+    /// reported to the muxer right away, instead of being held back because of
+    /// `with_max_negotiating_outbound` or failing because the outbound part of the muxer is
+    /// closed.
+    ///
+    /// The handler is informed of the changes of this value through `inject_outbound_ready()`.
+    #[inline]
+    pub fn is_outbound_ready(&self) -> bool {
+        !self.outbound_closed
+            && !self.outbound_closed_locally
+            && self.delayed_dial_upgrades.is_empty()
+            && self.can_open_outbound()
+    }
+
+    /// Returns all the counters of pending negotiations at once.
+    #[inline]
+    pub fn stats(&self) -> NodeHandlerWrapperStats {
+        NodeHandlerWrapperStats {
+            negotiating_in: self.num_negotiating_in(),
+            negotiating_out: self.num_negotiating_out(),
+            queued_dial_upgrades: self.num_queued_dial_upgrades(),
+            delayed_dial_upgrades: self.num_delayed_dial_upgrades(),
+        }
+    }
+
+    /// Recomputes the value returned by `connection_keep_alive()`.
+    fn update_keep_alive(&mut self) {
+        self.keep_alive = if !self.negotiating_in.is_empty()
+            || !self.negotiating_out.is_empty()
+            || !self.queued_dial_upgrades.is_empty()
+            || !self.delayed_dial_upgrades.is_empty()
+        {
+            KeepAlive::Forever
+        } else {
+            // A handler that has panicked doesn't need the connection anymore.
+            self.call_handler(|handler| handler.connection_keep_alive())
+                .unwrap_or(KeepAlive::Now)
+        };
+    }
+
+    /// Called when `poll()` is about to return `Ready(None)` or an error. The handler isn't
+    /// called anymore after that, and all the pending negotiations are dropped.
+    fn finish(&mut self) {
+        debug_with_id!(self, "Handler finished");
+        self.finished = true;
+        self.pending_events.clear();
+        self.handler_end = None;
+        self.negotiating_in.clear();
+        self.negotiating_out.clear();
+        self.delayed_dial_upgrades.clear();
+        self.listen_protocol_cache = None;
+        // The substreams that are still going to be opened for these requests are closed
+        // immediately.
+        self.cancelled_dial_upgrades.extend(self.queued_dial_upgrades.keys().cloned());
+        self.queued_dial_upgrades.clear();
+        self.keep_alive = KeepAlive::Now;
+    }
+
+    /// Shuts down a handler that is being replaced, and polls it until it has finished or doesn't
+    /// produce any event anymore. Returns its custom events.
+    ///
+    /// The handler is polled at most `max_shutdown_events` times, or `MAX_PENDING_EVENTS` times
+    /// if there is no such limit, so that a handler that never stops producing events doesn't
+    /// block the task.
+    fn dispose_of_handler(&self, handler: &mut TProtoHandler) -> Vec<TProtoHandler::OutEvent> {
+        handler.shutdown();
+        let max_polls = self.max_shutdown_events.unwrap_or(MAX_PENDING_EVENTS);
+        let mut events = Vec::new();
+        for _ in 0 .. max_polls {
+            match handler.poll() {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))) => events.push(event),
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info, ..
+                }))) => {
+                    handler.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::MuxerClosed);
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)))
+                | Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)))
+                | Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound))) => (),
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err)))) | Err(err) => {
+                    debug_with_id!(self, "Replaced handler produced an error: {}", err);
+                    break;
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+            }
+        }
+        events
+    }
+
+    /// Returns the protocol names of an upgrade produced by the handler. Only the first name is
+    /// read if the handler declares `SINGLE_PROTOCOL`.
+    fn protocol_names_of(upgrade: &TProtoHandler::Protocol) -> Vec<Bytes> {
+        let names = upgrade.protocol_names().map(|(name, _)| name);
+        if TProtoHandler::SINGLE_PROTOCOL {
+            debug_assert!(
+                upgrade.protocol_names().nth(1).is_none(),
+                "handler declares SINGLE_PROTOCOL but produced an upgrade with multiple names"
+            );
+            let mut single = Vec::with_capacity(1);
+            single.extend(names.take(1));
+            single
+        } else {
+            names.collect()
+        }
+    }
+
+    /// Calls `f` with the handler. If `catch_unwind` is enabled and `f` panics, the handler isn't
+    /// called anymore and the next call to `poll()` closes the connection with an error. Returns
+    /// `None` in that case, or if the handler has panicked before.
+    fn call_handler<TRet, TFn>(&mut self, f: TFn) -> Option<TRet>
+    where
+        TFn: FnOnce(&mut TProtoHandler) -> TRet,
+    {
+        if self.handler_panicked {
+            return None;
+        }
+        if !self.catch_unwind {
+            return Some(f(&mut self.handler));
+        }
+        let handler = &mut self.handler;
+        match panic::catch_unwind(AssertUnwindSafe(move || f(handler))) {
+            Ok(ret) => Some(ret),
+            Err(_) => {
+                debug_with_id!(self, "Handler panicked; closing the connection");
+                self.handler_panicked = true;
+                let err = io::Error::new(io::ErrorKind::Other, "the protocols handler panicked");
+                self.handler_end = Some(Err(err));
+                None
+            }
+        }
+    }
+
+    /// Informs the handler if the value of `is_outbound_ready()` has changed since the last time.
+    fn report_outbound_ready(&mut self) {
+        let ready = self.is_outbound_ready();
+        if ready != self.outbound_ready {
+            debug_with_id!(self, "Outbound readiness changed to {}", ready);
+            self.outbound_ready = ready;
+            self.call_handler(|handler| handler.inject_outbound_ready(ready));
+        }
+    }
+
+    /// Returns true if a new outbound substream can be reported to the `NodeHandler` layer
+    /// without exceeding `max_negotiating_outbound`, and without overtaking a previous request in
+    /// ordered mode.
+    fn can_open_outbound(&self) -> bool {
+        if self.ordered_outbound && !self.queued_dial_upgrades.is_empty() {
+            return false;
+        }
+        match self.max_negotiating_outbound {
+            Some(max) => self.queued_dial_upgrades.len() + self.negotiating_out.len() < max,
+            None => true,
+        }
+    }
+
+    /// Assigns an identifier to an outbound substream request and queues it. Returns the event
+    /// to report to the `NodeHandler` layer.
+    fn queue_dial_upgrade(
+        &mut self,
+        upgrade: TProtoHandler::Protocol,
+        info: TProtoHandler::OutboundOpenInfo,
+        timeout: Option<Duration>,
+    ) -> NodeHandlerEvent<u64, TProtoHandler::OutEvent> {
+        let id = self.unique_dial_upgrade_id;
+        debug_assert!(id != u64::max_value(), "outbound substream identifiers exhausted");
+        self.unique_dial_upgrade_id = id.wrapping_add(1);
+        self.queued_dial_upgrades.insert(id, (upgrade, timeout, info));
+        if let Some(open_timeout) = self.open_timeout {
+            let mut deadline = self.timer.delay(self.timer.now() + open_timeout);
+            // Register the current task, so that it is notified when the deadline is reached.
+            // The outcome is handled by `poll_open_deadlines`.
+            let _ = deadline.poll();
+            self.open_deadlines.push((id, deadline));
+        }
+        NodeHandlerEvent::OutboundSubstreamRequest(id)
+    }
+
+    /// Fails the outbound substream requests whose substream hasn't been opened before
+    /// `open_timeout`. Their substreams are closed if they get opened later.
+    fn poll_open_deadlines(&mut self) {
+        if self.open_deadlines.is_empty() {
+            return;
+        }
+        let queued = &self.queued_dial_upgrades;
+        self.open_deadlines.retain(|(id, _)| queued.contains_key(id));
+
+        let mut expired = Vec::new();
+        for n in (0..self.open_deadlines.len()).rev() {
+            match self.open_deadlines[n].1.poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(())) => expired.push(self.open_deadlines[n].0),
+                Err(err) => {
+                    debug_with_id!(self, "Timer error while waiting for a substream: {:?}", err)
+                }
+            }
+            self.open_deadlines.remove(n);
+        }
+
+        for id in expired.into_iter().rev() {
+            let (_, _, info) = self
+                .queued_dial_upgrades
+                .remove(&id)
+                .expect("open_deadlines only contains queued requests at this point; qed");
+            self.cancelled_dial_upgrades.push(id);
+            debug_with_id!(self, "The outbound substream #{} wasn't opened in time", id);
+            let err = ProtocolsHandlerUpgrErr::Timeout;
+            self.call_handler(|handler| handler.inject_dial_upgrade_error(info, err));
+        }
+    }
+
+    /// Removes the pending outbound substream requests for which the handler's
+    /// `should_open_outbound` returns false.
+    fn cancel_unwanted_dial_upgrades(&mut self) {
+        let handler = &self.handler;
+        let unwanted = self
+            .queued_dial_upgrades
+            .iter()
+            .filter(|(_, (_, _, info))| !handler.should_open_outbound(info))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in unwanted {
+            self.queued_dial_upgrades.remove(&id);
+            self.cancelled_dial_upgrades.push(id);
+        }
+        self.delayed_dial_upgrades
+            .retain(|&(_, ref info, _, _)| handler.should_open_outbound(info));
+    }
+
+    /// Stops opening outbound substreams at the request of the handler. The pending requests are
+    /// dropped without the handler being notified, as with `cancel_unwanted_dial_upgrades`.
+    fn close_outbound_locally(&mut self) {
+        debug_with_id!(self, "Handler closed the outbound substreams");
+        self.outbound_closed_locally = true;
+        self.cancelled_dial_upgrades.extend(self.queued_dial_upgrades.keys().cloned());
+        self.queued_dial_upgrades.clear();
+        self.delayed_dial_upgrades.clear();
+    }
+
+    /// Returns whether one more inbound substream can be accepted without exceeding the limit set
+    /// with `with_inbound_rate_limit`, and records it if so.
+    fn accept_inbound_within_rate_limit(&mut self) -> bool {
+        let (max_per_interval, interval) = match self.inbound_rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let now = self.timer.now();
+        while let Some(accepted) = self.inbound_accepted.front().cloned() {
+            if now.duration_since(accepted) < interval {
+                break;
+            }
+            self.inbound_accepted.pop_front();
+        }
+        if self.inbound_accepted.len() >= max_per_interval {
+            return false;
+        }
+        self.inbound_accepted.push_back(now);
+        true
+    }
+
+    /// Removes `id` from `cancelled_dial_upgrades`. Returns false if it wasn't there.
+    fn take_cancelled(&mut self, id: u64) -> bool {
+        match self.cancelled_dial_upgrades.iter().position(|cancelled| *cancelled == id) {
+            Some(pos) => {
+                self.cancelled_dial_upgrades.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stores an outbound substream request that can't be reported yet. It goes after the
+    /// delayed requests of the same or higher priority, but before the ones of lower priority.
+    /// In ordered mode, it always goes last.
+    fn delay_dial_upgrade(
+        &mut self,
+        upgrade: TProtoHandler::Protocol,
+        info: TProtoHandler::OutboundOpenInfo,
+        timeout: Option<Duration>,
+        priority: u8,
+    ) {
+        if self.ordered_outbound {
+            self.delayed_dial_upgrades.push_back((upgrade, info, timeout, priority));
+            return;
+        }
+        let pos = self
+            .delayed_dial_upgrades
+            .iter()
+            .position(|&(_, _, _, other)| other < priority)
+            .unwrap_or(self.delayed_dial_upgrades.len());
+        self.delayed_dial_upgrades.insert(pos, (upgrade, info, timeout, priority));
+    }
+}
+
+impl<TProtoHandler> NodeHandlerWrapper<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::NamesIter: Clone,
+{
+    /// Returns the upgrade to apply to a new inbound substream. If the cache is enabled, only
+    /// calls the handler's `listen_protocol()` if `listen_protocol_version()` has changed since
+    /// the previous call. Returns `None` if the handler has panicked.
+    fn listen_protocol(&mut self) -> Option<TProtoHandler::Protocol> {
+        let clone = match self.listen_protocol_clone {
+            Some(clone) => clone,
+            None => return self.call_handler(|handler| handler.listen_protocol()),
+        };
+
+        let version = match self.call_handler(|handler| handler.listen_protocol_version())? {
+            Some(version) => version,
+            None => {
+                self.listen_protocol_cache = None;
+                return self.call_handler(|handler| handler.listen_protocol());
+            }
+        };
+
+        if let Some((cached_version, ref protocol)) = self.listen_protocol_cache {
+            if cached_version == version {
+                return Some(clone(protocol));
+            }
+        }
+
+        let protocol = self.call_handler(|handler| handler.listen_protocol())?;
+        self.listen_protocol_cache = Some((version, clone(&protocol)));
+        Some(protocol)
+    }
+
+    /// Shuts down the handler and returns a future that drives it until it has finished.
+    ///
+    /// The events produced by the handler in the meantime are discarded, and its outbound
+    /// substream requests fail with `ProtocolsHandlerUpgrErr::MuxerClosed`. If a shutdown timeout
+    /// has been set with `with_shutdown_timeout`, the future resolves at the latest when it
+    /// elapses. If the handler produces an error, the future resolves to this error.
+    pub fn close(mut self) -> NodeHandlerWrapperClose<TProtoHandler> {
+        NodeHandler::shutdown(&mut self);
+        NodeHandlerWrapperClose { wrapper: self }
+    }
+
+    /// Polls the wrapper until it doesn't produce any event anymore, and returns all the events
+    /// produced in the meantime, along with how the handler has finished if it has.
+    ///
+    /// This is meant for driving the wrapper from a loop that doesn't use futures combinators.
+    /// The substream negotiations progress as with `poll()`, and the method returns as soon as
+    /// nothing is ready, without waiting. Like `poll()`, it must be called from within a task,
+    /// which is notified once there is more to do.
+    pub fn drain_events(
+        &mut self,
+    ) -> (
+        Vec<NodeHandlerEvent<u64, TProtoHandler::OutEvent>>,
+        Option<Result<(), io::Error>>,
+    ) {
+        let mut events = Vec::new();
+        loop {
+            match NodeHandler::poll(self) {
+                Ok(Async::Ready(Some(event))) => events.push(event),
+                Ok(Async::Ready(None)) => return (events, Some(Ok(()))),
+                Ok(Async::NotReady) => return (events, None),
+                Err(err) => return (events, Some(Err(err))),
+            }
+        }
+    }
+
+    /// Applies the jitter configured with `with_timeout_jitter`, if any, to a timeout.
+    #[inline]
+    fn jittered(&mut self, timeout: Duration) -> Duration {
+        match self.timeout_jitter {
+            Some(ref mut jitter) => jitter.apply(timeout),
+            None => timeout,
+        }
+    }
+
+    /// Continues the negotiation of newly-opened substreams.
+    fn poll_negotiations(&mut self) {
+        // Inbound and outbound negotiations are interleaved, and the direction that goes first
+        // alternates at each call, so that a large number of substreams in one direction can't
+        // delay the other direction.
+        // Each negotiation is taken from the front of its list and, if it isn't finished, pushed
+        // back at the end. The lists thus work as round-robin queues that keep their order: every
+        // negotiation is polled exactly once per call (unless the budget runs out, in which case
+        // we wake ourselves up and continue where we stopped), and all those that aren't finished
+        // have registered the current task for a wakeup.
+        let mut remaining_in = self.negotiating_in.len();
+        let mut remaining_out = self.negotiating_out.len();
+        let mut out_turn = self.poll_outbound_first;
+        self.poll_outbound_first = !self.poll_outbound_first;
+        let mut budget = self.poll_budget;
+        while remaining_in != 0 || remaining_out != 0 {
+            if budget == Some(0) {
+                // The negotiations that we didn't poll are at the front of the lists, and will be
+                // the first ones to be polled next time. Make sure that there is a next time.
+                task::current().notify();
+                break;
+            }
+            if let Some(ref mut budget) = budget {
+                *budget -= 1;
+            }
+            if (out_turn && remaining_out != 0) || remaining_in == 0 {
+                remaining_out -= 1;
+                self.poll_next_negotiating_out();
+            } else {
+                remaining_in -= 1;
+                self.poll_next_negotiating_in();
+            }
+            out_turn = !out_turn;
+        }
+    }
+
+    /// Polls the handler as long as it produces events, so that a burst of events doesn't
+    /// require one wake-up of the task per event. Returns how the handler has finished, if it
+    /// has.
+    fn poll_handler(&mut self) -> Option<Result<(), io::Error>> {
+        // Whatever a handler would produce after `Ready(None)` is never seen, because it isn't
+        // polled again: `finish()` is called as soon as its remaining events are delivered.
+        debug_assert!(!self.finished, "the handler must not be polled after it has finished");
+        let mut end = None;
+        while self.pending_events.len() < MAX_PENDING_EVENTS {
+            // A handler that keeps producing events after it has been shut down would prevent
+            // the connection from ever closing.
+            if self.shutting_down && Some(self.shutdown_events) == self.max_shutdown_events {
+                warn_with_id!(
+                    self,
+                    "Handler produced too many events after shutdown; closing the connection"
+                );
+                end = Some(Ok(()));
+                break;
+            }
+
+            self.report_outbound_ready();
+            let polled = match self.call_handler(|handler| handler.poll()) {
+                Some(polled) => polled,
+                None => {
+                    // The handler has panicked.
+                    end = self.handler_end.take();
+                    break;
+                }
+            };
+            if let (true, &Ok(Async::Ready(Some(_)))) = (self.shutting_down, &polled) {
+                self.shutdown_events += 1;
+            }
+            match polled {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))) => {
+                    debug_with_id!(self, "Handler produced an event");
+                    self.pending_events.push_back(NodeHandlerEvent::Custom(event));
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }))) => {
+                    if self.outbound_closed_locally {
+                        let err = ProtocolsHandlerUpgrErr::MuxerClosed;
+                        self.call_handler(|handler| handler.inject_dial_upgrade_error(info, err));
+                        continue;
+                    }
+
+                    // If the remote is known to reject all the protocols of the upgrade, there's
+                    // no point in opening a substream. Report the error and poll the handler
+                    // again.
+                    if let Some(cache) = self.protocols_cache.as_mut() {
+                        if cache.all_rejected(Self::protocol_names_of(&upgrade)) {
+                            let err = ProtocolChoiceError::NoProtocolFound;
+                            let err = io::Error::new(io::ErrorKind::Other, err);
+                            let err = ProtocolsHandlerUpgrErr::Upgrade(err);
+                            debug_with_id!(self, "Remote is known to reject the protocols");
+                            self.call_handler(|h| h.inject_dial_upgrade_error(info, err));
+                            continue;
+                        }
+                    }
+
+                    if !self.delayed_dial_upgrades.is_empty() || !self.can_open_outbound() {
+                        self.delay_dial_upgrade(upgrade, info, timeout, priority);
+                        continue;
+                    }
+
+                    debug_with_id!(self, "Handler requested an outbound substream");
+                    let event = self.queue_dial_upgrade(upgrade, info, timeout);
+                    self.pending_events.push_back(event);
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound))) => {
+                    self.cancel_unwanted_dial_upgrades();
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound))) => {
+                    debug_with_id!(self, "Handler closed the inbound substreams");
+                    self.inbound_closed_locally = true;
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound))) => {
+                    self.close_outbound_locally();
+                }
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err)))) | Err(err) => {
+                    // Returning an error tears down the connection.
+                    debug_with_id!(self, "Handler closed the connection: {}", err);
+                    end = Some(Err(err));
+                    break;
+                }
+                Ok(Async::Ready(None)) => {
+                    end = Some(Ok(()));
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+        end
+    }
+
+    /// Polls the inbound negotiation at the front of `negotiating_in`. It is pushed back at the
+    /// end if it isn't finished.
+    fn poll_next_negotiating_in(&mut self) {
+        debug_assert!(!self.finished, "the handler must not be called after it has finished");
+        let (mut start, mut in_progress) = match self.negotiating_in.pop_front() {
+            Some(negotiation) => negotiation,
+            None => return,
+        };
+        let mut result = in_progress.poll();
+        if let Ok(Async::NotReady) = result {
+            // If the negotiation has just identified a protocol that has its own timeout, switch
+            // to it. We poll again so that the new timer is registered.
+            let timeout = in_progress
+                .get_ref()
+                .negotiated_protocol()
+                .and_then(|name| self.inbound_timeouts.get(name))
+                .cloned();
+            if let Some(timeout) = timeout.filter(|timeout| *timeout != start.timeout) {
+                start.timeout = timeout;
+                let deadline = start.start + timeout;
+                in_progress = self.timer.timeout(in_progress.into_inner(), deadline);
+                result = in_progress.poll();
+            }
+        }
+        match result {
+            Ok(Async::Ready(upgrade)) => {
+                let protocol = negotiated_protocol(&in_progress);
+                let timing = start.finish(self.timer.now(), protocol);
+                self.negotiation_stats.record(timing.elapsed());
+                debug_with_id!(self, "Inbound substream negotiated in {:?}", timing.elapsed());
+                let endpoint = NodeHandlerEndpoint::Listener;
+                let result = self.call_handler(|handler| {
+                    handler.inject_protocol_negotiated(timing.protocol(), Endpoint::Listener);
+                    handler.inject_fully_negotiated_with_timing(upgrade, endpoint, timing)
+                });
+                if let Some(Err(_)) = result {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Handler rejected a negotiated inbound substream");
+                }
+            }
+            Ok(Async::NotReady) => {
+                self.negotiating_in.push_back((start, in_progress));
+            }
+            Err(err) => {
+                let err = if err.is_elapsed() {
+                    io::Error::new(io::ErrorKind::TimedOut, "inbound negotiation timed out")
+                } else if let Some(err) = err.into_inner() {
+                    err
+                } else {
+                    io::Error::new(io::ErrorKind::Other, "timer error during negotiation")
+                };
+                debug_with_id!(self, "Inbound negotiation failed: {}", err);
+                self.call_handler(|handler| handler.inject_listen_upgrade_error(err));
+            }
+        }
+    }
+
+    /// Polls the outbound negotiation at the front of `negotiating_out`. It is pushed back at the
+    /// end if it isn't finished.
+    fn poll_next_negotiating_out(&mut self) {
+        debug_assert!(!self.finished, "the handler must not be called after it has finished");
+        let (upgr_info, names, start, mut in_progress) = match self.negotiating_out.pop_front() {
+            Some(negotiation) => negotiation,
+            None => return,
+        };
+        match in_progress.poll() {
+            Ok(Async::Ready(upgrade)) => {
+                let protocol = negotiated_protocol(&in_progress);
+                let timing = start.finish(self.timer.now(), protocol);
+                self.negotiation_stats.record(timing.elapsed());
+                debug_with_id!(self, "Outbound substream negotiated in {:?}", timing.elapsed());
+                if let Some(name) = names.first() {
+                    let stats = self.protocol_stats.entry(name.clone());
+                    stats.or_default().successes += 1;
+                }
+                // If there was only one protocol, we know for sure that the remote supports it.
+                if let (Some(cache), 1) = (self.protocols_cache.as_mut(), names.len()) {
+                    cache.insert(names[0].clone(), true);
+                }
+                let endpoint = NodeHandlerEndpoint::Dialer(upgr_info);
+                let result = self.call_handler(|handler| {
+                    handler.inject_protocol_negotiated(timing.protocol(), Endpoint::Dialer);
+                    handler.inject_fully_negotiated_with_timing(upgrade, endpoint, timing)
+                });
+                if let Some(Err(_)) = result {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Handler rejected a negotiated outbound substream");
+                }
+            }
+            Ok(Async::NotReady) => {
+                self.negotiating_out.push_back((upgr_info, names, start, in_progress));
+            }
+            Err(err) => {
+                let first_name = names.first().cloned();
+                let err = if err.is_elapsed() {
+                    ProtocolsHandlerUpgrErr::Timeout
+                } else if let Some(err) = err.into_inner() {
+                    if let Some(cache) = self.protocols_cache.as_mut() {
+                        if is_no_protocol_found(&err) {
+                            for name in names {
+                                cache.insert(name, false);
+                            }
+                        }
+                    }
+                    ProtocolsHandlerUpgrErr::Upgrade(err)
+                } else {
+                    let msg = "timer error during negotiation";
+                    ProtocolsHandlerUpgrErr::Upgrade(io::Error::new(io::ErrorKind::Other, msg))
+                };
+                debug_with_id!(self, "Outbound negotiation failed: {}", err);
+                if let Some(name) = first_name {
+                    let stats = self.protocol_stats.entry(name);
+                    stats.or_default().failures += 1;
+                }
+                self.call_handler(|handler| handler.inject_dial_upgrade_error(upgr_info, err));
+            }
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for NodeHandlerWrapper<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("NodeHandlerWrapper")
+            .field("handler", &self.handler)
+            .field("negotiating_in", &self.negotiating_in.len())
+            .field("negotiating_out", &self.negotiating_out.len())
+            .field("queued_dial_upgrades", &self.queued_dial_upgrades.len())
+            .field("delayed_dial_upgrades", &self.delayed_dial_upgrades.len())
+            .field("in_timeout", &self.in_timeout)
+            .field("inbound_timeouts", &self.inbound_timeouts)
+            .field("out_timeout", &self.out_timeout)
+            .field("open_timeout", &self.open_timeout)
+            .field("timeout_jitter", &self.timeout_jitter.as_ref().map(|j| j.fraction))
+            .field("timer", &self.timer)
+            .field("log_id", &self.log_id)
+            .field("max_negotiating_outbound", &self.max_negotiating_outbound)
+            .field("inbound_rate_limit", &self.inbound_rate_limit)
+            .field("ordered_outbound", &self.ordered_outbound)
+            .field("keep_alive", &self.keep_alive)
+            .field("negotiation_stats", &self.negotiation_stats)
+            .field("catch_unwind", &self.catch_unwind)
+            .field("handler_panicked", &self.handler_panicked)
+            .field("poll_handler_first", &self.poll_handler_first)
+            .field("outbound_closed", &self.outbound_closed)
+            .field("inbound_closed_locally", &self.inbound_closed_locally)
+            .field("outbound_closed_locally", &self.outbound_closed_locally)
+            .field("outbound_ready", &self.outbound_ready)
+            .field("shutting_down", &self.shutting_down)
+            .field("finished", &self.finished)
+            .field("pending_events", &self.pending_events.len())
+            .finish()
+    }
+}
+
+impl<TProtoHandler> NodeHandler for NodeHandlerWrapper<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::NamesIter: Clone,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    // The first element of the tuple is the unique upgrade identifier
+    // (see `unique_dial_upgrade_id`).
+    type OutboundOpenInfo = u64;
+
+    fn inject_substream(
+        &mut self,
+        substream: Self::Substream,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) {
+        match endpoint {
+            NodeHandlerEndpoint::Listener => {
+                if self.finished {
+                    // Dropping the substream closes it.
+                    debug_with_id!(
+                        self,
+                        "Closing an inbound substream opened after the handler finished"
+                    );
+                    return;
+                }
+                if self.shutting_down {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Closing an inbound substream opened while shutting down");
+                    return;
+                }
+                if self.inbound_closed_locally {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Closing an inbound substream refused by the handler");
+                    return;
+                }
+                if self.handler_panicked {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Closing an inbound substream opened after a panic");
+                    return;
+                }
+                if self.call_handler(|handler| handler.should_accept_inbound()) != Some(true) {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Handler refused an inbound substream");
+                    return;
+                }
+                if !self.accept_inbound_within_rate_limit() {
+                    // Dropping the substream closes it.
+                    debug_with_id!(self, "Closing an inbound substream beyond the rate limit");
+                    return;
+                }
+                let protocol = match self.listen_protocol() {
+                    Some(protocol) => protocol,
+                    // The handler has panicked. Dropping the substream closes it.
+                    None => return,
+                };
+                let upgrade = upgrade::apply(substream, protocol, Endpoint::Listener);
+                let timeout = self.jittered(self.in_timeout);
+                let start = NegotiationStart::new(self.timer.now(), timeout);
+                let with_timeout = self.timer.timeout(upgrade, start.deadline());
+                debug_with_id!(self, "Negotiating an inbound substream");
+                self.negotiating_in.push_back((start, with_timeout));
+            }
+            NodeHandlerEndpoint::Dialer(upgrade_id) => {
+                let (proto_upgrade, timeout, user_data) =
+                    match self.queued_dial_upgrades.remove(&upgrade_id) {
+                        Some(queued) => queued,
+                        None => {
+                            // Dropping the substream closes it.
+                            let was_cancelled = self.take_cancelled(upgrade_id);
+                            debug_assert!(was_cancelled, "Received an upgrade with an invalid ID");
+                            debug_with_id!(
+                                self,
+                                "Closing a substream opened for a cancelled request"
+                            );
+                            return;
+                        }
+                    };
+
+                let names = Self::protocol_names_of(&proto_upgrade);
+                let upgrade = upgrade::apply(substream, proto_upgrade, Endpoint::Dialer);
+                let timeout = timeout.unwrap_or(self.out_timeout);
+                let timeout = self.jittered(timeout);
+                let start = NegotiationStart::new(self.timer.now(), timeout);
+                let with_timeout = self.timer.timeout(upgrade, start.deadline());
+                debug_with_id!(self, "Negotiating the outbound substream #{}", upgrade_id);
+                self.negotiating_out.push_back((user_data, names, start, with_timeout));
+            }
+        }
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        if !self.finished {
+            self.call_handler(|handler| handler.inject_inbound_closed());
+        }
+    }
+
+    fn inject_outbound_closed(&mut self, upgrade_id: Self::OutboundOpenInfo) {
+        if !self.outbound_closed && !self.finished {
+            self.outbound_closed = true;
+            self.call_handler(|handler| handler.inject_outbound_closed());
+            // The requests that we haven't reported yet would fail as well.
+            let delayed = self.delayed_dial_upgrades.drain(..).collect::<Vec<_>>();
+            for (_, info, _, _) in delayed {
+                let err = ProtocolsHandlerUpgrErr::MuxerClosed;
+                self.call_handler(|handler| handler.inject_dial_upgrade_error(info, err));
+            }
+        }
+
+        let (_, _, user_data) = match self.queued_dial_upgrades.remove(&upgrade_id) {
+            Some(queued) => queued,
+            None => {
+                // The handler doesn't expect anything for a cancelled request.
+                let was_cancelled = self.take_cancelled(upgrade_id);
+                debug_assert!(
+                    was_cancelled,
+                    "Received an outbound closed error with an invalid upgrade ID"
+                );
+                return;
+            }
+        };
+
+        let err = ProtocolsHandlerUpgrErr::MuxerClosed;
+        self.call_handler(|handler| handler.inject_dial_upgrade_error(user_data, err));
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        if !self.finished {
+            self.call_handler(|handler| handler.inject_event(event));
+        }
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        if !self.finished {
+            self.call_handler(|handler| handler.inject_connection_info(info));
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // The handler is only shut down once, and not at all if it has already finished.
+        if self.shutting_down || self.finished {
+            return;
+        }
+        self.shutting_down = true;
+        debug_with_id!(self, "Shutting down the handler");
+        // A handler that is shutting down doesn't receive new inbound substreams anymore. The
+        // outbound negotiations continue, as the handler might still be waiting for them.
+        self.negotiating_in.clear();
+        if let (Some(timeout), true) = (self.shutdown_timeout, self.shutdown_deadline.is_none()) {
+            self.shutdown_deadline = Some(self.timer.delay(self.timer.now() + timeout));
+        }
+        self.call_handler(|handler| handler.shutdown());
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<Option<NodeHandlerEvent<Self::OutboundOpenInfo, Self::OutEvent>>, io::Error> {
+        // Once finished, neither the handler nor the negotiations are touched anymore.
+        if self.finished {
+            debug_with_id!(self, "NodeHandlerWrapper polled after it has finished");
+            return Ok(Async::Ready(None));
+        }
+
+        // Forcibly close the connection if the handler takes too long to shut down.
+        let deadline_state = self.shutdown_deadline.as_mut().map(|deadline| deadline.poll());
+        match deadline_state {
+            Some(Ok(Async::Ready(()))) => {
+                debug_with_id!(self, "Handler didn't shut down in time; closing the connection");
+                // The handler isn't polled anymore, but the events that it has already produced
+                // are still delivered below.
+                self.shutdown_deadline = None;
+                if self.handler_end.is_none() {
+                    self.handler_end = Some(Ok(()));
+                }
+            }
+            Some(Err(err)) => {
+                debug_with_id!(
+                    self,
+                    "Timer error while waiting for the handler to shut down: {:?}",
+                    err
+                );
+                self.shutdown_deadline = None;
+            }
+            Some(Ok(Async::NotReady)) | None => (),
+        }
+
+        // Deliver the events that the handler has already produced.
+        if let Some(event) = self.pending_events.pop_front() {
+            // There might be more events to deliver, or the handler might have more to produce.
+            task::current().notify();
+            return Ok(Async::Ready(Some(event)));
+        }
+        match self.handler_end.take() {
+            Some(Err(err)) => {
+                self.finish();
+                return Err(err);
+            }
+            Some(Ok(())) => {
+                self.finish();
+                return Ok(Async::Ready(None));
+            }
+            None => (),
+        }
+
+        // Poll the handler before the negotiations if configured so, in order to deliver the
+        // events that it produces independently of them without waiting. It is polled again
+        // below, as usual.
+        let mut end = None;
+        if self.poll_handler_first {
+            end = self.poll_handler();
+        }
+
+        if end.is_none() {
+            self.poll_open_deadlines();
+            self.poll_negotiations();
+
+            // Report the outbound substream requests that were delayed, now that negotiations may
+            // have finished.
+            if !self.delayed_dial_upgrades.is_empty() && self.can_open_outbound() {
+                if let Some((upgrade, info, timeout, _)) = self.delayed_dial_upgrades.pop_front() {
+                    let event = self.queue_dial_upgrade(upgrade, info, timeout);
+                    if !self.pending_events.is_empty() {
+                        // The handler has been polled first and has produced events.
+                        task::current().notify();
+                    }
+                    self.update_keep_alive();
+                    return Ok(Async::Ready(Some(event)));
+                }
+            }
+
+            // Poll the handler at the end so that we see the consequences of the method calls on
+            // `self.handler`.
+            end = self.poll_handler();
+        }
+
+        // The handler must not be polled anymore if it has finished, but the events it has
+        // produced before that must still be delivered.
+        if let Some(event) = self.pending_events.pop_front() {
+            if end.is_some() {
+                self.handler_end = end;
+            }
+            if !self.pending_events.is_empty() || self.handler_end.is_some() {
+                task::current().notify();
+            }
+            self.update_keep_alive();
+            return Ok(Async::Ready(Some(event)));
+        }
+
+        match end {
+            Some(Err(err)) => {
+                self.finish();
+                Err(err)
+            }
+            Some(Ok(())) => {
+                self.finish();
+                Ok(Async::Ready(None))
+            }
+            None => {
+                self.update_keep_alive();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Future returned by `NodeHandlerWrapper::close`.
+pub struct NodeHandlerWrapperClose<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The wrapper being shut down.
+    wrapper: NodeHandlerWrapper<TProtoHandler>,
+}
+
+impl<TProtoHandler> Future for NodeHandlerWrapperClose<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::NamesIter: Clone,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            match try_ready!(NodeHandler::poll(&mut self.wrapper)) {
+                Some(NodeHandlerEvent::OutboundSubstreamRequest(id)) => {
+                    // There is nobody to open the substream.
+                    self.wrapper.inject_outbound_closed(id);
+                }
+                Some(NodeHandlerEvent::Custom(_)) => {
+                    debug_with_id!(
+                        self.wrapper,
+                        "Discarding an event produced while closing the handler"
+                    );
+                }
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for NodeHandlerWrapperClose<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("NodeHandlerWrapperClose")
+            .field("wrapper", &self.wrapper)
+            .finish()
+    }
+}
+
+/// Wraps around a `NodeHandlerWrapper`, and calls a closure on each event that it produces.
+///
+/// Created with `NodeHandlerWrapperBuilder::build_with` or
+/// `ProtocolsHandler::into_node_handler_with`.
+pub struct NodeHandlerWrapperWith<TProtoHandler, TFn>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying wrapper.
+    wrapper: NodeHandlerWrapper<TProtoHandler>,
+    /// Called on each event produced by `wrapper`.
+    on_event: TFn,
+}
+
+impl<TProtoHandler, TFn> NodeHandlerWrapperWith<TProtoHandler, TFn>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Returns a reference to the underlying `NodeHandlerWrapper`.
+    #[inline]
+    pub fn get_ref(&self) -> &NodeHandlerWrapper<TProtoHandler> {
+        &self.wrapper
+    }
+
+    /// Returns a mutable reference to the underlying `NodeHandlerWrapper`.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut NodeHandlerWrapper<TProtoHandler> {
+        &mut self.wrapper
+    }
+
+    /// Destroys this object and returns the underlying `NodeHandlerWrapper`.
+    #[inline]
+    pub fn into_inner(self) -> NodeHandlerWrapper<TProtoHandler> {
+        self.wrapper
+    }
+}
+
+impl<TProtoHandler, TFn> NodeHandler for NodeHandlerWrapperWith<TProtoHandler, TFn>
+where
+    TProtoHandler: ProtocolsHandler,
+    <TProtoHandler::Protocol as ConnectionUpgrade<TProtoHandler::Substream>>::NamesIter: Clone,
+    TFn: FnMut(&NodeHandlerEvent<u64, TProtoHandler::OutEvent>),
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type OutboundOpenInfo = u64;
+
+    #[inline]
+    fn inject_substream(
+        &mut self,
+        substream: Self::Substream,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) {
+        self.wrapper.inject_substream(substream, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.wrapper.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self, user_data: Self::OutboundOpenInfo) {
+        self.wrapper.inject_outbound_closed(user_data)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.wrapper.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.wrapper.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.wrapper.shutdown()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<Option<NodeHandlerEvent<Self::OutboundOpenInfo, Self::OutEvent>>, io::Error> {
+        let event = try_ready!(NodeHandler::poll(&mut self.wrapper));
+        if let Some(ref event) = event {
+            (self.on_event)(event);
+        }
+        Ok(Async::Ready(event))
+    }
+}
+
+impl<TProtoHandler, TFn> fmt::Debug for NodeHandlerWrapperWith<TProtoHandler, TFn>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("NodeHandlerWrapperWith")
+            .field("wrapper", &self.wrapper)
+            .finish()
+    }
+}
+
+/// When a negotiation started, and with which timeout.
+#[derive(Debug, Copy, Clone)]
+struct NegotiationStart {
+    start: Instant,
+    timeout: Duration,
+}
+
+impl NegotiationStart {
+    /// Records that a negotiation with the given timeout is starting at `start`.
+    #[inline]
+    fn new(start: Instant, timeout: Duration) -> Self {
+        NegotiationStart { start, timeout }
+    }
+
+    /// Returns when the negotiation times out.
+    #[inline]
+    fn deadline(&self) -> Instant {
+        self.start + self.timeout
+    }
+
+    /// Returns the timing of the negotiation, assuming that it has finished at `now` with the
+    /// given protocol.
+    #[inline]
+    fn finish(&self, now: Instant, protocol: Bytes) -> NegotiationTiming {
+        NegotiationTiming::new(now - self.start, self.timeout, protocol)
+    }
+}
+
+/// Source of time and timers used by a `NodeHandlerWrapper` for its timeouts.
+///
+/// The default uses the clock and the timer of the tokio execution context in which the
+/// `NodeHandlerWrapper` is polled. A custom one can be built from a `Clock` and the `Handle` of a
+/// `Timer`, for example in order to make time pass deterministically in tests.
+#[derive(Debug, Clone, Default)]
+pub struct TimerFactory {
+    /// Clock to use. `None` to use the one of the current execution context.
+    clock: Option<Clock>,
+    /// Timer on which the delays are registered.
+    handle: timer::Handle,
+}
+
+impl TimerFactory {
+    /// Creates a `TimerFactory` that uses the given clock and timer.
+    ///
+    /// The clock should be the same as the one the timer has been created with, otherwise the
+    /// timeouts may fire at the wrong time.
+    #[inline]
+    pub fn new(clock: Clock, handle: timer::Handle) -> Self {
+        TimerFactory {
+            clock: Some(clock),
+            handle,
+        }
+    }
+
+    /// Returns the current instant.
+    #[inline]
+    fn now(&self) -> Instant {
+        match self.clock {
+            Some(ref clock) => clock.now(),
+            None => clock::now(),
+        }
+    }
+
+    /// Builds a `Delay` that fires at `deadline`.
+    #[inline]
+    fn delay(&self, deadline: Instant) -> Delay {
+        self.handle.delay(deadline)
+    }
+
+    /// Wraps `future` so that it fails if it isn't finished at `deadline`.
+    #[inline]
+    fn timeout<T>(&self, future: T, deadline: Instant) -> Timeout<T> {
+        self.handle.timeout(future, deadline)
+    }
+}
+
+/// Source of the random deviations applied to the negotiation timeouts.
+///
+/// This doesn't need to be unpredictable, only to differ between wrappers, so we use a xorshift
+/// generator seeded from the current time and a global counter rather than a proper RNG.
+struct TimeoutJitter {
+    /// Maximum relative deviation, between 0 and 1.
+    fraction: f64,
+    /// State of the generator. Never 0.
+    state: u64,
+}
+
+/// Incremented for each `TimeoutJitter`, so that wrappers created at the same time get different
+/// seeds.
+static NEXT_JITTER_SEED: AtomicUsize = AtomicUsize::new(0);
+
+impl TimeoutJitter {
+    /// Creates a `TimeoutJitter` with a fresh seed.
+    fn new(fraction: f64) -> Self {
+        let counter = NEXT_JITTER_SEED.fetch_add(1, Ordering::Relaxed) as u64;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() ^ (u64::from(d.subsec_nanos()) << 32))
+            .unwrap_or(0);
+        // Spread the bits of the counter so that consecutive seeds don't look alike.
+        let state = time ^ counter.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        TimeoutJitter {
+            fraction,
+            state: if state == 0 { 0x9e37_79b9_7f4a_7c15 } else { state },
+        }
+    }
+
+    /// Returns a random number in `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns `timeout` randomly deviated by up to `fraction` of its value.
+    fn apply(&mut self, timeout: Duration) -> Duration {
+        let factor = 1.0 + self.fraction * (2.0 * self.next() - 1.0);
+        let nanos = (timeout.as_secs() as f64 * 1_000_000_000.0
+            + f64::from(timeout.subsec_nanos())) * factor;
+        let nanos = nanos as u64;
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+/// Snapshot of the pending negotiations of a `NodeHandlerWrapper`. See
+/// `NodeHandlerWrapper::stats()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NodeHandlerWrapperStats {
+    /// Number of inbound substreams being negotiated.
+    pub negotiating_in: usize,
+    /// Number of outbound substreams being negotiated.
+    pub negotiating_out: usize,
+    /// Number of outbound substreams requested and not opened yet.
+    pub queued_dial_upgrades: usize,
+    /// Number of outbound substream requests held back by `with_max_negotiating_outbound`.
+    pub delayed_dial_upgrades: usize,
+}
+
+/// Outcome of the outbound negotiations of a protocol. See `NodeHandlerWrapper::protocol_stats()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolStats {
+    /// Number of outbound substreams that have been successfully negotiated.
+    pub successes: u64,
+    /// Number of outbound substreams whose negotiation has failed or timed out.
+    pub failures: u64,
+}
+
+/// Effectiveness of the protocols cache of a `NodeHandlerWrapper`. See
+/// `NodeHandlerWrapper::protocols_cache_stats()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolsCacheStats {
+    /// Number of outbound substream requests that have failed immediately, without opening a
+    /// substream, because the remote is known to reject their protocols.
+    pub hits: u64,
+    /// Number of outbound substream requests for which a substream had to be opened.
+    pub misses: u64,
+}
+
+/// Statistics about the duration of the successful substream negotiations of a
+/// `NodeHandlerWrapper`.
+///
+/// The duration of a negotiation is measured from the moment the substream is handed to the
+/// `NodeHandlerWrapper` to the moment the upgrade is complete, using the clock of `tokio_timer`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NegotiationStats {
+    count: u64,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl NegotiationStats {
+    /// Returns the number of successful negotiations.
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the duration of the fastest negotiation, or `None` if there wasn't any.
+    #[inline]
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// Returns the duration of the slowest negotiation, or `None` if there wasn't any.
+    #[inline]
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    /// Records the duration of a successful negotiation.
+    fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.min = Some(self.min.map_or(duration, |min| cmp::min(min, duration)));
+        self.max = Some(self.max.map_or(duration, |max| cmp::max(max, duration)));
+    }
+}
+
+/// Maximum number of protocol names that a `ProtocolsCache` remembers.
+const PROTOCOLS_CACHE_MAX_SIZE: usize = 64;
+
+/// Remembers which protocols the remote has accepted or rejected during negotiations.
+struct ProtocolsCache {
+    /// For each protocol name, true if the remote supports it and false if it rejected it.
+    entries: FnvHashMap<Bytes, bool>,
+    /// Names present in `entries`, by order of insertion. Used to evict the oldest entries.
+    insertion_order: VecDeque<Bytes>,
+    /// Outcome of the lookups performed with `all_rejected`.
+    stats: ProtocolsCacheStats,
+}
+
+impl ProtocolsCache {
+    /// Creates an empty cache.
+    #[inline]
+    fn new() -> Self {
+        ProtocolsCache {
+            entries: FnvHashMap::default(),
+            insertion_order: VecDeque::new(),
+            stats: ProtocolsCacheStats::default(),
+        }
+    }
+
+    /// Records whether the remote supports the given protocol.
+    fn insert(&mut self, name: Bytes, supported: bool) {
+        if self.entries.insert(name.clone(), supported).is_none() {
+            self.insertion_order.push_back(name);
+            if self.insertion_order.len() > PROTOCOLS_CACHE_MAX_SIZE {
+                if let Some(oldest) = self.insertion_order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// Returns true if the list of names isn't empty and the remote is known to reject all of
+    /// them. Counts the lookup as a hit or a miss accordingly.
+    fn all_rejected<I>(&mut self, names: I) -> bool
+    where
+        I: IntoIterator<Item = Bytes>,
+    {
+        let mut any = false;
+        let mut names = names.into_iter();
+        let rejected = names.all(|name| {
+            any = true;
+            self.entries.get(&name) == Some(&false)
+        });
+        if rejected && any {
+            self.stats.hits += 1;
+            true
+        } else {
+            self.stats.misses += 1;
+            false
+        }
+    }
+}
+
+/// Returns the name of the protocol of a negotiation that has successfully completed.
+fn negotiated_protocol<TSubstream, TUpgrade>(
+    negotiation: &Timeout<UpgradeApplyFuture<TSubstream, TUpgrade>>,
+) -> Bytes
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream>,
+{
+    negotiation
+        .get_ref()
+        .negotiated_protocol()
+        .cloned()
+        .expect("the name is known once the upgrade has completed; qed")
+}
+
+/// Returns true if the error was produced because the remote doesn't support any of the
+/// protocols we proposed.
+pub(crate) fn is_no_protocol_found(err: &io::Error) -> bool {
+    match err.get_ref().and_then(|err| err.downcast_ref::<ProtocolChoiceError>()) {
+        Some(ProtocolChoiceError::NoProtocolFound) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, task::Task};
+    use std::cell::Cell;
+    use std::iter;
+    use std::io::{Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use tests::fake_time::FakeTime;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use tokio_mock_task::MockTask;
+    use upgrade::PlainTextConfig;
+    use void::Void;
+
+    /// Substream that produces some predefined data, then blocks forever. Everything written to
+    /// it is discarded. If there is a gate, no data is produced until the gate is opened.
+    struct TestSubstream(Cursor<Vec<u8>>, Option<Arc<Mutex<Gate>>>);
+
+    /// Holds back the data of a `TestSubstream`, and wakes up the reader once opened.
+    #[derive(Default)]
+    struct Gate {
+        open: bool,
+        waiting: Option<Task>,
+    }
+
+    impl Gate {
+        fn open(gate: &Mutex<Gate>) {
+            let mut gate = gate.lock().unwrap();
+            gate.open = true;
+            if let Some(task) = gate.waiting.take() {
+                task.notify();
+            }
+        }
+    }
+
+    impl TestSubstream {
+        /// Substream on which nothing is ever received.
+        fn pending() -> Self {
+            TestSubstream(Cursor::new(Vec::new()), None)
+        }
+
+        /// Same as `accepting_plaintext`, except that the data is only received once the returned
+        /// gate is opened.
+        fn gated_plaintext() -> (Self, Arc<Mutex<Gate>>) {
+            let gate = Arc::new(Mutex::new(Gate::default()));
+            let TestSubstream(data, _) = TestSubstream::accepting_plaintext();
+            (TestSubstream(data, Some(gate.clone())), gate)
+        }
+
+        /// Substream on which the remote rejects the first protocol that is proposed.
+        fn rejecting_plaintext() -> Self {
+            let mut data = Vec::new();
+            data.push(19);
+            data.extend_from_slice(b"/multistream/1.0.0\n");
+            data.push(3);
+            data.extend_from_slice(b"na\n");
+            TestSubstream(Cursor::new(data), None)
+        }
+
+        /// Substream on which the remote accepts `/plaintext/1.0.0`.
+        fn accepting_plaintext() -> Self {
+            let mut data = Vec::new();
+            data.push(19);
+            data.extend_from_slice(b"/multistream/1.0.0\n");
+            data.push(17);
+            data.extend_from_slice(b"/plaintext/1.0.0\n");
+            TestSubstream(Cursor::new(data), None)
+        }
+    }
+
+    impl Read for TestSubstream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(ref gate) = self.1 {
+                let mut gate = gate.lock().unwrap();
+                if !gate.open {
+                    gate.waiting = Some(task::current());
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            }
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl AsyncRead for TestSubstream {}
+
+    impl Write for TestSubstream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for TestSubstream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Handler that requests a single outbound substream and produces an event once it has been
+    /// negotiated.
+    #[derive(Default)]
+    struct OneOutboundHandler {
+        requested: bool,
+        negotiated: bool,
+    }
+
+    impl ProtocolsHandler for OneOutboundHandler {
+        type InEvent = Void;
+        type OutEvent = ();
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+        // Exercises the `SINGLE_PROTOCOL` path of the wrapper.
+        const SINGLE_PROTOCOL: bool = true;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            if let NodeHandlerEndpoint::Dialer(()) = endpoint {
+                self.negotiated = true;
+            }
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), ()>>, io::Error> {
+            if !self.requested {
+                self.requested = true;
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: PlainTextConfig,
+                    info: (),
+                    timeout: None,
+                    priority: 0,
+                })));
+            }
+            if self.negotiated {
+                self.negotiated = false;
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(()))));
+            }
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Handler that produces a given number of events in a row, and counts how many times it has
+    /// been polled.
+    struct BurstHandler {
+        remaining: usize,
+        polls: usize,
+    }
+
+    impl ProtocolsHandler for BurstHandler {
+        type InEvent = Void;
+        type OutEvent = usize;
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), usize>>, io::Error> {
+            self.polls += 1;
+            if self.remaining == 0 {
+                return Ok(Async::NotReady);
+            }
+            self.remaining -= 1;
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(self.remaining))))
+        }
+    }
+
+    /// Handler that produces `farewells` events once shut down, then finishes if `finish` is
+    /// true.
+    struct FarewellHandler {
+        farewells: usize,
+        finish: bool,
+        shutting_down: bool,
+    }
+
+    impl ProtocolsHandler for FarewellHandler {
+        type InEvent = Void;
+        type OutEvent = usize;
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {
+            self.shutting_down = true;
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), usize>>, io::Error> {
+            if !self.shutting_down {
+                return Ok(Async::NotReady);
+            }
+            if self.farewells != 0 {
+                self.farewells -= 1;
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(self.farewells))));
+            }
+            if self.finish {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// Polls the wrapper until it finishes, and returns the events it has produced.
+    fn drain_events(
+        task: &mut MockTask,
+        handler: &mut NodeHandlerWrapper<FarewellHandler>,
+    ) -> Vec<usize> {
+        let mut events = Vec::new();
+        loop {
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::Custom(event)))) => events.push(event),
+                Ok(Async::Ready(None)) => return events,
+                _ => panic!("expected the handler to finish"),
+            }
+        }
+    }
+
+    #[test]
+    fn events_produced_during_shutdown_are_delivered() {
+        let farewell = FarewellHandler { farewells: 2, finish: true, shutting_down: false };
+        let mut handler = farewell.into_node_handler();
+        let mut task = MockTask::new();
+
+        handler.shutdown();
+        assert_eq!(drain_events(&mut task, &mut handler), vec![1, 0]);
+    }
+
+    #[test]
+    fn events_produced_before_the_shutdown_deadline_are_delivered() {
+        let mut time = FakeTime::new();
+        let farewell = FarewellHandler { farewells: 2, finish: false, shutting_down: false };
+        let mut handler = farewell
+            .into_node_handler_builder()
+            .with_shutdown_timeout(Duration::from_secs(5))
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        handler.shutdown();
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(1)))) => (),
+            _ => panic!("expected the first event"),
+        }
+
+        // The second event has already been produced when the deadline is reached.
+        time.advance(Duration::from_secs(6));
+        assert_eq!(drain_events(&mut task, &mut handler), vec![0]);
+    }
+
+    #[test]
+    fn events_produced_after_shutdown_are_capped() {
+        let farewell = FarewellHandler { farewells: 5, finish: false, shutting_down: false };
+        let mut handler = farewell
+            .into_node_handler_builder()
+            .with_max_shutdown_events(3)
+            .build();
+        let mut task = MockTask::new();
+
+        handler.shutdown();
+        assert_eq!(drain_events(&mut task, &mut handler), vec![4, 3, 2]);
+        assert_eq!(handler.handler.farewells, 2);
+    }
+
+    #[test]
+    fn shutdown_in_progress_is_reported() {
+        let farewell = FarewellHandler { farewells: 1, finish: true, shutting_down: false };
+        let mut handler = farewell.into_node_handler();
+        let mut task = MockTask::new();
+        assert!(!handler.is_shutting_down());
+
+        handler.shutdown();
+        assert!(handler.is_shutting_down());
+        assert_eq!(drain_events(&mut task, &mut handler), vec![0]);
+        assert!(!handler.is_shutting_down());
+    }
+
+    /// Handler that records the names of the protocols negotiated on inbound substreams.
+    #[derive(Default)]
+    struct RecordingHandler {
+        negotiated: Vec<Bytes>,
+    }
+
+    impl ProtocolsHandler for RecordingHandler {
+        type InEvent = Void;
+        type OutEvent = Void;
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            panic!("the wrapper must pass the timing")
+        }
+
+        fn inject_fully_negotiated_with_timing(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+            timing: NegotiationTiming,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            self.negotiated.push(timing.protocol().clone());
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), Void>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn negotiated_protocol_is_reported() {
+        let mut handler = RecordingHandler::default().into_node_handler();
+        let mut runtime = Runtime::new().unwrap();
+
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+        runtime
+            .block_on(future::poll_fn(|| -> Poll<_, ()> { Ok(Async::Ready(handler.poll())) }))
+            .unwrap()
+            .unwrap();
+        assert_eq!(handler.handler.negotiated, vec![Bytes::from("/plaintext/1.0.0")]);
+    }
+
+    #[test]
+    fn negotiated_protocol_is_injected_before_the_substream() {
+        let handler = TestProtocolsHandler::new(RecordingHandler::default());
+        let mut handler = handler.into_node_handler();
+        let mut runtime = Runtime::new().unwrap();
+
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+        runtime
+            .block_on(future::poll_fn(|| -> Poll<_, ()> { Ok(Async::Ready(handler.poll())) }))
+            .unwrap()
+            .unwrap();
+
+        let name = Bytes::from("/plaintext/1.0.0");
+        let calls = handler.handler.calls();
+        let position = |call| calls.iter().position(|c| *c == call);
+        let named = position(Call::ProtocolNegotiated(name, Endpoint::Listener)).unwrap();
+        let injected = position(Call::FullyNegotiated(NodeHandlerEndpoint::Listener)).unwrap();
+        assert!(named < injected);
+    }
+
+    #[test]
+    fn outbound_negotiation_not_starved_by_inbound() {
+        let mut handler = OneOutboundHandler::default().into_node_handler_builder().build();
+        let mut runtime = Runtime::new().unwrap();
+        let mut poll_once = |handler: &mut NodeHandlerWrapper<OneOutboundHandler>| {
+            runtime
+                .block_on(future::poll_fn(|| -> Poll<_, ()> { Ok(Async::Ready(handler.poll())) }))
+                .unwrap()
+        };
+
+        let upgrade_id = match poll_once(&mut handler) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+
+        for _ in 0..500 {
+            handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        }
+        let endpoint = NodeHandlerEndpoint::Dialer(upgrade_id);
+        handler.inject_substream(TestSubstream::accepting_plaintext(), endpoint);
+
+        // The outbound negotiation must complete during the first call to `poll()`, despite all
+        // the inbound substreams that are stuck negotiating.
+        match poll_once(&mut handler) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(())))) => (),
+            _ => panic!("the outbound negotiation didn't complete"),
+        }
+        assert_eq!(handler.num_negotiating_in(), 500);
+        assert_eq!(handler.negotiation_stats().count(), 1);
+    }
+
+    #[test]
+    fn inbound_substreams_are_dropped_after_shutdown() {
+        let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+        let mut handler = handler.into_node_handler();
+        let mut runtime = Runtime::new().unwrap();
+        let mut poll_once = |handler: &mut NodeHandlerWrapper<_>| {
+            runtime
+                .block_on(future::poll_fn(|| -> Poll<_, ()> { Ok(Async::Ready(handler.poll())) }))
+                .unwrap()
+        };
+
+        let upgrade_id = match poll_once(&mut handler) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        assert_eq!(handler.num_negotiating_in(), 1);
+
+        handler.shutdown();
+        assert_eq!(handler.num_negotiating_in(), 0);
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+        assert_eq!(handler.num_negotiating_in(), 0);
+
+        // The outbound negotiation still completes.
+        let endpoint = NodeHandlerEndpoint::Dialer(upgrade_id);
+        handler.inject_substream(TestSubstream::accepting_plaintext(), endpoint);
+        match poll_once(&mut handler) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(())))) => (),
+            _ => panic!("the outbound negotiation didn't complete"),
+        }
+        let calls = handler.handler.calls();
+        assert!(calls.contains(&Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(()))));
+        assert!(!calls.contains(&Call::FullyNegotiated(NodeHandlerEndpoint::Listener)));
+    }
+
+    /// Handler that finishes, but produces events if it is polled again afterwards.
+    #[derive(Default)]
+    struct ZombieHandler {
+        polls: usize,
+    }
+
+    impl ProtocolsHandler for ZombieHandler {
+        type InEvent = Void;
+        type OutEvent = ();
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), ()>>, io::Error> {
+            self.polls += 1;
+            if self.polls == 1 {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(()))))
+            }
+        }
+    }
+
+    #[test]
+    fn handler_isnt_polled_after_finishing() {
+        let mut handler = ZombieHandler::default().into_node_handler();
+        let mut task = MockTask::new();
+        for _ in 0..3 {
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(None)) => (),
+                _ => panic!("expected the wrapper to have finished"),
+            }
+        }
+        assert_eq!(handler.handler.polls, 1);
+    }
+
+    /// Handler that only accepts events while its gate is open.
+    struct BackpressureHandler {
+        gate: Arc<Mutex<Gate>>,
+    }
+
+    impl ProtocolsHandler for BackpressureHandler {
+        type InEvent = Void;
+        type OutEvent = Void;
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+            let mut gate = self.gate.lock().unwrap();
+            if gate.open {
+                Ok(Async::Ready(()))
+            } else {
+                gate.waiting = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), Void>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn event_readiness_goes_through_the_combinators() {
+        let gate = Arc::new(Mutex::new(Gate::default()));
+        let handler = BackpressureHandler { gate: gate.clone() }
+            .map_in_event(|event: u32| -> Option<Void> { panic!("unexpected event {}", event) })
+            .map_out_event(void::unreachable);
+        let mut handler: NodeHandlerWrapper<_> = handler.into_node_handler();
+        let mut task = MockTask::new();
+
+        match task.enter(|| handler.poll_ready_event()) {
+            Ok(Async::NotReady) => (),
+            _ => panic!("expected the handler not to be ready"),
+        }
+        assert!(!task.is_notified());
+
+        Gate::open(&gate);
+        assert!(task.is_notified());
+        match task.enter(|| handler.poll_ready_event()) {
+            Ok(Async::Ready(())) => (),
+            _ => panic!("expected the handler to be ready"),
+        }
+    }
+
+    /// Handler that produces the given events in order, then doesn't produce anything.
+    struct ScriptedHandler<TInfo = ()> {
+        events: VecDeque<ProtocolsHandlerEvent<PlainTextConfig, TInfo, ()>>,
+    }
+
+    impl<TInfo> ProtocolsHandler for ScriptedHandler<TInfo> {
+        type InEvent = Void;
+        type OutEvent = ();
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = TInfo;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn is_idle(&self) -> bool {
+            self.events.is_empty()
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: TInfo, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, TInfo, ()>>, io::Error> {
+            match self.events.pop_front() {
+                Some(event) => Ok(Async::Ready(Some(event))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn protocols_cache_hits_are_counted() {
+        let request = || ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            upgrade: PlainTextConfig,
+            info: (),
+            timeout: None,
+            priority: 0,
+        };
+        let mut events = VecDeque::new();
+        events.push_back(request());
+        let mut handler = ScriptedHandler { events }.into_node_handler();
+        let mut task = MockTask::new();
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        let stats = ProtocolsCacheStats { hits: 0, misses: 1 };
+        assert_eq!(handler.protocols_cache_stats(), Some(stats));
+
+        let substream = TestSubstream::rejecting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(upgrade_id));
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+        // The remote is now known to reject the protocol.
+        handler.get_mut().events.push_back(request());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        let stats = ProtocolsCacheStats { hits: 1, misses: 1 };
+        assert_eq!(handler.protocols_cache_stats(), Some(stats));
+
+        let handler = ScriptedHandler::<()> { events: VecDeque::new() }
+            .into_node_handler_builder()
+            .with_protocol_cache(false)
+            .build();
+        assert!(handler.protocols_cache_stats().is_none());
+    }
+
+    #[test]
+    fn idleness_accounts_for_the_substreams_and_the_handler() {
+        let time = FakeTime::new();
+        let mut handler = ScriptedHandler { events: VecDeque::new() }
+            .into_node_handler_builder()
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+        assert!(handler.is_idle());
+
+        handler.get_mut().events.push_back(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            upgrade: PlainTextConfig,
+            info: (),
+            timeout: None,
+            priority: 0,
+        });
+        assert!(!handler.is_idle());
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        assert!(!handler.is_idle());
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(upgrade_id));
+        assert!(!handler.is_idle());
+
+        // The negotiation completes.
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        assert_eq!(handler.negotiation_stats().count(), 1);
+        assert!(handler.is_idle());
+    }
+
+    #[test]
+    fn many_outbound_requests_are_matched_by_id() {
+        let request = || ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            upgrade: PlainTextConfig,
+            info: (),
+            timeout: None,
+            priority: 0,
+        };
+        let events = (0..1000).map(|_| request()).collect();
+        let mut handler = ScriptedHandler { events }.into_node_handler();
+        let mut task = MockTask::new();
+
+        let mut ids = Vec::new();
+        while let Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) =
+            task.enter(|| handler.poll())
+        {
+            ids.push(id);
+        }
+        assert_eq!(ids.len(), 1000);
+        assert_eq!(handler.peek_next_dial_upgrade().map(|(id, _)| id), Some(ids[0]));
+
+        // The substreams are opened in the reverse order.
+        for id in ids.into_iter().rev() {
+            handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(id));
+        }
+        assert_eq!(handler.num_queued_dial_upgrades(), 0);
+        assert_eq!(handler.num_negotiating_out(), 1000);
+    }
+
+    #[test]
+    fn directions_can_be_closed_by_the_handler() {
+        let request = || ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            upgrade: PlainTextConfig,
+            info: (),
+            timeout: None,
+            priority: 0,
+        };
+        let mut events = VecDeque::new();
+        events.push_back(request());
+        events.push_back(ProtocolsHandlerEvent::CloseOutbound);
+        events.push_back(request());
+        events.push_back(ProtocolsHandlerEvent::CloseInbound);
+        let handler = TestProtocolsHandler::new(ScriptedHandler { events });
+        let mut handler = handler.into_node_handler();
+        let mut task = MockTask::new();
+
+        // The request produced before `CloseOutbound` has already been reported, but the
+        // substream opened for it is closed.
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        let dialer = NodeHandlerEndpoint::Dialer(upgrade_id);
+        handler.inject_substream(TestSubstream::accepting_plaintext(), dialer);
+        assert_eq!(handler.num_negotiating_out(), 0);
+        assert!(!handler.is_outbound_ready());
+
+        // The request produced after `CloseOutbound` fails immediately.
+        let rejected = Call::DialUpgradeError((), ProtocolsHandlerUpgrErr::MuxerClosed.to_string());
+        assert!(handler.get_ref().calls().contains(&rejected));
+
+        let listener = NodeHandlerEndpoint::Listener;
+        handler.inject_substream(TestSubstream::accepting_plaintext(), listener);
+        assert_eq!(handler.num_negotiating_in(), 0);
+    }
+
+    #[test]
+    fn ordered_outbound_requests_are_reported_one_at_a_time() {
+        let mut events = VecDeque::new();
+        for priority in &[0, 5, 1] {
+            events.push_back(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade: PlainTextConfig,
+                info: (),
+                timeout: None,
+                priority: *priority,
+            });
+        }
+        let mut handler = ScriptedHandler { events }
+            .into_node_handler_builder()
+            .with_ordered_outbound(true)
+            .build();
+        let mut task = MockTask::new();
+        let mut next_request = |handler: &mut NodeHandlerWrapper<_>| {
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+                _ => panic!("expected an outbound substream request"),
+            }
+        };
+
+        let first = next_request(&mut handler);
+        assert_eq!(handler.num_delayed_dial_upgrades(), 2);
+
+        // The next request is only reported once the substream has been opened.
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(first));
+        let second = next_request(&mut handler);
+        assert_eq!(handler.num_delayed_dial_upgrades(), 1);
+
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(second));
+        let third = next_request(&mut handler);
+        assert_eq!(handler.num_delayed_dial_upgrades(), 0);
+        assert!(first < second && second < third);
+    }
+
+    #[test]
+    fn delayed_requests_are_reported_by_priority() {
+        let events = [(1, 0), (2, 0), (3, 5)]
+            .iter()
+            .map(|&(info, priority)| ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade: PlainTextConfig,
+                info,
+                timeout: None,
+                priority,
+            })
+            .collect();
+        let mut handler = TestProtocolsHandler::new(ScriptedHandler::<u32> { events })
+            .into_node_handler_builder()
+            .with_max_negotiating_outbound(1)
+            .build();
+        let mut task = MockTask::new();
+
+        let mut id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        assert_eq!(handler.num_delayed_dial_upgrades(), 2);
+
+        // Each negotiation that finishes lets the next delayed request through.
+        for _ in 0..2 {
+            let substream = TestSubstream::accepting_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(id));
+            id = match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+                _ => panic!("expected an outbound substream request"),
+            };
+        }
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(id));
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+        // The request with the highest priority has overtaken the one queued before it.
+        let negotiated = handler
+            .get_ref()
+            .calls()
+            .iter()
+            .filter_map(|call| match *call {
+                Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(info)) => Some(info),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(negotiated, vec![1, 3, 2]);
+    }
+
+    /// What `RequestingHandler` does when polled.
+    #[derive(Debug, Clone)]
+    enum Action {
+        /// Requests an outbound substream.
+        Request(u32),
+        /// Cancels the given requests.
+        Cancel(Vec<u32>),
+    }
+
+    /// Handler that produces the event matching each action injected in it.
+    #[derive(Default)]
+    struct RequestingHandler {
+        actions: VecDeque<Action>,
+        cancelled: Vec<u32>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = Action;
+        type OutEvent = ();
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            PlainTextConfig
+        }
+
+        fn should_open_outbound(&self, info: &u32) -> bool {
+            !self.cancelled.contains(info)
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, action: Action) {
+            self.actions.push_back(action);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, u32, ()>>, io::Error> {
+            let event = match self.actions.pop_front() {
+                Some(Action::Request(info)) => ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: PlainTextConfig,
+                    info,
+                    timeout: None,
+                    priority: 0,
+                },
+                Some(Action::Cancel(infos)) => {
+                    self.cancelled.extend(infos);
+                    ProtocolsHandlerEvent::CancelOutbound
+                }
+                None => return Ok(Async::NotReady),
+            };
+            Ok(Async::Ready(Some(event)))
+        }
+    }
+
+    #[test]
+    fn cancelled_requests_dont_open_substreams() {
+        let mut handler = TestProtocolsHandler::new(RequestingHandler::default())
+            .into_node_handler_builder()
+            .with_max_negotiating_outbound(2)
+            .build();
+        let mut task = MockTask::new();
+        let mut next_request = |handler: &mut NodeHandlerWrapper<_>| {
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => Some(id),
+                Ok(Async::NotReady) => None,
+                _ => panic!("expected an outbound substream request"),
+            }
+        };
+
+        // Requests 1 and 2 are reported, requests 3 and 4 have to wait.
+        for info in 1..5 {
+            handler.inject_event(Action::Request(info));
+        }
+        let first = next_request(&mut handler).unwrap();
+        let second = next_request(&mut handler).unwrap();
+        assert_eq!(next_request(&mut handler), None);
+        assert_eq!(handler.num_delayed_dial_upgrades(), 2);
+
+        // Cancelling request 2 must not let request 4 through in its place.
+        handler.inject_event(Action::Cancel(vec![2, 4]));
+        assert_eq!(next_request(&mut handler), None);
+        assert_eq!(handler.num_queued_dial_upgrades(), 1);
+        assert_eq!(handler.num_delayed_dial_upgrades(), 1);
+
+        // The substream opened for request 2 is closed instead of being negotiated.
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(second));
+        assert_eq!(handler.num_negotiating_out(), 0);
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(first));
+        let third = next_request(&mut handler).unwrap();
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(third));
+        assert_eq!(next_request(&mut handler), None);
+
+        let calls = handler.get_ref().calls();
+        let negotiated = calls
+            .iter()
+            .filter_map(|call| match *call {
+                Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(info)) => Some(info),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(negotiated, vec![1, 3]);
+        assert!(!calls.iter().any(|call| match *call {
+            Call::DialUpgradeError(..) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn poll_budget_is_respected() {
+        let mut handler = OneOutboundHandler::default()
+            .into_node_handler_builder()
+            .with_poll_budget(10)
+            .build();
+        let mut task = MockTask::new();
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(_)))) => (),
+            _ => panic!("expected an outbound substream request"),
+        }
+
+        for _ in 0..25 {
+            let substream = TestSubstream::accepting_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+        }
+
+        // The first call can only poll 10 of the 25 negotiations, and must ask to be polled again.
+        let _ = task.enter(|| handler.poll());
+        assert!(handler.negotiation_stats().count() <= 10);
+        assert!(handler.num_negotiating_in() >= 15);
+        assert!(task.is_notified());
+
+        // All the negotiations eventually complete.
+        let mut polls = 1;
+        while handler.num_negotiating_in() != 0 {
+            let before = handler.negotiation_stats().count();
+            let _ = task.enter(|| handler.poll());
+            assert!(handler.negotiation_stats().count() - before <= 10);
+            polls += 1;
+            assert!(polls <= 25, "the negotiations didn't complete");
+        }
+        assert_eq!(handler.negotiation_stats().count(), 25);
+    }
+
+    #[test]
+    fn poll_budget_doesnt_starve_negotiations() {
+        let time = FakeTime::new();
+        let mut handler = OneOutboundHandler::default()
+            .into_node_handler_builder()
+            .with_poll_budget(10)
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(_)))) => (),
+            _ => panic!("expected an outbound substream request"),
+        }
+
+        // Negotiations that never finish. A negotiation registers itself in its gate when it is
+        // polled, which lets us know which ones have been polled.
+        let mut gates = Vec::new();
+        for _ in 0..25 {
+            let (substream, gate) = TestSubstream::gated_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+            gates.push(gate);
+        }
+
+        // With 25 negotiations and a budget of 10, each negotiation must be polled at least once
+        // every 3 calls.
+        let mut last_polled = vec![0; gates.len()];
+        for tick in 1..=30 {
+            let _ = task.enter(|| handler.poll());
+            let mut polled = 0;
+            for (n, gate) in gates.iter().enumerate() {
+                if gate.lock().unwrap().waiting.take().is_some() {
+                    last_polled[n] = tick;
+                    polled += 1;
+                }
+            }
+            assert_eq!(polled, 10);
+            for last in &last_polled {
+                assert!(tick - last < 3, "a negotiation has been starved");
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_negotiations_are_woken_up() {
+        let mut handler = OneOutboundHandler::default().into_node_handler_builder().build();
+        let mut runtime = Runtime::new().unwrap();
+        let mut task = MockTask::new();
+        let mut poll_once = |task: &mut MockTask, handler: &mut NodeHandlerWrapper<_>| {
+            runtime
+                .block_on(future::poll_fn(|| -> Poll<_, ()> {
+                    Ok(Async::Ready(task.enter(|| handler.poll())))
+                }))
+                .unwrap()
+        };
+
+        match poll_once(&mut task, &mut handler) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(_)))) => (),
+            _ => panic!("expected an outbound substream request"),
+        }
+
+        let mut gates = Vec::new();
+        for _ in 0..64 {
+            let (substream, gate) = TestSubstream::gated_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+            gates.push(gate);
+        }
+
+        // Nothing can make progress yet, but every negotiation must have registered the task.
+        let _ = poll_once(&mut task, &mut handler);
+        assert_eq!(handler.num_negotiating_in(), 64);
+        assert!(!task.is_notified());
+
+        // Open the gates in an order unrelated to the order in which the negotiations are stored,
+        // a few at a time. Each batch must wake up the task and complete at the next poll, even
+        // though the negotiations are moved around in the list as they are polled.
+        let mut order = (0..gates.len()).map(|n| (n * 37) % 64).collect::<Vec<_>>();
+        let mut completed = 0;
+        while !order.is_empty() {
+            let batch = order.split_off(order.len().saturating_sub(5));
+            for n in &batch {
+                Gate::open(&gates[*n]);
+            }
+            completed += batch.len();
+            assert!(task.is_notified(), "opening a gate didn't wake up the task");
+
+            // Polling the wrapper again must register the task anew for the negotiations that
+            // are still pending, otherwise the next batch would never be noticed.
+            let _ = poll_once(&mut task, &mut handler);
+            assert!(!task.is_notified());
+            assert_eq!(handler.negotiation_stats().count(), completed as u64);
+            assert_eq!(handler.num_negotiating_in(), 64 - completed);
+        }
+    }
+
+    #[test]
+    fn shutdown_is_idempotent() {
+        let handler = TestProtocolsHandler::new(DummyProtocolsHandler::<TestSubstream>::default());
+        let mut handler = handler.into_node_handler();
+        let mut task = MockTask::new();
+
+        handler.shutdown();
+        handler.shutdown();
+        for _ in 0..3 {
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(None)) => (),
+                _ => panic!("expected the handler to be finished"),
+            }
+        }
+
+        // Once finished, everything is a no-op.
+        handler.shutdown();
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        handler.inject_inbound_closed();
+        assert_eq!(handler.num_negotiating_in(), 0);
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to still be finished"),
+        }
+
+        assert_eq!(handler.handler.calls(), &[Call::Shutdown, Call::Poll][..]);
+        assert!(handler.connection_keep_alive() == KeepAlive::Now);
+    }
+
+    #[test]
+    fn build_with_observes_every_event() {
+        let seen = Cell::new(0);
+        let burst = BurstHandler { remaining: 3, polls: 0 };
+        let mut handler = burst
+            .into_node_handler_builder()
+            .with_in_negotiation_timeout(Duration::from_secs(5))
+            .build_with(|event| {
+                if let NodeHandlerEvent::Custom(_) = *event {
+                    seen.set(seen.get() + 1);
+                }
+            });
+        let mut task = MockTask::new();
+
+        let mut events = Vec::new();
+        while let Ok(Async::Ready(Some(NodeHandlerEvent::Custom(event)))) =
+            task.enter(|| handler.poll())
+        {
+            events.push(event);
+        }
+        assert_eq!(events, vec![2, 1, 0]);
+        assert_eq!(seen.get(), 3);
+        assert_eq!(handler.get_ref().in_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn close_drives_the_handler_to_completion() {
+        let handler = DummyProtocolsHandler::<TestSubstream>::default().into_node_handler();
+        let mut close = handler.close();
+        let mut task = MockTask::new();
+        match task.enter(|| close.poll()) {
+            Ok(Async::Ready(())) => (),
+            _ => panic!("expected the handler to be closed"),
+        }
+    }
+
+    #[test]
+    fn handler_can_be_polled_before_the_negotiations() {
+        for &first in &[false, true] {
+            let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+            let mut handler = handler
+                .into_node_handler_builder()
+                .with_poll_handler_first(first)
+                .build();
+            let mut task = MockTask::new();
+
+            let upgrade_id = match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+                _ => panic!("expected an outbound substream request"),
+            };
+            let substream = TestSubstream::accepting_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(upgrade_id));
+            let before = handler.handler.calls().len();
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::Custom(())))) => (),
+                _ => panic!("expected the handler to report the negotiated substream"),
+            }
+
+            let calls = &handler.handler.calls()[before..];
+            let name = Call::ProtocolNegotiated(Bytes::from("/plaintext/1.0.0"), Endpoint::Dialer);
+            let negotiated = Call::FullyNegotiated(NodeHandlerEndpoint::Dialer(()));
+            let expected = if first {
+                vec![Call::Poll, name, negotiated, Call::Poll, Call::Poll]
+            } else {
+                vec![name, negotiated, Call::Poll, Call::Poll]
+            };
+            assert_eq!(calls, &expected[..]);
+        }
+    }
+
+    #[test]
+    fn handler_panics_are_turned_into_errors() {
+        let burst = BurstHandler { remaining: 2, polls: 0 };
+        let panicking = burst.inspect_out(|event| {
+            if let ProtocolsHandlerEvent::Custom(0) = *event {
+                panic!("test handler panic");
+            }
+        });
+        let mut handler = panicking.into_node_handler_builder().with_catch_unwind(true).build();
+        let mut task = MockTask::new();
+
+        // The event produced before the panic is still delivered.
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(1)))) => (),
+            _ => panic!("expected the event produced before the panic"),
+        }
+        assert!(task.enter(|| handler.poll()).is_err());
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the wrapper to have finished"),
+        }
+    }
+
+    /// Handler that produces `remaining` events, then panics. Counts the calls it receives after
+    /// that.
+    struct PanickingHandler {
+        remaining: usize,
+        panicked: bool,
+        calls_after_panic: Cell<usize>,
+    }
+
+    impl PanickingHandler {
+        fn touch(&self) {
+            if self.panicked {
+                self.calls_after_panic.set(self.calls_after_panic.get() + 1);
+            }
+        }
+    }
+
+    impl ProtocolsHandler for PanickingHandler {
+        type InEvent = Void;
+        type OutEvent = usize;
+        type Substream = TestSubstream;
+        type Protocol = PlainTextConfig;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            self.touch();
+            PlainTextConfig
+        }
+
+        fn should_accept_inbound(&self) -> bool {
+            self.touch();
+            true
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TestSubstream,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+            self.touch();
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn ready_for_event(&self) -> bool {
+            self.touch();
+            true
+        }
+
+        fn is_idle(&self) -> bool {
+            self.touch();
+            true
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {
+            self.touch();
+        }
+
+        fn inject_inbound_closed(&mut self) {
+            self.touch();
+        }
+
+        fn shutdown(&mut self) {
+            self.touch();
+        }
+
+        fn connection_keep_alive(&self) -> KeepAlive {
+            self.touch();
+            KeepAlive::Forever
+        }
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<PlainTextConfig, (), usize>>, io::Error> {
+            self.touch();
+            if self.remaining == 0 {
+                self.panicked = true;
+                panic!("test handler panic");
+            }
+            self.remaining -= 1;
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(self.remaining))))
+        }
+    }
+
+    #[test]
+    fn panicked_handler_isnt_called_anymore() {
+        let panicking = PanickingHandler {
+            remaining: 2,
+            panicked: false,
+            calls_after_panic: Cell::new(0),
+        };
+        let mut handler = panicking.into_node_handler_builder().with_catch_unwind(true).build();
+        let mut task = MockTask::new();
+
+        // The handler panics during the first poll, while its events are still pending.
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(1)))) => (),
+            _ => panic!("expected the first event"),
+        }
+        assert!(handler.handler.panicked);
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        handler.inject_inbound_closed();
+        assert_eq!(handler.num_negotiating_in(), 0);
+        assert!(handler.supported_protocol_names().is_empty());
+        assert!(handler.ready_for_event());
+        assert!(!handler.is_idle());
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(0)))) => (),
+            _ => panic!("expected the second event"),
+        }
+        assert!(task.enter(|| handler.poll()).is_err());
+        assert!(handler.is_idle());
+        handler.shutdown();
+
+        assert_eq!(handler.handler.calls_after_panic.get(), 0);
+    }
+
+    #[test]
+    fn drain_events_collects_a_whole_burst() {
+        const BURST: usize = MAX_PENDING_EVENTS * 2 + 5;
+        let handler = BurstHandler { remaining: BURST, polls: 0 };
+        let mut handler = handler.into_node_handler();
+        let mut task = MockTask::new();
+
+        let (events, end) = task.enter(|| handler.drain_events());
+        assert_eq!(events.len(), BURST);
+        assert!(end.is_none());
+        assert_eq!(handler.handler.remaining, 0);
+
+        let farewell = FarewellHandler { farewells: 2, finish: true, shutting_down: false };
+        let mut handler = farewell.into_node_handler();
+        handler.shutdown();
+        let (events, end) = task.enter(|| handler.drain_events());
+        assert_eq!(events.len(), 2);
+        match end {
+            Some(Ok(())) => (),
+            _ => panic!("expected the handler to have finished"),
+        }
+    }
+
+    #[test]
+    fn replaced_handler_is_disposed_of() {
+        let old = FarewellHandler { farewells: 3, finish: true, shutting_down: false };
+        let mut handler = old.into_node_handler();
+        let mut task = MockTask::new();
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+
+        let new = FarewellHandler { farewells: 1, finish: true, shutting_down: false };
+        let events = task.enter(|| handler.replace_handler(new));
+        assert_eq!(events, vec![2, 1, 0]);
+        // The new handler is running and receives the substreams still being negotiated.
+        assert!(!handler.handler.shutting_down);
+        assert_eq!(handler.num_negotiating_in(), 1);
+
+        handler.shutdown();
+        assert_eq!(drain_events(&mut task, &mut handler), vec![0]);
+    }
+
+    #[test]
+    fn replaced_handler_that_never_finishes_is_dropped() {
+        let endless = || BurstHandler { remaining: ::std::usize::MAX, polls: 0 };
+        let mut task = MockTask::new();
+
+        let mut handler = endless().into_node_handler();
+        let events = task.enter(|| handler.replace_handler(endless()));
+        assert_eq!(events.len(), MAX_PENDING_EVENTS);
+
+        let mut handler = endless().into_node_handler_builder().with_max_shutdown_events(5).build();
+        let events = task.enter(|| handler.replace_handler(endless()));
+        assert_eq!(events.len(), 5);
+    }
+
+    #[test]
+    fn bursts_of_events_are_buffered() {
+        const BURST: usize = MAX_PENDING_EVENTS * 2 + 5;
+        let handler = BurstHandler { remaining: BURST, polls: 0 };
+        let mut handler = handler.into_node_handler();
+        let mut task = MockTask::new();
+
+        // The first call collects as many events as the buffer allows, and asks to be polled
+        // again.
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(n)))) => assert_eq!(n, BURST - 1),
+            _ => panic!("expected an event"),
+        }
+        assert!(task.is_notified());
+        assert_eq!(handler.handler.remaining, BURST - MAX_PENDING_EVENTS);
+        assert_eq!(handler.handler.polls, MAX_PENDING_EVENTS);
+
+        // The buffered events are then delivered in order, and the handler is only polled again
+        // once the buffer is empty.
+        let mut expected = BURST - 1;
+        while expected != 0 {
+            expected -= 1;
+            match task.enter(|| handler.poll()) {
+                Ok(Async::Ready(Some(NodeHandlerEvent::Custom(n)))) => assert_eq!(n, expected),
+                _ => panic!("expected an event"),
+            }
+            assert!(task.is_notified());
+        }
+        // The last batch stopped when the handler returned `NotReady`.
+        assert_eq!(handler.handler.remaining, 0);
+        assert_eq!(handler.handler.polls, BURST + 1);
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::NotReady) => (),
+            _ => panic!("expected no more events"),
+        }
+        assert!(!task.is_notified());
+        assert_eq!(handler.handler.polls, BURST + 2);
+    }
+
+    /// Handler that reports a configurable `listen_protocol_version()`, and counts how many times
+    /// `listen_protocol()` has been called.
+    struct VersionedHandler<TProto> {
+        version: Option<u64>,
+        listen_protocol_calls: Cell<usize>,
+        protocol: fn() -> TProto,
+    }
+
+    impl<TProto> VersionedHandler<TProto> {
+        fn new(protocol: fn() -> TProto) -> Self {
+            VersionedHandler {
+                version: None,
+                listen_protocol_calls: Cell::new(0),
+                protocol,
+            }
+        }
+    }
+
+    impl<TProto> ProtocolsHandler for VersionedHandler<TProto>
+    where
+        TProto: ConnectionUpgrade<TestSubstream>,
+    {
+        type InEvent = Void;
+        type OutEvent = Void;
+        type Substream = TestSubstream;
+        type Protocol = TProto;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            self.listen_protocol_calls.set(self.listen_protocol_calls.get() + 1);
+            (self.protocol)()
+        }
+
+        fn listen_protocol_version(&self) -> Option<u64> {
+            self.version
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: TProto::Output,
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), TProto::Output> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: Void) {}
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(&mut self) -> Poll<Option<ProtocolsHandlerEvent<TProto, (), Void>>, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Same as `PlainTextConfig`, but can't be cloned.
+    struct NonCloneUpgrade(PlainTextConfig);
+
+    impl ConnectionUpgrade<TestSubstream> for NonCloneUpgrade {
+        type NamesIter = <PlainTextConfig as ConnectionUpgrade<TestSubstream>>::NamesIter;
+        type UpgradeIdentifier = ();
+        type Output = TestSubstream;
+        type Future = <PlainTextConfig as ConnectionUpgrade<TestSubstream>>::Future;
+
+        fn protocol_names(&self) -> Self::NamesIter {
+            ConnectionUpgrade::<TestSubstream>::protocol_names(&self.0)
+        }
+
+        fn upgrade(self, socket: TestSubstream, id: (), ty: Endpoint) -> Self::Future {
+            self.0.upgrade(socket, id, ty)
+        }
+    }
+
+    #[test]
+    fn listen_protocol_is_cached_while_version_is_unchanged() {
+        let mut handler = VersionedHandler::new(|| PlainTextConfig)
+            .into_node_handler_builder()
+            .with_listen_protocol_cache(true)
+            .build();
+        let inject = |handler: &mut NodeHandlerWrapper<VersionedHandler<PlainTextConfig>>| {
+            handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+            handler.handler.listen_protocol_calls.get()
+        };
+
+        // Without a version, the protocol is rebuilt for each substream.
+        assert_eq!(inject(&mut handler), 1);
+        assert_eq!(inject(&mut handler), 2);
+
+        handler.handler.version = Some(0);
+        assert_eq!(inject(&mut handler), 3);
+        assert_eq!(inject(&mut handler), 3);
+        handler.handler.version = Some(1);
+        assert_eq!(inject(&mut handler), 4);
+        assert_eq!(inject(&mut handler), 4);
+    }
+
+    #[test]
+    fn listen_protocol_isnt_cached_unless_enabled() {
+        // The upgrade doesn't need to be `Clone` for the wrapper to be built and used.
+        let mut handler = VersionedHandler::new(|| NonCloneUpgrade(PlainTextConfig))
+            .into_node_handler();
+        handler.handler.version = Some(0);
+        for calls in 1 .. 4 {
+            handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+            assert_eq!(handler.handler.listen_protocol_calls.get(), calls);
+        }
+        assert_eq!(handler.num_negotiating_in(), 3);
+    }
+
+    #[test]
+    fn substream_opening_times_out() {
+        let mut time = FakeTime::new();
+        let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_open_timeout(Duration::from_secs(5))
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        time.advance(Duration::from_secs(4));
+        assert!(!task.is_notified());
+
+        time.advance(Duration::from_secs(2));
+        assert!(task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        assert_eq!(handler.num_queued_dial_upgrades(), 0);
+        let timed_out = Call::DialUpgradeError((), ProtocolsHandlerUpgrErr::Timeout.to_string());
+        assert!(handler.handler.calls().contains(&timed_out));
+
+        // The substream opened afterwards is closed without involving the handler.
+        let calls = handler.handler.calls().len();
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(upgrade_id));
+        assert_eq!(handler.num_negotiating_out(), 0);
+        assert_eq!(handler.handler.calls().len(), calls);
+    }
+
+    /// Upgrade that supports `/plaintext/1.0.0`, but never finishes once negotiated.
+    struct EndlessUpgrade;
+
+    impl ConnectionUpgrade<TestSubstream> for EndlessUpgrade {
+        type NamesIter = <PlainTextConfig as ConnectionUpgrade<TestSubstream>>::NamesIter;
+        type UpgradeIdentifier = ();
+        type Output = ();
+        type Future = future::Empty<(), io::Error>;
+
+        fn protocol_names(&self) -> Self::NamesIter {
+            ConnectionUpgrade::<TestSubstream>::protocol_names(&PlainTextConfig)
+        }
+
+        fn upgrade(self, _: TestSubstream, _: (), _: Endpoint) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    /// Upgrade that supports a single protocol with the given name, and never finishes.
+    struct NamedUpgrade(&'static str);
+
+    impl ConnectionUpgrade<TestSubstream> for NamedUpgrade {
+        type NamesIter = iter::Once<(Bytes, ())>;
+        type UpgradeIdentifier = ();
+        type Output = ();
+        type Future = future::Empty<(), io::Error>;
+
+        fn protocol_names(&self) -> Self::NamesIter {
+            iter::once((Bytes::from(self.0), ()))
+        }
+
+        fn upgrade(self, _: TestSubstream, _: (), _: Endpoint) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    #[test]
+    fn supported_protocol_names_include_all_the_handlers() {
+        let first = VersionedHandler::new(|| NamedUpgrade("/first/1.0.0"));
+        let second = VersionedHandler::new(|| NamedUpgrade("/second/1.0.0"));
+        let handler = first.select(second).into_node_handler();
+        assert_eq!(
+            handler.supported_protocol_names(),
+            vec![Bytes::from("/first/1.0.0"), Bytes::from("/second/1.0.0")]
+        );
+
+        let handler = DummyProtocolsHandler::<TestSubstream>::default().into_node_handler();
+        assert!(handler.supported_protocol_names().is_empty());
+    }
+
+    #[test]
+    fn inbound_timeout_depends_on_the_negotiated_protocol() {
+        // The default timeout is 10 seconds. The protocol-specific one can be longer or shorter.
+        for &(protocol_timeout, still_running_at) in &[(20, 15), (3, 2)] {
+            let mut time = FakeTime::new();
+            let mut timeouts = HashMap::new();
+            timeouts.insert(Bytes::from("/plaintext/1.0.0"), Duration::from_secs(protocol_timeout));
+            let handler = TestProtocolsHandler::new(VersionedHandler::new(|| EndlessUpgrade));
+            let mut handler = handler
+                .into_node_handler_builder()
+                .with_in_negotiation_timeout(Duration::from_secs(10))
+                .with_inbound_timeouts(timeouts)
+                .with_timer(time.factory())
+                .build();
+            let mut task = MockTask::new();
+
+            let substream = TestSubstream::accepting_plaintext();
+            handler.inject_substream(substream, NodeHandlerEndpoint::Listener);
+            assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+            time.advance(Duration::from_secs(still_running_at));
+            assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+            assert_eq!(handler.num_negotiating_in(), 1);
+
+            time.advance(Duration::from_secs(protocol_timeout - still_running_at + 1));
+            assert!(task.is_notified());
+            assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+            assert_eq!(handler.num_negotiating_in(), 0);
+            let timed_out = Call::ListenUpgradeError(io::ErrorKind::TimedOut);
+            assert!(handler.handler.calls().contains(&timed_out));
+        }
+    }
+
+    #[test]
+    fn inbound_substreams_beyond_the_rate_limit_are_closed() {
+        let mut time = FakeTime::new();
+        let handler = TestProtocolsHandler::new(DummyProtocolsHandler::<TestSubstream>::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_inbound_rate_limit(2, Duration::from_secs(10))
+            .with_timer(time.factory())
+            .build();
+
+        for _ in 0..3 {
+            handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        }
+        assert_eq!(handler.num_negotiating_in(), 2);
+
+        // More substreams are accepted once the previous ones are older than the interval.
+        time.advance(Duration::from_secs(6));
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        assert_eq!(handler.num_negotiating_in(), 2);
+        time.advance(Duration::from_secs(5));
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        assert_eq!(handler.num_negotiating_in(), 4);
+    }
+
+    #[test]
+    fn inbound_negotiation_times_out() {
+        let mut time = FakeTime::new();
+        let handler = TestProtocolsHandler::new(DummyProtocolsHandler::<TestSubstream>::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_in_negotiation_timeout(Duration::from_secs(10))
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Listener);
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        time.advance(Duration::from_secs(9));
+        assert!(!task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        assert_eq!(handler.num_negotiating_in(), 1);
+
+        time.advance(Duration::from_secs(2));
+        assert!(task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        assert_eq!(handler.num_negotiating_in(), 0);
+        let timed_out = Call::ListenUpgradeError(io::ErrorKind::TimedOut);
+        assert!(handler.handler.calls().contains(&timed_out));
+    }
+
+    #[test]
+    fn outbound_negotiation_times_out() {
+        let mut time = FakeTime::new();
+        let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_out_negotiation_timeout(Duration::from_secs(10))
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        assert_eq!(handler.peek_next_dial_upgrade().map(|(id, _)| id), Some(upgrade_id));
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(upgrade_id));
+        assert!(handler.peek_next_dial_upgrade().is_none());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        time.advance(Duration::from_secs(9));
+        assert!(!task.is_notified());
+
+        time.advance(Duration::from_secs(2));
+        assert!(task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        let timed_out = Call::DialUpgradeError((), ProtocolsHandlerUpgrErr::Timeout.to_string());
+        assert!(handler.handler.calls().contains(&timed_out));
+        let stats = handler.protocol_stats()[&Bytes::from("/plaintext/1.0.0")];
+        assert_eq!(stats, ProtocolStats { successes: 0, failures: 1 });
+    }
+
+    #[test]
+    fn outbound_readiness_is_reported() {
+        let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_max_negotiating_outbound(1)
+            .build();
+        let mut task = MockTask::new();
+        assert!(handler.is_outbound_ready());
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        assert!(!handler.is_outbound_ready());
+        assert!(handler.handler.calls().contains(&Call::OutboundReady(false)));
+
+        let substream = TestSubstream::accepting_plaintext();
+        handler.inject_substream(substream, NodeHandlerEndpoint::Dialer(upgrade_id));
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::Custom(())))) => (),
+            _ => panic!("expected the handler to report the negotiated substream"),
+        }
+        assert!(handler.is_outbound_ready());
+        let readiness = handler
+            .handler
+            .calls()
+            .iter()
+            .filter_map(|call| match *call {
+                Call::OutboundReady(ready) => Some(ready),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(readiness, vec![false, true]);
+    }
+
+    #[test]
+    fn out_negotiation_timeout_can_be_changed() {
+        let mut time = FakeTime::new();
+        let handler = TestProtocolsHandler::new(OneOutboundHandler::default());
+        let mut handler = handler
+            .into_node_handler_builder()
+            .with_out_negotiation_timeout(Duration::from_secs(10))
+            .with_timer(time.factory())
+            .build();
+        let mut task = MockTask::new();
+
+        let upgrade_id = match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(NodeHandlerEvent::OutboundSubstreamRequest(id)))) => id,
+            _ => panic!("expected an outbound substream request"),
+        };
+        handler.set_out_negotiation_timeout(Duration::from_secs(2));
+        handler.inject_substream(TestSubstream::pending(), NodeHandlerEndpoint::Dialer(upgrade_id));
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+
+        // The negotiation started after the change, and therefore uses the new timeout.
+        time.advance(Duration::from_secs(3));
+        assert!(task.is_notified());
+        assert!(task.enter(|| handler.poll()).unwrap().is_not_ready());
+        let timed_out = Call::DialUpgradeError((), ProtocolsHandlerUpgrErr::Timeout.to_string());
+        assert!(handler.handler.calls().contains(&timed_out));
+    }
+
+    #[test]
+    fn timeout_jitter_stays_within_bounds() {
+        let base = Duration::from_secs(10);
+        let mut jitter = TimeoutJitter::new(0.2);
+        let timeouts = (0..100).map(|_| jitter.apply(base)).collect::<Vec<_>>();
+        for timeout in &timeouts {
+            assert!(*timeout >= Duration::from_secs(8));
+            assert!(*timeout <= Duration::from_secs(12));
+        }
+        assert!(timeouts.iter().any(|t| *t != timeouts[0]));
+
+        // Two wrappers created at the same time don't produce the same timeouts.
+        let mut other = TimeoutJitter::new(0.2);
+        let mut jitter = TimeoutJitter::new(0.2);
+        assert_ne!(other.apply(base), jitter.apply(base));
+    }
+
+    #[test]
+    fn nan_timeout_jitter_is_ignored() {
+        let builder = DummyProtocolsHandler::<TestSubstream>::default()
+            .into_node_handler_builder()
+            .with_timeout_jitter(::std::f64::NAN);
+        assert_eq!(builder.timeout_jitter, Some(0.0));
+
+        let mut handler = builder.build();
+        let base = Duration::from_secs(10);
+        assert_eq!(handler.jittered(base), base);
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::prelude::*;
+use nodes::handled_node::NodeHandlerEndpoint;
+use nodes::protocols_handler::{ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+use std::{collections::VecDeque, fmt, io, marker::PhantomData};
+use tokio_io::{AsyncRead, AsyncWrite};
+use void::{self, Void};
+use ConnectionUpgrade;
+
+/// Builds a `ProtocolsHandler` that accepts inbound substreams with the given upgrade, and that
+/// produces an event for each of them by calling `on_negotiated` with the output of the upgrade.
+///
+/// The handler never opens any outbound substream.
+#[inline]
+pub fn oneshot_handler<TSubstream, TUpgrade, TMap, TOutEvent>(
+    upgrade: TUpgrade,
+    on_negotiated: TMap,
+) -> OneShotHandler<TSubstream, TUpgrade, TMap, TOutEvent>
+where
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TMap: FnMut(TUpgrade::Output) -> TOutEvent,
+{
+    OneShotHandler {
+        upgrade,
+        on_negotiated,
+        pending_events: VecDeque::new(),
+        shutting_down: false,
+        marker: PhantomData,
+    }
+}
+
+/// Implementation of `ProtocolsHandler` returned by `oneshot_handler`.
+pub struct OneShotHandler<TSubstream, TUpgrade, TMap, TOutEvent> {
+    /// The upgrade to apply to inbound substreams.
+    upgrade: TUpgrade,
+    /// Turns the output of the upgrade into an event.
+    on_negotiated: TMap,
+    /// Events produced by `on_negotiated` and not returned by `poll()` yet.
+    pending_events: VecDeque<TOutEvent>,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream, TUpgrade, TMap, TOutEvent> fmt::Debug
+    for OneShotHandler<TSubstream, TUpgrade, TMap, TOutEvent>
+where
+    TUpgrade: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("OneShotHandler")
+            .field("upgrade", &self.upgrade)
+            .field("pending_events", &self.pending_events.len())
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TSubstream, TUpgrade, TMap, TOutEvent> ProtocolsHandler
+    for OneShotHandler<TSubstream, TUpgrade, TMap, TOutEvent>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TMap: FnMut(TUpgrade::Output) -> TOutEvent,
+{
+    type InEvent = Void;
+    type OutEvent = TOutEvent;
+    type Substream = TSubstream;
+    type Protocol = TUpgrade;
+    type OutboundOpenInfo = Void;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.upgrade.clone()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        // The protocol never changes.
+        Some(0)
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        !self.shutting_down
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        match endpoint {
+            NodeHandlerEndpoint::Listener => {
+                if self.shutting_down {
+                    return Err(protocol);
+                }
+                let event = (self.on_negotiated)(protocol);
+                self.pending_events.push_back(event);
+                Ok(())
+            }
+            NodeHandlerEndpoint::Dialer(info) => void::unreachable(info),
+        }
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        void::unreachable(event)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        _: ProtocolsHandlerUpgrErr,
+    ) {
+        void::unreachable(info)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {}
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+        }
+
+        if self.shutting_down {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    #[test]
+    fn pending_events_are_delivered_after_shutdown() {
+        let mut handler =
+            oneshot_handler::<Cursor<Vec<u8>>, _, _, _>(DeniedConnectionUpgrade, |()| 5u32);
+        let mut task = MockTask::new();
+
+        assert!(handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).is_ok());
+        handler.shutdown();
+        assert!(!handler.should_accept_inbound());
+        assert!(handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).is_err());
+
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(5)))) => (),
+            _ => panic!("expected the event of the negotiated substream"),
+        }
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to have finished"),
+        }
+    }
+}
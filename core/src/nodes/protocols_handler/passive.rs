@@ -0,0 +1,155 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::prelude::*;
+use nodes::handled_node::NodeHandlerEndpoint;
+use nodes::protocols_handler::{ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+use std::{fmt, io, marker::PhantomData};
+use tokio_io::{AsyncRead, AsyncWrite};
+use void::Void;
+use ConnectionUpgrade;
+
+/// Implementation of `ProtocolsHandler` that accepts the inbound substreams negotiated with a
+/// given upgrade, and immediately closes them. It never opens any outbound substream and never
+/// produces any event.
+///
+/// Contrary to `DummyProtocolsHandler`, the remote can successfully negotiate the protocol.
+pub struct PassiveProtocolsHandler<TSubstream, TUpgrade> {
+    /// The upgrade to apply to inbound substreams.
+    upgrade: TUpgrade,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream, TUpgrade> PassiveProtocolsHandler<TSubstream, TUpgrade> {
+    /// Creates a `PassiveProtocolsHandler` that accepts the inbound substreams negotiated with
+    /// `upgrade`.
+    #[inline]
+    pub fn new(upgrade: TUpgrade) -> Self {
+        PassiveProtocolsHandler {
+            upgrade,
+            shutting_down: false,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<TSubstream, TUpgrade> fmt::Debug for PassiveProtocolsHandler<TSubstream, TUpgrade>
+where
+    TUpgrade: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("PassiveProtocolsHandler")
+            .field("upgrade", &self.upgrade)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TSubstream, TUpgrade> ProtocolsHandler for PassiveProtocolsHandler<TSubstream, TUpgrade>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+{
+    type InEvent = Void;
+    type OutEvent = Void;
+    type Substream = TSubstream;
+    type Protocol = TUpgrade;
+    type OutboundOpenInfo = Void;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.upgrade.clone()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        // The protocol never changes.
+        Some(0)
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        !self.shutting_down
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        _: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        // Dropping the output of the upgrade closes the substream.
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_event(&mut self, _: Self::InEvent) {}
+
+    #[inline]
+    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: ProtocolsHandlerUpgrErr) {
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {}
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    #[inline]
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if self.shutting_down {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::PlainTextConfig;
+
+    #[test]
+    fn inbound_substreams_are_accepted_until_shutdown() {
+        let mut handler = PassiveProtocolsHandler::<Cursor<Vec<u8>>, _>::new(PlainTextConfig);
+        assert!(handler.should_accept_inbound());
+        let endpoint = NodeHandlerEndpoint::Listener;
+        assert!(handler.inject_fully_negotiated(Cursor::new(Vec::new()), endpoint).is_ok());
+        assert!(handler.poll().unwrap().is_not_ready());
+
+        handler.shutdown();
+        assert!(!handler.should_accept_inbound());
+        match handler.poll() {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to finish"),
+        }
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{cmp, collections::VecDeque, fmt, io, time::Duration};
+use tokio_timer::{clock, Interval};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that limits the rate at which outbound substreams are
+/// requested.
+///
+/// At most `max_per_interval` outbound substream requests are produced during each interval.
+/// The requests beyond that are buffered and produced in order during the next intervals.
+pub struct RateLimit<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Outbound substream requests produced by the handler and not reported yet.
+    queue: VecDeque<(
+        TProtoHandler::Protocol,
+        TProtoHandler::OutboundOpenInfo,
+        Option<Duration>,
+        u8,
+    )>,
+    /// Maximum number of requests to report during each interval.
+    max_per_interval: usize,
+    /// Number of requests reported during the current interval.
+    reported_this_interval: usize,
+    /// Stream that produces an element at the start of each interval.
+    interval: Interval,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+}
+
+impl<TProtoHandler> RateLimit<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `RateLimit`. A `max_per_interval` of 0 is treated as 1.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, max_per_interval: usize, interval: Duration) -> Self {
+        RateLimit {
+            inner,
+            queue: VecDeque::new(),
+            max_per_interval: cmp::max(max_per_interval, 1),
+            reported_this_interval: 0,
+            interval: Interval::new(clock::now() + interval, interval),
+            shutting_down: false,
+        }
+    }
+
+    /// Returns the number of outbound substream requests waiting to be produced.
+    #[inline]
+    pub fn queued_requests(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for RateLimit<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("RateLimit")
+            .field("inner", &self.inner)
+            .field("queued_requests", &self.queue.len())
+            .field("max_per_interval", &self.max_per_interval)
+            .field("reported_this_interval", &self.reported_this_interval)
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for RateLimit<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    fn inject_outbound_closed(&mut self) {
+        // The requests that are still buffered would fail anyway.
+        self.inner.inject_outbound_closed();
+        for (_, info, _, _) in self.queue.drain(..) {
+            self.inner.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::MuxerClosed);
+        }
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // The requests that are still buffered will never be reported. Let the handler know.
+        self.shutting_down = true;
+        for (_, info, _, _) in self.queue.drain(..) {
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "handler shutting down");
+            self.inner.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Upgrade(err));
+        }
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if !self.queue.is_empty() {
+            KeepAlive::Forever
+        } else {
+            self.inner.connection_keep_alive()
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => self.reported_this_interval = 0,
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+            }
+        }
+
+        if self.reported_this_interval < self.max_per_interval {
+            if let Some((upgrade, info, timeout, priority)) = self.queue.pop_front() {
+                self.reported_this_interval += 1;
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                })));
+            }
+        }
+
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }) => {
+                    if self.shutting_down {
+                        let err = io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "handler shutting down",
+                        );
+                        let err = ProtocolsHandlerUpgrErr::Upgrade(err);
+                        self.inner.inject_dial_upgrade_error(info, err);
+                    } else if self.queue.is_empty()
+                        && self.reported_this_interval < self.max_per_interval
+                    {
+                        self.reported_this_interval += 1;
+                        return Ok(Async::Ready(Some(
+                            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                                upgrade,
+                                info,
+                                timeout,
+                                priority,
+                            },
+                        )));
+                    } else {
+                        self.queue.push_back((upgrade, info, timeout, priority));
+                    }
+                }
+                Some(ProtocolsHandlerEvent::CancelOutbound) => {
+                    let inner = &self.inner;
+                    self.queue.retain(|&(_, ref info, _, _)| inner.should_open_outbound(info));
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                }
+                Some(ProtocolsHandlerEvent::CloseOutbound) => {
+                    // The buffered requests are dropped like the ones of the wrapper.
+                    self.queue.clear();
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                }
+                event => return Ok(Async::Ready(event)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use tests::fake_time::FakeTime;
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// What `RequestingHandler` does when polled.
+    #[derive(Debug, Clone)]
+    enum Action {
+        /// Requests an outbound substream.
+        Request(u32),
+        /// Cancels the given requests.
+        Cancel(Vec<u32>),
+    }
+
+    /// Handler that produces the event matching each action injected in it, and records the
+    /// requests that failed.
+    #[derive(Default)]
+    struct RequestingHandler {
+        actions: VecDeque<Action>,
+        cancelled: HashSet<u32>,
+        failed: Vec<u32>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = Action;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn should_open_outbound(&self, info: &u32) -> bool {
+            !self.cancelled.contains(info)
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, action: Action) {
+            self.actions.push_back(action);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, info: u32, _: ProtocolsHandlerUpgrErr) {
+            self.failed.push(info);
+        }
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            let event = match self.actions.pop_front() {
+                Some(Action::Request(info)) => ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: DeniedConnectionUpgrade,
+                    info,
+                    timeout: None,
+                    priority: 0,
+                },
+                Some(Action::Cancel(infos)) => {
+                    self.cancelled.extend(infos);
+                    ProtocolsHandlerEvent::CancelOutbound
+                }
+                None => return Ok(Async::NotReady),
+            };
+            Ok(Async::Ready(Some(event)))
+        }
+    }
+
+    /// A `RateLimit` of 2 requests every 10 seconds, with the time under control of the test.
+    struct Limited {
+        time: FakeTime,
+        task: MockTask,
+        handler: RateLimit<RequestingHandler>,
+    }
+
+    impl Limited {
+        fn new() -> Self {
+            let time = FakeTime::new();
+            let inner = RequestingHandler::default();
+            let handler = time.enter(|| inner.rate_limit_outbound(2, Duration::from_secs(10)));
+            Limited { time, task: MockTask::new(), handler }
+        }
+
+        /// Injects new requests, then polls the handler until it is no longer ready and returns
+        /// the information of the requests it has produced.
+        fn requests(&mut self, requests: &[u32]) -> Vec<u32> {
+            for request in requests {
+                self.handler.inject_event(Action::Request(*request));
+            }
+            let (handler, task) = (&mut self.handler, &mut self.task);
+            self.time.enter(|| {
+                let mut infos = Vec::new();
+                loop {
+                    match task.enter(|| handler.poll()).unwrap() {
+                        Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                            info, ..
+                        })) => infos.push(info),
+                        Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)) => (),
+                        Async::NotReady => return infos,
+                        _ => panic!("unexpected event"),
+                    }
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn requests_are_limited_per_interval() {
+        let mut limited = Limited::new();
+        assert_eq!(limited.requests(&[0, 1, 2, 3, 4]), vec![0, 1]);
+        assert_eq!(limited.handler.queued_requests(), 3);
+
+        limited.time.advance(Duration::from_secs(5));
+        assert!(!limited.task.is_notified());
+        assert!(limited.requests(&[]).is_empty());
+
+        // The queued requests go first, in order.
+        limited.time.advance(Duration::from_secs(5));
+        assert!(limited.task.is_notified());
+        assert_eq!(limited.requests(&[5]), vec![2, 3]);
+        limited.time.advance(Duration::from_secs(10));
+        assert_eq!(limited.requests(&[]), vec![4, 5]);
+        assert!(limited.handler.inner.failed.is_empty());
+    }
+
+    #[test]
+    fn shutdown_reports_the_queued_requests() {
+        let mut limited = Limited::new();
+        assert_eq!(limited.requests(&[0, 1, 2, 3]), vec![0, 1]);
+        limited.handler.shutdown();
+        assert_eq!(limited.handler.queued_requests(), 0);
+        assert_eq!(limited.handler.inner.failed, vec![2, 3]);
+
+        // The requests produced afterwards aren't reported either.
+        limited.time.advance(Duration::from_secs(10));
+        assert!(limited.requests(&[4]).is_empty());
+        assert_eq!(limited.handler.inner.failed, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn closed_outbound_substreams_report_the_queued_requests() {
+        let mut limited = Limited::new();
+        assert_eq!(limited.requests(&[0, 1, 2, 3]), vec![0, 1]);
+        limited.handler.inject_outbound_closed();
+        assert_eq!(limited.handler.queued_requests(), 0);
+        assert_eq!(limited.handler.inner.failed, vec![2, 3]);
+    }
+
+    #[test]
+    fn cancelled_requests_leave_the_queue() {
+        let mut limited = Limited::new();
+        assert_eq!(limited.requests(&[0, 1, 2, 3, 4]), vec![0, 1]);
+        limited.handler.inject_event(Action::Cancel(vec![2, 4]));
+        assert!(limited.requests(&[]).is_empty());
+        assert_eq!(limited.handler.queued_requests(), 1);
+
+        limited.time.advance(Duration::from_secs(10));
+        assert_eq!(limited.requests(&[]), vec![3]);
+        // Cancelled requests aren't reported to the handler.
+        assert!(limited.handler.inner.failed.is_empty());
+    }
+}
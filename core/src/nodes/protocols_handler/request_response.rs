@@ -0,0 +1,283 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::prelude::*;
+use nodes::handled_node::NodeHandlerEndpoint;
+use nodes::protocols_handler::{
+    KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io, marker::PhantomData};
+use tokio_io::{AsyncRead, AsyncWrite};
+use ConnectionUpgrade;
+
+/// Builds a `ProtocolsHandler` that sends each request injected in it on a new outbound
+/// substream negotiated with `upgrade`, and produces the response.
+///
+/// Once a substream has been negotiated, `exchange` is called with the output of the upgrade and
+/// the request, and returns a future that writes the request and reads the response. This covers
+/// the common pattern of opening a substream, writing, reading and reporting the result, without
+/// having to go through `inject_fully_negotiated` manually.
+///
+/// Only the dialing side is handled: the inbound substreams are refused.
+#[inline]
+pub fn request_response_handler<TSubstream, TUpgrade, TRequest, TExchange, TFuture>(
+    upgrade: TUpgrade,
+    exchange: TExchange,
+) -> RequestResponseHandler<TSubstream, TUpgrade, TRequest, TExchange, TFuture>
+where
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TExchange: FnMut(TUpgrade::Output, TRequest) -> TFuture,
+    TFuture: Future<Error = io::Error>,
+{
+    RequestResponseHandler {
+        upgrade,
+        exchange,
+        queued_requests: VecDeque::new(),
+        opening: 0,
+        exchanges: Vec::new(),
+        pending_events: VecDeque::new(),
+        shutting_down: false,
+        marker: PhantomData,
+    }
+}
+
+/// Event produced by a `RequestResponseHandler`.
+#[derive(Debug)]
+pub enum RequestResponseHandlerEvent<TResponse> {
+    /// The response to one of the requests.
+    Response(TResponse),
+    /// Opening the substream or exchanging the request and the response failed.
+    Failed(io::Error),
+}
+
+/// Implementation of `ProtocolsHandler` returned by `request_response_handler`.
+pub struct RequestResponseHandler<TSubstream, TUpgrade, TRequest, TExchange, TFuture>
+where
+    TFuture: Future,
+{
+    /// The upgrade to apply to the substreams.
+    upgrade: TUpgrade,
+    /// Starts the exchange on a negotiated substream.
+    exchange: TExchange,
+    /// Requests injected in the handler for which no substream has been requested yet.
+    queued_requests: VecDeque<TRequest>,
+    /// Number of outbound substreams that have been requested and not negotiated yet.
+    opening: usize,
+    /// The exchanges in progress.
+    exchanges: Vec<TFuture>,
+    /// Events to return from `poll()`.
+    pending_events: VecDeque<RequestResponseHandlerEvent<TFuture::Item>>,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream, TUpgrade, TRequest, TExchange, TFuture> ProtocolsHandler
+    for RequestResponseHandler<TSubstream, TUpgrade, TRequest, TExchange, TFuture>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<TSubstream> + Clone,
+    TExchange: FnMut(TUpgrade::Output, TRequest) -> TFuture,
+    TFuture: Future<Error = io::Error>,
+{
+    type InEvent = TRequest;
+    type OutEvent = RequestResponseHandlerEvent<TFuture::Item>;
+    type Substream = TSubstream;
+    type Protocol = TUpgrade;
+    type OutboundOpenInfo = TRequest;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.upgrade.clone()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        // The protocol never changes.
+        Some(0)
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        false
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        let request = match endpoint {
+            NodeHandlerEndpoint::Dialer(request) => request,
+            NodeHandlerEndpoint::Listener => return Err(protocol),
+        };
+        self.opening -= 1;
+        let exchange = (self.exchange)(protocol, request);
+        self.exchanges.push(exchange);
+        Ok(())
+    }
+
+    #[inline]
+    fn inject_event(&mut self, request: Self::InEvent) {
+        if self.shutting_down {
+            debug!("Dropping a request injected in a handler that is shutting down");
+            return;
+        }
+        self.queued_requests.push_back(request);
+    }
+
+    fn inject_dial_upgrade_error(&mut self, _: TRequest, error: ProtocolsHandlerUpgrErr) {
+        self.opening -= 1;
+        let event = RequestResponseHandlerEvent::Failed(error.into());
+        self.pending_events.push_back(event);
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {}
+
+    #[inline]
+    fn shutdown(&mut self) {
+        // The exchanges in progress are allowed to finish.
+        self.shutting_down = true;
+        self.queued_requests.clear();
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        let idle =
+            self.queued_requests.is_empty() && self.opening == 0 && self.exchanges.is_empty();
+        if idle {
+            KeepAlive::Now
+        } else {
+            KeepAlive::Forever
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        for n in (0..self.exchanges.len()).rev() {
+            let event = match self.exchanges[n].poll() {
+                Ok(Async::Ready(response)) => RequestResponseHandlerEvent::Response(response),
+                Ok(Async::NotReady) => continue,
+                Err(err) => {
+                    debug!("Error during a request-response exchange: {}", err);
+                    RequestResponseHandlerEvent::Failed(err)
+                }
+            };
+            self.exchanges.remove(n);
+            self.pending_events.push_back(event);
+        }
+
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+        }
+
+        if let Some(request) = self.queued_requests.pop_front() {
+            self.opening += 1;
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade: self.upgrade.clone(),
+                info: request,
+                timeout: None,
+                priority: 0,
+            })));
+        }
+
+        if self.shutting_down && self.opening == 0 && self.exchanges.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl<TSubstream, TUpgrade, TRequest, TExchange, TFuture> fmt::Debug
+    for RequestResponseHandler<TSubstream, TUpgrade, TRequest, TExchange, TFuture>
+where
+    TUpgrade: fmt::Debug,
+    TFuture: Future,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("RequestResponseHandler")
+            .field("upgrade", &self.upgrade)
+            .field("queued_requests", &self.queued_requests.len())
+            .field("opening", &self.opening)
+            .field("exchanges", &self.exchanges.len())
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::io::Cursor;
+    use tokio_mock_task::MockTask;
+    use upgrade::PlainTextConfig;
+
+    #[test]
+    fn each_request_gets_its_own_substream() {
+        let mut handler = request_response_handler(
+            PlainTextConfig,
+            |_: Cursor<Vec<u8>>, request: u32| future::ok::<_, io::Error>(request * 2),
+        );
+        let mut task = MockTask::new();
+
+        handler.inject_event(1);
+        handler.inject_event(2);
+        let mut infos = Vec::new();
+        while let Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+            info,
+            ..
+        }))) = task.enter(|| handler.poll())
+        {
+            infos.push(info);
+        }
+        assert_eq!(infos, vec![1, 2]);
+        assert!(handler.connection_keep_alive().is_forever());
+
+        let endpoint = NodeHandlerEndpoint::Dialer(infos[0]);
+        assert!(handler.inject_fully_negotiated(Cursor::new(Vec::new()), endpoint).is_ok());
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(
+                RequestResponseHandlerEvent::Response(2),
+            )))) => (),
+            _ => panic!("expected the response"),
+        }
+
+        handler.inject_dial_upgrade_error(infos[1], ProtocolsHandlerUpgrErr::Timeout);
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(
+                RequestResponseHandlerEvent::Failed(ref err),
+            )))) if err.kind() == io::ErrorKind::TimedOut => (),
+            _ => panic!("expected the failure"),
+        }
+        assert!(!handler.connection_keep_alive().is_forever());
+
+        handler.shutdown();
+        match task.enter(|| handler.poll()) {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("expected the handler to finish"),
+        }
+    }
+}
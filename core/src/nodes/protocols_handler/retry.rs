@@ -0,0 +1,545 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::node_handler::is_no_protocol_found;
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{cmp, fmt, io, time::Duration};
+use tokio_timer::{clock, Delay};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that retries the outbound upgrades that failed.
+///
+/// When an outbound upgrade fails because of a timeout or of an error during the negotiation,
+/// the same request is produced again after `backoff`, until `max_attempts` attempts have been
+/// made. Only then is the error reported to the inner handler. Errors that can't be transient,
+/// such as the remote not supporting any of the protocols or the muxer being closed, are
+/// reported immediately.
+pub struct RetryOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Maximum number of attempts for each request, including the first one.
+    max_attempts: usize,
+    /// Time to wait before producing a failed request again.
+    backoff: Duration,
+    /// Requests that failed and are waiting for their backoff to elapse, with the error to report
+    /// if they end up not being retried.
+    retrying: Vec<(
+        Delay,
+        RetryOutboundInfo<TProtoHandler::Protocol, TProtoHandler::OutboundOpenInfo>,
+        ProtocolsHandlerUpgrErr,
+    )>,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+}
+
+/// Information passed along with the outbound substream requests of a `RetryOutbound`.
+/// Contains what is needed to produce the request again.
+pub struct RetryOutboundInfo<TUpgrade, TInfo> {
+    /// Copy of the upgrade.
+    upgrade: TUpgrade,
+    /// Timeout requested by the handler.
+    timeout: Option<Duration>,
+    /// Priority requested by the handler.
+    priority: u8,
+    /// Number of times the request has been produced.
+    attempts: usize,
+    /// Information produced by the inner handler.
+    info: TInfo,
+}
+
+impl<TUpgrade, TInfo> fmt::Debug for RetryOutboundInfo<TUpgrade, TInfo>
+where
+    TInfo: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("RetryOutboundInfo")
+            .field("info", &self.info)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl<TUpgrade, TInfo> RetryOutboundInfo<TUpgrade, TInfo> {
+    /// Returns the information produced by the inner handler.
+    #[inline]
+    pub fn info(&self) -> &TInfo {
+        &self.info
+    }
+
+    /// Returns the number of times the request has been produced.
+    #[inline]
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+}
+
+impl<TProtoHandler> RetryOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `RetryOutbound`. A `max_attempts` of 0 is treated as 1.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, max_attempts: usize, backoff: Duration) -> Self {
+        RetryOutbound {
+            inner,
+            max_attempts: cmp::max(max_attempts, 1),
+            backoff,
+            retrying: Vec::new(),
+            shutting_down: false,
+        }
+    }
+
+    /// Returns the number of failed requests that are waiting to be retried.
+    #[inline]
+    pub fn num_retrying(&self) -> usize {
+        self.retrying.len()
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for RetryOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("RetryOutbound")
+            .field("inner", &self.inner)
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("retrying", &self.retrying.len())
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for RetryOutbound<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::Protocol: Clone,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo =
+        RetryOutboundInfo<TProtoHandler::Protocol, TProtoHandler::OutboundOpenInfo>;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(&info.info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer(info) => NodeHandlerEndpoint::Dialer(info.info),
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        let endpoint = match endpoint {
+            NodeHandlerEndpoint::Dialer(info) => NodeHandlerEndpoint::Dialer(info.info),
+            NodeHandlerEndpoint::Listener => NodeHandlerEndpoint::Listener,
+        };
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.retrying.is_empty() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        let transient = match error {
+            ProtocolsHandlerUpgrErr::Timeout => true,
+            ProtocolsHandlerUpgrErr::MuxerClosed => false,
+            ProtocolsHandlerUpgrErr::Upgrade(ref err) => !is_no_protocol_found(err),
+        };
+
+        if !transient || self.shutting_down || info.attempts >= self.max_attempts {
+            self.inner.inject_dial_upgrade_error(info.info, error);
+            return;
+        }
+
+        debug!("Outbound upgrade failed after {} attempt(s); retrying", info.attempts);
+        let delay = Delay::new(clock::now() + self.backoff);
+        self.retrying.push((delay, info, error));
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    fn inject_outbound_closed(&mut self) {
+        // The requests waiting for their backoff would fail anyway.
+        self.inner.inject_outbound_closed();
+        for (_, info, _) in self.retrying.drain(..) {
+            self.inner.inject_dial_upgrade_error(info.info, ProtocolsHandlerUpgrErr::MuxerClosed);
+        }
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // The requests waiting for their backoff will never be retried. Report their last error.
+        self.shutting_down = true;
+        for (_, info, error) in self.retrying.drain(..) {
+            self.inner.inject_dial_upgrade_error(info.info, error);
+        }
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if !self.retrying.is_empty() {
+            KeepAlive::Forever
+        } else {
+            self.inner.connection_keep_alive()
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        for n in (0..self.retrying.len()).rev() {
+            // If the timer fails, we retry immediately rather than waiting forever.
+            let ready = match self.retrying[n].0.poll() {
+                Ok(Async::Ready(())) | Err(_) => true,
+                Ok(Async::NotReady) => false,
+            };
+            if !ready {
+                continue;
+            }
+
+            let (_, mut info, _) = self.retrying.remove(n);
+            info.attempts += 1;
+            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade: info.upgrade.clone(),
+                timeout: info.timeout,
+                priority: info.priority,
+                info,
+            })));
+        }
+
+        match try_ready!(self.inner.poll()) {
+            Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                upgrade,
+                info,
+                timeout,
+                priority,
+            }) => {
+                let info = RetryOutboundInfo {
+                    upgrade: upgrade.clone(),
+                    timeout,
+                    priority,
+                    attempts: 1,
+                    info,
+                };
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                })))
+            }
+            Some(ProtocolsHandlerEvent::Custom(event)) => {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))))
+            }
+            Some(ProtocolsHandlerEvent::CancelOutbound) => {
+                let inner = &self.inner;
+                self.retrying.retain(|&(_, ref info, _)| inner.should_open_outbound(&info.info));
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)))
+            }
+            Some(ProtocolsHandlerEvent::CloseOutbound) => {
+                // The requests waiting for their backoff are dropped like the ones of the wrapper.
+                self.retrying.clear();
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)))
+            }
+            Some(ProtocolsHandlerEvent::CloseInbound) => {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)))
+            }
+            Some(ProtocolsHandlerEvent::Close(err)) => {
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multistream_select::ProtocolChoiceError;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use tests::fake_time::FakeTime;
+    use tokio_mock_task::MockTask;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that requests an outbound substream for each event injected in it, and records
+    /// the requests that failed along with their error.
+    #[derive(Default)]
+    struct RequestingHandler {
+        requests: VecDeque<u32>,
+        failed: Vec<(u32, String)>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = u32;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.requests.push_back(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, info: u32, error: ProtocolsHandlerUpgrErr) {
+            self.failed.push((info, error.to_string()));
+        }
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            match self.requests.pop_front() {
+                Some(info) => Ok(Async::Ready(Some(
+                    ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                        upgrade: DeniedConnectionUpgrade,
+                        info,
+                        timeout: None,
+                        priority: 0,
+                    },
+                ))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    type TestInfo = RetryOutboundInfo<DeniedConnectionUpgrade, u32>;
+
+    /// A `RetryOutbound` with a backoff of 5 seconds, with the time under control of the test.
+    struct Retrying {
+        time: FakeTime,
+        task: MockTask,
+        handler: RetryOutbound<RequestingHandler>,
+    }
+
+    impl Retrying {
+        fn new(max_attempts: usize) -> Self {
+            let inner = RequestingHandler::default();
+            Retrying {
+                time: FakeTime::new(),
+                task: MockTask::new(),
+                handler: inner.retry_outbound(max_attempts, Duration::from_secs(5)),
+            }
+        }
+
+        /// Polls the handler and returns the outbound substream request it produces, if any.
+        fn poll(&mut self) -> Option<TestInfo> {
+            let (handler, task) = (&mut self.handler, &mut self.task);
+            match self.time.enter(|| task.enter(|| handler.poll())).unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    info, ..
+                })) => Some(info),
+                Async::NotReady => None,
+                _ => panic!("unexpected event"),
+            }
+        }
+
+        /// Makes the inner handler request a substream, and returns the request.
+        fn request(&mut self, request: u32) -> TestInfo {
+            self.handler.inject_event(request);
+            self.poll().expect("expected an outbound substream request")
+        }
+
+        fn fail(&mut self, info: TestInfo, error: ProtocolsHandlerUpgrErr) {
+            let handler = &mut self.handler;
+            self.time.enter(|| handler.inject_dial_upgrade_error(info, error));
+        }
+    }
+
+    fn handshake_error() -> ProtocolsHandlerUpgrErr {
+        ProtocolsHandlerUpgrErr::Upgrade(io::Error::new(io::ErrorKind::Other, "bad handshake"))
+    }
+
+    #[test]
+    fn transient_errors_are_retried_after_the_backoff() {
+        let mut retrying = Retrying::new(3);
+        let info = retrying.request(7);
+        assert_eq!(info.attempts(), 1);
+
+        retrying.fail(info, ProtocolsHandlerUpgrErr::Timeout);
+        assert_eq!(retrying.handler.num_retrying(), 1);
+        assert!(retrying.poll().is_none());
+        retrying.time.advance(Duration::from_secs(4));
+        assert!(!retrying.task.is_notified());
+
+        retrying.time.advance(Duration::from_secs(1));
+        assert!(retrying.task.is_notified());
+        let info = retrying.poll().expect("expected the request to be retried");
+        assert_eq!(*info.info(), 7);
+        assert_eq!(info.attempts(), 2);
+        assert!(retrying.handler.inner.failed.is_empty());
+    }
+
+    #[test]
+    fn error_is_reported_after_the_last_attempt() {
+        let mut retrying = Retrying::new(2);
+        let info = retrying.request(7);
+        retrying.fail(info, ProtocolsHandlerUpgrErr::Timeout);
+        retrying.time.advance(Duration::from_secs(5));
+        let info = retrying.poll().expect("expected the request to be retried");
+
+        retrying.fail(info, handshake_error());
+        assert_eq!(retrying.handler.num_retrying(), 0);
+        let expected = vec![(7, handshake_error().to_string())];
+        assert_eq!(retrying.handler.inner.failed, expected);
+    }
+
+    #[test]
+    fn permanent_errors_are_reported_immediately() {
+        let mut retrying = Retrying::new(3);
+        let info = retrying.request(1);
+        retrying.fail(info, ProtocolsHandlerUpgrErr::MuxerClosed);
+
+        let info = retrying.request(2);
+        let err = io::Error::new(io::ErrorKind::Other, ProtocolChoiceError::NoProtocolFound);
+        retrying.fail(info, ProtocolsHandlerUpgrErr::Upgrade(err));
+
+        assert_eq!(retrying.handler.num_retrying(), 0);
+        let failed = retrying.handler.inner.failed.iter().map(|f| f.0).collect::<Vec<_>>();
+        assert_eq!(failed, vec![1, 2]);
+    }
+
+    #[test]
+    fn shutdown_reports_the_last_error_of_the_pending_retries() {
+        let mut retrying = Retrying::new(3);
+        let info = retrying.request(7);
+        retrying.fail(info, handshake_error());
+        assert_eq!(retrying.handler.num_retrying(), 1);
+
+        retrying.handler.shutdown();
+        assert_eq!(retrying.handler.num_retrying(), 0);
+        let expected = vec![(7, handshake_error().to_string())];
+        assert_eq!(retrying.handler.inner.failed, expected);
+    }
+}
@@ -0,0 +1,793 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use either::EitherOutput;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    inject_negotiated, inject_protocol_name, KeepAlive, ListenProtocolVersion, MapInEvent,
+    MapOutEvent, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr, ShutdownPolicy,
+};
+use std::{cell::RefCell, cmp, io};
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{self, map::Map, toggleable::Toggleable, OrUpgrade};
+use void::Void;
+use {ConnectionUpgrade, Endpoint};
+
+/// Implementation of `ProtocolsHandler` that combines two protocols into one.
+///
+/// By default, as soon as one of the two handlers produces `Ready(None)`, `shutdown()` is called
+/// on the other one. This can be changed with `with_shutdown_policy`. In all cases, the combined
+/// handler only produces `Ready(None)` once both handlers are done.
+#[derive(Debug, Clone)]
+pub struct ProtocolsHandlerSelect<TProto1, TProto2> {
+    /// The first handler.
+    proto1: TProto1,
+    /// The second handler.
+    proto2: TProto2,
+    /// If true, `proto1` has produced `Ready(None)` and must not be polled again.
+    proto1_done: bool,
+    /// If true, `proto2` has produced `Ready(None)` and must not be polled again.
+    proto2_done: bool,
+    /// Which handler to poll first during the next call to `poll()`. Alternated at each call so
+    /// that neither handler can starve the other.
+    poll_proto2_first: bool,
+    /// How to handle the protocol names that both handlers accept on inbound substreams.
+    conflict_policy: ConflictPolicy,
+    /// With `ConflictPolicy::Error`, the conflicting names that have been logged last. Avoids
+    /// logging the same conflict every time the protocols are advertised.
+    reported_conflicts: RefCell<Vec<Bytes>>,
+    /// Whether to shut down a handler when the other one has finished.
+    shutdown_policy: ShutdownPolicy,
+    /// Combines the versions of the protocols of the two handlers.
+    listen_version: ListenProtocolVersion<(u64, u64)>,
+    /// Name passed to `inject_protocol_negotiated`, held until we know which handler the
+    /// substream belongs to.
+    negotiated_protocol: Option<(Bytes, Endpoint)>,
+}
+
+/// Implementation of `ProtocolsHandler` returned by `ProtocolsHandler::select_void`.
+///
+/// This is a `ProtocolsHandlerSelect` whose events are those of `TProto2` alone, since
+/// `TProto1` neither receives nor produces any.
+pub type ProtocolsHandlerSelectVoid<TProto1, TProto2> = MapInEvent<
+    MapOutEvent<
+        ProtocolsHandlerSelect<TProto1, TProto2>,
+        fn(EitherOutput<Void, <TProto2 as ProtocolsHandler>::OutEvent>)
+            -> <TProto2 as ProtocolsHandler>::OutEvent,
+    >,
+    <TProto2 as ProtocolsHandler>::InEvent,
+    fn(<TProto2 as ProtocolsHandler>::InEvent)
+        -> Option<EitherOutput<Void, <TProto2 as ProtocolsHandler>::InEvent>>,
+>;
+
+/// How a `ProtocolsHandlerSelect` handles the protocol names that both of its handlers accept on
+/// inbound substreams.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The substream goes to the first handler. This is the default.
+    First,
+    /// The substream goes to the second handler.
+    Last,
+    /// The substream goes to the first handler, but the conflict is logged when it is first
+    /// detected. Useful in order to detect unintended overlaps.
+    Error,
+}
+
+impl Default for ConflictPolicy {
+    #[inline]
+    fn default() -> Self {
+        ConflictPolicy::First
+    }
+}
+
+impl<TProto1, TProto2> ProtocolsHandlerSelect<TProto1, TProto2> {
+    /// Builds a `ProtocolsHandlerSelect`.
+    #[inline]
+    pub(crate) fn new(proto1: TProto1, proto2: TProto2) -> Self {
+        ProtocolsHandlerSelect {
+            proto1,
+            proto2,
+            proto1_done: false,
+            proto2_done: false,
+            poll_proto2_first: false,
+            conflict_policy: ConflictPolicy::default(),
+            reported_conflicts: RefCell::new(Vec::new()),
+            shutdown_policy: ShutdownPolicy::Any,
+            listen_version: ListenProtocolVersion::default(),
+            negotiated_protocol: None,
+        }
+    }
+
+    /// Sets how to handle the protocol names that both handlers accept on inbound substreams.
+    #[inline]
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Sets what happens to a handler when the other one has finished. The default is
+    /// `ShutdownPolicy::Any`.
+    #[inline]
+    pub fn with_shutdown_policy(mut self, policy: ShutdownPolicy) -> Self {
+        self.shutdown_policy = policy;
+        self
+    }
+
+    /// Remembers `conflicts` as the names that both handlers accept. Returns true if they differ
+    /// from the ones remembered before, in which case they should be reported.
+    fn remember_conflicts(&self, conflicts: &[Bytes]) -> bool {
+        let mut reported = self.reported_conflicts.borrow_mut();
+        if *reported == conflicts {
+            return false;
+        }
+        *reported = conflicts.to_vec();
+        true
+    }
+}
+
+impl<TSubstream, TProto1, TProto2, TProto1Out, TProto2Out>
+    ProtocolsHandlerSelect<TProto1, TProto2>
+where
+    TProto1: ProtocolsHandler<Substream = TSubstream>,
+    TProto2: ProtocolsHandler<Substream = TSubstream>,
+    TProto1::Protocol: ConnectionUpgrade<TSubstream, Output = TProto1Out>,
+    TProto2::Protocol: ConnectionUpgrade<TSubstream, Output = TProto2Out>,
+{
+    /// Dispatches a fully-negotiated substream to the handler it belongs to.
+    fn dispatch_negotiated(
+        &mut self,
+        protocol: EitherOutput<TProto1Out, TProto2Out>,
+        endpoint: NodeHandlerEndpoint<
+            EitherOutput<TProto1::OutboundOpenInfo, TProto2::OutboundOpenInfo>,
+        >,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), EitherOutput<TProto1Out, TProto2Out>> {
+        let negotiated = self.negotiated_protocol.take();
+        match (protocol, endpoint) {
+            (EitherOutput::First(protocol), NodeHandlerEndpoint::Dialer(info)) => match info {
+                EitherOutput::First(info) => {
+                    let endpoint = NodeHandlerEndpoint::Dialer(info);
+                    inject_protocol_name(&mut self.proto1, negotiated);
+                    inject_negotiated(&mut self.proto1, protocol, endpoint, timing)
+                        .map_err(EitherOutput::First)
+                }
+                EitherOutput::Second(_) => {
+                    panic!("wrong API usage: the protocol doesn't match the upgrade info")
+                }
+            },
+            (EitherOutput::Second(protocol), NodeHandlerEndpoint::Dialer(info)) => match info {
+                EitherOutput::Second(info) => {
+                    let endpoint = NodeHandlerEndpoint::Dialer(info);
+                    inject_protocol_name(&mut self.proto2, negotiated);
+                    inject_negotiated(&mut self.proto2, protocol, endpoint, timing)
+                        .map_err(EitherOutput::Second)
+                }
+                EitherOutput::First(_) => {
+                    panic!("wrong API usage: the protocol doesn't match the upgrade info")
+                }
+            },
+            (EitherOutput::First(protocol), NodeHandlerEndpoint::Listener) => {
+                let endpoint = NodeHandlerEndpoint::Listener;
+                inject_protocol_name(&mut self.proto1, negotiated);
+                inject_negotiated(&mut self.proto1, protocol, endpoint, timing)
+                    .map_err(EitherOutput::First)
+            }
+            (EitherOutput::Second(protocol), NodeHandlerEndpoint::Listener) => {
+                let endpoint = NodeHandlerEndpoint::Listener;
+                inject_protocol_name(&mut self.proto2, negotiated);
+                inject_negotiated(&mut self.proto2, protocol, endpoint, timing)
+                    .map_err(EitherOutput::Second)
+            }
+        }
+    }
+}
+
+/// Upgrade used by `ProtocolsHandlerSelect`. Dispatches between the upgrades of the two handlers
+/// and wraps their output in an `EitherOutput`.
+pub type SelectUpgrade<TProto1, TProto2, TProto1Out, TProto2Out> = OrUpgrade<
+    ExcludeNames<Toggleable<Map<TProto1, fn(TProto1Out) -> EitherOutput<TProto1Out, TProto2Out>>>>,
+    Toggleable<Map<TProto2, fn(TProto2Out) -> EitherOutput<TProto1Out, TProto2Out>>>,
+>;
+
+/// Wraps around a `ConnectionUpgrade` and hides some of its protocol names. Used by
+/// `ProtocolsHandlerSelect` in order to apply its `ConflictPolicy`.
+#[derive(Debug, Clone)]
+pub struct ExcludeNames<TUpgrade> {
+    inner: TUpgrade,
+    excluded: Vec<Bytes>,
+}
+
+impl<TUpgrade> ExcludeNames<TUpgrade> {
+    /// Wraps around `inner` without hiding any name.
+    #[inline]
+    fn none(inner: TUpgrade) -> Self {
+        ExcludeNames {
+            inner,
+            excluded: Vec::new(),
+        }
+    }
+}
+
+impl<C, TUpgrade> ConnectionUpgrade<C> for ExcludeNames<TUpgrade>
+where
+    C: AsyncRead + AsyncWrite,
+    TUpgrade: ConnectionUpgrade<C>,
+{
+    type NamesIter = ExcludeNamesIter<TUpgrade::NamesIter>;
+    type UpgradeIdentifier = TUpgrade::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        ExcludeNamesIter {
+            inner: self.inner.protocol_names(),
+            excluded: self.excluded.clone(),
+        }
+    }
+
+    type Output = TUpgrade::Output;
+    type Future = TUpgrade::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: upgrade::Endpoint,
+    ) -> Self::Future {
+        self.inner.upgrade(socket, id, ty)
+    }
+}
+
+/// Iterator over the protocol names of an `ExcludeNames`.
+#[derive(Debug, Clone)]
+pub struct ExcludeNamesIter<TIter> {
+    inner: TIter,
+    excluded: Vec<Bytes>,
+}
+
+impl<TIter, TId> Iterator for ExcludeNamesIter<TIter>
+where
+    TIter: Iterator<Item = (Bytes, TId)>,
+{
+    type Item = (Bytes, TId);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let excluded = &self.excluded;
+        self.inner.find(|(name, _)| !excluded.contains(name))
+    }
+}
+
+impl<TSubstream, TProto1, TProto2, TProto1Out, TProto2Out>
+    ProtocolsHandler for ProtocolsHandlerSelect<TProto1, TProto2>
+where
+    TProto1: ProtocolsHandler<Substream = TSubstream>,
+    TProto2: ProtocolsHandler<Substream = TSubstream>,
+    TSubstream: AsyncRead + AsyncWrite,
+    TProto1::Protocol: ConnectionUpgrade<TSubstream, Output = TProto1Out>,
+    TProto2::Protocol: ConnectionUpgrade<TSubstream, Output = TProto2Out>,
+    TProto1Out: Send + 'static,
+    TProto2Out: Send + 'static,
+    <TProto1::Protocol as ConnectionUpgrade<TSubstream>>::Future: Send + 'static,
+    <TProto2::Protocol as ConnectionUpgrade<TSubstream>>::Future: Send + 'static,
+{
+    type InEvent = EitherOutput<TProto1::InEvent, TProto2::InEvent>;
+    type OutEvent = EitherOutput<TProto1::OutEvent, TProto2::OutEvent>;
+    type Substream = TSubstream;
+    type Protocol = SelectUpgrade<TProto1::Protocol, TProto2::Protocol, TProto1Out, TProto2Out>;
+    type OutboundOpenInfo = EitherOutput<TProto1::OutboundOpenInfo, TProto2::OutboundOpenInfo>;
+
+    fn listen_protocol(&self) -> Self::Protocol {
+        let proto1 = upgrade::map(self.proto1.listen_protocol(), EitherOutput::First as fn(_) -> _);
+        let proto2 = upgrade::map(self.proto2.listen_protocol(), EitherOutput::Second as fn(_) -> _);
+        let mut proto1 = ExcludeNames::none(upgrade::toggleable(proto1));
+
+        if self.conflict_policy != ConflictPolicy::First {
+            let names2 = proto2.protocol_names().map(|(name, _)| name).collect::<Vec<_>>();
+            let conflicts = proto1
+                .protocol_names()
+                .map(|(name, _)| name)
+                .filter(|name| names2.contains(name))
+                .collect::<Vec<_>>();
+            if self.conflict_policy == ConflictPolicy::Last {
+                proto1.excluded = conflicts;
+            } else if self.remember_conflicts(&conflicts) && !conflicts.is_empty() {
+                error!("Both handlers of a ProtocolsHandlerSelect accept the protocols {:?}; \
+                        the first one takes precedence", conflicts);
+            }
+        }
+
+        upgrade::or(proto1, upgrade::toggleable(proto2))
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        let proto1 = self.proto1.listen_protocol_version()?;
+        let proto2 = self.proto2.listen_protocol_version()?;
+        Some(self.listen_version.get((proto1, proto2)))
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        // We don't know which of the two handlers the substream is for, so accept it if either
+        // of them would.
+        (!self.proto1_done && self.proto1.should_accept_inbound())
+            || (!self.proto2_done && self.proto2.should_accept_inbound())
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        match *info {
+            EitherOutput::First(ref info) => self.proto1.should_open_outbound(info),
+            EitherOutput::Second(ref info) => self.proto2.should_open_outbound(info),
+        }
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, None)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
+        self.dispatch_negotiated(protocol, endpoint, Some(timing))
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match event {
+            EitherOutput::First(event) => self.proto1.inject_event(event),
+            EitherOutput::Second(event) => self.proto2.inject_event(event),
+        }
+    }
+
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        let mut events1 = Vec::new();
+        let mut events2 = Vec::new();
+        for event in events {
+            match event {
+                EitherOutput::First(event) => events1.push(event),
+                EitherOutput::Second(event) => events2.push(event),
+            }
+        }
+        if !events1.is_empty() {
+            self.proto1.inject_events(events1);
+        }
+        if !events2.is_empty() {
+            self.proto2.inject_events(events2);
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        // We don't know which of the two handlers the next event is for.
+        self.proto1.ready_for_event() && self.proto2.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.proto1.poll_ready_event());
+        self.proto2.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.proto1.is_idle() && self.proto2.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.proto1.inject_connection_info(info.clone());
+        self.proto2.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        match info {
+            EitherOutput::First(info) => self.proto1.inject_dial_upgrade_error(info, error),
+            EitherOutput::Second(info) => self.proto2.inject_dial_upgrade_error(info, error),
+        }
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        // We have no way to know which of the two handlers the remote wanted to talk to.
+        let error2 = io::Error::new(error.kind(), error.to_string());
+        self.proto1.inject_listen_upgrade_error(error);
+        self.proto2.inject_listen_upgrade_error(error2);
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.negotiated_protocol = Some((name.clone(), endpoint));
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.proto1.inject_inbound_closed();
+        self.proto2.inject_inbound_closed();
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.proto1.inject_outbound_closed();
+        self.proto2.inject_outbound_closed();
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.proto1.inject_outbound_ready(ready);
+        self.proto2.inject_outbound_ready(ready);
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        if !self.proto1_done {
+            self.proto1.shutdown();
+        }
+        if !self.proto2_done {
+            self.proto2.shutdown();
+        }
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        match (self.proto1_done, self.proto2_done) {
+            (false, false) => {
+                cmp::max(self.proto1.connection_keep_alive(), self.proto2.connection_keep_alive())
+            }
+            (false, true) => self.proto1.connection_keep_alive(),
+            (true, false) => self.proto2.connection_keep_alive(),
+            (true, true) => KeepAlive::Now,
+        }
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        loop {
+            let done_before = (self.proto1_done, self.proto2_done);
+            self.poll_proto2_first = !self.poll_proto2_first;
+
+            for &poll_proto2 in &[self.poll_proto2_first, !self.poll_proto2_first] {
+                if !poll_proto2 && !self.proto1_done {
+                    match self.proto1.poll()? {
+                        Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))) => {
+                            let event = ProtocolsHandlerEvent::Custom(EitherOutput::First(event));
+                            return Ok(Async::Ready(Some(event)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                            upgrade,
+                            info,
+                            timeout,
+                            priority,
+                        })) => {
+                            let proto1 = upgrade::map::<_, fn(_) -> _>(
+                                upgrade,
+                                EitherOutput::First,
+                            );
+                            let mut proto2 = upgrade::toggleable(upgrade::map::<_, fn(_) -> _>(
+                                self.proto2.listen_protocol(),
+                                EitherOutput::Second,
+                            ));
+                            proto2.disable();
+                            let event = ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                                upgrade: upgrade::or(
+                                    ExcludeNames::none(upgrade::toggleable(proto1)),
+                                    proto2,
+                                ),
+                                info: EitherOutput::First(info),
+                                timeout,
+                                priority,
+                            };
+                            return Ok(Async::Ready(Some(event)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::Close(err))) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))));
+                        }
+                        Async::Ready(None) => {
+                            self.proto1_done = true;
+                            if !self.proto2_done && self.shutdown_policy == ShutdownPolicy::Any {
+                                self.proto2.shutdown();
+                            }
+                        }
+                        Async::NotReady => (),
+                    }
+                }
+
+                if poll_proto2 && !self.proto2_done {
+                    match self.proto2.poll()? {
+                        Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))) => {
+                            let event = ProtocolsHandlerEvent::Custom(EitherOutput::Second(event));
+                            return Ok(Async::Ready(Some(event)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                            upgrade,
+                            info,
+                            timeout,
+                            priority,
+                        })) => {
+                            let mut proto1 = upgrade::toggleable(upgrade::map::<_, fn(_) -> _>(
+                                self.proto1.listen_protocol(),
+                                EitherOutput::First,
+                            ));
+                            proto1.disable();
+                            let proto2 = upgrade::map::<_, fn(_) -> _>(
+                                upgrade,
+                                EitherOutput::Second,
+                            );
+                            let event = ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                                upgrade: upgrade::or(
+                                    ExcludeNames::none(proto1),
+                                    upgrade::toggleable(proto2),
+                                ),
+                                info: EitherOutput::Second(info),
+                                timeout,
+                                priority,
+                            };
+                            return Ok(Async::Ready(Some(event)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseInbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                        }
+                        Async::Ready(Some(ProtocolsHandlerEvent::Close(err))) => {
+                            return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Close(err))));
+                        }
+                        Async::Ready(None) => {
+                            self.proto2_done = true;
+                            if !self.proto1_done && self.shutdown_policy == ShutdownPolicy::Any {
+                                self.proto1.shutdown();
+                            }
+                        }
+                        Async::NotReady => (),
+                    }
+                }
+            }
+
+            if self.proto1_done && self.proto2_done {
+                return Ok(Async::Ready(None));
+            }
+
+            // If one of the handlers has just finished, the other one may have been shut down and
+            // needs to be polled again in order to observe the consequences of `shutdown()`.
+            if done_before == (self.proto1_done, self.proto2_done) {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use nodes::protocols_handler::DummyProtocolsHandler;
+    use std::io::Cursor;
+    use std::iter;
+    use tests::test_protocols_handler::{Call, TestProtocolsHandler};
+    use upgrade::choice::EitherUpgradeIdentifier;
+
+    type Substream = Cursor<Vec<u8>>;
+
+    /// Upgrade that advertises `/foo/1.0.0`.
+    #[derive(Debug, Clone)]
+    struct FooUpgrade;
+
+    impl<C> ConnectionUpgrade<C> for FooUpgrade
+    where
+        C: AsyncRead + AsyncWrite,
+    {
+        type NamesIter = iter::Once<(Bytes, ())>;
+        type UpgradeIdentifier = ();
+
+        fn protocol_names(&self) -> Self::NamesIter {
+            iter::once((Bytes::from("/foo/1.0.0"), ()))
+        }
+
+        type Output = ();
+        type Future = future::FutureResult<(), io::Error>;
+
+        fn upgrade(self, _: C, _: (), _: upgrade::Endpoint) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    /// Returns which of the two handlers each advertised name goes to.
+    fn advertised<TProto1, TProto2>(
+        handler: &ProtocolsHandlerSelect<TProto1, TProto2>,
+    ) -> Vec<(Bytes, bool)>
+    where
+        ProtocolsHandlerSelect<TProto1, TProto2>: ProtocolsHandler<Substream = Substream>,
+        <ProtocolsHandlerSelect<TProto1, TProto2> as ProtocolsHandler>::Protocol:
+            ConnectionUpgrade<Substream, UpgradeIdentifier = EitherUpgradeIdentifier<(), ()>>,
+    {
+        handler
+            .listen_protocol()
+            .protocol_names()
+            .map(|(name, id)| match id {
+                EitherUpgradeIdentifier::First(()) => (name, false),
+                EitherUpgradeIdentifier::Second(()) => (name, true),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn conflict_policy_decides_which_handler_wins() {
+        let foo = || {
+            DummyProtocolsHandler::<Substream>::default().map_protocol(|_| FooUpgrade, |()| ())
+        };
+        let name = Bytes::from("/foo/1.0.0");
+
+        let first = foo().select(foo());
+        assert_eq!(advertised(&first), vec![(name.clone(), false), (name.clone(), true)]);
+
+        let last = foo().select(foo()).with_conflict_policy(ConflictPolicy::Last);
+        assert_eq!(advertised(&last), vec![(name.clone(), true)]);
+
+        let error = foo().select(foo()).with_conflict_policy(ConflictPolicy::Error);
+        assert_eq!(advertised(&error), vec![(name.clone(), false), (name, true)]);
+    }
+
+    #[test]
+    fn conflicts_are_reported_once() {
+        let foo = || {
+            DummyProtocolsHandler::<Substream>::default().map_protocol(|_| FooUpgrade, |()| ())
+        };
+        let handler = foo().select(foo()).with_conflict_policy(ConflictPolicy::Error);
+        let name = Bytes::from("/foo/1.0.0");
+
+        handler.listen_protocol();
+        assert_eq!(*handler.reported_conflicts.borrow(), vec![name.clone()]);
+        // The conflict has already been reported by `listen_protocol`.
+        assert!(!handler.remember_conflicts(&[name.clone()]));
+
+        // Once the conflict changes, even if it disappears, the next one is reported again.
+        assert!(handler.remember_conflicts(&[]));
+        assert!(handler.remember_conflicts(&[name]));
+    }
+
+    fn is_finished<T>(poll: Async<Option<T>>) -> bool {
+        match poll {
+            Async::Ready(None) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn shutdown_policy_all_keeps_the_other_handler_running() {
+        let closing = DummyProtocolsHandler::<Substream>::closing;
+        let active = DummyProtocolsHandler::<Substream>::default;
+
+        let mut any = closing().select(active());
+        assert!(is_finished(any.poll().unwrap()));
+
+        let mut all = closing().select(active()).with_shutdown_policy(ShutdownPolicy::All);
+        assert!(all.poll().unwrap().is_not_ready());
+        assert!(all.proto1_done);
+        assert!(!all.proto2_done);
+
+        all.shutdown();
+        assert!(is_finished(all.poll().unwrap()));
+    }
+
+    /// Handler that produces the events injected in it.
+    #[derive(Default)]
+    struct EchoHandler {
+        events: Vec<u32>,
+    }
+
+    impl ProtocolsHandler for EchoHandler {
+        type InEvent = u32;
+        type OutEvent = u32;
+        type Substream = Substream;
+        type Protocol = FooUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            FooUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.events.push(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(&mut self) -> Poll<Option<ProtocolsHandlerEvent<FooUpgrade, (), u32>>, io::Error> {
+            match self.events.pop() {
+                Some(event) => Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event)))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn select_void_uses_the_events_of_the_other_handler() {
+        let mut handler = DummyProtocolsHandler::<Substream>::default()
+            .select_void(EchoHandler::default());
+        handler.inject_event(5);
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Custom(5))) => (),
+            _ => panic!("expected the event to be echoed"),
+        }
+    }
+
+    #[test]
+    fn negotiated_protocol_goes_to_the_handler_of_the_substream() {
+        let echo = || TestProtocolsHandler::new(EchoHandler::default());
+        let mut handler = echo().select(echo());
+        let name = Bytes::from("/foo/1.0.0");
+        handler.inject_protocol_negotiated(&name, Endpoint::Listener);
+        let protocol = EitherOutput::Second(());
+        assert!(handler.inject_fully_negotiated(protocol, NodeHandlerEndpoint::Listener).is_ok());
+
+        let negotiated = Call::ProtocolNegotiated(name, Endpoint::Listener);
+        assert!(!handler.proto1.calls().contains(&negotiated));
+        assert!(handler.proto2.calls().contains(&negotiated));
+    }
+}
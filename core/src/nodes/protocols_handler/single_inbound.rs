@@ -0,0 +1,324 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    inject_negotiated, KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{fmt, io};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that only lets one inbound substream be open at a time.
+///
+/// The protocols of the handler are still advertised, as recommended by `listen_protocol()`,
+/// but the inbound substreams opened while one is active are closed. An inbound substream
+/// becomes active when it is injected in the handler with `inject_fully_negotiated`.
+///
+/// Since the `NodeHandlerWrapper` isn't notified when a substream is closed, the handler has to
+/// report it by producing an event for which `is_closed` returns true. The event is still
+/// passed to the outside afterwards.
+pub struct SingleInbound<TProtoHandler, TIsClosed> {
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// Indicates whether an event produced by the handler signals that the inbound substream
+    /// has been closed.
+    is_closed: TIsClosed,
+    /// If true, an inbound substream is currently open.
+    active: bool,
+}
+
+impl<TProtoHandler, TIsClosed> SingleInbound<TProtoHandler, TIsClosed> {
+    /// Creates a `SingleInbound`.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, is_closed: TIsClosed) -> Self {
+        SingleInbound {
+            inner,
+            is_closed,
+            active: false,
+        }
+    }
+
+    /// Returns true if an inbound substream is currently open.
+    #[inline]
+    pub fn is_inbound_active(&self) -> bool {
+        self.active
+    }
+}
+
+impl<TProtoHandler, TIsClosed, TOutput> SingleInbound<TProtoHandler, TIsClosed>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::Protocol: ConnectionUpgrade<TProtoHandler::Substream, Output = TOutput>,
+{
+    /// Injects a negotiated substream in the handler, unless it is an inbound substream and one
+    /// is already active.
+    fn inject_negotiated(
+        &mut self,
+        protocol: TOutput,
+        endpoint: NodeHandlerEndpoint<TProtoHandler::OutboundOpenInfo>,
+        timing: Option<NegotiationTiming>,
+    ) -> Result<(), TOutput> {
+        let is_inbound = !endpoint.is_dialer();
+        if is_inbound && self.active {
+            debug!("Closing an inbound substream while another one is active");
+            return Err(protocol);
+        }
+        inject_negotiated(&mut self.inner, protocol, endpoint, timing)?;
+        if is_inbound {
+            self.active = true;
+        }
+        Ok(())
+    }
+}
+
+impl<TProtoHandler, TIsClosed> fmt::Debug for SingleInbound<TProtoHandler, TIsClosed>
+where
+    TProtoHandler: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("SingleInbound")
+            .field("inner", &self.inner)
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl<TProtoHandler, TIsClosed> ProtocolsHandler for SingleInbound<TProtoHandler, TIsClosed>
+where
+    TProtoHandler: ProtocolsHandler,
+    TIsClosed: FnMut(&TProtoHandler::OutEvent) -> bool,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        !self.active && self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inject_negotiated(protocol, endpoint, None)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inject_negotiated(protocol, endpoint, Some(timing))
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    #[inline]
+    fn inject_events<TIter>(&mut self, events: TIter)
+    where
+        TIter: IntoIterator<Item = Self::InEvent>,
+    {
+        self.inner.inject_events(events)
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_closed(&mut self) {
+        self.inner.inject_outbound_closed()
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        let event = self.inner.poll()?;
+        if let Async::Ready(Some(ProtocolsHandlerEvent::Custom(ref event))) = event {
+            if self.active && (self.is_closed)(event) {
+                debug!("Inbound substream closed; accepting a new one");
+                self.active = false;
+            }
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that reports the closure of its inbound substream when it receives an event.
+    #[derive(Default)]
+    struct ClosingHandler {
+        closed: bool,
+    }
+
+    impl ProtocolsHandler for ClosingHandler {
+        type InEvent = ();
+        type OutEvent = &'static str;
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, _: ()) {
+            self.closed = true;
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: (), _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<
+            Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, (), &'static str>>,
+            io::Error,
+        > {
+            if self.closed {
+                self.closed = false;
+                Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom("closed"))))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn only_one_inbound_substream_at_a_time() {
+        let mut handler = ClosingHandler::default().single_inbound(|event| *event == "closed");
+        assert!(handler.should_accept_inbound());
+        assert!(handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).is_ok());
+        assert!(!handler.should_accept_inbound());
+        assert!(handler.inject_fully_negotiated((), NodeHandlerEndpoint::Listener).is_err());
+        // Outbound substreams aren't affected.
+        assert!(handler.inject_fully_negotiated((), NodeHandlerEndpoint::Dialer(())).is_ok());
+
+        handler.inject_event(());
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Custom("closed"))) => (),
+            _ => panic!("expected the closing event"),
+        }
+        assert!(handler.should_accept_inbound());
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, ListenProtocolVersion, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::{collections::VecDeque, fmt, io, time::Duration};
+use {ConnectionUpgrade, Endpoint};
+
+/// Wrapper around a protocol handler that can be disabled and re-enabled at runtime, without
+/// destroying the handler and its state.
+///
+/// While disabled, no protocol is accepted on inbound substreams, and the outbound substream
+/// requests of the handler are buffered. They are produced once the handler is re-enabled.
+/// Negotiations that were already in progress when the handler got disabled still complete.
+pub struct Toggle<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// The underlying handler.
+    inner: TProtoHandler,
+    /// If false, the handler is disabled.
+    enabled: bool,
+    /// Outbound substream requests produced by the handler while disabled.
+    queue: VecDeque<(
+        TProtoHandler::Protocol,
+        TProtoHandler::OutboundOpenInfo,
+        Option<Duration>,
+        u8,
+    )>,
+    /// If true, `shutdown()` has been called.
+    shutting_down: bool,
+    /// Combines the version of the protocol of the handler with `enabled`.
+    listen_version: ListenProtocolVersion<(u64, bool)>,
+}
+
+/// Event that can be injected in a `Toggle`.
+#[derive(Debug, Clone)]
+pub enum ToggleEvent<TInEvent> {
+    /// Enables the handler, if it was disabled.
+    Enable,
+    /// Disables the handler, if it was enabled.
+    Disable,
+    /// Event for the underlying handler.
+    Inner(TInEvent),
+}
+
+impl<TProtoHandler> Toggle<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    /// Creates a `Toggle`, enabled or disabled.
+    #[inline]
+    pub(crate) fn new(inner: TProtoHandler, enabled: bool) -> Self {
+        Toggle {
+            inner,
+            enabled,
+            queue: VecDeque::new(),
+            shutting_down: false,
+            listen_version: ListenProtocolVersion::default(),
+        }
+    }
+
+    /// Returns true if the handler is enabled.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the number of outbound substream requests buffered while the handler is disabled.
+    #[inline]
+    pub fn queued_requests(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Reports an error for all the buffered outbound substream requests.
+    fn drain_queue<F>(&mut self, mut error: F)
+    where
+        F: FnMut() -> ProtocolsHandlerUpgrErr,
+    {
+        for (_, info, _, _) in self.queue.drain(..) {
+            self.inner.inject_dial_upgrade_error(info, error());
+        }
+    }
+}
+
+impl<TProtoHandler> fmt::Debug for Toggle<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Toggle")
+            .field("inner", &self.inner)
+            .field("enabled", &self.enabled)
+            .field("queued_requests", &self.queue.len())
+            .field("shutting_down", &self.shutting_down)
+            .finish()
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for Toggle<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    type InEvent = ToggleEvent<TProtoHandler::InEvent>;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    /// `None` while the handler is disabled, which doesn't advertise any protocol.
+    type Protocol = Option<TProtoHandler::Protocol>;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+    const SINGLE_PROTOCOL: bool = TProtoHandler::SINGLE_PROTOCOL;
+
+    #[inline]
+    fn listen_protocol(&self) -> Self::Protocol {
+        if self.enabled {
+            Some(self.inner.listen_protocol())
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn listen_protocol_version(&self) -> Option<u64> {
+        let inner = self.inner.listen_protocol_version()?;
+        Some(self.listen_version.get((inner, self.enabled)))
+    }
+
+    #[inline]
+    fn should_accept_inbound(&self) -> bool {
+        self.enabled && self.inner.should_accept_inbound()
+    }
+
+    #[inline]
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    #[inline]
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    #[inline]
+    fn inject_event(&mut self, event: Self::InEvent) {
+        match event {
+            ToggleEvent::Enable => self.enabled = true,
+            ToggleEvent::Disable => self.enabled = false,
+            ToggleEvent::Inner(event) => self.inner.inject_event(event),
+        }
+    }
+
+    #[inline]
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    #[inline]
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.inner.is_idle()
+    }
+
+    #[inline]
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    #[inline]
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    #[inline]
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    #[inline]
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    #[inline]
+    fn inject_inbound_closed(&mut self) {
+        self.inner.inject_inbound_closed()
+    }
+
+    fn inject_outbound_closed(&mut self) {
+        // The requests that are still buffered would fail anyway.
+        self.inner.inject_outbound_closed();
+        self.drain_queue(|| ProtocolsHandlerUpgrErr::MuxerClosed);
+    }
+
+    #[inline]
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        // The requests that are still buffered will never be reported. Let the handler know.
+        self.shutting_down = true;
+        self.drain_queue(|| {
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "handler shutting down");
+            ProtocolsHandlerUpgrErr::Upgrade(err)
+        });
+        self.inner.shutdown()
+    }
+
+    #[inline]
+    fn connection_keep_alive(&self) -> KeepAlive {
+        // A disabled handler is kept around in order to be re-enabled later, so its buffered
+        // requests aren't a reason to keep the connection alive.
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        if self.enabled {
+            if let Some((upgrade, info, timeout, priority)) = self.queue.pop_front() {
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: Some(upgrade),
+                    info,
+                    timeout,
+                    priority,
+                })));
+            }
+        }
+
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    timeout,
+                    priority,
+                }) => {
+                    if self.shutting_down {
+                        let err = io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "handler shutting down",
+                        );
+                        let err = ProtocolsHandlerUpgrErr::Upgrade(err);
+                        self.inner.inject_dial_upgrade_error(info, err);
+                    } else if self.enabled {
+                        return Ok(Async::Ready(Some(
+                            ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                                upgrade: Some(upgrade),
+                                info,
+                                timeout,
+                                priority,
+                            },
+                        )));
+                    } else {
+                        self.queue.push_back((upgrade, info, timeout, priority));
+                    }
+                }
+                Some(ProtocolsHandlerEvent::CancelOutbound) => {
+                    let inner = &self.inner;
+                    self.queue.retain(|&(_, ref info, _, _)| inner.should_open_outbound(info));
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CancelOutbound)));
+                }
+                Some(ProtocolsHandlerEvent::CloseOutbound) => {
+                    // The buffered requests are dropped like the ones of the wrapper.
+                    self.queue.clear();
+                    return Ok(Async::Ready(Some(ProtocolsHandlerEvent::CloseOutbound)));
+                }
+                event => return Ok(Async::Ready(event.map(|event| event.map_protocol(Some)))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use upgrade::DeniedConnectionUpgrade;
+
+    /// Handler that requests an outbound substream for each event injected in it, and records
+    /// the requests that failed.
+    #[derive(Default)]
+    struct RequestingHandler {
+        requests: VecDeque<u32>,
+        failed: Vec<u32>,
+    }
+
+    impl ProtocolsHandler for RequestingHandler {
+        type InEvent = u32;
+        type OutEvent = ();
+        type Substream = Cursor<Vec<u8>>;
+        type Protocol = DeniedConnectionUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            DeniedConnectionUpgrade
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.requests.push_back(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, info: u32, _: ProtocolsHandlerUpgrErr) {
+            self.failed.push(info);
+        }
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<DeniedConnectionUpgrade, u32, ()>>, io::Error> {
+            match self.requests.pop_front() {
+                Some(info) => Ok(Async::Ready(Some(
+                    ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                        upgrade: DeniedConnectionUpgrade,
+                        info,
+                        timeout: None,
+                        priority: 0,
+                    },
+                ))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Polls the handler until it is no longer ready, and returns the information of the
+    /// requests it has produced.
+    fn requests(handler: &mut Toggle<RequestingHandler>) -> Vec<u32> {
+        let mut infos = Vec::new();
+        loop {
+            match handler.poll().unwrap() {
+                Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info,
+                    ..
+                })) => {
+                    assert!(upgrade.is_some());
+                    infos.push(info)
+                }
+                Async::NotReady => return infos,
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_handler_refuses_inbound_substreams() {
+        let mut handler = RequestingHandler::default().with_toggle(false);
+        assert!(handler.listen_protocol().is_none());
+        assert!(!handler.should_accept_inbound());
+
+        handler.inject_event(ToggleEvent::Enable);
+        assert!(handler.listen_protocol().is_some());
+        assert!(handler.should_accept_inbound());
+    }
+
+    #[test]
+    fn requests_are_released_in_order_once_enabled() {
+        let mut handler = RequestingHandler::default().with_toggle(false);
+        for request in 0..3 {
+            handler.inject_event(ToggleEvent::Inner(request));
+        }
+        assert!(requests(&mut handler).is_empty());
+        assert_eq!(handler.queued_requests(), 3);
+
+        handler.inject_event(ToggleEvent::Enable);
+        handler.inject_event(ToggleEvent::Inner(3));
+        assert_eq!(requests(&mut handler), vec![0, 1, 2, 3]);
+        assert!(handler.inner.failed.is_empty());
+    }
+
+    #[test]
+    fn shutdown_reports_the_queued_requests() {
+        let mut handler = RequestingHandler::default().with_toggle(false);
+        for request in 0..2 {
+            handler.inject_event(ToggleEvent::Inner(request));
+        }
+        assert!(requests(&mut handler).is_empty());
+
+        handler.shutdown();
+        assert_eq!(handler.queued_requests(), 0);
+        assert_eq!(handler.inner.failed, vec![0, 1]);
+
+        // Requests produced after the shutdown are reported as well, even once re-enabled.
+        handler.inject_event(ToggleEvent::Enable);
+        handler.inject_event(ToggleEvent::Inner(2));
+        assert!(requests(&mut handler).is_empty());
+        assert_eq!(handler.inner.failed, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Combines three or four handlers without nesting `EitherOutput`s in their events.
+//!
+//! Under the hood, the handlers are combined with `ProtocolsHandlerSelect`, and the nested
+//! `EitherOutput`s are flattened into a `TupleOutput3` or a `TupleOutput4`.
+
+use either::EitherOutput;
+use nodes::protocols_handler::{
+    MapInEvent, MapOutEvent, MapOutboundOpenInfo, ProtocolsHandler, ProtocolsHandlerSelect,
+};
+
+/// Builds a `ProtocolsHandler` that handles all the handlers of a tuple together.
+///
+/// The listening upgrades of the handlers are all advertised, with the same precedence as with
+/// `select`: when multiple handlers accept the same protocol, the first one wins. The events and
+/// the information of the outbound substream requests are tagged with the position of the
+/// handler in the tuple, with a `TupleOutput3` or a `TupleOutput4`.
+///
+/// Two handlers can be combined with `ProtocolsHandler::select`.
+///
+/// > **Note**: The information of the outbound substream requests of the handlers must implement
+/// >           `Clone`, because of `map_outbound_open_info`.
+#[inline]
+pub fn tuple_handler<TTuple>(handlers: TTuple) -> TTuple::Handler
+where
+    TTuple: ProtocolsHandlerTuple,
+{
+    handlers.into_handler()
+}
+
+/// Tuple of handlers that can be passed to `tuple_handler`.
+pub trait ProtocolsHandlerTuple {
+    /// The handler that combines the handlers of the tuple.
+    type Handler: ProtocolsHandler;
+
+    /// Combines the handlers of the tuple.
+    fn into_handler(self) -> Self::Handler;
+}
+
+/// Event or information that belongs to one of the three handlers of a `TupleHandler3`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TupleOutput3<A, B, C> {
+    First(A),
+    Second(B),
+    Third(C),
+}
+
+/// Event or information that belongs to one of the four handlers of a `TupleHandler4`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TupleOutput4<A, B, C, D> {
+    First(A),
+    Second(B),
+    Third(C),
+    Fourth(D),
+}
+
+/// What `ProtocolsHandlerSelect` produces when combining three handlers.
+type Nested3<A, B, C> = EitherOutput<EitherOutput<A, B>, C>;
+
+/// What `ProtocolsHandlerSelect` produces when combining four handlers.
+type Nested4<A, B, C, D> = EitherOutput<Nested3<A, B, C>, D>;
+
+impl<A, B, C> TupleOutput3<A, B, C> {
+    fn from_nested(nested: Nested3<A, B, C>) -> Self {
+        match nested {
+            EitherOutput::First(EitherOutput::First(a)) => TupleOutput3::First(a),
+            EitherOutput::First(EitherOutput::Second(b)) => TupleOutput3::Second(b),
+            EitherOutput::Second(c) => TupleOutput3::Third(c),
+        }
+    }
+
+    fn into_nested(self) -> Nested3<A, B, C> {
+        match self {
+            TupleOutput3::First(a) => EitherOutput::First(EitherOutput::First(a)),
+            TupleOutput3::Second(b) => EitherOutput::First(EitherOutput::Second(b)),
+            TupleOutput3::Third(c) => EitherOutput::Second(c),
+        }
+    }
+}
+
+impl<A, B, C, D> TupleOutput4<A, B, C, D> {
+    fn from_nested(nested: Nested4<A, B, C, D>) -> Self {
+        match nested {
+            EitherOutput::First(nested) => match TupleOutput3::from_nested(nested) {
+                TupleOutput3::First(a) => TupleOutput4::First(a),
+                TupleOutput3::Second(b) => TupleOutput4::Second(b),
+                TupleOutput3::Third(c) => TupleOutput4::Third(c),
+            },
+            EitherOutput::Second(d) => TupleOutput4::Fourth(d),
+        }
+    }
+
+    fn into_nested(self) -> Nested4<A, B, C, D> {
+        match self {
+            TupleOutput4::First(a) => EitherOutput::First(TupleOutput3::First(a).into_nested()),
+            TupleOutput4::Second(b) => EitherOutput::First(TupleOutput3::Second(b).into_nested()),
+            TupleOutput4::Third(c) => EitherOutput::First(TupleOutput3::Third(c).into_nested()),
+            TupleOutput4::Fourth(d) => EitherOutput::Second(d),
+        }
+    }
+}
+
+/// Implementation of `ProtocolsHandler` returned by `tuple_handler` for three handlers.
+pub type TupleHandler3<A, B, C> = MapOutboundOpenInfo<
+    MapInEvent<
+        MapOutEvent<
+            ProtocolsHandlerSelect<ProtocolsHandlerSelect<A, B>, C>,
+            fn(
+                Nested3<
+                    <A as ProtocolsHandler>::OutEvent,
+                    <B as ProtocolsHandler>::OutEvent,
+                    <C as ProtocolsHandler>::OutEvent,
+                >,
+            ) -> TupleOutput3<
+                <A as ProtocolsHandler>::OutEvent,
+                <B as ProtocolsHandler>::OutEvent,
+                <C as ProtocolsHandler>::OutEvent,
+            >,
+        >,
+        TupleOutput3<
+            <A as ProtocolsHandler>::InEvent,
+            <B as ProtocolsHandler>::InEvent,
+            <C as ProtocolsHandler>::InEvent,
+        >,
+        fn(
+            TupleOutput3<
+                <A as ProtocolsHandler>::InEvent,
+                <B as ProtocolsHandler>::InEvent,
+                <C as ProtocolsHandler>::InEvent,
+            >,
+        ) -> Option<
+            Nested3<
+                <A as ProtocolsHandler>::InEvent,
+                <B as ProtocolsHandler>::InEvent,
+                <C as ProtocolsHandler>::InEvent,
+            >,
+        >,
+    >,
+    TupleOutput3<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+    fn(
+        Nested3<
+            <A as ProtocolsHandler>::OutboundOpenInfo,
+            <B as ProtocolsHandler>::OutboundOpenInfo,
+            <C as ProtocolsHandler>::OutboundOpenInfo,
+        >,
+    ) -> TupleOutput3<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+    fn(
+        TupleOutput3<
+            <A as ProtocolsHandler>::OutboundOpenInfo,
+            <B as ProtocolsHandler>::OutboundOpenInfo,
+            <C as ProtocolsHandler>::OutboundOpenInfo,
+        >,
+    ) -> Nested3<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+>;
+
+/// Implementation of `ProtocolsHandler` returned by `tuple_handler` for four handlers.
+pub type TupleHandler4<A, B, C, D> = MapOutboundOpenInfo<
+    MapInEvent<
+        MapOutEvent<
+            ProtocolsHandlerSelect<ProtocolsHandlerSelect<ProtocolsHandlerSelect<A, B>, C>, D>,
+            fn(
+                Nested4<
+                    <A as ProtocolsHandler>::OutEvent,
+                    <B as ProtocolsHandler>::OutEvent,
+                    <C as ProtocolsHandler>::OutEvent,
+                    <D as ProtocolsHandler>::OutEvent,
+                >,
+            ) -> TupleOutput4<
+                <A as ProtocolsHandler>::OutEvent,
+                <B as ProtocolsHandler>::OutEvent,
+                <C as ProtocolsHandler>::OutEvent,
+                <D as ProtocolsHandler>::OutEvent,
+            >,
+        >,
+        TupleOutput4<
+            <A as ProtocolsHandler>::InEvent,
+            <B as ProtocolsHandler>::InEvent,
+            <C as ProtocolsHandler>::InEvent,
+            <D as ProtocolsHandler>::InEvent,
+        >,
+        fn(
+            TupleOutput4<
+                <A as ProtocolsHandler>::InEvent,
+                <B as ProtocolsHandler>::InEvent,
+                <C as ProtocolsHandler>::InEvent,
+                <D as ProtocolsHandler>::InEvent,
+            >,
+        ) -> Option<
+            Nested4<
+                <A as ProtocolsHandler>::InEvent,
+                <B as ProtocolsHandler>::InEvent,
+                <C as ProtocolsHandler>::InEvent,
+                <D as ProtocolsHandler>::InEvent,
+            >,
+        >,
+    >,
+    TupleOutput4<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+        <D as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+    fn(
+        Nested4<
+            <A as ProtocolsHandler>::OutboundOpenInfo,
+            <B as ProtocolsHandler>::OutboundOpenInfo,
+            <C as ProtocolsHandler>::OutboundOpenInfo,
+            <D as ProtocolsHandler>::OutboundOpenInfo,
+        >,
+    ) -> TupleOutput4<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+        <D as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+    fn(
+        TupleOutput4<
+            <A as ProtocolsHandler>::OutboundOpenInfo,
+            <B as ProtocolsHandler>::OutboundOpenInfo,
+            <C as ProtocolsHandler>::OutboundOpenInfo,
+            <D as ProtocolsHandler>::OutboundOpenInfo,
+        >,
+    ) -> Nested4<
+        <A as ProtocolsHandler>::OutboundOpenInfo,
+        <B as ProtocolsHandler>::OutboundOpenInfo,
+        <C as ProtocolsHandler>::OutboundOpenInfo,
+        <D as ProtocolsHandler>::OutboundOpenInfo,
+    >,
+>;
+
+impl<A, B, C> ProtocolsHandlerTuple for (A, B, C)
+where
+    A: ProtocolsHandler,
+    B: ProtocolsHandler,
+    C: ProtocolsHandler,
+    TupleHandler3<A, B, C>: ProtocolsHandler,
+{
+    type Handler = TupleHandler3<A, B, C>;
+
+    fn into_handler(self) -> Self::Handler {
+        let (a, b, c) = self;
+        let select = ProtocolsHandlerSelect::new(ProtocolsHandlerSelect::new(a, b), c);
+        let map_out: fn(_) -> _ = TupleOutput3::from_nested;
+        let map_in: fn(_) -> _ = |event: TupleOutput3<_, _, _>| Some(event.into_nested());
+        let map_info: fn(_) -> _ = TupleOutput3::from_nested;
+        let unmap_info: fn(_) -> _ = TupleOutput3::into_nested;
+        let events = MapInEvent::new(MapOutEvent::new(select, map_out), map_in);
+        MapOutboundOpenInfo::new(events, map_info, unmap_info)
+    }
+}
+
+impl<A, B, C, D> ProtocolsHandlerTuple for (A, B, C, D)
+where
+    A: ProtocolsHandler,
+    B: ProtocolsHandler,
+    C: ProtocolsHandler,
+    D: ProtocolsHandler,
+    TupleHandler4<A, B, C, D>: ProtocolsHandler,
+{
+    type Handler = TupleHandler4<A, B, C, D>;
+
+    fn into_handler(self) -> Self::Handler {
+        let (a, b, c, d) = self;
+        let select = ProtocolsHandlerSelect::new(ProtocolsHandlerSelect::new(a, b), c);
+        let select = ProtocolsHandlerSelect::new(select, d);
+        let map_out: fn(_) -> _ = TupleOutput4::from_nested;
+        let map_in: fn(_) -> _ = |event: TupleOutput4<_, _, _, _>| Some(event.into_nested());
+        let map_info: fn(_) -> _ = TupleOutput4::from_nested;
+        let unmap_info: fn(_) -> _ = TupleOutput4::into_nested;
+        let events = MapInEvent::new(MapOutEvent::new(select, map_out), map_in);
+        MapOutboundOpenInfo::new(events, map_info, unmap_info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{future, prelude::*};
+    use nodes::handled_node::NodeHandlerEndpoint;
+    use nodes::protocols_handler::{ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr};
+    use std::{io, io::Cursor, iter};
+    use upgrade::{self, ConnectionUpgrade};
+
+    type Substream = Cursor<Vec<u8>>;
+
+    /// Upgrade that advertises a single protocol name.
+    #[derive(Debug, Clone)]
+    struct NamedUpgrade(&'static str);
+
+    impl ConnectionUpgrade<Substream> for NamedUpgrade {
+        type NamesIter = iter::Once<(Bytes, ())>;
+        type UpgradeIdentifier = ();
+
+        fn protocol_names(&self) -> Self::NamesIter {
+            iter::once((Bytes::from(self.0), ()))
+        }
+
+        type Output = ();
+        type Future = future::FutureResult<(), io::Error>;
+
+        fn upgrade(self, _: Substream, _: (), _: upgrade::Endpoint) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    /// Handler that produces the events injected in it, and requests an outbound substream for
+    /// each of them.
+    struct EchoHandler {
+        name: &'static str,
+        events: Vec<u32>,
+        requests: Vec<u32>,
+    }
+
+    impl EchoHandler {
+        fn new(name: &'static str) -> Self {
+            EchoHandler {
+                name,
+                events: Vec::new(),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl ProtocolsHandler for EchoHandler {
+        type InEvent = u32;
+        type OutEvent = u32;
+        type Substream = Substream;
+        type Protocol = NamedUpgrade;
+        type OutboundOpenInfo = u32;
+
+        fn listen_protocol(&self) -> Self::Protocol {
+            NamedUpgrade(self.name)
+        }
+
+        fn inject_fully_negotiated(
+            &mut self,
+            _: (),
+            _: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        ) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn inject_event(&mut self, event: u32) {
+            self.events.push(event);
+            self.requests.push(event);
+        }
+
+        fn inject_dial_upgrade_error(&mut self, _: u32, _: ProtocolsHandlerUpgrErr) {}
+
+        fn inject_inbound_closed(&mut self) {}
+
+        fn shutdown(&mut self) {}
+
+        fn poll(
+            &mut self,
+        ) -> Poll<Option<ProtocolsHandlerEvent<NamedUpgrade, u32, u32>>, io::Error> {
+            if let Some(event) = self.events.pop() {
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::Custom(event))));
+            }
+            if let Some(info) = self.requests.pop() {
+                return Ok(Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade: NamedUpgrade(self.name),
+                    info,
+                    timeout: None,
+                    priority: 0,
+                })));
+            }
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn all_the_protocols_are_advertised() {
+        let handler = tuple_handler((
+            EchoHandler::new("/a/1.0.0"),
+            EchoHandler::new("/b/1.0.0"),
+            EchoHandler::new("/c/1.0.0"),
+            EchoHandler::new("/d/1.0.0"),
+        ));
+        let names = handler
+            .listen_protocol()
+            .protocol_names()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["/a/1.0.0", "/b/1.0.0", "/c/1.0.0", "/d/1.0.0"]);
+    }
+
+    #[test]
+    fn events_and_requests_are_tagged_with_the_position() {
+        let mut handler = tuple_handler((
+            EchoHandler::new("/a/1.0.0"),
+            EchoHandler::new("/b/1.0.0"),
+            EchoHandler::new("/c/1.0.0"),
+        ));
+        handler.inject_event(TupleOutput3::Second(5));
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::Custom(TupleOutput3::Second(5)))) => (),
+            _ => panic!("expected the event of the second handler"),
+        }
+        match handler.poll().unwrap() {
+            Async::Ready(Some(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                info: TupleOutput3::Second(5),
+                ..
+            })) => (),
+            _ => panic!("expected the request of the second handler"),
+        }
+        assert!(handler.poll().unwrap().is_not_ready());
+    }
+}
@@ -221,7 +221,9 @@ where
     {
         let connected_point = self.to_connected_point();
         let handler = builder(&connected_point);
-        let id = self.active_nodes.add_reach_attempt(self.upgrade, handler);
+        let remote_addr = self.send_back_addr.clone();
+        let id = self.active_nodes.add_reach_attempt(self.upgrade, handler, Endpoint::Listener,
+                                                     remote_addr);
         self.other_reach_attempts.push((
             id,
             connected_point,
@@ -393,8 +395,9 @@ where
             Err((_, addr)) => return Err(addr),
         };
 
+        let reach_id = self.active_nodes.add_reach_attempt(future, handler, Endpoint::Dialer,
+                                                           addr.clone());
         let connected_point = ConnectedPoint::Dialer { address: addr };
-        let reach_id = self.active_nodes.add_reach_attempt(future, handler);
         self.reach_attempts.other_reach_attempts.push((reach_id, connected_point));
         Ok(())
     }
@@ -486,12 +489,12 @@ where
                         Err(IoError::new(IoErrorKind::Other, msg))
                     }
                 });
-                self.active_nodes.add_reach_attempt(fut, handler)
+                self.active_nodes.add_reach_attempt(fut, handler, Endpoint::Dialer, first.clone())
             },
             Err((_, addr)) => {
                 let msg = format!("unsupported multiaddr {}", addr);
                 let fut = future::err(IoError::new(IoErrorKind::Other, msg));
-                self.active_nodes.add_reach_attempt(fut, handler)
+                self.active_nodes.add_reach_attempt(fut, handler, Endpoint::Dialer, addr)
             },
         };
 
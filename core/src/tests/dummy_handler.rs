@@ -25,7 +25,7 @@ use std::io::{self, Error as IoError};
 use super::dummy_muxer::DummyMuxer;
 use futures::prelude::*;
 use muxing::SubstreamRef;
-use nodes::handled_node::{NodeHandler, NodeHandlerEndpoint, NodeHandlerEvent};
+use nodes::handled_node::{ConnectionInfo, NodeHandler, NodeHandlerEndpoint, NodeHandlerEvent};
 use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -58,6 +58,7 @@ pub(crate) enum Event {
     Substream(Option<usize>),
     OutboundClosed,
     InboundClosed,
+    ConnectionInfo(ConnectionInfo),
 }
 
 impl NodeHandler for Handler {
@@ -88,6 +89,9 @@ impl NodeHandler for Handler {
     fn inject_event(&mut self, inevent: Self::InEvent) {
         self.events.push(inevent)
     }
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.events.push(Event::ConnectionInfo(info))
+    }
     fn shutdown(&mut self) {
         self.state = Some(HandlerState::Ready(None));
     }
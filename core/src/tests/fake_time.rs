@@ -0,0 +1,99 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Source of time and timers that only move forward when told to, for use in tests.
+
+use nodes::protocols_handler::TimerFactory;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_executor::{self, park::{Park, Unpark}};
+use tokio_timer::{clock::{self, Clock, Now}, timer, Timer};
+
+/// Source of time that only moves forward when told to.
+#[derive(Clone)]
+struct MockNow(Arc<Mutex<Instant>>);
+
+impl Now for MockNow {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// `Park` implementation that never blocks.
+struct MockPark;
+
+impl Park for MockPark {
+    type Unpark = MockPark;
+    type Error = ();
+
+    fn unpark(&self) -> MockPark {
+        MockPark
+    }
+
+    fn park(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn park_timeout(&mut self, _: Duration) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl Unpark for MockPark {
+    fn unpark(&self) {}
+}
+
+/// Timer driven by a `MockNow`, allowing the tests to fire timeouts deterministically.
+pub(crate) struct FakeTime {
+    now: MockNow,
+    timer: Timer<MockPark, Clock>,
+}
+
+impl FakeTime {
+    pub(crate) fn new() -> Self {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let timer = Timer::new_with_now(MockPark, Clock::new_with_now(now.clone()));
+        FakeTime { now, timer }
+    }
+
+    /// Returns a `TimerFactory` for a `NodeHandlerWrapper`.
+    pub(crate) fn factory(&self) -> TimerFactory {
+        TimerFactory::new(Clock::new_with_now(self.now.clone()), self.timer.handle())
+    }
+
+    /// Runs `f` with this clock and timer as the ones of the current execution context, for the
+    /// code that uses `tokio_timer::clock::now()` and the default timer.
+    pub(crate) fn enter<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let clock = Clock::new_with_now(self.now.clone());
+        let mut enter = tokio_executor::enter().expect("nested execution context");
+        timer::with_default(&self.timer.handle(), &mut enter, |enter| {
+            clock::with_default(&clock, enter, |_| f())
+        })
+    }
+
+    /// Moves the time forward, and fires the timers that have elapsed.
+    pub(crate) fn advance(&mut self, duration: Duration) {
+        *(self.now.0).lock().unwrap() += duration;
+        self.timer.turn(Some(Duration::from_secs(0))).unwrap();
+    }
+}
@@ -26,3 +26,9 @@ pub(crate) mod dummy_transport;
 
 #[cfg(test)]
 pub(crate) mod dummy_handler;
+
+#[cfg(test)]
+pub(crate) mod test_protocols_handler;
+
+#[cfg(test)]
+pub(crate) mod fake_time;
@@ -0,0 +1,221 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! `ProtocolsHandler` wrapper that records every call made to it, for use in tests.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use nodes::handled_node::{ConnectionInfo, NodeHandlerEndpoint};
+use nodes::protocols_handler::{
+    KeepAlive, NegotiationTiming, ProtocolsHandler, ProtocolsHandlerEvent,
+    ProtocolsHandlerUpgrErr,
+};
+use std::io;
+use tokio_mock_task::MockTask;
+use {ConnectionUpgrade, Endpoint};
+
+/// A call that has been made to a `TestProtocolsHandler`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Call<TInEvent, TOutboundOpenInfo> {
+    FullyNegotiated(NodeHandlerEndpoint<TOutboundOpenInfo>),
+    ProtocolNegotiated(Bytes, Endpoint),
+    Event(TInEvent),
+    DialUpgradeError(TOutboundOpenInfo, String),
+    ListenUpgradeError(io::ErrorKind),
+    InboundClosed,
+    OutboundClosed,
+    OutboundReady(bool),
+    Shutdown,
+    Poll,
+}
+
+/// Event produced by the `ProtocolsHandler` wrapped in a `TestProtocolsHandler`.
+pub(crate) type TestHandlerEvent<TProtoHandler> = ProtocolsHandlerEvent<
+    <TProtoHandler as ProtocolsHandler>::Protocol,
+    <TProtoHandler as ProtocolsHandler>::OutboundOpenInfo,
+    <TProtoHandler as ProtocolsHandler>::OutEvent,
+>;
+
+/// Wraps around a `ProtocolsHandler` and records a timeline of all the calls made to it.
+pub(crate) struct TestProtocolsHandler<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+{
+    inner: TProtoHandler,
+    calls: Vec<Call<TProtoHandler::InEvent, TProtoHandler::OutboundOpenInfo>>,
+}
+
+impl<TProtoHandler> TestProtocolsHandler<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::InEvent: Clone,
+    TProtoHandler::OutboundOpenInfo: Clone,
+{
+    pub(crate) fn new(inner: TProtoHandler) -> Self {
+        TestProtocolsHandler {
+            inner,
+            calls: Vec::new(),
+        }
+    }
+
+    /// Returns the calls that have been made so far, in order.
+    pub(crate) fn calls(&self) -> &[Call<TProtoHandler::InEvent, TProtoHandler::OutboundOpenInfo>] {
+        &self.calls
+    }
+
+    /// Returns the wrapped handler.
+    pub(crate) fn inner(&self) -> &TProtoHandler {
+        &self.inner
+    }
+
+    /// Polls the handler from within a mock task until it returns `NotReady`, `Ready(None)` or an
+    /// error. Returns the events that have been produced, and whether the handler is finished.
+    pub(crate) fn poll_all(
+        &mut self,
+    ) -> Result<(Vec<TestHandlerEvent<TProtoHandler>>, bool), io::Error> {
+        let mut task = MockTask::new();
+        let mut events = Vec::new();
+        loop {
+            match task.enter(|| self.poll())? {
+                Async::Ready(Some(event)) => events.push(event),
+                Async::Ready(None) => return Ok((events, true)),
+                Async::NotReady => return Ok((events, false)),
+            }
+        }
+    }
+}
+
+impl<TProtoHandler> ProtocolsHandler for TestProtocolsHandler<TProtoHandler>
+where
+    TProtoHandler: ProtocolsHandler,
+    TProtoHandler::InEvent: Clone,
+    TProtoHandler::OutboundOpenInfo: Clone,
+{
+    type InEvent = TProtoHandler::InEvent;
+    type OutEvent = TProtoHandler::OutEvent;
+    type Substream = TProtoHandler::Substream;
+    type Protocol = TProtoHandler::Protocol;
+    type OutboundOpenInfo = TProtoHandler::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> Self::Protocol {
+        self.inner.listen_protocol()
+    }
+
+    fn listen_protocol_version(&self) -> Option<u64> {
+        self.inner.listen_protocol_version()
+    }
+
+    fn should_accept_inbound(&self) -> bool {
+        self.inner.should_accept_inbound()
+    }
+
+    fn should_open_outbound(&self, info: &Self::OutboundOpenInfo) -> bool {
+        self.inner.should_open_outbound(info)
+    }
+
+    fn inject_fully_negotiated(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.calls.push(Call::FullyNegotiated(endpoint.clone()));
+        self.inner.inject_fully_negotiated(protocol, endpoint)
+    }
+
+    fn inject_fully_negotiated_with_timing(
+        &mut self,
+        protocol: <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output,
+        endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
+        timing: NegotiationTiming,
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<Self::Substream>>::Output> {
+        self.calls.push(Call::FullyNegotiated(endpoint.clone()));
+        self.inner.inject_fully_negotiated_with_timing(protocol, endpoint, timing)
+    }
+
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.calls.push(Call::Event(event.clone()));
+        self.inner.inject_event(event)
+    }
+
+    fn ready_for_event(&self) -> bool {
+        self.inner.ready_for_event()
+    }
+
+    fn poll_ready_event(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_ready_event()
+    }
+
+    fn inject_connection_info(&mut self, info: ConnectionInfo) {
+        self.inner.inject_connection_info(info)
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr,
+    ) {
+        self.calls.push(Call::DialUpgradeError(info.clone(), error.to_string()));
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    fn inject_listen_upgrade_error(&mut self, error: io::Error) {
+        self.calls.push(Call::ListenUpgradeError(error.kind()));
+        self.inner.inject_listen_upgrade_error(error)
+    }
+
+    fn inject_protocol_negotiated(&mut self, name: &Bytes, endpoint: Endpoint) {
+        self.calls.push(Call::ProtocolNegotiated(name.clone(), endpoint));
+        self.inner.inject_protocol_negotiated(name, endpoint)
+    }
+
+    fn inject_inbound_closed(&mut self) {
+        self.calls.push(Call::InboundClosed);
+        self.inner.inject_inbound_closed()
+    }
+
+    fn inject_outbound_closed(&mut self) {
+        self.calls.push(Call::OutboundClosed);
+        self.inner.inject_outbound_closed()
+    }
+
+    fn inject_outbound_ready(&mut self, ready: bool) {
+        self.calls.push(Call::OutboundReady(ready));
+        self.inner.inject_outbound_ready(ready)
+    }
+
+    fn shutdown(&mut self) {
+        self.calls.push(Call::Shutdown);
+        self.inner.shutdown()
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        Option<ProtocolsHandlerEvent<Self::Protocol, Self::OutboundOpenInfo, Self::OutEvent>>,
+        io::Error,
+    > {
+        self.calls.push(Call::Poll);
+        self.inner.poll()
+    }
+}
@@ -20,7 +20,8 @@
 
 use bytes::Bytes;
 use futures::{prelude::*, future::Either};
-use multistream_select::{self, DialerSelectFuture, ListenerSelectFuture};
+use multistream_select::{self, DialerSelectFuture, ListenerSelectFuture, ProtocolChoiceError};
+use multistream_select::protocol::MultistreamSelectError;
 use std::{io::{Error as IoError, ErrorKind as IoErrorKind}, mem};
 use tokio_io::{AsyncRead, AsyncWrite};
 use upgrade::{ConnectionUpgrade, Endpoint};
@@ -35,9 +36,18 @@ where
     U::NamesIter: Clone, // TODO: not elegant
     C: AsyncRead + AsyncWrite,
 {
+    debug!("Starting protocol negotiation");
+    let iter = NamedProtocolNames(upgrade.protocol_names());
+    let future = NegotiationFuture {
+        inner: match e {
+            Endpoint::Listener => Either::A(multistream_select::listener_select_proto(conn, iter)),
+            Endpoint::Dialer => Either::B(multistream_select::dialer_select_proto(conn, iter)),
+        }
+    };
+
     UpgradeApplyFuture {
         inner: UpgradeApplyState::Init {
-            future: negotiate(conn, &upgrade, e),
+            future,
             upgrade,
             endpoint: e,
         }
@@ -59,16 +69,42 @@ where
     C: AsyncRead + AsyncWrite
 {
     Init {
-        future: NegotiationFuture<C, ProtocolNames<U::NamesIter>, U::UpgradeIdentifier>,
+        future: NegotiationFuture<
+            C,
+            NamedProtocolNames<U::NamesIter>,
+            (Bytes, U::UpgradeIdentifier),
+        >,
         upgrade: U,
         endpoint: Endpoint
     },
     Upgrade {
-        future: U::Future
+        future: U::Future,
+        /// Name of the protocol that has been negotiated.
+        protocol: Bytes
+    },
+    Done {
+        /// Name of the protocol that has been negotiated.
+        protocol: Bytes
     },
     Undefined
 }
 
+impl<C, U> UpgradeApplyFuture<C, U>
+where
+    U: ConnectionUpgrade<C>,
+    C: AsyncRead + AsyncWrite
+{
+    /// Returns the name of the protocol that has been negotiated, or `None` if the negotiation
+    /// is still in progress. The name remains available once the upgrade has completed.
+    pub fn negotiated_protocol(&self) -> Option<&Bytes> {
+        match self.inner {
+            UpgradeApplyState::Upgrade { ref protocol, .. } => Some(protocol),
+            UpgradeApplyState::Done { ref protocol } => Some(protocol),
+            UpgradeApplyState::Init { .. } | UpgradeApplyState::Undefined => None,
+        }
+    }
+}
+
 impl<C, U> Future for UpgradeApplyFuture<C, U>
 where
     U: ConnectionUpgrade<C>,
@@ -82,7 +118,7 @@ where
         loop {
             match mem::replace(&mut self.inner, UpgradeApplyState::Undefined) {
                 UpgradeApplyState::Init { mut future, upgrade, endpoint } => {
-                    let (upgrade_id, connection) = match future.poll()? {
+                    let ((protocol, upgrade_id), connection) = match future.poll()? {
                         Async::Ready(x) => x,
                         Async::NotReady => {
                             self.inner = UpgradeApplyState::Init { future, upgrade, endpoint };
@@ -90,17 +126,19 @@ where
                         }
                     };
                     self.inner = UpgradeApplyState::Upgrade {
-                        future: upgrade.upgrade(connection, upgrade_id, endpoint)
+                        future: upgrade.upgrade(connection, upgrade_id, endpoint),
+                        protocol
                     };
                 }
-                UpgradeApplyState::Upgrade { mut future } => {
+                UpgradeApplyState::Upgrade { mut future, protocol } => {
                     match future.poll() {
                         Ok(Async::NotReady) => {
-                            self.inner = UpgradeApplyState::Upgrade { future };
+                            self.inner = UpgradeApplyState::Upgrade { future, protocol };
                             return Ok(Async::NotReady)
                         }
                         Ok(Async::Ready(x)) => {
                             debug!("Successfully applied negotiated protocol");
+                            self.inner = UpgradeApplyState::Done { protocol };
                             return Ok(Async::Ready(x))
                         }
                         Err(e) => {
@@ -109,7 +147,7 @@ where
                         }
                     }
                 }
-                UpgradeApplyState::Undefined =>
+                UpgradeApplyState::Done { .. } | UpgradeApplyState::Undefined =>
                     panic!("UpgradeApplyState::poll called after completion")
             }
         }
@@ -163,7 +201,15 @@ where
                 Ok(Async::Ready(x))
             }
             Err(e) => {
-                let err = IoError::new(IoErrorKind::Other, e);
+                // The original error is kept as the payload of the `IoError`, so that it can be
+                // recovered with `get_ref()` and `downcast_ref()`.
+                let kind = match e {
+                    ProtocolChoiceError::MultistreamSelectError(
+                        MultistreamSelectError::IoError(ref err),
+                    ) => err.kind(),
+                    _ => IoErrorKind::Other,
+                };
+                let err = IoError::new(kind, e);
                 debug!("Error while negotiated protocol upgrade: {:?}", err);
                 Err(err)
             }
@@ -171,6 +217,27 @@ where
     }
 }
 
+/// Same as `ProtocolNames`, except that the name of each protocol is added to its identifier,
+/// so that we know which name has been negotiated. Used by `apply`.
+#[derive(Clone)]
+struct NamedProtocolNames<I>(I);
+
+impl<I, Id> Iterator for NamedProtocolNames<I>
+where
+    I: Iterator<Item=(Bytes, Id)>
+{
+    type Item = (Bytes, fn(&Bytes, &Bytes) -> bool, (Bytes, Id));
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let f = <Bytes as PartialEq>::eq as fn(&Bytes, &Bytes) -> bool;
+        self.0.next().map(|(b, id)| (b.clone(), f, (b, id)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
 /// Iterator adapter which adds equality matching predicates to items.
 /// Used in `NegotiationFuture`.
 #[derive(Clone)]
@@ -192,4 +259,77 @@ where
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor, Read, Write};
+    use upgrade::PlainTextConfig;
+
+    /// Stream that produces some predefined data, then fails with the given error. Everything
+    /// written to it is discarded.
+    struct TestStream(Cursor<Vec<u8>>, IoErrorKind);
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(self.1.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl AsyncRead for TestStream {}
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for TestStream {
+        fn shutdown(&mut self) -> Poll<(), IoError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn rejection_is_recoverable() {
+        let mut data = Vec::new();
+        data.push(19);
+        data.extend_from_slice(b"/multistream/1.0.0\n");
+        data.push(3);
+        data.extend_from_slice(b"na\n");
+        let stream = TestStream(Cursor::new(data), IoErrorKind::UnexpectedEof);
+
+        let err = negotiate::<_, TestStream, _>(stream, &PlainTextConfig, Endpoint::Dialer)
+            .wait()
+            .err()
+            .expect("negotiation should fail");
+        assert_eq!(err.kind(), IoErrorKind::Other);
+        match err.get_ref().and_then(|err| err.downcast_ref::<ProtocolChoiceError>()) {
+            Some(ProtocolChoiceError::NoProtocolFound) => (),
+            _ => panic!("the original error was lost"),
+        }
+    }
+
+    #[test]
+    fn io_error_kind_is_preserved() {
+        let stream = TestStream(Cursor::new(Vec::new()), IoErrorKind::ConnectionReset);
 
+        let err = negotiate::<_, TestStream, _>(stream, &PlainTextConfig, Endpoint::Dialer)
+            .wait()
+            .err()
+            .expect("negotiation should fail");
+        assert_eq!(err.kind(), IoErrorKind::ConnectionReset);
+        match err.get_ref().and_then(|err| err.downcast_ref::<ProtocolChoiceError>()) {
+            Some(ProtocolChoiceError::MultistreamSelectError(
+                MultistreamSelectError::IoError(ref err),
+            )) => assert_eq!(err.kind(), IoErrorKind::ConnectionReset),
+            _ => panic!("the original error was lost"),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use std::{fmt, io::Error as IoError, vec};
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{ConnectionUpgrade, Endpoint};
+
+/// Erases the type of an upgrade, so that upgrades of different types but with the same output
+/// can be used interchangeably.
+///
+/// The upgrade must be `Clone`, `Send` and `'static`, and so must be its future.
+#[inline]
+pub fn boxed<C, U>(upgrade: U) -> BoxUpgrade<C, U::Output>
+where
+    U: ConnectionUpgrade<C> + Clone + Send + 'static,
+    U::Future: Send + 'static,
+{
+    BoxUpgrade {
+        inner: Box::new(upgrade),
+    }
+}
+
+/// See `upgrade::boxed`.
+pub struct BoxUpgrade<C, O> {
+    inner: Box<AbstractUpgrade<C, O> + Send>,
+}
+
+impl<C, O> Clone for BoxUpgrade<C, O> {
+    #[inline]
+    fn clone(&self) -> Self {
+        BoxUpgrade {
+            inner: self.inner.clone_box(),
+        }
+    }
+}
+
+impl<C, O> fmt::Debug for BoxUpgrade<C, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("BoxUpgrade").finish()
+    }
+}
+
+impl<C, O> ConnectionUpgrade<C> for BoxUpgrade<C, O>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type NamesIter = vec::IntoIter<(Bytes, usize)>;
+    // Index of the protocol in the list returned by the underlying upgrade.
+    type UpgradeIdentifier = usize;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.inner.protocol_names().into_iter()
+    }
+
+    type Output = O;
+    type Future = Box<Future<Item = O, Error = IoError> + Send>;
+
+    #[inline]
+    fn upgrade(self, socket: C, id: Self::UpgradeIdentifier, ty: Endpoint) -> Self::Future {
+        self.inner.upgrade(socket, id, ty)
+    }
+}
+
+/// Object-safe equivalent of `ConnectionUpgrade`.
+trait AbstractUpgrade<C, O> {
+    fn protocol_names(&self) -> Vec<(Bytes, usize)>;
+    fn upgrade(
+        self: Box<Self>,
+        socket: C,
+        id: usize,
+        ty: Endpoint,
+    ) -> Box<Future<Item = O, Error = IoError> + Send>;
+    fn clone_box(&self) -> Box<AbstractUpgrade<C, O> + Send>;
+}
+
+impl<C, U> AbstractUpgrade<C, U::Output> for U
+where
+    U: ConnectionUpgrade<C> + Clone + Send + 'static,
+    U::Future: Send + 'static,
+{
+    fn protocol_names(&self) -> Vec<(Bytes, usize)> {
+        ConnectionUpgrade::protocol_names(self)
+            .enumerate()
+            .map(|(index, (name, _))| (name, index))
+            .collect()
+    }
+
+    fn upgrade(
+        self: Box<Self>,
+        socket: C,
+        id: usize,
+        ty: Endpoint,
+    ) -> Box<Future<Item = U::Output, Error = IoError> + Send> {
+        // The names of an upgrade don't change, so we find the identifier again from the index.
+        let (_, id) = ConnectionUpgrade::protocol_names(&*self)
+            .nth(id)
+            .expect("the index was produced by protocol_names; qed");
+        Box::new(ConnectionUpgrade::upgrade(*self, socket, id, ty))
+    }
+
+    #[inline]
+    fn clone_box(&self) -> Box<AbstractUpgrade<C, U::Output> + Send> {
+        Box::new(self.clone())
+    }
+}
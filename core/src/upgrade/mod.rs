@@ -19,15 +19,19 @@
 // DEALINGS IN THE SOFTWARE.
 
 pub mod apply;
+pub mod boxed;
 pub mod choice;
 pub mod denied;
 pub mod loop_upg;
 pub mod map;
+pub mod optional;
 pub mod plaintext;
+pub mod shared;
 pub mod toggleable;
 pub mod traits;
 
 pub use self::apply::{apply, negotiate};
+pub use self::boxed::{boxed, BoxUpgrade};
 pub use self::choice::{or, OrUpgrade};
 pub use self::denied::DeniedConnectionUpgrade;
 pub use self::loop_upg::{loop_upg, Loop};
@@ -0,0 +1,89 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use futures::future;
+use std::io::Error as IoError;
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{ConnectionUpgrade, Endpoint};
+
+/// An `Option<U>` is an upgrade that behaves like `U` if it is `Some`, and that doesn't advertise
+/// any protocol if it is `None`.
+impl<C, U> ConnectionUpgrade<C> for Option<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<C>,
+{
+    type NamesIter = OptionalIter<U::NamesIter>;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        OptionalIter {
+            inner: self.as_ref().map(|upgrade| upgrade.protocol_names()),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = future::Either<future::Empty<U::Output, IoError>, U::Future>;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+    ) -> Self::Future {
+        match self {
+            Some(inner) => future::Either::B(inner.upgrade(socket, id, ty)),
+            None => future::Either::A(future::empty()),
+        }
+    }
+}
+
+/// Iterator returned by the `ConnectionUpgrade` implementation of `Option`.
+#[derive(Debug, Clone)]
+pub struct OptionalIter<I> {
+    inner: Option<I>,
+}
+
+impl<I> Iterator for OptionalIter<I>
+where I: Iterator
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner {
+            Some(ref mut inner) => inner.next(),
+            None => None,
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.inner {
+            Some(ref inner) => inner.size_hint(),
+            None => (0, Some(0)),
+        }
+    }
+}
+
+impl<I> ExactSizeIterator for OptionalIter<I>
+where I: ExactSizeIterator {}
@@ -0,0 +1,57 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use upgrade::{ConnectionUpgrade, Endpoint};
+
+/// An `Arc<U>` is an upgrade that behaves like `U`.
+///
+/// Cloning an `Arc` is cheap, which makes it suitable for upgrades that hold a sizable
+/// configuration and that are produced often, such as the one returned by
+/// `ProtocolsHandler::listen_protocol`. The inner upgrade is only cloned if the `Arc` is shared
+/// at the time the negotiation succeeds, as `upgrade` needs to take ownership of it.
+impl<C, U> ConnectionUpgrade<C> for Arc<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<C> + Clone,
+{
+    type NamesIter = U::NamesIter;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        (**self).protocol_names()
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+    ) -> Self::Future {
+        let inner = Arc::try_unwrap(self).unwrap_or_else(|shared| (*shared).clone());
+        inner.upgrade(socket, id, ty)
+    }
+}
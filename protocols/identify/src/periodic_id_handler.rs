@@ -20,7 +20,9 @@
 
 use futures::prelude::*;
 use libp2p_core::nodes::handled_node::NodeHandlerEndpoint;
-use libp2p_core::nodes::protocols_handler::{ProtocolsHandler, ProtocolsHandlerEvent};
+use libp2p_core::nodes::protocols_handler::{
+    ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+};
 use libp2p_core::upgrade::{self, toggleable::Toggleable};
 use libp2p_core::{ConnectionUpgrade, Multiaddr};
 use std::io;
@@ -104,7 +106,7 @@ where
         &mut self,
         protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
         _endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
-    ) {
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
         match protocol {
             IdentifyOutput::RemoteInfo {
                 info,
@@ -114,6 +116,7 @@ where
                     info,
                     observed_addr,
                 });
+                Ok(())
             }
             IdentifyOutput::Sender { .. } => unreachable!(
                 "Sender can only be produced if we listen for the identify \
@@ -129,8 +132,12 @@ where
     fn inject_inbound_closed(&mut self) {}
 
     #[inline]
-    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, err: io::Error) {
-        self.pending_result = Some(PeriodicIdentificationEvent::IdentificationError(err));
+    fn inject_dial_upgrade_error(
+        &mut self,
+        _: Self::OutboundOpenInfo,
+        err: ProtocolsHandlerUpgrErr,
+    ) {
+        self.pending_result = Some(PeriodicIdentificationEvent::IdentificationError(err.into()));
         if let Some(ref mut next_id) = self.next_id {
             next_id.reset(Instant::now() + TRY_AGAIN_ON_ERR);
         }
@@ -171,7 +178,12 @@ where
                 next_id.reset(Instant::now() + DELAY_TO_NEXT_ID);
                 let mut upgrade = self.config.clone();
                 upgrade.enable();
-                let ev = ProtocolsHandlerEvent::OutboundSubstreamRequest { upgrade, info: () };
+                let ev = ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    upgrade,
+                    info: (),
+                    timeout: None,
+                    priority: 0,
+                };
                 Ok(Async::Ready(Some(ev)))
             }
             Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
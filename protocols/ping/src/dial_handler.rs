@@ -21,6 +21,7 @@
 use futures::prelude::*;
 use libp2p_core::{
     nodes::{NodeHandlerEndpoint, ProtocolsHandler, ProtocolsHandlerEvent},
+    nodes::protocols_handler::{KeepAlive, ProtocolsHandlerUpgrErr},
     upgrade::toggleable,
     ConnectionUpgrade,
 };
@@ -165,7 +166,7 @@ where
         &mut self,
         protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
         _endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
-    ) {
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
         match protocol {
             PingOutput::Pinger(mut substream) => {
                 debug_assert!(_endpoint.is_dialer());
@@ -174,12 +175,18 @@ where
                         // We always upgrade with the intent of immediately pinging.
                         substream.ping(Instant::now());
                         self.out_state = OutState::WaitingForPong { substream, expires };
+                        Ok(())
+                    }
+                    state => {
+                        // We didn't request this substream.
+                        self.out_state = state;
+                        Err(PingOutput::Pinger(substream))
                     }
-                    _ => (),
                 }
             }
-            PingOutput::Ponger(_) => {
+            PingOutput::Ponger(substream) => {
                 debug_assert!(false, "Received an unexpected incoming ping substream");
+                Err(PingOutput::Ponger(substream))
             }
         }
     }
@@ -189,7 +196,7 @@ where
     fn inject_inbound_closed(&mut self) {}
 
     #[inline]
-    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: io::Error) {
+    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: ProtocolsHandlerUpgrErr) {
         // In case of error while upgrading, there's not much we can do except shut down.
         // TODO: we assume that the error is about ping not being supported, which is not
         //       necessarily the case
@@ -215,6 +222,20 @@ where
         }
     }
 
+    fn connection_keep_alive(&self) -> KeepAlive {
+        // Between two pings, the periodic pinging shouldn't be a reason to keep the connection
+        // alive.
+        match self.out_state {
+            OutState::NeedToOpen { .. }
+            | OutState::Upgrading { .. }
+            | OutState::WaitingForPong { .. } => KeepAlive::Forever,
+            OutState::Idle { .. }
+            | OutState::Disabled
+            | OutState::Shutdown
+            | OutState::Poisoned => KeepAlive::Now,
+        }
+    }
+
     fn poll(
         &mut self,
     ) -> Poll<
@@ -255,6 +276,8 @@ where
                         ProtocolsHandlerEvent::OutboundSubstreamRequest {
                             upgrade: self.ping_config,
                             info: (),
+                            timeout: None,
+                            priority: 0,
                         },
                     )));
                 }
@@ -22,6 +22,7 @@ use arrayvec::ArrayVec;
 use futures::prelude::*;
 use libp2p_core::{
     nodes::{NodeHandlerEndpoint, ProtocolsHandler, ProtocolsHandlerEvent},
+    nodes::protocols_handler::ProtocolsHandlerUpgrErr,
     ConnectionUpgrade,
 };
 use protocol::{Ping, PingListener, PingOutput};
@@ -80,19 +81,22 @@ where
         &mut self,
         protocol: <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output,
         _endpoint: NodeHandlerEndpoint<Self::OutboundOpenInfo>,
-    ) {
+    ) -> Result<(), <Self::Protocol as ConnectionUpgrade<TSubstream>>::Output> {
         if self.shutdown {
-            return;
+            return Err(protocol);
         }
 
         match protocol {
-            PingOutput::Pinger(_) => {
+            PingOutput::Pinger(substream) => {
                 debug_assert!(false, "Received an unexpected outgoing ping substream");
+                Err(PingOutput::Pinger(substream))
             }
             PingOutput::Ponger(listener) => {
                 debug_assert!(_endpoint.is_listener());
-                // Try insert the element, but don't care if the list is full.
-                let _ = self.ping_in_substreams.try_push(listener);
+                // Try insert the element, and give the substream back if the list is full.
+                self.ping_in_substreams
+                    .try_push(listener)
+                    .map_err(|err| PingOutput::Ponger(err.element()))
             }
         }
     }
@@ -104,7 +108,8 @@ where
     fn inject_inbound_closed(&mut self) {}
 
     #[inline]
-    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: io::Error) {}
+    fn inject_dial_upgrade_error(&mut self, _: Self::OutboundOpenInfo, _: ProtocolsHandlerUpgrErr) {
+    }
 
     #[inline]
     fn shutdown(&mut self) {